@@ -0,0 +1,491 @@
+//! Derive and attribute macros for [PK Command](https://docs.rs/pk-command).
+//!
+//! Do not depend on this crate directly. Enable the `macros` feature of `pk-command` instead,
+//! which re-exports [`PkVariables`](macro@PkVariables) and [`pk_methods`](macro@pk_methods).
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{
+    Attribute, Data, DeriveInput, Error, Fields, FnArg, Ident, ImplItem, ItemImpl, LitStr, Path,
+    Result, Type,
+};
+
+/// Implements `PkVariableAccessor` for a struct, exposing its fields as variables.
+///
+/// Each field becomes a variable named after the field in upper case, so a field has to be named
+/// with 5 characters, or carry a `name`. Names are checked at compile time: they must be exactly 5
+/// printable ASCII characters, and unique within the struct.
+///
+/// As `PkVariableAccessor::set()` only gets `&self`, the fields must provide interior mutability
+/// through `pk_command::codec::VariableCell`, which is implemented for [`Cell`](core::cell::Cell)
+/// and [`RefCell`](core::cell::RefCell). The values are encoded with `PkCodec`.
+///
+/// The generated code only needs `alloc`, so it works in `no_std` environments as well.
+///
+/// # Field attributes
+/// - `#[pk(name = "SPEED")]`: The name of the variable.
+/// - `#[pk(read_only)]`: The Host cannot write the variable. `SENDV` is refused with an `ERROR`.
+/// - `#[pk(write_only)]`: The Host cannot read the variable. `REQUV` is refused with an `ERROR`.
+/// - `#[pk(codec = Cbor)]`: Encodes the value through a wrapper codec, such as
+///   `pk_command::codec::Cbor` or `pk_command::codec::Postcard`. The wrapper must be a tuple
+///   struct around the value.
+/// - `#[pk(on_change = Self::speed_changed)]`: Calls `Self::speed_changed(&self)` after the Host
+///   sets the variable. Like the listeners of `PkHashmapVariable`, it runs within `poll()`.
+/// - `#[pk(description = "Fan speed in RPM")]`: The description listed by `LISTO`.
+/// - `#[pk(skip)]`: The field is not a variable.
+///
+/// # Example
+/// ```
+/// use pk_command::PkVariables;
+/// use std::cell::{Cell, RefCell};
+///
+/// #[derive(PkVariables)]
+/// struct Fan {
+///     #[pk(description = "Fan speed in RPM", on_change = Self::apply)]
+///     speed: Cell<u16>,
+///     #[pk(name = "VERSN", read_only)]
+///     version: RefCell<String>,
+///     #[pk(skip)]
+///     applied: Cell<u16>,
+/// }
+///
+/// impl Fan {
+///     fn apply(&self) {
+///         self.applied.set(self.speed.get());
+///     }
+/// }
+/// ```
+///
+/// A name that is not 5 characters long does not compile:
+/// ```compile_fail
+/// use pk_command::PkVariables;
+/// use std::cell::Cell;
+///
+/// #[derive(PkVariables)]
+/// struct Fan {
+///     #[pk(name = "FANSPEED")]
+///     speed: Cell<u16>,
+/// }
+/// ```
+#[proc_macro_derive(PkVariables, attributes(pk))]
+pub fn derive_pk_variables(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    expand_variables(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Implements `PkMethodAccessor` for the type of an `impl` block, exposing its methods.
+///
+/// Each method becomes a PK method named after the function in upper case, so it has to be named
+/// with 5 characters, or carry a `name`. Like with [`PkVariables`](macro@PkVariables), names are
+/// checked at compile time.
+///
+/// Methods take `&self`. Their arguments are decoded from the parameter with `PkCodec`: no
+/// argument expects no data, one argument is decoded as is, and several arguments are decoded as
+/// a tuple. A parameter that does not decode is reported to the Host with `ERROR`. The returned
+/// value is encoded with `PkCodec` as the result.
+///
+/// The generated code only needs `alloc`, so it works in `no_std` environments as well.
+///
+/// # Method attributes
+/// - `#[pk(name = "ADDXY")]`: The name of the method.
+/// - `#[pk(pollable)]`: The method returns a `Pin<Box<dyn Pollable>>` which is handed to the
+///   state machine as is, for methods that run for a while.
+/// - `#[pk(guard = Self::unlocked)]`: Calls `Self::unlocked(&self)` when the Host starts an
+///   `INVOK`, which is refused with an `ERROR` unless it returns `true`.
+/// - `#[pk(description = "Adds two numbers")]`: The description listed by `LISTO`.
+/// - `#[pk(skip)]`: The function is not a PK method.
+///
+/// # Example
+/// ```
+/// use pk_command::{PkPromise, Pollable, pk_methods};
+/// use std::pin::Pin;
+///
+/// struct Calculator;
+///
+/// #[pk_methods]
+/// impl Calculator {
+///     #[pk(name = "ADDXY", description = "Adds two numbers")]
+///     fn add(&self, a: i32, b: i32) -> i32 {
+///         a + b
+///     }
+///
+///     #[pk(pollable)]
+///     fn sleep(&self, millis: u64) -> Pin<Box<dyn Pollable>> {
+///         PkPromise::execute(move |resolve| {
+///             std::thread::sleep(std::time::Duration::from_millis(millis));
+///             resolve(vec![]);
+///         })
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn pk_methods(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return Error::new(
+            TokenStream2::from(attr).span(),
+            "#[pk_methods] takes no arguments",
+        )
+        .into_compile_error()
+        .into();
+    }
+    let item = syn::parse_macro_input!(item as ItemImpl);
+    expand_methods(item)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// The options of a `#[pk(...)]` attribute. Which ones are allowed depends on the item.
+#[derive(Default)]
+struct Options {
+    name: Option<LitStr>,
+    description: Option<LitStr>,
+    read_only: Option<Span>,
+    write_only: Option<Span>,
+    codec: Option<Path>,
+    on_change: Option<Path>,
+    guard: Option<Path>,
+    pollable: Option<Span>,
+    skip: Option<Span>,
+}
+
+enum Item {
+    Field,
+    Method,
+}
+
+impl Options {
+    fn from_attrs(attrs: &[Attribute], item: Item) -> Result<Options> {
+        let mut options = Options::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("pk")) {
+            attr.parse_nested_meta(|meta| {
+                let key = meta
+                    .path
+                    .get_ident()
+                    .map(Ident::to_string)
+                    .unwrap_or_default();
+                let allowed = match item {
+                    Item::Field => [
+                        "name",
+                        "description",
+                        "read_only",
+                        "write_only",
+                        "codec",
+                        "on_change",
+                        "skip",
+                    ]
+                    .contains(&key.as_str()),
+                    Item::Method => {
+                        ["name", "description", "pollable", "guard", "skip"].contains(&key.as_str())
+                    }
+                };
+                if !allowed {
+                    return Err(meta.error("unknown `pk` option"));
+                }
+                let span = meta.path.span();
+                match key.as_str() {
+                    "name" => options.name = Some(meta.value()?.parse()?),
+                    "description" => options.description = Some(meta.value()?.parse()?),
+                    "codec" => options.codec = Some(meta.value()?.parse()?),
+                    "on_change" => options.on_change = Some(meta.value()?.parse()?),
+                    "guard" => options.guard = Some(meta.value()?.parse()?),
+                    "read_only" => options.read_only = Some(span),
+                    "write_only" => options.write_only = Some(span),
+                    "pollable" => options.pollable = Some(span),
+                    _ => options.skip = Some(span),
+                }
+                Ok(())
+            })?;
+        }
+        if let (Some(_), Some(span)) = (options.read_only, options.write_only) {
+            return Err(Error::new(
+                span,
+                "a variable cannot be both `read_only` and `write_only`",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// Collects the object names of an accessor, and checks them.
+#[derive(Default)]
+struct Names(Vec<String>);
+
+impl Names {
+    /// Returns the name given with `name`, or else the upper-cased `ident`.
+    fn add(&mut self, name: Option<&LitStr>, ident: &Ident) -> Result<LitStr> {
+        let name = match name {
+            Some(name) => name.clone(),
+            None => LitStr::new(
+                &ident.to_string().trim_start_matches("r#").to_uppercase(),
+                ident.span(),
+            ),
+        };
+        let value = name.value();
+        if value.len() != 5 || !value.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(Error::new(
+                name.span(),
+                format!(
+                    "`{value}` is not a valid name: PK object names are exactly 5 printable ASCII characters. Set one with #[pk(name = \"...\")]"
+                ),
+            ));
+        }
+        if self.0.contains(&value) {
+            return Err(Error::new(
+                name.span(),
+                format!("`{value}` is used more than once"),
+            ));
+        }
+        self.0.push(value);
+        Ok(name)
+    }
+}
+
+fn expand_variables(input: DeriveInput) -> Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new(
+                    input.ident.span(),
+                    "#[derive(PkVariables)] needs named fields",
+                ));
+            }
+        },
+        _ => {
+            return Err(Error::new(
+                input.ident.span(),
+                "#[derive(PkVariables)] only works on structs",
+            ));
+        }
+    };
+
+    let mut names = Names::default();
+    let mut getters = Vec::new();
+    let mut setters = Vec::new();
+    let mut modes = Vec::new();
+    let mut infos = Vec::new();
+    for field in fields {
+        let options = Options::from_attrs(&field.attrs, Item::Field)?;
+        if options.skip.is_some() {
+            continue;
+        }
+        let ident = field.ident.as_ref().unwrap();
+        let name = names.add(options.name.as_ref(), ident)?;
+        let ty = &field.ty;
+        let value_ty = quote!(<#ty as ::pk_command::codec::VariableCell>::Value);
+
+        let value = quote!(::pk_command::codec::VariableCell::load(&self.#ident));
+        let encoded = match &options.codec {
+            Some(codec) => quote!(::pk_command::codec::PkCodec::encode(&#codec(#value))),
+            None => quote!(::pk_command::codec::PkCodec::encode(&#value)),
+        };
+        getters.push(quote!(#name => ::core::option::Option::Some(#encoded),));
+
+        let decoded = match &options.codec {
+            Some(codec) => quote! {
+                let #codec(value) =
+                    <#codec<#value_ty> as ::pk_command::codec::PkCodec>::decode(&value)?;
+            },
+            None => quote! {
+                let value = <#value_ty as ::pk_command::codec::PkCodec>::decode(&value)?;
+            },
+        };
+        let on_change = options.on_change.as_ref().map(|f| quote!(#f(self);));
+        setters.push(quote! {
+            #name => {
+                #decoded
+                ::pk_command::codec::VariableCell::store(&self.#ident, value);
+                #on_change
+                ::core::result::Result::Ok(())
+            }
+        });
+
+        // 访问限制由状态机根据 access() 检查，get/set 本身对 Device 上的代码不设限
+        let access = match (options.read_only, options.write_only) {
+            (Some(_), _) => quote!(ReadOnly),
+            (_, Some(_)) => quote!(WriteOnly),
+            _ => quote!(ReadWrite),
+        };
+        modes.push(quote!(#name => ::pk_command::types::AccessMode::#access,));
+        let description = options
+            .description
+            .unwrap_or_else(|| LitStr::new("", Span::call_site()));
+        infos.push(quote! {
+            ::pk_command::types::VariableInfo {
+                name: ::pk_command::__private::String::from(#name),
+                access: ::pk_command::types::AccessMode::#access,
+                description: ::pk_command::__private::String::from(#description),
+            }
+        });
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::pk_command::PkVariableAccessor for #ident #ty_generics #where_clause {
+            fn get(
+                &self,
+                key: ::pk_command::__private::String,
+            ) -> ::core::option::Option<::pk_command::__private::Vec<u8>> {
+                match key.as_str() {
+                    #(#getters)*
+                    _ => ::core::option::Option::None,
+                }
+            }
+
+            fn set(
+                &self,
+                key: ::pk_command::__private::String,
+                value: ::pk_command::__private::Vec<u8>,
+            ) -> ::core::result::Result<(), ::pk_command::types::PkError> {
+                match key.as_str() {
+                    #(#setters)*
+                    _ => ::core::result::Result::Err(
+                        ::pk_command::types::PkError::VariableNotFound(key)
+                    ),
+                }
+            }
+
+            fn list(&self) -> ::pk_command::__private::Vec<::pk_command::types::VariableInfo> {
+                ::pk_command::__private::vec![#(#infos),*]
+            }
+
+            fn access(&self, key: &str) -> ::pk_command::types::AccessMode {
+                match key {
+                    #(#modes)*
+                    _ => ::pk_command::types::AccessMode::ReadWrite,
+                }
+            }
+        }
+    })
+}
+
+fn expand_methods(mut item: ItemImpl) -> Result<TokenStream2> {
+    if let Some((_, path, _)) = &item.trait_ {
+        return Err(Error::new(
+            path.span(),
+            "#[pk_methods] goes on an inherent `impl` block",
+        ));
+    }
+
+    let mut names = Names::default();
+    let mut arms = Vec::new();
+    let mut guards = Vec::new();
+    let mut infos = Vec::new();
+    for impl_item in &mut item.items {
+        let ImplItem::Fn(function) = impl_item else {
+            continue;
+        };
+        let options = Options::from_attrs(&function.attrs, Item::Method)?;
+        // `pk` 不是真正的属性，展开后必须去掉
+        function.attrs.retain(|attr| !attr.path().is_ident("pk"));
+        if options.skip.is_some() {
+            continue;
+        }
+        let sig = &function.sig;
+        let ident = &sig.ident;
+        let name = names.add(options.name.as_ref(), ident)?;
+
+        match sig.receiver() {
+            Some(receiver) if receiver.reference.is_some() && receiver.mutability.is_none() => {}
+            _ => {
+                return Err(Error::new(
+                    sig.span(),
+                    "PK methods take `&self`; mark other functions with #[pk(skip)]",
+                ));
+            }
+        }
+        if sig.asyncness.is_some() || !sig.generics.params.is_empty() {
+            return Err(Error::new(
+                sig.span(),
+                "PK methods cannot be `async` or generic; return a `Pollable` with #[pk(pollable)] instead",
+            ));
+        }
+
+        let types: Vec<&Type> = sig
+            .inputs
+            .iter()
+            .filter_map(|input| match input {
+                FnArg::Typed(arg) => Some(&*arg.ty),
+                FnArg::Receiver(_) => None,
+            })
+            .collect();
+        let args: Vec<Ident> = (0..types.len()).map(|i| format_ident!("arg{i}")).collect();
+        let decode = match types.as_slice() {
+            [ty] => quote!(let arg0 = <#ty as ::pk_command::codec::PkCodec>::decode(&param)?;),
+            _ => quote! {
+                let (#(#args,)*) = <(#(#types,)*) as ::pk_command::codec::PkCodec>::decode(&param)?;
+            },
+        };
+        let call = if options.pollable.is_some() {
+            quote!(self.#ident(#(#args),*))
+        } else {
+            quote! {
+                ::pk_command::__private::Box::pin(::pk_command::__private::Resolved(
+                    ::core::result::Result::Ok(::core::option::Option::Some(
+                        ::pk_command::codec::PkCodec::encode(&self.#ident(#(#args),*)),
+                    )),
+                ))
+            }
+        };
+        arms.push(quote! {
+            #name => {
+                #decode
+                ::core::result::Result::Ok(#call)
+            }
+        });
+
+        if let Some(guard) = &options.guard {
+            guards.push(quote!(#name => #guard(self),));
+        }
+
+        let description = options
+            .description
+            .unwrap_or_else(|| LitStr::new("", Span::call_site()));
+        infos.push(quote! {
+            ::pk_command::types::MethodInfo {
+                name: ::pk_command::__private::String::from(#name),
+                description: ::pk_command::__private::String::from(#description),
+            }
+        });
+    }
+
+    let self_ty = &item.self_ty;
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        #item
+
+        impl #impl_generics ::pk_command::PkMethodAccessor for #self_ty #where_clause {
+            fn call(
+                &self,
+                key: ::pk_command::__private::String,
+                param: ::pk_command::__private::Vec<u8>,
+            ) -> ::core::result::Result<
+                ::core::pin::Pin<::pk_command::__private::Box<dyn ::pk_command::Pollable>>,
+                ::pk_command::types::PkError,
+            > {
+                match key.as_str() {
+                    #(#arms)*
+                    _ => ::core::result::Result::Err(::pk_command::types::PkError::Method(
+                        ::pk_command::__private::String::from("method not found"),
+                    )),
+                }
+            }
+
+            fn list(&self) -> ::pk_command::__private::Vec<::pk_command::types::MethodInfo> {
+                ::pk_command::__private::vec![#(#infos),*]
+            }
+
+            fn is_permitted(&self, key: &str) -> bool {
+                match key {
+                    #(#guards)*
+                    _ => true,
+                }
+            }
+        }
+    })
+}
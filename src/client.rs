@@ -0,0 +1,268 @@
+//! Asynchronous host client.
+//!
+//! [`PkCommand`] is poll-based: a host calls [`perform()`](crate::PkCommand::perform), keeps calling
+//! [`poll()`](crate::PkCommand::poll) and [`incoming_command()`](crate::PkCommand::incoming_command),
+//! and finally collects the result. [`PkClient`] wraps all of that into `async fn`s. It owns a
+//! [`PkAsyncTransport`] and drives the state machine until the chain of the request is finished.
+//!
+//! The client comes in two flavours, which only differ in the timers they use:
+//! - [`tokio_adapter::PkClient`](crate::tokio_adapter::PkClient), behind the `tokio-runtime` feature.
+//! - [`smol_adapter::PkClient`](crate::smol_adapter::PkClient), behind the `smol-runtime` feature.
+//!
+//! # Example
+//! ```no_run
+//! use pk_command::tokio_adapter::PkClient;
+//! use pk_command::transport::PkAsyncTransport;
+//! use pk_command::types::PkError;
+//! use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
+//!
+//! # struct MyTransport;
+//! # impl PkAsyncTransport for MyTransport {
+//! #     async fn send(&mut self, _packet: Vec<u8>) -> Result<(), PkError> { Ok(()) }
+//! #     async fn recv(&mut self) -> Result<Vec<u8>, PkError> { Ok(vec![]) }
+//! # }
+//! #[tokio::main(flavor = "current_thread")]
+//! async fn main() -> Result<(), PkError> {
+//!     let pk = PkCommand::<_, _, std::time::Instant>::new(
+//!         PkCommandConfig::default(64),
+//!         PkHashmapVariable::new(vec![]),
+//!         PkHashmapMethod::new(vec![]),
+//!     );
+//!     let mut client = PkClient::new(MyTransport, pk);
+//!
+//!     let value: Vec<u8> = client.request_variable("VARIA").await?;
+//!     client.send_variable("VARIA", b"new value".to_vec()).await?;
+//!     let speed: u16 = client.request_variable("SPEED").await?;
+//!     client.send_variable("SPEED", speed + 100).await?;
+//!     let echoed = client.invoke("ECHOO", value).await?;
+//!     println!("device runs PK Command {}", client.version().await?);
+//!     # let _ = echoed;
+//!     Ok(())
+//! }
+//! ```
+
+use std::future::Future;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+use crate::codec::PkCodec;
+use crate::transport::PkAsyncTransport;
+use crate::types::{Capabilities, Catalogue, Notification, Operation, PkError, TransactionOutcome};
+use crate::{PkCommand, PkMethodAccessor, PkVariableAccessor};
+
+/// Timer facilities of an async runtime, as needed by [`PkClient`].
+///
+/// Implemented by [`TokioRuntime`](crate::tokio_adapter::TokioRuntime) and
+/// [`SmolRuntime`](crate::smol_adapter::SmolRuntime). You only need to implement it yourself
+/// if you want to run the client on another runtime.
+pub trait PkRuntime {
+    /// Waits for `future` for at most `duration`.
+    ///
+    /// # Returns
+    /// `Some(output)` if the future completed in time, or `None` if the time ran out.
+    fn timeout<F: Future>(duration: Duration, future: F)
+    -> impl Future<Output = Option<F::Output>>;
+}
+
+/// An asynchronous host client that turns PK Command transactions into awaitable futures.
+///
+/// Each method performs one root operation, drives the wrapped [`PkCommand`] over the
+/// transport and resolves once the `ENDTR` of that chain is acknowledged.
+///
+/// Usually you would use it through the runtime-specific aliases,
+/// [`tokio_adapter::PkClient`](crate::tokio_adapter::PkClient) or
+/// [`smol_adapter::PkClient`](crate::smol_adapter::PkClient). See the [module-level documentation](self) for an example.
+///
+/// # Note
+///
+/// [`PkCommand`] is not thread-safe, and so are the futures returned by this client.
+/// Run them on a single-threaded runtime or within a local task set.
+pub struct PkClient<T, VA, MA, R>
+where
+    T: PkAsyncTransport,
+    VA: PkVariableAccessor,
+    MA: PkMethodAccessor,
+    R: PkRuntime,
+{
+    pk: PkCommand<VA, MA, Instant>,
+    transport: T,
+    poll_interval: Duration,
+    _runtime: PhantomData<R>,
+}
+
+impl<T, VA, MA, R> PkClient<T, VA, MA, R>
+where
+    T: PkAsyncTransport,
+    VA: PkVariableAccessor,
+    MA: PkMethodAccessor,
+    R: PkRuntime,
+{
+    /// Creates a client that drives `pk` over `transport`.
+    ///
+    /// The state machine should be idle. Its configuration (timeouts, packet limit) is used as is.
+    pub fn new(transport: T, pk: PkCommand<VA, MA, Instant>) -> Self {
+        PkClient {
+            pk,
+            transport,
+            poll_interval: Duration::from_millis(5),
+            _runtime: PhantomData,
+        }
+    }
+
+    /// Sets how long the client waits for an incoming packet before polling the state machine again.
+    ///
+    /// This bounds the precision of the protocol timers. Default is 5ms.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Returns the wrapped state machine.
+    pub fn command(&self) -> &PkCommand<VA, MA, Instant> {
+        &self.pk
+    }
+
+    /// Consumes the client, returning the transport and the state machine.
+    pub fn into_inner(self) -> (T, PkCommand<VA, MA, Instant>) {
+        (self.transport, self.pk)
+    }
+
+    /// Gets the value of a variable on the device (`REQUV`), decoded as `V`. (See [`PkCodec`].)
+    ///
+    /// Use `Vec<u8>` to get the raw value.
+    ///
+    /// Returns [`PkError::VariableNotFound`] if the device has no such variable,
+    /// [`PkError::AccessDenied`] if it is write-only, or [`PkError::InvalidData`] if the value does
+    /// not decode.
+    pub async fn request_variable<V: PkCodec>(&mut self, name: &str) -> Result<V, PkError> {
+        let data = self
+            .transact(Operation::RequireVariable, Some(name.to_string()), None)
+            .await?;
+        V::decode(&data)
+    }
+
+    /// Sets the value of a variable on the device (`SENDV`), encoded from `V`. (See [`PkCodec`].)
+    ///
+    /// Returns [`PkError::AccessDenied`] if the variable is read-only.
+    pub async fn send_variable<V: PkCodec>(&mut self, name: &str, value: V) -> Result<(), PkError> {
+        self.transact(
+            Operation::SendVariable,
+            Some(name.to_string()),
+            Some(value.encode()),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Invokes a method on the device (`INVOK`) and returns its result.
+    ///
+    /// An empty `param` is sent as `EMPTY`, and an empty result means that the method returned nothing.
+    pub async fn invoke(&mut self, name: &str, param: Vec<u8>) -> Result<Vec<u8>, PkError> {
+        self.transact(Operation::Invoke, Some(name.to_string()), Some(param))
+            .await
+    }
+
+    /// Lists the variables and methods exposed by the device (`LISTO`).
+    pub async fn list_objects(&mut self) -> Result<Catalogue, PkError> {
+        let data = self.transact(Operation::ListObjects, None, None).await?;
+        Catalogue::parse(&data)
+    }
+
+    /// Gets the version of the PK Command interpreter on the device (`PKVER`).
+    pub async fn version(&mut self) -> Result<String, PkError> {
+        self.transact(Operation::GetVersion, None, None)
+            .await
+            .map(|data| String::from_utf8_lossy(&data).into_owned())
+    }
+
+    /// Subscribes to the changes of a variable on the device (`SUBSC`), and returns its current
+    /// value decoded as `V`. (See [`PkCommand::subscribe()`].)
+    ///
+    /// The new values are then collected with [`next_notification()`](Self::next_notification).
+    pub async fn subscribe<V: PkCodec>(
+        &mut self,
+        name: &str,
+        min_interval: Duration,
+    ) -> Result<V, PkError> {
+        self.pk.subscribe(name, min_interval)?;
+        V::decode(&self.finish().await?)
+    }
+
+    /// Cancels a subscription to a variable on the device (`UNSUB`).
+    ///
+    /// Notifications already received are still returned by [`next_notification()`](Self::next_notification).
+    pub async fn unsubscribe(&mut self, name: &str) -> Result<(), PkError> {
+        self.transact(Operation::Unsubscribe, Some(name.to_string()), None)
+            .await
+            .map(|_| ())
+    }
+
+    /// Waits until the device pushes a new value of a subscribed variable.
+    ///
+    /// Wrap it in a timeout of your runtime if the variable may not change for a long time.
+    pub async fn next_notification(&mut self) -> Result<Notification, PkError> {
+        loop {
+            if let Some(notification) = self.pk.take_notification() {
+                return Ok(notification);
+            }
+            if let Some(cmd) = self.pk.poll() {
+                self.transport.send(cmd.to_bytes()).await?;
+            }
+            if let Some(received) = R::timeout(self.poll_interval, self.transport.recv()).await {
+                self.feed(received?)?;
+            }
+        }
+    }
+
+    /// Starts a session with a handshake, and returns the negotiated parameters.
+    /// (See [`PkCommand::handshake()`].)
+    ///
+    /// Returns [`PkError::Incompatible`] if the device cannot talk to this side.
+    pub async fn handshake(&mut self) -> Result<Capabilities, PkError> {
+        self.pk.handshake()?;
+        self.finish().await?;
+        Ok(self.pk.capabilities())
+    }
+
+    async fn transact(
+        &mut self,
+        operation: Operation,
+        object: Option<String>,
+        data: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, PkError> {
+        self.pk.perform(operation, object, data)?;
+        self.finish().await
+    }
+
+    /// Hands a received packet to the state machine.
+    ///
+    /// Malformed packets are dropped, the peer retransmits them on ACK timeout. Other errors, like
+    /// a full inbound queue, are returned.
+    fn feed(&self, packet: Vec<u8>) -> Result<(), PkError> {
+        match self.pk.incoming_command(packet) {
+            Err(e) if !e.is_malformed() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drives the chain started on the state machine until it is over, and collects its outcome.
+    async fn finish(&mut self) -> Result<Vec<u8>, PkError> {
+        loop {
+            if let Some(cmd) = self.pk.poll() {
+                self.transport.send(cmd.to_bytes()).await?;
+            }
+            if self.pk.is_settled() {
+                break;
+            }
+            if let Some(received) = R::timeout(self.poll_interval, self.transport.recv()).await {
+                self.feed(received?)?;
+            }
+        }
+
+        match self.pk.take_outcome() {
+            Some(TransactionOutcome::Failed { reason, .. }) => Err(reason),
+            Some(TransactionOutcome::Completed(data)) => Ok(data.unwrap_or_default()),
+            None => Ok(Vec::new()),
+        }
+    }
+}
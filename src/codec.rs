@@ -0,0 +1,402 @@
+//! Typed values on top of the raw bytes of PK Command.
+//!
+//! The protocol itself only moves `Vec<u8>`s around. A [`PkCodec`] turns a Rust value into those
+//! bytes and back, so that both sides do not have to hand-roll the encoding of every variable.
+//!
+//! Built-in codecs:
+//!
+//! | Type                                   | Encoding                                          |
+//! |----------------------------------------|---------------------------------------------------|
+//! | `u8` … `u128`, `i8` … `i128`, `f32`, `f64` | Little-endian, fixed width                    |
+//! | `bool`                                 | One byte, `0` or `1`                              |
+//! | [`String`]                             | UTF-8, without terminator                         |
+//! | `[T; N]`                               | The `N` elements one after another (`T` must have a fixed width) |
+//! | `()`                                   | Nothing                                           |
+//! | Tuples of up to 6 elements             | The elements one after another (all but the last must have a fixed width) |
+//! | [`Vec<u8>`]                            | As is                                             |
+//! | [`Cbor<T>`]                            | [CBOR](https://cbor.io/) via serde (`cbor` feature) |
+//! | [`Postcard<T>`]                        | [postcard](https://docs.rs/postcard) via serde (`postcard` feature) |
+//!
+//! On a Device with [`PkHashmapVariable`](crate::PkHashmapVariable), register variables as
+//! [`TypedVariable`]s to have the values checked before they are stored. With the `macros`
+//! feature, the accessors generated by `#[derive(PkVariables)]` and `#[pk_methods]` also encode
+//! their values with these codecs. On a Host, the
+//! [client](crate::client) encodes and decodes variables with these codecs.
+//!
+//! # Example
+//! ```
+//! use pk_command::codec::PkCodec;
+//!
+//! let bytes = 1500u16.encode();
+//! assert_eq!(bytes, vec![0xDC, 0x05]);
+//! assert_eq!(u16::decode(&bytes), Ok(1500));
+//! assert!(u32::decode(&bytes).is_err());
+//! ```
+
+#[cfg(not(feature = "std"))]
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::types::PkError;
+
+/// Converts a value to the bytes of a variable or a parameter, and back.
+pub trait PkCodec: Sized {
+    /// The length of every encoded value, if it is always the same.
+    ///
+    /// Tuples use this to tell their elements apart. Leave it `None` for variable-length encodings.
+    const WIDTH: Option<usize> = None;
+
+    /// Encodes the value.
+    ///
+    /// Encoding the built-in types never fails. Codecs that can fail, like the serde-based ones,
+    /// document when they panic.
+    fn encode(&self) -> Vec<u8>;
+
+    /// Decodes a value.
+    ///
+    /// # Errors
+    /// Returns [`PkError::InvalidData`] if the bytes are not a valid encoding of the type.
+    fn decode(bytes: &[u8]) -> Result<Self, PkError>;
+}
+
+macro_rules! impl_le_codec {
+    ($($t:ty),*) => {
+        $(
+            impl PkCodec for $t {
+                const WIDTH: Option<usize> = Some(core::mem::size_of::<$t>());
+
+                fn encode(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn decode(bytes: &[u8]) -> Result<Self, PkError> {
+                    bytes
+                        .try_into()
+                        .map(<$t>::from_le_bytes)
+                        .map_err(|_| PkError::InvalidData(stringify!($t)))
+                }
+            }
+        )*
+    };
+}
+
+impl_le_codec!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+impl PkCodec for bool {
+    const WIDTH: Option<usize> = Some(1);
+
+    fn encode(&self) -> Vec<u8> {
+        vec![*self as u8]
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, PkError> {
+        match bytes {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            _ => Err(PkError::InvalidData("bool")),
+        }
+    }
+}
+
+impl PkCodec for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, PkError> {
+        String::from_utf8(bytes.to_vec()).map_err(|_| PkError::InvalidData("UTF-8 string"))
+    }
+}
+
+impl PkCodec for Vec<u8> {
+    fn encode(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, PkError> {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Arrays are encoded as their elements one after another, so `T` must have a fixed
+/// [`WIDTH`](PkCodec::WIDTH) to tell them apart. Arrays of a variable-length type, like
+/// `[String; 2]`, do not compile.
+///
+/// ```compile_fail
+/// use pk_command::codec::PkCodec;
+///
+/// let bytes = [String::from("a"), String::from("bc")].encode();
+/// ```
+impl<T: PkCodec, const N: usize> PkCodec for [T; N] {
+    const WIDTH: Option<usize> = match T::WIDTH {
+        Some(width) => Some(width * N),
+        None => None,
+    };
+
+    fn encode(&self) -> Vec<u8> {
+        const { assert!(T::WIDTH.is_some(), "array elements must have a fixed width") };
+        self.iter().flat_map(PkCodec::encode).collect()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, PkError> {
+        const EXPECTED: &str = "fixed-size array";
+        let width = const {
+            match T::WIDTH {
+                Some(width) => width,
+                None => panic!("array elements must have a fixed width"),
+            }
+        };
+        if width * N != bytes.len() {
+            return Err(PkError::InvalidData(EXPECTED));
+        }
+        // 宽度为 0 时（如 `[(); N]`）每个元素都是空的字节串
+        let elements = (0..N)
+            .map(|i| T::decode(&bytes[i * width..(i + 1) * width]))
+            .collect::<Result<Vec<T>, PkError>>()?;
+        elements
+            .try_into()
+            .map_err(|_| PkError::InvalidData(EXPECTED))
+    }
+}
+
+impl PkCodec for () {
+    const WIDTH: Option<usize> = Some(0);
+
+    fn encode(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, PkError> {
+        if bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(PkError::InvalidData("no data"))
+        }
+    }
+}
+
+const fn add_widths(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        _ => None,
+    }
+}
+
+/// Takes a fixed-width value off the front of `bytes`.
+fn take<T: PkCodec>(bytes: &mut &[u8]) -> Result<T, PkError> {
+    const EXPECTED: &str = "fixed-width tuple element";
+    let width = T::WIDTH.ok_or(PkError::InvalidData(EXPECTED))?;
+    if bytes.len() < width {
+        return Err(PkError::InvalidData(EXPECTED));
+    }
+    let (head, rest) = bytes.split_at(width);
+    *bytes = rest;
+    T::decode(head)
+}
+
+/// Tuples are encoded as their elements one after another. All elements but the last one must
+/// have a fixed [`WIDTH`](PkCodec::WIDTH), so `(u8, String)` works but `(String, u8)` does not.
+macro_rules! impl_tuple_codec {
+    ($($head:ident),* ; $last:ident) => {
+        #[allow(non_snake_case, unused_mut)]
+        impl<$($head: PkCodec,)* $last: PkCodec> PkCodec for ($($head,)* $last,) {
+            const WIDTH: Option<usize> = {
+                let width = $last::WIDTH;
+                $(let width = add_widths($head::WIDTH, width);)*
+                width
+            };
+
+            fn encode(&self) -> Vec<u8> {
+                let ($($head,)* $last,) = self;
+                let mut bytes = Vec::new();
+                $(bytes.extend($head.encode());)*
+                bytes.extend($last.encode());
+                bytes
+            }
+
+            fn decode(bytes: &[u8]) -> Result<Self, PkError> {
+                let mut rest = bytes;
+                $(let $head = take::<$head>(&mut rest)?;)*
+                Ok(($($head,)* $last::decode(rest)?,))
+            }
+        }
+    };
+}
+
+impl_tuple_codec!(; A);
+impl_tuple_codec!(A; B);
+impl_tuple_codec!(A, B; C);
+impl_tuple_codec!(A, B, C; D);
+impl_tuple_codec!(A, B, C, D; E);
+impl_tuple_codec!(A, B, C, D, E; F);
+
+/// A field that holds the value of a variable generated by
+/// [`#[derive(PkVariables)]`](crate::PkVariables).
+///
+/// [`PkVariableAccessor::set()`](crate::PkVariableAccessor::set) only gets `&self`, so the fields
+/// need interior mutability. This is implemented for [`Cell`](core::cell::Cell) and
+/// [`RefCell`](core::cell::RefCell); implement it for other containers, like a mutex, if needed.
+pub trait VariableCell {
+    /// The type of the value, which is encoded with [`PkCodec`].
+    type Value;
+
+    /// Returns a copy of the value.
+    fn load(&self) -> Self::Value;
+
+    /// Replaces the value.
+    fn store(&self, value: Self::Value);
+}
+
+impl<T: Copy> VariableCell for core::cell::Cell<T> {
+    type Value = T;
+
+    fn load(&self) -> T {
+        self.get()
+    }
+
+    fn store(&self, value: T) {
+        self.set(value);
+    }
+}
+
+impl<T: Clone> VariableCell for core::cell::RefCell<T> {
+    type Value = T;
+
+    fn load(&self) -> T {
+        self.borrow().clone()
+    }
+
+    fn store(&self, value: T) {
+        self.replace(value);
+    }
+}
+
+/// Encodes any serde type as [CBOR](https://cbor.io/).
+///
+/// **Note**: This is only available when the `cbor` feature is enabled.
+///
+/// # Panics
+/// [`encode()`](PkCodec::encode) panics if the [`Serialize`](serde::Serialize) implementation of
+/// `T` fails, for example on a map with keys that are not supported. The derived implementations
+/// of plain structs and enums never fail.
+///
+/// # Example
+/// ```
+/// use pk_command::codec::{Cbor, PkCodec};
+///
+/// let point = Cbor((3i32, -4i32));
+/// assert_eq!(Cbor::<(i32, i32)>::decode(&point.encode()), Ok(point));
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "cbor")))]
+#[cfg(feature = "cbor")]
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Cbor<T>(pub T);
+
+#[cfg(feature = "cbor")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> PkCodec for Cbor<T> {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // 写入 Vec 不会发生 I/O 错误，只有 serde 实现本身出错时才会失败
+        ciborium::into_writer(&self.0, &mut bytes).expect("failed to serialize as CBOR");
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, PkError> {
+        ciborium::from_reader(bytes)
+            .map(Cbor)
+            .map_err(|_| PkError::InvalidData("CBOR"))
+    }
+}
+
+/// Encodes any serde type with [postcard](https://docs.rs/postcard), a compact format made for
+/// embedded devices.
+///
+/// **Note**: This is only available when the `postcard` feature is enabled.
+///
+/// # Panics
+/// [`encode()`](PkCodec::encode) panics if the [`Serialize`](serde::Serialize) implementation of
+/// `T` fails, or if it needs a feature that postcard does not support, like sequences of unknown
+/// length (`serialize_seq(None)`). The derived implementations of plain structs and enums never
+/// fail.
+#[cfg_attr(docsrs, doc(cfg(feature = "postcard")))]
+#[cfg(feature = "postcard")]
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Postcard<T>(pub T);
+
+#[cfg(feature = "postcard")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> PkCodec for Postcard<T> {
+    fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(&self.0).expect("failed to serialize with postcard")
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, PkError> {
+        postcard::from_bytes(bytes)
+            .map(Postcard)
+            .map_err(|_| PkError::InvalidData("postcard"))
+    }
+}
+
+/// A validation function for a [`TypedVariable`]. Returns a description of the problem if the
+/// value is rejected.
+#[cfg(feature = "std")]
+pub type Validator<T> = Box<dyn Fn(&T) -> Result<(), String>>;
+
+/// A variable of a [`PkHashmapVariable`](crate::PkHashmapVariable) that holds a `T`.
+///
+/// Values set by a Host are decoded and validated before they are stored. A value that does not
+/// decode is refused with [`PkError::InvalidData`], and one rejected by the validator with
+/// [`PkError::Variable`]. Either way, the Host receives an `ERROR` and the old value is kept.
+///
+/// **Note**: This is only available when the `std` feature is enabled.
+///
+/// # Example
+/// ```
+/// use pk_command::PkHashmapVariable;
+/// use pk_command::codec::TypedVariable;
+///
+/// let vars = PkHashmapVariable::new(vec![]).with_typed(
+///     TypedVariable::new("SPEED", 1200u16)
+///         .validate(|rpm| {
+///             if *rpm <= 3000 {
+///                 Ok(())
+///             } else {
+///                 Err(String::from("too fast"))
+///             }
+///         })
+///         .on_change(|rpm| println!("fan speed is now {rpm}")),
+/// );
+/// ```
+#[cfg(feature = "std")]
+pub struct TypedVariable<T> {
+    pub(crate) name: String,
+    pub(crate) initial: T,
+    pub(crate) validator: Option<Validator<T>>,
+    pub(crate) listener: Option<Box<dyn Fn(T)>>,
+}
+
+#[cfg(feature = "std")]
+impl<T: PkCodec + 'static> TypedVariable<T> {
+    /// Creates a typed variable with its name and initial value.
+    pub fn new(name: &str, initial: T) -> Self {
+        TypedVariable {
+            name: name.to_string(),
+            initial,
+            validator: None,
+            listener: None,
+        }
+    }
+
+    /// Sets a function that checks each new value before it is stored.
+    pub fn validate(mut self, validator: impl Fn(&T) -> Result<(), String> + 'static) -> Self {
+        self.validator = Some(Box::new(validator));
+        self
+    }
+
+    /// Sets a function that is called with each new value once it is stored.
+    ///
+    /// Like the listeners of [`PkHashmapVariable`](crate::PkHashmapVariable), it runs synchronously
+    /// within [`PkCommand::poll()`](crate::PkCommand::poll).
+    pub fn on_change(mut self, listener: impl Fn(T) + 'static) -> Self {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+}
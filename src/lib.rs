@@ -64,6 +64,9 @@
 //! }
 //! ```
 //!
+//! If your transport implements [`PkTransport`], [`PkCommand::run_with()`] runs this loop
+//! for you. Some common transports are ready-made in the [`transport`] module.
+//!
 //! # Feature flags
 //! - `std`: Enables features that require the Rust standard library. (Mainly the convenient wrappers like [`PkPromise`], [`PkHashmapVariable`], [`PkHashmapMethod`]) **Enabled by default.**
 //! - `embassy`: Enables integration with the [Embassy](https://embassy.dev/) async framework. Flags below are also enabled when this is active:
@@ -71,6 +74,12 @@
 //!   - `embassy-runtime`: Enables the support for [embassy-executor](https://crates.io/crates/embassy-executor) crate, which provides integration between the main state machine and Embassy async tasks.
 //! - `tokio-runtime`: Enables integration with the [Tokio](https://tokio.rs/) async runtime. Provides [`tokio_adapter`] for running async operations within method implementations. Requires `std` feature.
 //! - `smol-runtime`: Enables integration with the [Smol](https://github.com/smol-rs/smol) async executor. Provides [`smol_adapter`] for running async operations within method implementations. Requires `std` feature.
+//!
+//! - `cbor`: Enables the [`Cbor`](codec::Cbor) codec, which encodes any serde type as CBOR. Requires `std` feature.
+//! - `postcard`: Enables the [`Postcard`](codec::Postcard) codec, which encodes any serde type with postcard. Requires `std` feature.
+//! - `macros`: Enables `#[derive(PkVariables)]` and `#[pk_methods]`, which generate [`PkVariableAccessor`] and [`PkMethodAccessor`] implementations from a struct and an `impl` block. Works in no_std environments.
+//!
+//! With either `tokio-runtime` or `smol-runtime` enabled, an async host client is also available. (See [`client`].)
 
 #![warn(missing_docs)]
 #![cfg_attr(not(feature = "std"), no_std)]
@@ -78,6 +87,58 @@
 
 const PK_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Default depth of the inbound queue. (See [`PkCommandConfig::with_inbound_queue()`].)
+const DEFAULT_INBOUND_QUEUE_DEPTH: usize = 4;
+
+/// Default maximum number of subscriptions a Device keeps. (See [`PkCommandConfig::with_subscriptions()`].)
+const DEFAULT_MAX_SUBSCRIPTIONS: usize = 8;
+
+/// Default number of retransmissions of a command before giving up. (See [`PkCommandConfig::with_retries()`].)
+const DEFAULT_MAX_RETRIES: u32 = 10;
+
+/// Initial state of the pseudo-random numbers of the [`Backoff`] jitter. Any value but 0.
+const JITTER_SEED: u32 = 0x9E37_79B9;
+
+/// Object of a `START` command which offers protocol extensions, listed in its data.
+const EXTENSION_OBJECT: &str = "PKEXT";
+
+/// Extension offering (in `START`) or accepting (in `ACKNO START`) windowed transfers, followed by
+/// the window size, e.g. `WINDOW=8`.
+const WINDOW_EXTENSION: &str = "WINDOW=";
+
+/// Extension offering (in `START`) or accepting (in `ACKNO START`) an express chain, whose
+/// parameter and result are carried inline. (See [`PkCommandConfig::with_express()`].)
+const EXPRESS_EXTENSION: &str = "EXPRESS";
+
+/// Extension offering (in `START`) or accepting (in `ACKNO START`) piggybacked acknowledgements.
+/// (See [`PkCommandConfig::with_piggyback()`].)
+const PIGGYBACK_EXTENSION: &str = "PIGGYBACK";
+
+/// Length of the header of a command with both an object and data, like `!!SDATA VARIA `.
+const HEADER_LEN: usize = 14;
+
+/// Largest number of `SDATA` packets in flight. (See [`PkCommandConfig::with_window()`].)
+const MAX_WINDOW: u16 = 64;
+
+/// Extracts the major version out of a version string like `1.2.0`.
+fn major_version(version: &str) -> Option<u32> {
+    version.split('.').next()?.parse().ok()
+}
+
+/// Splits the space-separated tokens in the data of `START`, `ACKNO START` or `ACKNO SDATA`.
+fn extension_tokens(data: Option<&[u8]>) -> impl Iterator<Item = &str> {
+    data.and_then(|d| core::str::from_utf8(d).ok())
+        .unwrap_or_default()
+        .split(' ')
+        .filter(|token| !token.is_empty())
+}
+
+/// Reads the size out of a `WINDOW=<size>` extension token. A window of 1 is a plain transfer.
+fn window_size(token: &str) -> Option<u16> {
+    let size: u16 = token.strip_prefix(WINDOW_EXTENSION)?.parse().ok()?;
+    (size > 1).then_some(size.min(MAX_WINDOW))
+}
+
 // Compile-time guard: async runtime adapters require `std` feature.
 #[cfg(all(
     any(feature = "tokio-runtime", feature = "smol-runtime"),
@@ -90,6 +151,8 @@ extern crate alloc;
 #[cfg(not(feature = "std"))]
 use alloc::{
     boxed::Box,
+    collections::VecDeque,
+    format,
     string::{String, ToString},
     vec,
     vec::Vec,
@@ -102,6 +165,8 @@ extern crate core as std;
 // are re-exported by `std` crate from `core`,
 // so just simply renaming `core` as `std` should work
 use std::cell::{Cell, RefCell};
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
 use std::ops::Add;
 use std::pin::Pin;
 use std::task::Poll;
@@ -109,7 +174,29 @@ use std::time::Duration;
 
 /// Core data structures and types for PK Command.
 pub mod types;
-use types::{Command, Operation, Role, Stage, Status};
+use types::{
+    AccessMode, Backoff, Capabilities, Catalogue, Checksum, Command, FailureOrigin, MethodInfo,
+    Notification, Operation, PkError, QueueOverflow, Role, Stage, Status, TieBreak,
+    TransactionOutcome, VariableInfo,
+};
+
+pub mod transport;
+use transport::PkTransport;
+
+pub mod codec;
+
+#[cfg_attr(
+    docsrs,
+    doc(cfg(all(
+        feature = "std",
+        any(feature = "tokio-runtime", feature = "smol-runtime")
+    )))
+)]
+#[cfg(all(
+    feature = "std",
+    any(feature = "tokio-runtime", feature = "smol-runtime")
+))]
+pub mod client;
 
 /// Utilities used in examples.
 #[doc(hidden)]
@@ -126,9 +213,32 @@ pub use util::async_adapters::smol as smol_adapter;
 #[cfg(all(feature = "std", feature = "tokio-runtime"))]
 #[cfg_attr(docsrs, doc(cfg(all(feature = "std", feature = "tokio-runtime"))))]
 pub use util::async_adapters::tokio as tokio_adapter;
+use util::rtt::RttEstimator;
 #[cfg(feature = "std")]
 #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
-pub use util::{PkHashmapMethod, PkHashmapVariable, PkPromise, msg_id};
+pub use util::{PkHashmapMethod, PkHashmapMethodBuilder, PkHashmapVariable, PkPromise, msg_id};
+
+#[cfg_attr(docsrs, doc(cfg(feature = "macros")))]
+#[cfg(feature = "macros")]
+pub use pk_command_macros::{PkVariables, pk_methods};
+
+/// Items used by the code that the macros generate. Not part of the public API.
+#[doc(hidden)]
+pub mod __private {
+    #[cfg(not(feature = "std"))]
+    pub use alloc::{boxed::Box, string::String, vec, vec::Vec};
+    #[cfg(feature = "std")]
+    pub use std::{boxed::Box, string::String, vec, vec::Vec};
+
+    /// A [`Pollable`](crate::Pollable) that is ready from the start.
+    pub struct Resolved(pub Result<Option<Vec<u8>>, crate::types::PkError>);
+
+    impl crate::Pollable for Resolved {
+        fn poll(&self) -> std::task::Poll<Result<Option<Vec<u8>>, crate::types::PkError>> {
+            std::task::Poll::Ready(self.0.clone())
+        }
+    }
+}
 
 /// Trait defining how to access (get/set) variables by their string key.
 ///
@@ -139,6 +249,7 @@ pub use util::{PkHashmapMethod, PkHashmapVariable, PkPromise, msg_id};
 /// # Example
 /// ```
 /// use pk_command::PkVariableAccessor;
+/// use pk_command::types::PkError;
 ///
 /// struct MyVariableStore;
 /// impl PkVariableAccessor for MyVariableStore {
@@ -149,7 +260,7 @@ pub use util::{PkHashmapMethod, PkHashmapVariable, PkPromise, msg_id};
 ///             None
 ///         }
 ///     }
-///     fn set(&self, key: String, value: Vec<u8>) -> Result<(), String> {
+///     fn set(&self, key: String, value: Vec<u8>) -> Result<(), PkError> {
 ///         // Logic to store the value
 ///         Ok(())
 ///     }
@@ -172,8 +283,31 @@ pub trait PkVariableAccessor {
     /// * `value`: The new data for the variable.
     ///
     /// # Returns
-    /// `Ok(())` if successful, or an `Err(String)` describing the error.
-    fn set(&self, key: String, value: Vec<u8>) -> Result<(), String>;
+    /// `Ok(())` if successful, or an error (usually [`PkError::Variable`]) describing the failure.
+    fn set(&self, key: String, value: Vec<u8>) -> Result<(), PkError>;
+
+    /// Lists the variables, to answer the `LISTO` root operation. (See [`Catalogue`].)
+    ///
+    /// The default implementation lists nothing, so the variables stay undiscoverable.
+    fn list(&self) -> Vec<VariableInfo> {
+        Vec::new()
+    }
+
+    /// Returns how the Host may access a variable.
+    ///
+    /// [`PkCommand`] checks this when the Host starts a `REQUV` or `SENDV` chain, and aborts it with
+    /// [`PkError::AccessDenied`] if the variable is not readable or writable, without calling
+    /// [`get()`](PkVariableAccessor::get) or [`set()`](PkVariableAccessor::set). Those two stay
+    /// unrestricted for the code on the Device itself.
+    ///
+    /// The default implementation looks the variable up in [`list()`](PkVariableAccessor::list),
+    /// and allows everything for variables that are not listed.
+    fn access(&self, key: &str) -> AccessMode {
+        self.list()
+            .into_iter()
+            .find(|info| info.name == key)
+            .map_or(AccessMode::ReadWrite, |info| info.access)
+    }
 }
 
 /// A handle for a long-running operation that can be polled for completion.
@@ -190,9 +324,16 @@ pub trait Pollable {
     /// # Returns
     /// - `Poll::Ready(Ok(Some(data)))`: Operation finished with result data.
     /// - `Poll::Ready(Ok(None))`: Operation finished successfully with no data.
-    /// - `Poll::Ready(Err(e))`: Operation failed with an error message.
+    /// - `Poll::Ready(Err(e))`: Operation failed with an error (usually [`PkError::Method`]).
     /// - `Poll::Pending`: Operation is still in progress.
-    fn poll(&self) -> std::task::Poll<Result<Option<Vec<u8>>, String>>;
+    fn poll(&self) -> std::task::Poll<Result<Option<Vec<u8>>, PkError>>;
+
+    /// Asks the operation to stop, because the transaction was aborted before it finished, e.g.
+    /// [cancelled](crate::PkCommand::cancel) by the Host.
+    ///
+    /// The state machine drops the `Pollable` right after this, and never polls it again.
+    /// The default implementation does nothing: the operation runs to completion and its result is discarded.
+    fn cancel(&self) {}
 }
 
 /// Trait defining how to invoke methods by their string key.
@@ -203,24 +344,25 @@ pub trait Pollable {
 ///
 /// # Example
 /// ```
+/// use pk_command::types::PkError;
 /// use pk_command::{PkMethodAccessor, Pollable};
 /// use std::pin::Pin;
 /// use std::task::Poll;
 ///
 /// struct MyMethod;
 /// impl Pollable for MyMethod {
-///     fn poll(&self) -> Poll<Result<Option<Vec<u8>>, String>> {
+///     fn poll(&self) -> Poll<Result<Option<Vec<u8>>, PkError>> {
 ///         Poll::Ready(Ok(Some(b"Hello from PK!".to_vec())))
 ///     }
 /// }
 ///
 /// struct MyMethodStore;
 /// impl PkMethodAccessor for MyMethodStore {
-///     fn call(&self, key: String, param: Vec<u8>) -> Result<Pin<Box<dyn Pollable>>, String> {
+///     fn call(&self, key: String, param: Vec<u8>) -> Result<Pin<Box<dyn Pollable>>, PkError> {
 ///         if key == "GREET" {
 ///             Ok(Box::pin(MyMethod))
 ///         } else {
-///             Err("Method not found".to_string())
+///             Err(PkError::Method("method not found".to_string()))
 ///         }
 ///     }
 /// }
@@ -234,8 +376,26 @@ pub trait PkMethodAccessor {
     ///
     /// # Returns
     /// A `Result` containing a pinned, boxed `Pollable` that will resolve to the method's output,
-    /// or an `Err(String)` if the method call cannot be initiated.
-    fn call(&self, key: String, param: Vec<u8>) -> Result<Pin<Box<dyn Pollable>>, String>;
+    /// or an error (usually [`PkError::Method`]) if the method call cannot be initiated.
+    fn call(&self, key: String, param: Vec<u8>) -> Result<Pin<Box<dyn Pollable>>, PkError>;
+
+    /// Lists the methods, to answer the `LISTO` root operation. (See [`Catalogue`].)
+    ///
+    /// The default implementation lists nothing, so the methods stay undiscoverable.
+    fn list(&self) -> Vec<MethodInfo> {
+        Vec::new()
+    }
+
+    /// Returns `true` if the Host may call the method right now.
+    ///
+    /// [`PkCommand`] checks this when the Host starts an `INVOK` chain, and aborts it with
+    /// [`PkError::AccessDenied`] otherwise, without calling [`call()`](PkMethodAccessor::call).
+    ///
+    /// The default implementation permits every method.
+    fn is_permitted(&self, key: &str) -> bool {
+        let _ = key;
+        true
+    }
 }
 
 /// Trait representing an instant in time.
@@ -378,6 +538,39 @@ pub struct PkCommandConfig {
     packet_limit: u64,
     /// The version string of the package.
     pk_version: &'static str,
+    /// Integrity check to negotiate for each transaction chain. Default is [`Checksum::None`].
+    checksum: Checksum,
+    /// How many `SDATA` packets may be in flight at once. Default is 1 (plain transfers).
+    window: u16,
+    /// Whether small transactions may skip the data phases. Default is `false`.
+    express: bool,
+    /// Whether the Device may acknowledge `ENDTR` with its reply. Default is `false`.
+    piggyback: bool,
+    /// How many received commands can wait for [`PkCommand::poll()`]. Default is 4.
+    inbound_queue_depth: usize,
+    /// What to do with a received command when the inbound queue is full. Default is [`QueueOverflow::DropOldest`].
+    inbound_overflow: QueueOverflow,
+    /// How many variables the Host may subscribe to at the same time. Default is 8.
+    max_subscriptions: usize,
+    /// The minimum interval between two notifications of a variable. Default is 100ms.
+    notify_interval: Duration,
+    /// How to resolve a collision of `START`s. Default is [`TieBreak::Yield`].
+    tie_break: TieBreak,
+    /// The longest a chain performed by this Host may take. Default is no limit.
+    deadline: Option<Duration>,
+    /// How many `AWAIT`s the Host acknowledges in a chain. Default is no limit.
+    max_awaits: Option<u32>,
+    /// The longest a method may run on the Device. Default is no limit.
+    max_execution: Option<Duration>,
+    /// How many times a command is retransmitted before the transaction fails. Default is 10.
+    max_retries: u32,
+    /// How the ACK timeout grows with each retransmission. Default is [`Backoff::Fixed`].
+    backoff: Backoff,
+    /// Bounds of the ACK timeout measured from round-trip times, if it is adaptive. Default is a
+    /// fixed ACK timeout.
+    rto_bounds: Option<(Duration, Duration)>,
+    /// How long [`PkCommand::run_with()`] sleeps when there is nothing to do. Default is 1ms.
+    idle_wait: Duration,
 }
 
 impl PkCommandConfig {
@@ -390,6 +583,8 @@ impl PkCommandConfig {
     ///
     /// # Arguments
     /// * `packet_limit`: The maximum packet size (MTU) of the underlying transport (e.g., 64 for HID).
+    ///   Chains carrying data fail with [`PkError::Incompatible`] if it leaves no room for any
+    ///   after the 14-byte header of `SDATA` and the [checksum](Self::with_checksum) trailer.
     ///
     /// # Returns
     /// A [`PkCommandConfig`] instance with default timeouts and the specified packet limit.
@@ -403,6 +598,22 @@ impl PkCommandConfig {
             await_interval: Duration::from_millis(300),
             packet_limit,
             pk_version: PK_VERSION,
+            checksum: Checksum::None,
+            window: 1,
+            express: false,
+            piggyback: false,
+            inbound_queue_depth: DEFAULT_INBOUND_QUEUE_DEPTH,
+            inbound_overflow: QueueOverflow::DropOldest,
+            max_subscriptions: DEFAULT_MAX_SUBSCRIPTIONS,
+            notify_interval: Duration::from_millis(100),
+            tie_break: TieBreak::Yield,
+            deadline: None,
+            max_awaits: None,
+            max_execution: None,
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: Backoff::Fixed,
+            rto_bounds: None,
+            idle_wait: Duration::from_millis(1),
         }
     }
 
@@ -412,10 +623,11 @@ impl PkCommandConfig {
     /// * `ack_timeout`: Timeout for ACKs in milliseconds.
     /// * `inter_command_timeout`: Timeout between commands in milliseconds.
     /// * `await_interval`: Interval for sending `AWAIT` keep-alives in milliseconds.
-    /// * `packet_limit`: Maximum length of a single packet in bytes.
+    /// * `packet_limit`: Maximum length of a single packet in bytes. (See [`default()`](Self::default).)
     ///
     /// # Note
-    /// To avoid undesirable behavior, you should ensure that the timeout values on both sides (Host and Device) are exactly the same.
+    /// To avoid undesirable behavior, you should ensure that the timeout values on both sides (Host and Device) are exactly the same,
+    /// or let the Host start the session with a [handshake](crate::PkCommand::handshake).
     pub fn new(
         ack_timeout: u64,
         inter_command_timeout: u64,
@@ -428,10 +640,351 @@ impl PkCommandConfig {
             await_interval: Duration::from_millis(await_interval),
             packet_limit,
             pk_version: PK_VERSION,
+            checksum: Checksum::None,
+            window: 1,
+            express: false,
+            piggyback: false,
+            inbound_queue_depth: DEFAULT_INBOUND_QUEUE_DEPTH,
+            inbound_overflow: QueueOverflow::DropOldest,
+            max_subscriptions: DEFAULT_MAX_SUBSCRIPTIONS,
+            notify_interval: Duration::from_millis(100),
+            tie_break: TieBreak::Yield,
+            deadline: None,
+            max_awaits: None,
+            max_execution: None,
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: Backoff::Fixed,
+            rto_bounds: None,
+            idle_wait: Duration::from_millis(1),
+        }
+    }
+
+    /// Enables the integrity check extension with the given checksum. (See [`Checksum`].)
+    ///
+    /// As a Host, the checksum is offered at the start of every chain. As a Device, any checksum
+    /// offered by the Host is accepted, unless this is [`Checksum::None`] (the default).
+    ///
+    /// Both sides fall back to plain transfers when the peer does not support or enable checksums.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    /// use pk_command::types::Checksum;
+    ///
+    /// let config = PkCommandConfig::default(64).with_checksum(Checksum::Crc16);
+    /// ```
+    pub fn with_checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Enables windowed transfers, with up to `window` `SDATA` packets in flight at once.
+    ///
+    /// By default (a window of 1), every `SDATA` waits for the `ACKNO` of the previous one. With a
+    /// larger window, the sender goes on while the previous packets are not acknowledged yet, and
+    /// the receiver acknowledges them cumulatively, listing those which arrived out of order. Only
+    /// the missing packets are retransmitted. This saves a round trip per packet on slow links.
+    ///
+    /// Like [checksums](Self::with_checksum), this is negotiated per transaction chain: the Host
+    /// offers its window with `START`, and the Device accepts the smaller of both. It applies to the
+    /// parameter as well as to the returned data. Both sides fall back to plain transfers when the
+    /// peer does not support or enable windows.
+    ///
+    /// The window is at most 64. A window larger than the [inbound queue](Self::with_inbound_queue)
+    /// of the receiver may overflow it when the packets arrive in a burst.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    /// use pk_command::types::QueueOverflow;
+    ///
+    /// let config = PkCommandConfig::default(64)
+    ///     .with_window(8)
+    ///     .with_inbound_queue(8, QueueOverflow::DropOldest);
+    /// ```
+    pub fn with_window(mut self, window: u16) -> Self {
+        self.window = window.clamp(1, MAX_WINDOW);
+        self
+    }
+
+    /// Enables express chains for small transactions.
+    ///
+    /// A plain chain runs through all of its phases, even when there is nearly nothing to transfer:
+    /// reading a 4-byte variable takes 16 packets. In an express chain, the root operation carries
+    /// its parameter, and the Device answers with the result in the `ACKNO` of the root operation,
+    /// so that the same read takes 4 packets. The Device skips the `ACKNO` and answers with `AWAIT`
+    /// while a method is still running, and with `RTURN` followed by the usual `SDATA` and `ENDTR`
+    /// if the result does not fit in one packet.
+    ///
+    /// This is negotiated per transaction chain, like [checksums](Self::with_checksum): the Host only
+    /// offers it with `START` if the parameter fits in the root operation. Otherwise, or when the
+    /// peer does not support or enable express chains, the chain runs through all of its phases.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    ///
+    /// let config = PkCommandConfig::default(64).with_express(true);
+    /// ```
+    pub fn with_express(mut self, express: bool) -> Self {
+        self.express = express;
+        self
+    }
+
+    /// Enables piggybacked acknowledgements.
+    ///
+    /// In a plain chain, every command is acknowledged before the next one is sent. With
+    /// piggybacked acknowledgements, each side sends its commands (the root operation, `EMPTY` or
+    /// `SDATA` and `ENDTR` for the Host, `RTURN`, `SDATA` and `ENDTR` for the Device) back to back,
+    /// and only the last one is acknowledged. The Device answers the Host's `ENDTR` with `AWAIT` or
+    /// `RTURN` right away, without waiting for `QUERY`. This saves about half of the packets of a
+    /// chain. A method which is still running after half the ACK timeout is acknowledged with
+    /// `AWAIT`.
+    ///
+    /// When a command is lost, the receiver acknowledges the last command it received in order,
+    /// and the sender sends again the ones after it. As the commands arrive back to back, the
+    /// [inbound queue](Self::with_inbound_queue) should hold as many of them as the peer sends at
+    /// once. This is not used together with [windows](Self::with_window), which already send
+    /// `SDATA` without waiting.
+    ///
+    /// This is negotiated per transaction chain, like [checksums](Self::with_checksum). Both sides
+    /// fall back to separate acknowledgements when the peer does not support or enable them.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    ///
+    /// let config = PkCommandConfig::default(64).with_piggyback(true);
+    /// ```
+    pub fn with_piggyback(mut self, piggyback: bool) -> Self {
+        self.piggyback = piggyback;
+        self
+    }
+
+    /// Sets the depth of the inbound queue and what happens when it overflows. (See [`QueueOverflow`].)
+    ///
+    /// Received commands wait in this queue until [`poll()`](crate::PkCommand::poll) processes them,
+    /// one per call. A deeper queue tolerates longer bursts between polls (e.g., an `ACKNO` immediately
+    /// followed by `AWAIT`) at the cost of memory. The depth is at least 1.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    /// use pk_command::types::QueueOverflow;
+    ///
+    /// let config = PkCommandConfig::default(64).with_inbound_queue(8, QueueOverflow::Reject);
+    /// ```
+    pub fn with_inbound_queue(mut self, depth: usize, overflow: QueueOverflow) -> Self {
+        self.inbound_queue_depth = depth.max(1);
+        self.inbound_overflow = overflow;
+        self
+    }
+
+    /// Sets how many variables the Host may subscribe to at the same time, and the minimum interval
+    /// between two notifications of a variable, in milliseconds. (See [`Notification`].)
+    ///
+    /// This only matters on the Device. A subscription beyond the limit is refused with `ERROR`, and
+    /// a Host asking for a shorter interval gets this one. Each subscribed variable is read through
+    /// the [`PkVariableAccessor`] once per interval, to find out whether it has changed.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    ///
+    /// let config = PkCommandConfig::default(64).with_subscriptions(2, 500);
+    /// ```
+    pub fn with_subscriptions(mut self, max_subscriptions: usize, min_interval: u64) -> Self {
+        self.max_subscriptions = max_subscriptions;
+        self.notify_interval = Duration::from_millis(min_interval);
+        self
+    }
+
+    /// Sets how this side resolves a collision, when both sides start a transaction at the same time.
+    /// (See [`TieBreak`].)
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    /// use pk_command::types::TieBreak;
+    ///
+    /// // One configuration for each end of the link.
+    /// let pc = PkCommandConfig::default(64).with_tie_break(TieBreak::Win);
+    /// let mcu = PkCommandConfig::default(64).with_tie_break(TieBreak::Yield);
+    /// ```
+    pub fn with_tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Sets how many times a command is retransmitted, and how long to wait for its `ACKNO` each
+    /// time. (See [`Backoff`].)
+    ///
+    /// Once a command was retransmitted `max_retries` times without being acknowledged, the peer is
+    /// considered gone: the transaction is aborted with `ERROR`, and
+    /// [`take_outcome()`](crate::PkCommand::take_outcome) yields [`PkError::Timeout`]. The `ERROR`
+    /// itself is retransmitted as many times at most. Use [`u32::MAX`] to retransmit forever.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    /// use pk_command::types::Backoff;
+    /// use std::time::Duration;
+    ///
+    /// let config = PkCommandConfig::default(64).with_retries(
+    ///     5,
+    ///     Backoff::Exponential {
+    ///         max_timeout: Duration::from_secs(1),
+    ///         jitter: true,
+    ///     },
+    /// );
+    /// ```
+    pub fn with_retries(mut self, max_retries: u32, backoff: Backoff) -> Self {
+        self.max_retries = max_retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Makes the ACK timeout adapt to the link, between `min_timeout` and `max_timeout` milliseconds.
+    ///
+    /// The round-trip time of every command is measured, from sending it to receiving its `ACKNO`,
+    /// and the timeout follows a smoothed round-trip time and its variance, the way TCP does it.
+    /// Retransmitted commands are not measured, as their `ACKNO` may be for any of the copies;
+    /// instead, the timeout doubles each time it runs out, until the next measurement.
+    /// The configured ACK timeout is used until the first measurement.
+    ///
+    /// The current timeout is returned by [`PkCommand::retransmission_timeout()`].
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    ///
+    /// // From a fast USB link to a slow UART bridge.
+    /// let config = PkCommandConfig::default(64).with_adaptive_timeout(5, 2000);
+    /// ```
+    pub fn with_adaptive_timeout(mut self, min_timeout: u64, max_timeout: u64) -> Self {
+        self.rto_bounds = Some((
+            Duration::from_millis(min_timeout),
+            Duration::from_millis(max_timeout.max(min_timeout)),
+        ));
+        self
+    }
+
+    /// Sets the longest a chain performed by this Host may take, in milliseconds, unless
+    /// [`perform_within()`](crate::PkCommand::perform_within) gives another one.
+    ///
+    /// Once it is over, the Host aborts the chain with `ERROR`, and
+    /// [`take_outcome()`](crate::PkCommand::take_outcome) yields [`PkError::Timeout`].
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    ///
+    /// let config = PkCommandConfig::default(64).with_deadline(5000);
+    /// ```
+    pub fn with_deadline(mut self, deadline: u64) -> Self {
+        self.deadline = Some(Duration::from_millis(deadline));
+        self
+    }
+
+    /// Sets how many `AWAIT`s the Host acknowledges in a chain. The next one aborts the chain with
+    /// `ERROR`, and [`take_outcome()`](crate::PkCommand::take_outcome) yields [`PkError::Timeout`].
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    ///
+    /// // About 3 seconds with the default `AWAIT` interval.
+    /// let config = PkCommandConfig::default(64).with_max_awaits(10);
+    /// ```
+    pub fn with_max_awaits(mut self, max_awaits: u32) -> Self {
+        self.max_awaits = Some(max_awaits);
+        self
+    }
+
+    /// Sets the longest a method may run on the Device, in milliseconds.
+    ///
+    /// Once it is over, the method is [cancelled](crate::Pollable::cancel) and the Device aborts the
+    /// chain with `ERROR`. The Host then gets a [`PkError::Remote`] describing a timeout.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    ///
+    /// let config = PkCommandConfig::default(64).with_max_execution(10_000);
+    /// ```
+    pub fn with_max_execution(mut self, max_execution: u64) -> Self {
+        self.max_execution = Some(Duration::from_millis(max_execution));
+        self
+    }
+
+    /// Sets how long [`run_with()`](crate::PkCommand::run_with) sleeps, in milliseconds, when it
+    /// neither received nor sent anything. `0` never sleeps, which keeps a CPU core busy unless the
+    /// transport waits in [`recv()`](PkTransport::recv) itself.
+    ///
+    /// The sleep needs the `std` feature. Without it, the transport has to do the waiting.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkCommandConfig;
+    ///
+    /// let config = PkCommandConfig::default(64).with_idle_wait(5);
+    /// ```
+    pub fn with_idle_wait(mut self, idle_wait: u64) -> Self {
+        self.idle_wait = Duration::from_millis(idle_wait);
+        self
+    }
+
+    /// Returns the capabilities this configuration advertises in a [handshake](crate::PkCommand::handshake).
+    pub fn capabilities(&self) -> Capabilities {
+        let mut extensions = 0;
+        if !self.checksum.is_none() {
+            extensions |= Capabilities::CHECKSUM;
+        }
+        if self.window > 1 {
+            extensions |= Capabilities::WINDOW;
+        }
+        if self.express {
+            extensions |= Capabilities::EXPRESS;
+        }
+        if self.piggyback {
+            extensions |= Capabilities::PIGGYBACK;
+        }
+        Capabilities {
+            major: major_version(self.pk_version).unwrap_or_default(),
+            packet_limit: self.packet_limit,
+            ack_timeout: self.ack_timeout,
+            inter_command_timeout: self.inter_command_timeout,
+            await_interval: self.await_interval,
+            extensions,
         }
     }
 }
 
+/// A root operation, its object, its parameter and its deadline, put aside after losing a collision.
+type DeferredChain<Instant> = (Operation, Option<String>, Vec<u8>, Option<Instant>);
+
+/// A variable the Host subscribed to, as recorded by the Device.
+struct Subscription<Instant> {
+    name: String,
+    interval: Duration,
+    /// The value the Host was last told about, or `None` if it has to be sent again.
+    last_value: Option<Vec<u8>>,
+    /// When the variable is checked next.
+    due: Instant,
+}
+
+/// An `SDATA` sent in a window, and not acknowledged yet.
+struct InFlight<Instant> {
+    command: Command,
+    /// When it was last sent.
+    sent_at: Instant,
+    /// How long to wait for its `ACKNO`, grown by the [`Backoff`] on each retransmission.
+    wait: Duration,
+    retransmissions: u32,
+    /// The receiver has it, but misses some of the packets before it.
+    selected: bool,
+}
+
 /// The main state machine for handling the PK Command protocol.
 ///
 /// It manages the lifecycle of a transaction, including:
@@ -457,6 +1010,12 @@ impl PkCommandConfig {
 /// - **Host** is the one who calls [`perform()`](crate::PkCommand::perform) to initiate a transaction (e.g., `SENDV`, `INVOK`).
 /// - **Device** is the one who reacts against the transaction and automatically responds to incoming root commands using the provided accessors.
 ///
+/// Every [`PkCommand`] can play both roles, one transaction at a time: while idle, it serves the
+/// transactions of the peer, and it can [`perform()`](crate::PkCommand::perform) its own. So an
+/// embedded device can also raise events to a PC by performing, say, an `INVOK` on it. If both
+/// sides start a transaction at the same time, the [`TieBreak`] rule in their configuration decides
+/// which one goes first.
+///
 /// # Example
 /// ```no_run
 /// use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
@@ -497,8 +1056,8 @@ where
     sending_data_progress: Cell<u64>,
     root_operation: Cell<Operation>,
     root_object: RefCell<Option<String>>,
-    command_buffer: RefCell<Command>,
-    command_processed: Cell<bool>,
+    inbound_queue: RefCell<VecDeque<Command>>,
+    dropped_commands: Cell<u32>, // 入站队列满时丢弃的指令数
     last_command_time: Cell<Instant>,
     device_op_pending: Cell<bool>,
     device_await_deadline: Cell<Option<Instant>>,
@@ -507,6 +1066,35 @@ where
     method_accessor: MA,
     pending_pollable: RefCell<Option<Pin<Box<dyn Pollable>>>>,
     device_should_return: Cell<bool>, // 设备是否“收到了 QUERY 但还没有返回值”
+    chain_checksum: Cell<Checksum>,   // 本条链协商得到的校验方式
+    last_ack: RefCell<Option<Command>>, // 最近一次回复的 ACK，用于应答对方的重传
+    last_error: RefCell<Option<(PkError, FailureOrigin)>>, // 使本条链中止的错误及其来源
+    own_chain: Cell<bool>,            // 上一条链是本端发起的，结果还没被取走
+    held_outcome: RefCell<Option<TransactionOutcome>>, // 为对方的链服务之前，先存起来的本端的结果
+    session: Cell<Capabilities>,      // 当前生效的会话参数，握手后为双方的最小公共值
+    subscriptions: RefCell<Vec<Subscription<Instant>>>, // Device 上 Host 订阅的变量
+    notifications: RefCell<VecDeque<Notification>>, // Host 收到、还没被取走的通知
+    incoming_notification: RefCell<Option<Notification>>, // Host 正在分段接收的通知
+    deferred: RefCell<Option<DeferredChain<Instant>>>, // 冲突时让出、之后再开始的根操作
+    cancelling: Cell<bool>,           // Host 调用了 cancel()，下次 poll 时发送 ERROR
+    chain_deadline: Cell<Option<Instant>>, // Host 的这条链必须在此之前结束
+    awaits: Cell<u32>,                // Host 在这条链中确认过的 AWAIT 数
+    execution_deadline: Cell<Option<Instant>>, // Device 上的方法必须在此之前完成
+    retransmissions: Cell<u32>,       // 最后发送的指令已经重传的次数
+    ack_wait: Cell<Duration>,         // 等待最后发送的指令的 ACK 的时长，重传时按退避策略增长
+    jitter_state: Cell<u32>,          // 退避抖动用的伪随机数状态
+    rtt: Cell<Option<RttEstimator>>,  // 自适应 ACK 超时时的往返时间估计
+    sent_at: Cell<Instant>,           // 最后发送的指令第一次发出的时刻，用于测量往返时间
+    chain_window: Cell<u16>,          // 本条链协商得到的 SDATA 窗口，1 即逐个确认
+    in_flight: RefCell<VecDeque<InFlight<Instant>>>, // 窗口中已发出、还没被确认的 SDATA
+    held_chunks: RefCell<Vec<(u16, Vec<u8>)>>, // 窗口模式下先于之前的分段到达的 SDATA
+    chain_express: Cell<bool>,        // 本条链是否为快速链，参数和结果直接随指令传输
+    chain_piggyback: Cell<bool>,      // 本条链是否捎带确认：一轮指令连续发送，只确认最后一条
+    implied_ack: Cell<bool>,          // 刚发出的指令之后还有同一轮的指令，下次 poll 时当作已被确认
+    quiet_ack: Cell<bool>,            // 正在处理的指令之后还有同一轮的指令，不单独确认
+    unacked: RefCell<VecDeque<Command>>, // 捎带确认时本轮已发出、还没被确认的指令
+    resend: RefCell<VecDeque<Command>>, // 对方只确认了之前的指令，需要依次重发的指令
+    rewinds: Cell<u32>,               // 对方连续多少次确认到同一条指令
 }
 
 impl<
@@ -518,62 +1106,749 @@ impl<
     /// Ingests a raw command received from the other party.
     ///
     /// This should be called whenever new bytes arrive on your transport layer. The
-    /// state machine will parse the bytes and update its internal buffers for the
-    /// next [`poll()`](crate::PkCommand::poll) cycle.
+    /// state machine will parse the bytes and append the command to its inbound queue.
+    /// Each [`poll()`](crate::PkCommand::poll) processes one queued command, in the order they arrived.
     ///
     /// # Arguments
     /// * `command_bytes`: The raw bytes of the received command.
     ///
     /// # Returns
-    /// `Ok(())` if the command was successfully parsed and buffered.
-    /// `Err(&'static str)` if parsing failed (e.g., invalid format, unknown operation).
-    pub fn incoming_command(&self, command_bytes: Vec<u8>) -> Result<(), &'static str> {
-        match Command::parse(&command_bytes) {
-            // Pass as slice
-            Ok(parsed_command) => {
-                self.command_buffer.replace(parsed_command);
-                self.command_processed.set(false);
-                self.last_command_time.replace(Instant::now());
-                Ok(())
+    /// `Ok(free)` if the command was successfully parsed and queued, where `free` is the number of
+    /// commands that can still be queued before the queue overflows. When it reaches 0, poll the
+    /// state machine before feeding it more data.
+    /// `Err(PkError)` if parsing failed (see [`Command::parse()`]), or [`PkError::QueueFull`] if the
+    /// queue is full and configured to [reject](QueueOverflow::Reject) new commands.
+    ///
+    /// A full queue configured to [drop the oldest](QueueOverflow::DropOldest) command still returns
+    /// `Ok(0)`. The drops are counted by [`dropped_commands()`](crate::PkCommand::dropped_commands).
+    pub fn incoming_command(&self, command_bytes: Vec<u8>) -> Result<usize, PkError> {
+        let parsed_command = Command::parse(&command_bytes)?;
+        let depth = self.config.inbound_queue_depth;
+        let mut queue = self.inbound_queue.borrow_mut();
+        if queue.len() >= depth {
+            match self.config.inbound_overflow {
+                QueueOverflow::DropOldest => {
+                    queue.pop_front();
+                    self.dropped_commands
+                        .set(self.dropped_commands.get().saturating_add(1));
+                }
+                QueueOverflow::Reject => return Err(PkError::QueueFull),
             }
-            Err(e) => Err(e),
         }
+        queue.push_back(parsed_command);
+        self.last_command_time.replace(Instant::now());
+        Ok(depth - queue.len())
+    }
+
+    /// Returns how many received commands were dropped so far because the inbound queue was full.
+    ///
+    /// Only a queue configured to [drop the oldest](QueueOverflow::DropOldest) command drops any.
+    /// A steadily growing count means the state machine is not polled often enough for the link,
+    /// or the [queue](PkCommandConfig::with_inbound_queue) is too shallow.
+    pub fn dropped_commands(&self) -> u32 {
+        self.dropped_commands.get()
     }
 
     /// Slices a chunk of data from internal buffers for multipart transfer.
     ///
     /// This is an internal utility used during `SDATA` phases.
-    fn slice_data(&self, role: Role) -> Result<(Vec<u8>, bool), &'static str> {
+    fn slice_data(&self, role: Role) -> Result<(Vec<u8>, bool), PkError> {
         // 如果 Role 是 Device 则默认在发送返回值，反之亦然
         match role {
             Role::Device => {
                 let data = self.data_return.borrow();
                 if data.is_empty() {
-                    return Err("No return data to slice.");
+                    return Err(PkError::Internal("no return data to slice"));
                 }
-                let start = self.sending_data_progress.get() as usize;
-                let end =
-                    std::cmp::min(start + (self.config.packet_limit - 14) as usize, data.len());
-                let is_last_packet = end == data.len();
-                self.sending_data_progress.set(end as u64);
-                Ok((data[start..end].to_vec(), is_last_packet))
+                self.slice_chunk(&data)
             }
             Role::Host => {
                 let data = self.data_param.borrow();
                 if data.is_empty() {
-                    return Err("No parameter data to slice.");
+                    return Err(PkError::Internal("no parameter data to slice"));
                 }
-                let start = self.sending_data_progress.get() as usize;
-                let end =
-                    std::cmp::min(start + (self.config.packet_limit - 14) as usize, data.len());
-                let is_last_packet = end == data.len();
-                self.sending_data_progress.set(end as u64);
-                Ok((data[start..end].to_vec(), is_last_packet))
+                self.slice_chunk(&data)
+            }
+            Role::Idle => Err(PkError::Internal("cannot slice data in Idle role")),
+        }
+    }
+
+    /// How many bytes of payload fit in a command with a `checksum` trailer, after the header.
+    ///
+    /// Returns `None` if the packet limit leaves no room at all.
+    fn payload_capacity(&self, checksum: Checksum) -> Option<usize> {
+        usize::try_from(self.session.get().packet_limit)
+            .unwrap_or(usize::MAX)
+            .checked_sub(HEADER_LEN + checksum.size())
+            .filter(|&capacity| capacity > 0)
+    }
+
+    /// Takes the next chunk out of `data`, with the checksum trailer appended if the chain uses one.
+    ///
+    /// Fails with [`PkError::Incompatible`] if the packet limit is too small to carry any data.
+    fn slice_chunk(&self, data: &[u8]) -> Result<(Vec<u8>, bool), PkError> {
+        let checksum = self.chain_checksum.get();
+        let Some(chunk_size) = self.payload_capacity(checksum) else {
+            return Err(PkError::Incompatible(format!(
+                "packet limit {} is too small",
+                self.session.get().packet_limit
+            )));
+        };
+        let start = self.sending_data_progress.get() as usize;
+        let end = std::cmp::min(start + chunk_size, data.len());
+        let is_last_packet = end == data.len();
+        self.sending_data_progress.set(end as u64);
+        let mut chunk = data[start..end].to_vec();
+        chunk.extend(checksum.compute(&chunk));
+        Ok((chunk, is_last_packet))
+    }
+
+    /// Strips and verifies the checksum trailer of a received `SDATA` payload.
+    ///
+    /// Returns `None` if the packet is corrupted and should be dropped.
+    fn verify_chunk(&self, data: Option<&[u8]>) -> Option<Vec<u8>> {
+        self.chain_checksum
+            .get()
+            .strip(data.unwrap_or_default())
+            .map(|payload| payload.to_vec())
+    }
+
+    /// Builds an `ENDTR` command, carrying the checksum of the whole `payload` if the chain uses one.
+    fn end_transaction(&self, msg_id: u16, payload: &[u8]) -> Command {
+        let checksum = self.chain_checksum.get();
+        Command {
+            msg_id,
+            operation: Operation::EndTransaction,
+            object: checksum.to_name().map(String::from),
+            data: checksum.to_name().map(|_| checksum.compute(payload)),
+        }
+    }
+
+    /// Checks the whole-payload checksum carried by a received `ENDTR`.
+    fn verify_payload(&self, endtr: &Command, payload: &[u8]) -> bool {
+        let checksum = self.chain_checksum.get();
+        checksum.is_none()
+            || (endtr.object.as_deref() == checksum.to_name()
+                && endtr.data.as_deref() == Some(&checksum.compute(payload)[..]))
+    }
+
+    /// Lists the extensions a Host offers in `START`, according to the configuration.
+    fn offer_extensions(&self) -> Option<Vec<u8>> {
+        let mut tokens: Vec<String> = Vec::new();
+        if self.session.get().supports(Capabilities::CHECKSUM) {
+            tokens.extend(self.config.checksum.to_name().map(String::from));
+        }
+        if self.session.get().supports(Capabilities::WINDOW) {
+            tokens.push(format!("{WINDOW_EXTENSION}{}", self.config.window));
+        }
+        // 参数要能放进根操作。没有对象的根操作带不了数据，只有参数为空时才可以
+        let param = self.data_param.borrow();
+        if self.session.get().supports(Capabilities::EXPRESS)
+            && (self.root_object.borrow().is_some() || param.is_empty())
+            && self.fits_inline(&param, self.config.checksum)
+        {
+            tokens.push(String::from(EXPRESS_EXTENSION));
+        }
+        if self.session.get().supports(Capabilities::PIGGYBACK) {
+            tokens.push(String::from(PIGGYBACK_EXTENSION));
+        }
+        if tokens.is_empty() {
+            None
+        } else {
+            Some(tokens.join(" ").into_bytes())
+        }
+    }
+
+    /// Accepts (as a Device) some of the extensions offered in `START`, and enables them for the chain.
+    ///
+    /// Returns the accepted ones, to be listed in `ACKNO START`.
+    fn accept_extensions(&self, start: &Command) -> Option<Vec<u8>> {
+        if start.object.as_deref() != Some(EXTENSION_OBJECT) {
+            return None;
+        }
+        let mut accepted: Vec<String> = Vec::new();
+        for token in extension_tokens(start.data.as_deref()) {
+            if let Some(checksum) = Checksum::from_name(token)
+                && self.session.get().supports(Capabilities::CHECKSUM)
+                && self.chain_checksum.get().is_none()
+            {
+                self.chain_checksum.set(checksum);
+                accepted.push(String::from(token));
+            } else if let Some(window) = window_size(token)
+                && self.session.get().supports(Capabilities::WINDOW)
+                && self.chain_window.get() == 1
+            {
+                let window = window.min(self.config.window);
+                self.chain_window.set(window);
+                accepted.push(format!("{WINDOW_EXTENSION}{window}"));
+            } else if token == EXPRESS_EXTENSION
+                && self.session.get().supports(Capabilities::EXPRESS)
+            {
+                self.chain_express.set(true);
+                accepted.push(String::from(token));
+            } else if token == PIGGYBACK_EXTENSION
+                && self.session.get().supports(Capabilities::PIGGYBACK)
+                && self.chain_window.get() == 1
+            {
+                // 窗口已经让 SDATA 不等 ACK 就发送，两者不同时使用
+                self.chain_piggyback.set(true);
+                accepted.push(String::from(token));
+            }
+        }
+        if accepted.is_empty() {
+            None
+        } else {
+            Some(accepted.join(" ").into_bytes())
+        }
+    }
+
+    /// Enables (as a Host) the extensions the Device accepted in `ACKNO START`.
+    fn apply_extensions(&self, ack: &Command) {
+        for token in extension_tokens(ack.data.as_deref()) {
+            if let Some(checksum) = Checksum::from_name(token)
+                && checksum == self.config.checksum
+            {
+                self.chain_checksum.set(checksum);
+            } else if let Some(window) = window_size(token)
+                && self.session.get().supports(Capabilities::WINDOW)
+            {
+                self.chain_window.set(window.min(self.config.window));
+            } else if token == EXPRESS_EXTENSION
+                && self.session.get().supports(Capabilities::EXPRESS)
+            {
+                self.chain_express.set(true);
+            } else if token == PIGGYBACK_EXTENSION
+                && self.session.get().supports(Capabilities::PIGGYBACK)
+                && self.chain_window.get() == 1
+            {
+                self.chain_piggyback.set(true);
             }
-            Role::Idle => Err("Cannot slice data in Idle role."),
         }
     }
 
+    /// Gets ready (as a Device which received `START`) to serve a new chain.
+    ///
+    /// A chain which ended normally leaves its parameter, result and outcome behind, so that they
+    /// can still be collected. They are forgotten here, before the new chain starts, except for the
+    /// outcome of a chain this side performed, which is held until it is taken.
+    fn begin_device_chain(&self) {
+        // 本端发起的链的结果还没被取走：另外存起来，不能被对方的链清掉
+        if self.own_chain.replace(false) {
+            let outcome = match self.last_error.take() {
+                Some((reason, origin)) => Some(TransactionOutcome::Failed { reason, origin }),
+                None if self.role.get() == Role::Host => {
+                    let data = self.data_return.take();
+                    Some(TransactionOutcome::Completed(
+                        (!data.is_empty()).then_some(data),
+                    ))
+                }
+                None => None,
+            };
+            self.held_outcome.replace(outcome);
+        }
+        // 否则同一个 Device 接连处理多条链时，上一条链的参数和结果会残留下来
+        self.data_param.borrow_mut().clear();
+        self.data_return.borrow_mut().clear();
+        self.sending_data_progress.set(0);
+        self.reset_extensions();
+        self.last_error.replace(None);
+        self.incoming_notification.replace(None);
+        self.role.set(Role::Device);
+        self.stage.set(Stage::Started);
+        self.status.set(Status::Other); // Awaiting root command from Host
+    }
+
+    /// Turns off the extensions negotiated for the chain, and forgets the packets of its window.
+    fn reset_extensions(&self) {
+        self.chain_checksum.set(Checksum::None);
+        self.chain_window.set(1);
+        self.chain_express.set(false);
+        self.chain_piggyback.set(false);
+        self.in_flight.borrow_mut().clear();
+        self.held_chunks.borrow_mut().clear();
+        self.implied_ack.set(false);
+        self.unacked.borrow_mut().clear();
+        self.resend.borrow_mut().clear();
+        self.rewinds.set(0);
+    }
+
+    /// Returns `true` if, in a chain with piggybacked acknowledgements, `command` is always followed
+    /// by another command from the same side. It is then sent without waiting for its `ACKNO`, and
+    /// the receiver does not acknowledge it on its own.
+    fn continues_turn(&self, command: &Command) -> bool {
+        if !self.chain_piggyback.get() {
+            return false;
+        }
+        match command.operation {
+            operation if operation.is_root() => !self.chain_express.get(),
+            Operation::Empty => true,
+            Operation::Data => command.object.as_deref() != Some(Operation::Notify.to_name()),
+            // 快速链中带着结果（或者为空）的 RTURN 结束了整条链
+            Operation::Return => {
+                !(self.chain_express.get()
+                    && (command.data.is_some()
+                        || command.object.as_deref() == Some(Operation::Empty.to_name())))
+            }
+            _ => false,
+        }
+    }
+
+    /// Takes (as the sender of a chain with piggybacked acknowledgements) an `ACKNO` of a command
+    /// before the last one sent into account: the receiver missed the commands after it, which are
+    /// then sent again, in order.
+    ///
+    /// Returns `false` if `ack` acknowledges the last command sent, and should be handled as usual.
+    fn rewind(&self, ack: &Command) -> bool {
+        if self.status.get() != Status::AwaitingAck {
+            return false;
+        }
+        let mut unacked = self.unacked.borrow_mut();
+        let Some(first) = unacked.front() else {
+            return false;
+        };
+        {
+            let last_sent = self.last_sent_command.borrow();
+            if ack.msg_id == last_sent.msg_id
+                && ack.object.as_deref() == Some(last_sent.operation.to_name())
+            {
+                return false;
+            }
+        }
+        if let Some(index) = unacked
+            .iter()
+            .position(|command| command.msg_id == ack.msg_id)
+        {
+            unacked.drain(..=index);
+            self.retransmissions.set(0);
+            self.rewinds.set(0);
+        } else if util::msg_id::increment(ack.msg_id) != first.msg_id {
+            // 过期的 ACK
+            return true;
+        } else if self.rewinds.get() >= self.config.max_retries {
+            // 一直没有进展，交给超时处理来放弃这条链
+            return true;
+        } else {
+            // 重发过的指令又丢了。超时重传单独计数，否则回应超时重传的 ACK 会让一次重传被算两次
+            self.rewinds.set(self.rewinds.get() + 1);
+        }
+        self.resend.replace(unacked.clone());
+        true
+    }
+
+    /// Sends again (as the sender of a chain with piggybacked acknowledgements) the next command
+    /// the receiver missed. (See [`Self::rewind()`].)
+    fn next_resend(&self) -> Option<Command> {
+        let command = self.resend.borrow_mut().pop_front()?;
+        self.last_command_time.set(Instant::now());
+        Some(command)
+    }
+
+    /// Forgets the commands of the current turn, once the receiver acknowledged or answered all of
+    /// them.
+    fn settle_turn(&self) {
+        self.unacked.borrow_mut().clear();
+        self.resend.borrow_mut().clear();
+        self.rewinds.set(0);
+    }
+
+    /// Checks whether `payload` fits in a single packet of an express chain, with a `checksum` trailer.
+    fn fits_inline(&self, payload: &[u8], checksum: Checksum) -> bool {
+        self.payload_capacity(checksum)
+            .is_some_and(|capacity| payload.len() <= capacity)
+    }
+
+    /// Builds the data of a command carrying `payload` inline in an express chain: nothing at all
+    /// if it is empty, or else the payload with the checksum trailer appended if the chain uses one.
+    fn inline_data(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.is_empty() {
+            return None;
+        }
+        let mut data = payload.to_vec();
+        data.extend(self.chain_checksum.get().compute(payload));
+        Some(data)
+    }
+
+    /// Reads the payload carried inline by a command of an express chain. (See [`Self::inline_data()`].)
+    ///
+    /// Returns `None` if the packet is corrupted and should be dropped.
+    fn read_inline(&self, data: Option<&[u8]>) -> Option<Vec<u8>> {
+        match data {
+            Some(_) => self.verify_chunk(data),
+            None => Some(Vec::new()),
+        }
+    }
+
+    /// Records (as the sender of a windowed transfer) an `SDATA` just sent, until it is acknowledged.
+    fn track_chunk(&self, command: &Command) {
+        if self.chain_window.get() > 1 {
+            self.in_flight.borrow_mut().push_back(InFlight {
+                command: command.clone(),
+                sent_at: Instant::now(),
+                wait: self.retransmission_timeout(),
+                retransmissions: 0,
+                selected: false,
+            });
+        }
+    }
+
+    /// Sends (as the sender of a windowed transfer) the next `SDATA`, without waiting for the
+    /// `ACKNO` of the previous ones.
+    fn send_next_chunk(&self) -> Result<Command, PkError> {
+        let (chunk, _) = self.slice_data(self.role.get())?;
+        let command = Command {
+            msg_id: util::msg_id::increment(self.last_sent_msg_id.get()),
+            operation: Operation::Data,
+            object: Some(self.root_operation.get().to_name().to_string()),
+            data: Some(chunk),
+        };
+        self.last_command_time.set(Instant::now());
+        self.last_sent_msg_id.set(command.msg_id);
+        self.last_sent_command.replace(command.clone());
+        self.status.set(Status::AwaitingAck);
+        self.track_chunk(&command);
+        Ok(command)
+    }
+
+    /// Keeps (as the sender of a windowed transfer) the window going: retransmits an `SDATA` which
+    /// was lost, or else sends the next one if the window is not full.
+    ///
+    /// An `SDATA` is lost when its ACK timeout ran out, or when the receiver acknowledged some
+    /// packets after it but not this one. It is then retransmitted right away, once.
+    fn poll_window(&self) -> Result<Option<Command>, PkError> {
+        let mut in_flight = self.in_flight.borrow_mut();
+        let last_selected = in_flight.iter().rposition(|chunk| chunk.selected);
+        for (index, chunk) in in_flight.iter_mut().enumerate() {
+            if chunk.selected {
+                continue;
+            }
+            let elapsed = chunk.sent_at.elapsed();
+            let timed_out = elapsed >= chunk.wait;
+            // 之后的分段已被选择确认：这一段丢了，不等超时
+            let skipped =
+                chunk.retransmissions == 0 && last_selected.is_some_and(|last| index < last);
+            if !timed_out && !skipped {
+                continue;
+            }
+            if chunk.retransmissions >= self.config.max_retries {
+                return Err(PkError::Timeout);
+            }
+            chunk.retransmissions += 1;
+            if timed_out {
+                let noise = elapsed.subsec_nanos();
+                chunk.wait = self
+                    .config
+                    .backoff
+                    .next_timeout(chunk.wait, self.next_random(noise));
+                if let Some(mut rtt) = self.rtt.get() {
+                    rtt.back_off();
+                    self.rtt.set(Some(rtt));
+                    chunk.wait = chunk.wait.max(rtt.rto());
+                }
+            }
+            chunk.sent_at = Instant::now();
+            self.last_command_time.set(Instant::now());
+            return Ok(Some(chunk.command.clone()));
+        }
+        let data_len = match self.role.get() {
+            Role::Host => self.data_param.borrow().len(),
+            _ => self.data_return.borrow().len(),
+        };
+        if in_flight.len() < usize::from(self.chain_window.get())
+            && (self.sending_data_progress.get() as usize) < data_len
+        {
+            drop(in_flight);
+            return self.send_next_chunk().map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Takes (as the sender of a windowed transfer) an `ACKNO SDATA` into account.
+    ///
+    /// Its MSG ID is the last `SDATA` the receiver got in order, and its data lists the MSG IDs of
+    /// those it got out of order.
+    fn acknowledge_chunks(&self, ack: &Command) {
+        let mut in_flight = self.in_flight.borrow_mut();
+        if let Some(index) = in_flight
+            .iter()
+            .position(|chunk| chunk.command.msg_id == ack.msg_id)
+        {
+            // 重传过的分段不知道 ACK 对应哪一次发送，不测量往返时间
+            let chunk = &in_flight[index];
+            if chunk.retransmissions == 0
+                && let Some(mut rtt) = self.rtt.get()
+            {
+                rtt.sample(chunk.sent_at.elapsed());
+                self.rtt.set(Some(rtt));
+            }
+            in_flight.drain(..=index);
+        }
+        for id in extension_tokens(ack.data.as_deref()).filter_map(|id| id.parse::<u16>().ok()) {
+            if let Some(chunk) = in_flight
+                .iter_mut()
+                .find(|chunk| chunk.command.msg_id == id)
+            {
+                chunk.selected = true;
+            }
+        }
+    }
+
+    /// Takes (as the receiver of a windowed transfer) an `SDATA` into `buffer`, or keeps it aside
+    /// until the packets before it arrive.
+    ///
+    /// Returns what to acknowledge: the MSG ID of the last `SDATA` received in order, and the MSG IDs
+    /// of those kept aside, if any. Returns `None` if the packet is corrupted and should be dropped.
+    fn receive_chunk(
+        &self,
+        recv: &Command,
+        buffer: &RefCell<Vec<u8>>,
+    ) -> Option<(u16, Option<Vec<u8>>)> {
+        let chunk = self.verify_chunk(recv.data.as_deref())?;
+        // 上一个 ACK 确认的就是按顺序收到的最后一段（或者之前的根操作、RTURN）
+        let mut last = self.last_ack.borrow().as_ref()?.msg_id;
+        let mut held = self.held_chunks.borrow_mut();
+        let distance = util::msg_id::distance(last, recv.msg_id);
+        if distance == 1 {
+            buffer.borrow_mut().extend_from_slice(&chunk);
+            last = recv.msg_id;
+            // 先到的分段现在接上了
+            while let Some(index) = held
+                .iter()
+                .position(|(id, _)| *id == util::msg_id::increment(last))
+            {
+                let (id, chunk) = held.swap_remove(index);
+                buffer.borrow_mut().extend_from_slice(&chunk);
+                last = id;
+            }
+        } else if (2..=self.chain_window.get()).contains(&distance)
+            && !held.iter().any(|(id, _)| *id == recv.msg_id)
+        {
+            held.push((recv.msg_id, chunk));
+        }
+        // 其余的是重复的分段，再确认一次即可
+        let selected = (!held.is_empty()).then(|| {
+            held.iter()
+                .map(|(id, _)| id.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+                .into_bytes()
+        });
+        Some((last, selected))
+    }
+
+    /// Checks (as a Device) that the Host may run a root operation on an object.
+    fn is_allowed(&self, operation: Operation, object: &str) -> bool {
+        match operation {
+            Operation::RequireVariable | Operation::Subscribe => {
+                self.variable_accessor.access(object).is_readable()
+            }
+            Operation::SendVariable => self.variable_accessor.access(object).is_writable(),
+            Operation::Invoke => self.method_accessor.is_permitted(object),
+            _ => true,
+        }
+    }
+
+    /// Records (as a Device) a subscription of the Host to a variable holding `value`, or updates it.
+    ///
+    /// `param` is the minimum interval between notifications requested by the Host, if any.
+    fn record_subscription(
+        &self,
+        name: String,
+        param: &[u8],
+        value: Vec<u8>,
+    ) -> Result<(), PkError> {
+        let requested = if param.is_empty() {
+            0
+        } else {
+            core::str::from_utf8(param)
+                .ok()
+                .and_then(|ms| ms.parse().ok())
+                .ok_or(PkError::InvalidData("interval in milliseconds"))?
+        };
+        let interval = Duration::from_millis(requested).max(self.config.notify_interval);
+        let due = Instant::now() + interval;
+        let mut subscriptions = self.subscriptions.borrow_mut();
+        if let Some(subscription) = subscriptions.iter_mut().find(|s| s.name == name) {
+            subscription.interval = interval;
+            subscription.last_value = Some(value);
+            subscription.due = due;
+        } else if subscriptions.len() >= self.config.max_subscriptions {
+            return Err(PkError::Variable(String::from("too many subscriptions")));
+        } else {
+            subscriptions.push(Subscription {
+                name,
+                interval,
+                last_value: Some(value),
+                due,
+            });
+        }
+        Ok(())
+    }
+
+    /// Finds (as a Device) a subscribed variable which is due and has changed since the Host was
+    /// last told about it. Returns its name and new value.
+    fn changed_subscription(&self) -> Option<(String, Vec<u8>)> {
+        let now = Instant::now();
+        let mut subscriptions = self.subscriptions.borrow_mut();
+        for subscription in subscriptions.iter_mut().filter(|s| now >= s.due) {
+            subscription.due = now + subscription.interval;
+            // 变量不存在了就不再通知，直到 Host 取消订阅
+            if let Some(value) = self.variable_accessor.get(subscription.name.clone())
+                && subscription.last_value.as_ref() != Some(&value)
+            {
+                subscription.last_value = Some(value.clone());
+                return Some((subscription.name.clone(), value));
+            }
+        }
+        None
+    }
+
+    /// Gives up (as a Device) the notification in progress. It is sent again when the variable is due.
+    fn abandon_notification(&self) {
+        let name = self.root_object.borrow();
+        if let Some(subscription) = self
+            .subscriptions
+            .borrow_mut()
+            .iter_mut()
+            .find(|s| name.as_ref() == Some(&s.name))
+        {
+            subscription.last_value = None;
+        }
+    }
+
+    /// Queues (as a Host) a received notification, replacing an older one of the same variable.
+    fn push_notification(&self, notification: Notification) {
+        let mut queue = self.notifications.borrow_mut();
+        match queue.iter_mut().find(|n| n.name == notification.name) {
+            Some(queued) => queued.value = notification.value,
+            None => queue.push_back(notification),
+        }
+    }
+
+    /// Negotiates (as a Device) the session with the capabilities a Host sent in a handshake.
+    ///
+    /// Returns what to append to the version string: this side's own capabilities.
+    fn accept_handshake(&self, param: &[u8]) -> Result<Vec<u8>, PkError> {
+        let own = self.config.capabilities();
+        let peer = Capabilities::parse(param)?;
+        self.session.set(own.negotiate(&peer)?);
+        // 握手开始了新的会话，之前的订阅不再有效
+        self.subscriptions.borrow_mut().clear();
+        Ok(own.to_bytes())
+    }
+
+    /// Negotiates (as a Host) the session with the reply of the Device to a handshake.
+    ///
+    /// The capabilities are cut off the reply, so that only the version string is returned.
+    fn apply_handshake(&self) -> Result<(), PkError> {
+        let own = self.config.capabilities();
+        let mut reply = self.data_return.borrow_mut();
+        match reply.iter().position(|&b| b == b'\n') {
+            Some(pos) => {
+                let peer = Capabilities::parse(&reply[pos + 1..])?;
+                reply.truncate(pos);
+                self.session.set(own.negotiate(&peer)?);
+            }
+            None => {
+                // 不认识握手的旧版本 Device 只回复版本号，此时只能检查主版本号，会话参数保持不变
+                let version = String::from_utf8_lossy(&reply);
+                if major_version(&version) != Some(own.major) {
+                    return Err(PkError::Incompatible(format!(
+                        "protocol version {} is not {}",
+                        version, own.major
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs (as a Device) the root operation of the chain with the received parameter, and keeps
+    /// its result in `data_return`. The method of an `INVOK` is started, and polled afterwards.
+    fn execute_root_operation(&self) -> Result<(), PkError> {
+        match self.root_operation.get() {
+            Operation::GetVersion => {
+                let mut version = self.config.pk_version.as_bytes().to_vec();
+                // 带参数的 PKVER 是握手，在版本号后面换行附上自己的参数
+                let param = self.data_param.borrow().clone();
+                if !param.is_empty() {
+                    version.push(b'\n');
+                    version.extend(self.accept_handshake(&param)?);
+                }
+                self.data_return.replace(version);
+            }
+            Operation::ListObjects => {
+                let catalogue = Catalogue {
+                    variables: self.variable_accessor.list(),
+                    methods: self.method_accessor.list(),
+                };
+                self.data_return.replace(catalogue.to_bytes());
+            }
+            Operation::RequireVariable => {
+                let key = self
+                    .root_object
+                    .borrow()
+                    .clone()
+                    .ok_or(PkError::Internal("missing object name for REQUV"))?;
+                let value = self
+                    .variable_accessor
+                    .get(key.clone())
+                    .ok_or(PkError::VariableNotFound(key))?;
+                self.data_return.replace(value);
+            }
+            Operation::SendVariable => {
+                let key = self
+                    .root_object
+                    .borrow()
+                    .clone()
+                    .ok_or(PkError::Internal("missing object name for SENDV"))?;
+                // 设置失败时用 ERROR 中止本条链，让 Host 知道失败原因
+                let value = self.data_param.borrow().clone();
+                self.variable_accessor.set(key, value)?;
+                self.data_return.replace(vec![]);
+            }
+            Operation::Subscribe => {
+                let key = self
+                    .root_object
+                    .borrow()
+                    .clone()
+                    .ok_or(PkError::Internal("missing object name for SUBSC"))?;
+                // 订阅时返回当前的值，之后只推送变化
+                let value = self
+                    .variable_accessor
+                    .get(key.clone())
+                    .ok_or(PkError::VariableNotFound(key.clone()))?;
+                let param = self.data_param.borrow().clone();
+                self.record_subscription(key, &param, value.clone())?;
+                self.data_return.replace(value);
+            }
+            Operation::Unsubscribe => {
+                let key = self.root_object.borrow().clone();
+                self.subscriptions
+                    .borrow_mut()
+                    .retain(|s| key.as_ref() != Some(&s.name));
+                self.data_return.replace(vec![]);
+            }
+            Operation::Invoke => {
+                let method_name = self
+                    .root_object
+                    .borrow()
+                    .clone()
+                    .ok_or(PkError::Internal("missing method name for INVOK"))?;
+                // 先取出参数，否则出错时 reset 会与这里的借用冲突
+                let param = self.data_param.borrow().clone();
+                let pollable = self.method_accessor.call(method_name, param)?;
+                self.pending_pollable.replace(Some(pollable));
+                self.device_op_pending.set(true);
+                self.device_await_deadline
+                    .set(Some(Instant::now() + self.session.get().await_interval));
+                self.execution_deadline
+                    .set(self.config.max_execution.map(|max| Instant::now() + max));
+            }
+            operation => return Err(PkError::NotRootOperation(operation)),
+        }
+        Ok(())
+    }
+
     /// Polls the state machine for progress and pending actions.
     ///
     /// See [`PkCommand`] for more details.
@@ -595,63 +1870,152 @@ impl<
             self.last_command_time.set(Instant::now());
             self.last_sent_msg_id.set(command.msg_id);
             self.last_sent_command.replace(command.clone());
+            self.retransmissions.set(0);
+            self.ack_wait.set(self.retransmission_timeout());
+            self.sent_at.set(Instant::now());
             // 因为 ACK 的函数并没有嵌套调用这个，所以
             self.status.set(Status::AwaitingAck);
+            // 捎带确认：记下本轮发出的指令，对方漏收时从那里重发
+            if self.chain_piggyback.get() {
+                self.unacked.borrow_mut().push_back(command.clone());
+                self.implied_ack.set(self.continues_turn(&command));
+            }
             Some(command)
         };
-        let reset_transaction_state = || {
-            self.stage.set(Stage::Idle);
-            self.status.set(Status::Other);
-            self.role.set(Role::Idle);
-            // Clear other relevant fields like root_operation, data_param, data_return, device_op_pending etc.
-            self.data_param.replace(vec![]);
-            self.data_return.replace(vec![]);
-            self.sending_data_progress.set(0);
-            self.device_op_pending.set(false);
-            self.device_await_deadline.set(None);
-            self.pending_pollable.replace(None); //确保清理
-            self.device_should_return.set(false);
-        };
-        let ack = move |msg_id: u16, operation: Operation| -> Option<Command> {
+        let reset_transaction_state = || self.reset_transaction_state();
+        let ack_with = move |msg_id: u16, operation: Operation, data: Option<Vec<u8>>| {
             self.last_command_time.set(Instant::now());
-            Some(Command {
+            let command = Command {
                 msg_id,
                 operation: Operation::Acknowledge,
                 object: Some(operation.to_name().to_string()),
-                data: None,
-            })
+                data,
+            };
+            // 缓存 ACK，对方没收到而重传时直接再发一次
+            self.last_ack.replace(Some(command.clone()));
+            // 同一轮之后还有指令，由对方的下一条指令（或者对最后一条的 ACK）一并确认
+            if self.quiet_ack.get() {
+                return None;
+            }
+            Some(command)
         };
-        let err = |msg: &'static str| -> Option<Command> {
+        let ack = move |msg_id: u16, operation: Operation| ack_with(msg_id, operation, None);
+        let err = |error: PkError| -> Option<Command> {
             // 在收到 ERROR 或 ACKNO ERROR 后，状态数据清零
             // 这个逻辑在下面处理 所以这里就不写了
             self.status.set(Status::AwaitingErrAck);
+            self.last_ack.replace(None);
             let command = Command {
                 msg_id: 0,
                 operation: Operation::Error,
                 object: Some(String::from("ERROR")),
-                data: Some(msg.as_bytes().to_vec()),
+                data: Some(error.to_wire().into_bytes()),
             };
+            self.last_error.replace(Some((error, FailureOrigin::Local)));
             self.last_command_time.set(Instant::now());
             self.last_sent_msg_id.set(command.msg_id);
             self.last_sent_command.replace(command.clone());
+            self.retransmissions.set(0);
+            self.ack_wait.set(self.retransmission_timeout());
+            self.sent_at.set(Instant::now());
             Some(command)
         };
-        // 首先检查是否有新的指令进入 command buffer
-        match self.command_processed.get() {
-            true => {
-                // Idle 则忽略当前 poll
-                if self.stage.get() == Stage::Idle {
+        let poll_window = || match self.poll_window() {
+            Ok(command) => command,
+            Err(e) => {
+                reset_transaction_state();
+                err(e)
+            }
+        };
+        // Host 放弃了当前的链：通知 Device 一同中止
+        if self.cancelling.replace(false) {
+            reset_transaction_state();
+            return err(PkError::Cancelled);
+        }
+        // 整条链超过了期限，同样中止
+        if self.role.get() == Role::Host
+            && self.stage.get() != Stage::Idle
+            && self.status.get() != Status::AwaitingErrAck
+            && self
+                .chain_deadline
+                .get()
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            reset_transaction_state();
+            return err(PkError::Timeout);
+        }
+        // 对方漏收了本轮的指令，先依次重发
+        if !self.resend.borrow().is_empty() {
+            return self.next_resend();
+        }
+        // 同一轮的下一条指令不等 ACK：当作刚发出的指令已经被确认了
+        let implied = self.implied_ack.replace(false) && self.status.get() == Status::AwaitingAck;
+        // 否则检查队列中是否有新的指令，每次 poll 只处理一条
+        let received = if implied {
+            let last_sent = self.last_sent_command.borrow();
+            Some(Command {
+                msg_id: last_sent.msg_id,
+                operation: Operation::Acknowledge,
+                object: Some(last_sent.operation.to_name().to_string()),
+                data: None,
+            })
+        } else {
+            self.inbound_queue.borrow_mut().pop_front()
+        };
+        match received {
+            None => {
+                // 因冲突而让出的链，在对方的链结束后重新开始
+                if self.stage.get() == Stage::Idle
+                    && self.status.get() == Status::Other
+                    && self.role.get() == Role::Idle
+                    && let Some((operation, object, data, deadline)) = self.deferred.take()
+                {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        // 还没轮到就已经过了期限
+                        self.last_error
+                            .replace(Some((PkError::Timeout, FailureOrigin::Local)));
+                    } else {
+                        let _ = self.start_chain(operation, object, Some(data), deadline);
+                    }
+                }
+                // Idle 则忽略当前 poll，除非作为 Device 有订阅的变量发生了变化，或者 ERROR 还在等待确认
+                if self.stage.get() == Stage::Idle && self.status.get() != Status::AwaitingErrAck {
+                    // Host 还没有取走上一条链的结果时不推送，以免覆盖
+                    if self.status.get() == Status::Other
+                        && self.role.get() == Role::Idle
+                        && let Some((name, value)) = self.changed_subscription()
+                    {
+                        self.role.set(Role::Device);
+                        self.stage.set(Stage::SendingResponse);
+                        self.root_operation.set(Operation::Notify);
+                        self.root_object.replace(Some(name.clone()));
+                        self.reset_extensions();
+                        let inline = !value.is_empty()
+                            && self.fits_inline(&value, self.chain_checksum.get());
+                        // 值能放进 NOTIF 时直接带上，这样一来回就结束了
+                        self.sending_data_progress
+                            .set(if inline { value.len() as u64 } else { 0 });
+                        let data = inline.then(|| value.clone());
+                        self.data_return.replace(value);
+                        return send(Command {
+                            msg_id: next_msg_id_for_send(),
+                            operation: Operation::Notify,
+                            object: Some(name),
+                            data,
+                        });
+                    }
                     return None;
                 }
                 if self.stage.get() == Stage::Started
                     && self.role.get() == Role::Host
                     && self.status.get() != Status::AwaitingAck
                 {
+                    let offer = self.offer_extensions();
                     return send(Command {
                         msg_id: next_msg_id_for_send(),
                         operation: Operation::Start,
-                        object: None,
-                        data: None,
+                        object: offer.as_ref().map(|_| String::from(EXTENSION_OBJECT)),
+                        data: offer,
                     });
                 }
                 // 当设备有挂起的 INVOK 操作并且处于响应阶段时，轮询 Pollable
@@ -675,41 +2039,69 @@ impl<
                                     pollable_store.take(); // Remove completed pollable
                                     self.device_op_pending.set(false);
                                     self.device_await_deadline.set(None);
+                                    // 返回值在这里发送，不再经过下方 device_should_return 的处理，
+                                    // 否则之后的轮询会把发送进度清零
+                                    self.device_should_return.set(false);
 
                                     match result {
                                         Ok(data_opt) => {
-                                            self.data_return.replace(data_opt.unwrap_or_default());
+                                            let result = data_opt.unwrap_or_default();
+                                            // 快速链中放得下的结果直接随 RTURN 返回
+                                            let inline = self.chain_express.get()
+                                                && self.fits_inline(
+                                                    &result,
+                                                    self.chain_checksum.get(),
+                                                );
+                                            let data =
+                                                inline.then(|| self.inline_data(&result)).flatten();
                                             // Stage is already SendingResponse.
-                                            self.sending_data_progress.set(0); // Reset for sending return data.
+                                            self.sending_data_progress.set(if inline {
+                                                result.len() as u64
+                                            } else {
+                                                0
+                                            });
 
-                                            let rturn_object_name =
-                                                if self.data_return.borrow().is_empty() {
-                                                    String::from("EMPTY")
-                                                } else {
-                                                    Operation::Invoke.to_name().to_string()
-                                                };
+                                            let rturn_object_name = if result.is_empty() {
+                                                String::from("EMPTY")
+                                            } else {
+                                                Operation::Invoke.to_name().to_string()
+                                            };
+                                            self.data_return.replace(result);
                                             return send(Command {
                                                 msg_id: next_msg_id_for_send(),
                                                 operation: Operation::Return,
                                                 object: Some(rturn_object_name),
-                                                data: None,
+                                                data,
                                             });
                                         }
-                                        Err(_) => {
+                                        Err(e) => {
+                                            // 释放借用，reset 时要清空 pending_pollable
+                                            drop(pollable_store);
                                             reset_transaction_state();
-                                            return err("INVOK operation failed");
+                                            return err(e);
                                         }
                                     }
                                 }
                                 Poll::Pending => {
+                                    if self
+                                        .execution_deadline
+                                        .get()
+                                        .is_some_and(|deadline| Instant::now() >= deadline)
+                                    {
+                                        // 释放借用，reset 时会取消还在执行的方法
+                                        drop(pollable_store);
+                                        reset_transaction_state();
+                                        return err(PkError::Timeout);
+                                    }
                                     if Instant::now()
                                         >= self
                                             .device_await_deadline
                                             .get()
                                             .unwrap_or(Instant::now())
                                     {
-                                        self.device_await_deadline
-                                            .set(Some(Instant::now() + self.config.await_interval));
+                                        self.device_await_deadline.set(Some(
+                                            Instant::now() + self.session.get().await_interval,
+                                        ));
                                         return send(Command {
                                             msg_id: next_msg_id_for_send(),
                                             operation: Operation::Await,
@@ -721,8 +2113,9 @@ impl<
                             }
                         } else {
                             // device_op_pending is true, but no pollable.
+                            drop(pollable_store);
                             reset_transaction_state();
-                            return err("Internal: Device op pending but no pollable.");
+                            return err(PkError::Internal("device op pending but no pollable"));
                         }
                     }
                 } // 结束 device_op_pending && Stage::SendingResponse 的处理
@@ -739,7 +2132,9 @@ impl<
                                 data: None,
                             });
                         }
-                        Operation::RequireVariable => {
+                        Operation::RequireVariable
+                        | Operation::ListObjects
+                        | Operation::Subscribe => {
                             if self.data_return.borrow().is_empty() {
                                 return send(Command {
                                     msg_id: next_msg_id_for_send(),
@@ -755,7 +2150,7 @@ impl<
                                 data: None,
                             });
                         }
-                        Operation::SendVariable => {
+                        Operation::SendVariable | Operation::Unsubscribe => {
                             // SENDV doesn't return data in the RTURN command itself.
                             // The result of the set operation is implicitly acknowledged by the ENDTR ACK.
                             // If there was an error during set, it would be handled by the error path.
@@ -777,12 +2172,41 @@ impl<
                     }
                 }
 
+                // 窗口模式：重传丢失的 SDATA，窗口没满时发送下一段
+                if !self.in_flight.borrow().is_empty() {
+                    return poll_window();
+                }
                 // 获取当前时间来比较超时
                 let elapsed_ms = self.last_command_time.get().elapsed();
                 match self.status.get() {
                     Status::AwaitingAck | Status::AwaitingErrAck => {
                         // 等待 ACK 时则检查 ACK 超时来确认是否重传
-                        if elapsed_ms >= self.config.ack_timeout {
+                        if elapsed_ms >= self.ack_wait.get() {
+                            if self.retransmissions.get() >= self.config.max_retries {
+                                // 对方一直没有回应，认为它已经不在了
+                                if self.status.get() == Status::AwaitingErrAck {
+                                    // ERROR 也没人确认，直接结束，保留原本的错误
+                                    reset_transaction_state();
+                                    return None;
+                                }
+                                reset_transaction_state();
+                                return err(PkError::Timeout);
+                            }
+                            self.retransmissions.set(self.retransmissions.get() + 1);
+                            let noise = elapsed_ms.subsec_nanos();
+                            let mut wait = self
+                                .config
+                                .backoff
+                                .next_timeout(self.ack_wait.get(), self.next_random(noise));
+                            // 自适应超时：超时说明估计偏小，像 TCP 一样加倍，直到下一次有效的测量
+                            if let Some(mut rtt) = self.rtt.get() {
+                                rtt.back_off();
+                                self.rtt.set(Some(rtt));
+                                wait = wait.max(rtt.rto());
+                            }
+                            self.ack_wait.set(wait);
+                            // 重传后重新计时，否则之后的每次轮询都会重传
+                            self.last_command_time.set(Instant::now());
                             return Some(self.last_sent_command.borrow().clone());
                         }
                     }
@@ -790,24 +2214,165 @@ impl<
                         // 仅当不在 Idle 状态且没有挂起的设备操作时检查指令间超时
                         if self.stage.get() != Stage::Idle
                             && !self.device_op_pending.get()
-                            && elapsed_ms >= self.config.inter_command_timeout
+                            && elapsed_ms >= self.session.get().inter_command_timeout
                         {
                             reset_transaction_state(); // 在发送错误前重置状态
-                            return err("Operation timed out");
+                            return err(PkError::Timeout);
                         }
                     }
                 }
             }
-            // 缓冲区内有新的指令
-            false => {
-                self.command_processed.set(true);
-                self.last_received_msg_id
-                    .set(self.command_buffer.borrow().msg_id); // Store received msg_id
-                let recv = self.command_buffer.borrow();
+            // 队列中有新的指令
+            Some(received) => {
+                let recv = &received;
+                match recv.operation {
+                    Operation::Error => {}
+                    // 窗口中的 SDATA 不必按顺序被确认
+                    Operation::Acknowledge
+                        if recv.object.as_deref() == Some(Operation::Data.to_name())
+                            && !self.in_flight.borrow().is_empty() =>
+                    {
+                        self.acknowledge_chunks(recv);
+                        let data = match self.role.get() {
+                            Role::Host => &self.data_param,
+                            _ => &self.data_return,
+                        };
+                        if !self.in_flight.borrow().is_empty()
+                            || (self.sending_data_progress.get() as usize) < data.borrow().len()
+                        {
+                            return poll_window();
+                        }
+                        // 全部确认了，和逐个确认时一样以 ENDTR 结束
+                        if self.role.get() == Role::Host {
+                            self.stage.set(Stage::ParameterSent);
+                        }
+                        return send(self.end_transaction(
+                            util::msg_id::increment(self.last_sent_msg_id.get()),
+                            &data.borrow(),
+                        ));
+                    }
+                    // 捎带确认：对方只确认到本轮中间的某条指令，重发之后的
+                    Operation::Acknowledge
+                        if recv.object.as_deref() != Some("ERROR")
+                            && !implied
+                            && self.chain_piggyback.get()
+                            && self.rewind(recv) =>
+                    {
+                        return self.next_resend();
+                    }
+                    Operation::Acknowledge if recv.object.as_deref() != Some("ERROR") => {
+                        // 只接受对最后发送的指令的 ACK。重传导致的重复 ACK 或过期的 ACK 直接忽略，
+                        // 否则会被当成对下一条指令的确认
+                        let last_sent = self.last_sent_command.borrow();
+                        if self.status.get() != Status::AwaitingAck
+                            || recv.msg_id != last_sent.msg_id
+                            || recv.object.as_deref() != Some(last_sent.operation.to_name())
+                        {
+                            return None;
+                        }
+                        if !implied {
+                            self.settle_turn();
+                        }
+                    }
+                    Operation::Acknowledge => {}
+                    _ => {
+                        // 对方重传了上一条指令（说明我们的 ACK 丢了），重发缓存的 ACK，不重复处理
+                        if let Some(cached) = self.last_ack.borrow().as_ref()
+                            && cached.msg_id == recv.msg_id
+                            && cached.object.as_deref() == Some(recv.operation.to_name())
+                        {
+                            // 本轮中间的指令本来就不单独确认
+                            if self.continues_turn(recv) {
+                                return None;
+                            }
+                            self.last_command_time.set(Instant::now());
+                            return Some(cached.clone());
+                        }
+                        // Host 重传了快速链的根操作或者捎带确认的 ENDTR，说明代替 ACK 的 AWAIT 或 RTURN
+                        // 丢了。捎带确认时把本轮还没被确认的指令全部重发，否则它们会自己重传
+                        if ((recv.operation.is_root() && self.chain_express.get())
+                            || (recv.operation == Operation::EndTransaction
+                                && self.chain_piggyback.get()))
+                            && self.role.get() == Role::Device
+                            && self.stage.get() == Stage::SendingResponse
+                        {
+                            let unacked = self.unacked.borrow().clone();
+                            self.resend.replace(unacked);
+                            return self.next_resend();
+                        }
+                        if self.chain_piggyback.get() {
+                            // RTURN 丢了，Host 却收到了之后的 SDATA 或 ENDTR：重发 ENDTR（或快速链的根操作），
+                            // 让 Device 整轮重发
+                            if self.role.get() == Role::Host
+                                && self.stage.get() == Stage::ParameterSent
+                                && matches!(
+                                    recv.operation,
+                                    Operation::Data | Operation::EndTransaction
+                                )
+                            {
+                                if self.continues_turn(recv) {
+                                    return None;
+                                }
+                                self.last_command_time.set(Instant::now());
+                                return Some(self.last_sent_command.borrow().clone());
+                            }
+                            // 漏收了之前的指令。本轮中间的指令直接丢掉，收到本轮最后的指令时重发对最后一条
+                            // 按顺序收到的指令的 ACK，对方从那里开始重发
+                            let receiving = match self.role.get() {
+                                Role::Device => matches!(
+                                    self.stage.get(),
+                                    Stage::Started
+                                        | Stage::RootOperationAssigned
+                                        | Stage::SendingParameter
+                                ),
+                                Role::Host => self.stage.get() == Stage::SendingResponse,
+                                Role::Idle => false,
+                            };
+                            if receiving
+                                && let Some(cached) = self.last_ack.borrow().as_ref()
+                                && util::msg_id::increment(cached.msg_id) != recv.msg_id
+                            {
+                                if self.continues_turn(recv) {
+                                    return None;
+                                }
+                                self.last_command_time.set(Instant::now());
+                                return Some(cached.clone());
+                            }
+                        }
+                    }
+                }
+                // 有效的 ACK：测量往返时间，但重传过的指令不知道 ACK 对应哪一次发送，不测
+                if recv.operation == Operation::Acknowledge
+                    && recv.object.as_deref() != Some("ERROR")
+                    && !implied
+                    && self.retransmissions.get() == 0
+                    && let Some(mut rtt) = self.rtt.get()
+                {
+                    rtt.sample(self.sent_at.get().elapsed());
+                    self.rtt.set(Some(rtt));
+                }
+                // 通知只在两条链之间发送。正忙时直接丢掉，Device 收到 START 后会放弃这次通知
+                if (recv.operation == Operation::Notify
+                    || (recv.operation == Operation::Data
+                        && recv.object.as_deref() == Some(Operation::Notify.to_name())))
+                    && !self.is_settled()
+                {
+                    return None;
+                }
+                self.last_received_msg_id.set(recv.msg_id); // Store received msg_id
+                self.quiet_ack.set(self.continues_turn(recv));
                 // 首先处理 Error 这种不被 Stage 描述的特殊情况
                 if recv.operation == Operation::Error {
                     reset_transaction_state();
-                    return ack(0, Operation::Error);
+                    let description = recv.data.as_deref().unwrap_or_default();
+                    self.last_error.replace(Some((
+                        PkError::from_remote(&String::from_utf8_lossy(description)),
+                        FailureOrigin::Remote,
+                    )));
+                    let reply = ack(0, Operation::Error);
+                    // ERROR 的 MSG ID 总是相同的，不能用来判断重传
+                    self.last_ack.replace(None);
+                    return reply;
                 } else if self.status.get() == Status::AwaitingErrAck {
                     if recv.operation == Operation::Acknowledge
                         && Some(String::from("ERROR")) == recv.object
@@ -818,19 +2383,96 @@ impl<
                         self.role.set(Role::Idle);
                         return None;
                     } else {
-                        return err("Should be ACKNO ERROR");
+                        // 对方还没收到 ERROR（比如还在发送之前的指令），重发同一个 ERROR，保留原本的错误
+                        self.last_command_time.set(Instant::now());
+                        return Some(self.last_sent_command.borrow().clone());
                     }
                 }
+                // 推送通知的同时 Host 开始了新的链：让路给 Host，这次通知之后再重新发送
+                if recv.operation == Operation::Start
+                    && self.role.get() == Role::Device
+                    && self.root_operation.get() == Operation::Notify
+                    && self.stage.get() != Stage::Idle
+                {
+                    self.abandon_notification();
+                    reset_transaction_state();
+                }
+                // 双方同时发起了事务链，按配置决定谁先来
+                if recv.operation == Operation::Start
+                    && self.stage.get() == Stage::Started
+                    && self.role.get() == Role::Host
+                {
+                    // 自己的 START 还没发出时没有冲突，直接让出
+                    let own_msg_id = self.last_sent_command.borrow().msg_id;
+                    if self.status.get() == Status::AwaitingAck
+                        && self.config.tie_break.wins(own_msg_id, recv.msg_id)
+                    {
+                        // 对方会让出，继续等待对我们的 START 的 ACK
+                        return None;
+                    }
+                    // 让出：暂存自己的根操作，先为对方服务
+                    self.deferred.replace(Some((
+                        self.root_operation.get(),
+                        self.root_object.take(),
+                        self.data_param.take(),
+                        self.chain_deadline.get(),
+                    )));
+                    reset_transaction_state();
+                }
                 match self.stage.get() {
                     Stage::Idle => {
-                        // 在 Idle 状态下只能收到 START，且自身为 Device
+                        // 两条链之间，Host 可能收到 Device 推送的通知
+                        match recv.operation {
+                            Operation::Notify => {
+                                let Some(name) = recv.object.clone() else {
+                                    return err(PkError::InvalidObject);
+                                };
+                                match recv.data.clone() {
+                                    Some(value) if !value.is_empty() => {
+                                        self.push_notification(Notification { name, value });
+                                    }
+                                    // 值在之后的 SDATA 中，以 ENDTR 结束
+                                    _ => {
+                                        self.incoming_notification.replace(Some(Notification {
+                                            name,
+                                            value: Vec::new(),
+                                        }));
+                                    }
+                                }
+                                return ack(recv.msg_id, recv.operation);
+                            }
+                            Operation::Data
+                                if recv.object.as_deref() == Some(Operation::Notify.to_name()) =>
+                            {
+                                let mut incoming = self.incoming_notification.borrow_mut();
+                                let Some(notification) = incoming.as_mut() else {
+                                    // Device 已经放弃了的通知
+                                    return None;
+                                };
+                                notification
+                                    .value
+                                    .extend_from_slice(recv.data.as_deref().unwrap_or_default());
+                                drop(incoming);
+                                return ack(recv.msg_id, recv.operation);
+                            }
+                            Operation::EndTransaction
+                                if self.incoming_notification.borrow().is_some() =>
+                            {
+                                if let Some(notification) = self.incoming_notification.take() {
+                                    self.push_notification(notification);
+                                }
+                                return ack(recv.msg_id, recv.operation);
+                            }
+                            _ => {}
+                        }
+                        // 除此之外在 Idle 状态下只能收到 START，且自身为 Device
                         if recv.operation != Operation::Start {
-                            return err("not in a chain");
+                            return err(PkError::UnexpectedCommand("START"));
                         }
-                        self.role.set(Role::Device);
-                        self.stage.set(Stage::Started);
-                        self.status.set(Status::Other); // Awaiting root command from Host
-                        return ack(recv.msg_id, recv.operation);
+                        self.begin_device_chain();
+                        // 不认识扩展的旧版本 Host 不会发送 PKEXT，此时回复普通的 ACKNO START
+                        let accepted = self.accept_extensions(recv);
+                        return ack_with(recv.msg_id, recv.operation, accepted);
                     }
                     Stage::Started => {
                         // 在 Started 状态下，根据当前角色不同，预期的行为应该是
@@ -839,13 +2481,25 @@ impl<
                         match self.role.get() {
                             Role::Host => {
                                 if recv.operation == Operation::Acknowledge {
+                                    self.apply_extensions(recv);
                                     self.status.set(Status::Other);
-                                    self.stage.set(Stage::RootOperationAssigned);
+                                    // 快速链：参数随根操作发送，之后直接等待结果
+                                    let express = self.chain_express.get();
+                                    self.stage.set(if express {
+                                        Stage::ParameterSent
+                                    } else {
+                                        Stage::RootOperationAssigned
+                                    });
+                                    let data = if express {
+                                        self.inline_data(&self.data_param.borrow())
+                                    } else {
+                                        None
+                                    };
                                     return send(Command {
                                         msg_id: next_msg_id_for_send(),
                                         operation: self.root_operation.get(),
                                         object: self.root_object.borrow().clone(),
-                                        data: None,
+                                        data,
                                     });
                                 }
                             }
@@ -853,21 +2507,87 @@ impl<
                                 if recv.operation.is_root() {
                                     self.root_operation.set(recv.operation);
                                     // Validate if object is present for ops that require it
-                                    if (recv.operation == Operation::RequireVariable
-                                        || recv.operation == Operation::SendVariable
-                                        || recv.operation == Operation::Invoke)
-                                        && recv.object.is_none()
+                                    if matches!(
+                                        recv.operation,
+                                        Operation::RequireVariable
+                                            | Operation::SendVariable
+                                            | Operation::Invoke
+                                            | Operation::Subscribe
+                                            | Operation::Unsubscribe
+                                    ) && recv.object.is_none()
                                     {
                                         reset_transaction_state();
-                                        return err(
-                                            "Operation requires an object but none was provided.",
-                                        );
+                                        return err(PkError::InvalidObject);
+                                    }
+                                    // 在传输参数之前就拒绝不允许的操作，不必调用 accessor
+                                    if let Some(object) = &recv.object
+                                        && !self.is_allowed(recv.operation, object)
+                                    {
+                                        reset_transaction_state();
+                                        return err(PkError::AccessDenied(object.clone()));
                                     }
                                     self.root_object.replace(recv.object.clone());
-                                    self.stage.set(Stage::RootOperationAssigned);
-                                    return ack(recv.msg_id, recv.operation);
+                                    if !self.chain_express.get() {
+                                        self.stage.set(Stage::RootOperationAssigned);
+                                        return ack(recv.msg_id, recv.operation);
+                                    }
+                                    // 快速链：参数就在根操作里，不再等待 EMPTY 或 SDATA、ENDTR 和 QUERY
+                                    let param = self.read_inline(recv.data.as_deref())?;
+                                    self.data_param.replace(param);
+                                    if let Err(e) = self.execute_root_operation() {
+                                        reset_transaction_state();
+                                        return err(e);
+                                    }
+                                    self.stage.set(Stage::SendingResponse);
+                                    if self.device_op_pending.get() {
+                                        // 同步的方法立即就有结果，否则用 AWAIT 代替 ACK，结果之后随 RTURN 返回
+                                        let ready = self
+                                            .pending_pollable
+                                            .borrow_mut()
+                                            .as_mut()
+                                            .map(|pollable| pollable.as_mut().poll());
+                                        match ready {
+                                            Some(Poll::Ready(Ok(data))) => {
+                                                self.pending_pollable.replace(None);
+                                                self.device_op_pending.set(false);
+                                                self.device_await_deadline.set(None);
+                                                self.data_return.replace(data.unwrap_or_default());
+                                            }
+                                            Some(Poll::Ready(Err(e))) => {
+                                                reset_transaction_state();
+                                                return err(e);
+                                            }
+                                            _ => {
+                                                return send(Command {
+                                                    msg_id: next_msg_id_for_send(),
+                                                    operation: Operation::Await,
+                                                    object: None,
+                                                    data: None,
+                                                });
+                                            }
+                                        }
+                                    }
+                                    let result = self.data_return.borrow().clone();
+                                    if self.fits_inline(&result, self.chain_checksum.get()) {
+                                        // 结果随 ACK 返回，链到此结束。ACK 丢了的话 Host 会重传根操作，届时重发缓存的 ACK
+                                        self.role.set(Role::Idle);
+                                        self.stage.set(Stage::Idle);
+                                        return ack_with(
+                                            recv.msg_id,
+                                            recv.operation,
+                                            self.inline_data(&result),
+                                        );
+                                    }
+                                    // 放不下：RTURN 代替 ACK，之后照常用 SDATA 和 ENDTR 返回
+                                    self.sending_data_progress.set(0);
+                                    return send(Command {
+                                        msg_id: next_msg_id_for_send(),
+                                        operation: Operation::Return,
+                                        object: Some(recv.operation.to_name().to_string()),
+                                        data: None,
+                                    });
                                 } else {
-                                    return err("not a root operation");
+                                    return err(PkError::NotRootOperation(recv.operation));
                                 }
                             }
                             _ => {
@@ -895,7 +2615,7 @@ impl<
                                     } else {
                                         match self.slice_data(Role::Host) {
                                             Ok((data_chunk, _is_last)) => {
-                                                return send(Command {
+                                                let command = send(Command {
                                                     msg_id: next_msg_id_for_send(),
                                                     operation: Operation::Data,
                                                     object: Some(
@@ -905,7 +2625,10 @@ impl<
                                                             .to_string(),
                                                     ),
                                                     data: Some(data_chunk),
-                                                });
+                                                })?;
+                                                // 窗口模式下之后的分段不等这一段的 ACK
+                                                self.track_chunk(&command);
+                                                return Some(command);
                                             }
                                             Err(e) => {
                                                 reset_transaction_state();
@@ -914,7 +2637,7 @@ impl<
                                         }
                                     }
                                 } else {
-                                    return err("Should be ACKNO");
+                                    return err(PkError::UnexpectedCommand("ACKNO"));
                                 }
                             }
                             Role::Device => {
@@ -922,16 +2645,23 @@ impl<
                                     self.stage.set(Stage::SendingParameter);
                                     return ack(recv.msg_id, recv.operation);
                                 } else if recv.operation == Operation::Data {
+                                    // 窗口模式下分段可能乱序到达
+                                    if self.chain_window.get() > 1 {
+                                        let (msg_id, selected) =
+                                            self.receive_chunk(recv, &self.data_param)?;
+                                        self.stage.set(Stage::SendingParameter);
+                                        return ack_with(msg_id, Operation::Data, selected);
+                                    }
+                                    // 校验失败的包当作丢包处理，不回复 ACK，等待对方超时重传
+                                    let mut chunk = self.verify_chunk(recv.data.as_deref())?;
                                     self.stage.set(Stage::SendingParameter);
                                     {
                                         // 缩小可变借用的作用域，确保归还
-                                        self.data_param
-                                            .borrow_mut()
-                                            .append(&mut recv.data.as_ref().unwrap().clone());
+                                        self.data_param.borrow_mut().append(&mut chunk);
                                     }
                                     return ack(recv.msg_id, recv.operation);
                                 } else {
-                                    return err("Should be EMPTY or DATA");
+                                    return err(PkError::UnexpectedCommand("EMPTY or SDATA"));
                                 }
                             }
                             _ => {
@@ -950,7 +2680,7 @@ impl<
                             Role::Host => {
                                 // Host 必须是收到了 ACKNO
                                 if recv.operation != Operation::Acknowledge {
-                                    return err("Host expected ACKNO in SendingParameter stage");
+                                    return err(PkError::UnexpectedCommand("ACKNO"));
                                 }
                                 self.status.set(Status::Other);
 
@@ -964,12 +2694,10 @@ impl<
                                     Operation::Empty => {
                                         // 收到对 EMPTY 的 ACKNO，参数传输结束，发送 ENDTR
                                         self.stage.set(Stage::ParameterSent);
-                                        return send(Command {
-                                            msg_id: next_msg_id_for_send(),
-                                            operation: Operation::EndTransaction,
-                                            object: None,
-                                            data: None,
-                                        });
+                                        return send(self.end_transaction(
+                                            next_msg_id_for_send(),
+                                            &self.data_param.borrow(),
+                                        ));
                                     }
                                     Operation::Data => {
                                         // 收到对 SDATA 的 ACKNO
@@ -995,35 +2723,56 @@ impl<
                                         } else {
                                             // 参数数据已全部发送完毕，发送 ENDTR
                                             self.stage.set(Stage::ParameterSent);
-                                            return send(Command {
-                                                msg_id: next_msg_id_for_send(),
-                                                operation: Operation::EndTransaction,
-                                                object: None,
-                                                data: None,
-                                            });
+                                            return send(self.end_transaction(
+                                                next_msg_id_for_send(),
+                                                &self.data_param.borrow(),
+                                            ));
                                         }
                                     }
                                     _ => {
-                                        return err(
-                                            "Host received ACKNO for unexpected command in SendingParameter stage",
-                                        );
+                                        return err(PkError::UnexpectedCommand(
+                                            "ACKNO SDATA or ACKNO EMPTY",
+                                        ));
                                     }
                                 }
                             }
                             Role::Device => {
                                 // Device 等待 SDATA 或 ENDTR
                                 if recv.operation == Operation::Data {
-                                    if let Some(ref data_vec) = recv.data {
-                                        self.data_param.borrow_mut().extend_from_slice(data_vec);
+                                    if self.chain_window.get() > 1 {
+                                        let (msg_id, selected) =
+                                            self.receive_chunk(recv, &self.data_param)?;
+                                        return ack_with(msg_id, Operation::Data, selected);
                                     }
+                                    let chunk = self.verify_chunk(recv.data.as_deref())?;
+                                    self.data_param.borrow_mut().extend_from_slice(&chunk);
                                     return ack(recv.msg_id, recv.operation);
                                 } else if recv.operation == Operation::EndTransaction {
+                                    if !self.verify_payload(recv, &self.data_param.borrow()) {
+                                        reset_transaction_state();
+                                        return err(PkError::ChecksumMismatch);
+                                    }
                                     self.stage.set(Stage::ParameterSent);
-                                    return ack(recv.msg_id, recv.operation);
+                                    if !self.chain_piggyback.get() {
+                                        return ack(recv.msg_id, recv.operation);
+                                    }
+                                    // 捎带确认：不等 QUERY 就开始执行，接下来的 AWAIT 或 RTURN 同时确认了 ENDTR
+                                    if let Err(e) = self.execute_root_operation() {
+                                        reset_transaction_state();
+                                        return err(e);
+                                    }
+                                    self.stage.set(Stage::SendingResponse);
+                                    self.device_should_return.set(true);
+                                    if self.device_op_pending.get() {
+                                        // 等方法完成的时间不能超过对方的 ACK 超时，否则 ENDTR 会被重传。
+                                        // 等一半，之后用 AWAIT 确认
+                                        self.device_await_deadline.set(Some(
+                                            Instant::now() + self.session.get().ack_timeout / 2,
+                                        ));
+                                    }
+                                    return None;
                                 } else {
-                                    return err(
-                                        "Device expected DATA or ENDTR in SendingParameter stage",
-                                    );
+                                    return err(PkError::UnexpectedCommand("SDATA or ENDTR"));
                                 }
                             }
                             Role::Idle => {
@@ -1037,6 +2786,18 @@ impl<
                         match self.role.get() {
                             Role::Host => match recv.operation {
                                 Operation::Acknowledge => {
+                                    if self.chain_express.get()
+                                        && recv.object.as_deref()
+                                            == Some(self.root_operation.get().to_name())
+                                    {
+                                        // 快速链：结果随根操作的 ACK 返回，链到此结束。
+                                        // 校验失败时当作 ACK 丢了，重传根操作
+                                        let result = self.read_inline(recv.data.as_deref())?;
+                                        self.data_return.replace(result);
+                                        self.stage.set(Stage::Idle);
+                                        self.status.set(Status::Other);
+                                        return None;
+                                    }
                                     self.status.set(Status::Other); // ACK received
                                     if Some(String::from("ENDTR")) == recv.object {
                                         return send(Command {
@@ -1048,15 +2809,42 @@ impl<
                                     } else if Some(String::from("QUERY")) == recv.object {
                                         return None;
                                     } else {
-                                        return err(
-                                            "Host: Unexpected ACK object in ParameterSent stage",
-                                        );
+                                        return err(PkError::UnexpectedCommand(
+                                            "ACKNO ENDTR or ACKNO QUERY",
+                                        ));
                                     }
                                 }
                                 Operation::Await => {
+                                    // AWAIT 和 RTURN 同时确认了本轮的指令
+                                    self.settle_turn();
+                                    self.awaits.set(self.awaits.get() + 1);
+                                    if self
+                                        .config
+                                        .max_awaits
+                                        .is_some_and(|max| self.awaits.get() > max)
+                                    {
+                                        reset_transaction_state();
+                                        return err(PkError::Timeout);
+                                    }
+                                    // Device 只会在收到 QUERY 之后发送 AWAIT，即使 ACKNO QUERY 丢了也不用再重传
+                                    self.status.set(Status::Other);
                                     return ack(recv.msg_id, recv.operation);
                                 }
                                 Operation::Return => {
+                                    self.settle_turn();
+                                    self.status.set(Status::Other); // 同上
+                                    // 快速链：结果随 RTURN 返回（或者为空），链到此结束
+                                    if self.chain_express.get()
+                                        && (recv.data.is_some()
+                                            || recv.object.as_deref()
+                                                == Some(Operation::Empty.to_name()))
+                                    {
+                                        let result = self.read_inline(recv.data.as_deref())?;
+                                        self.data_return.replace(result);
+                                        let rturn_ack = ack(recv.msg_id, recv.operation);
+                                        self.stage.set(Stage::Idle);
+                                        return rturn_ack;
+                                    }
                                     if Some(String::from("EMPTY")) == recv.object
                                         || Some(self.root_operation.get().to_name().to_string())
                                             == recv.object
@@ -1066,108 +2854,17 @@ impl<
                                     }
                                 }
                                 _ => {
-                                    return err("Should be ACKNO, AWAIT or RETURN");
+                                    return err(PkError::UnexpectedCommand(
+                                        "ACKNO, AWAIT or RTURN",
+                                    ));
                                 }
                             },
                             Role::Device => {
                                 if recv.operation == Operation::Query {
                                     // 开始执行逻辑，然后 ACK
-                                    match self.root_operation.get() {
-                                        Operation::GetVersion => {
-                                            self.data_return.replace(
-                                                self.config.pk_version.as_bytes().to_vec(),
-                                            );
-                                            self.stage.set(Stage::SendingResponse);
-                                        }
-                                        Operation::RequireVariable => {
-                                            let key = match self
-                                                .root_object
-                                                .borrow()
-                                                .as_ref()
-                                                .cloned()
-                                            {
-                                                Some(k) => k,
-                                                None => {
-                                                    // This check should ideally be when root_op was received
-                                                    reset_transaction_state();
-                                                    return err(
-                                                        "Internal: Missing object name for REQUV.",
-                                                    );
-                                                }
-                                            };
-                                            self.data_return.replace(
-                                                self.variable_accessor.get(key).unwrap_or(vec![]),
-                                            );
-                                            self.stage.set(Stage::SendingResponse);
-                                        }
-                                        Operation::SendVariable => {
-                                            let key = match self
-                                                .root_object
-                                                .borrow()
-                                                .as_ref()
-                                                .cloned()
-                                            {
-                                                Some(k) => k,
-                                                None => {
-                                                    // This check should ideally be when root_op was received
-                                                    reset_transaction_state();
-                                                    return err(
-                                                        "Internal: Missing object name for SENDV.",
-                                                    );
-                                                }
-                                            };
-                                            self.data_return.replace(
-                                                if let Err(e) = self
-                                                    .variable_accessor
-                                                    .set(key, self.data_param.borrow().clone())
-                                                {
-                                                    e.as_bytes().to_vec()
-                                                } else {
-                                                    vec![]
-                                                },
-                                            );
-                                            self.stage.set(Stage::SendingResponse); // Note: SENDV error reporting via data_return
-                                        }
-                                        Operation::Invoke => {
-                                            self.device_op_pending.set(true);
-                                            self.device_await_deadline.set(Some(
-                                                Instant::now() + self.config.await_interval,
-                                            ));
-                                            // The object for INVOK is self.root_object, not from QUERY (recv.object)
-                                            let method_name = match self
-                                                .root_object
-                                                .borrow()
-                                                .as_ref()
-                                                .cloned()
-                                            {
-                                                Some(name) => name,
-                                                None => {
-                                                    reset_transaction_state();
-                                                    return err(
-                                                        "Internal: Missing method name for INVOK",
-                                                    );
-                                                }
-                                            };
-                                            match self
-                                                .method_accessor
-                                                .call(method_name, self.data_param.borrow().clone())
-                                            {
-                                                Ok(pollable) => {
-                                                    self.pending_pollable.replace(Some(pollable));
-                                                }
-                                                Err(_) => {
-                                                    reset_transaction_state();
-                                                    // log::error!("Failed to create INVOK pollable: {}", e_str);
-                                                    return err(
-                                                        "Failed to initiate INVOK operation",
-                                                    );
-                                                }
-                                            }
-                                        }
-                                        _ => {
-                                            reset_transaction_state();
-                                            return err("Not a root operation");
-                                        }
+                                    if let Err(e) = self.execute_root_operation() {
+                                        reset_transaction_state();
+                                        return err(e);
                                     }
                                     self.stage.set(Stage::SendingResponse);
                                     self.device_should_return.set(true);
@@ -1186,26 +2883,39 @@ impl<
                             Role::Host => {
                                 // Host 等待 SDATA 或 ENDTR
                                 if recv.operation == Operation::Data {
-                                    // Host receives SDATA from Device
-                                    if let Some(ref data_vec) = recv.data {
-                                        self.data_return.borrow_mut().extend_from_slice(data_vec);
+                                    if self.chain_window.get() > 1 {
+                                        let (msg_id, selected) =
+                                            self.receive_chunk(recv, &self.data_return)?;
+                                        return ack_with(msg_id, Operation::Data, selected);
                                     }
+                                    // Host receives SDATA from Device
+                                    let chunk = self.verify_chunk(recv.data.as_deref())?;
+                                    self.data_return.borrow_mut().extend_from_slice(&chunk);
                                     return ack(recv.msg_id, recv.operation);
                                 } else if recv.operation == Operation::EndTransaction {
+                                    if !self.verify_payload(recv, &self.data_return.borrow()) {
+                                        reset_transaction_state();
+                                        return err(PkError::ChecksumMismatch);
+                                    }
+                                    // 握手的结果不影响 Device，所以照常结束本条链，只记录失败
+                                    if self.root_operation.get() == Operation::GetVersion
+                                        && !self.data_param.borrow().is_empty()
+                                        && let Err(e) = self.apply_handshake()
+                                    {
+                                        self.last_error.replace(Some((e, FailureOrigin::Local)));
+                                    }
                                     let endtr_ack = ack(recv.msg_id, recv.operation);
                                     self.stage.set(Stage::Idle);
                                     self.status.set(Status::Other); // After sending ACK, status is Other
                                     return endtr_ack;
                                 } else {
-                                    return err(
-                                        "Host expected SDATA or ENDTR in SendingResponse stage",
-                                    );
+                                    return err(PkError::UnexpectedCommand("SDATA or ENDTR"));
                                 }
                             }
                             Role::Device => {
                                 // Device 必须是收到了 ACKNO
                                 if recv.operation != Operation::Acknowledge {
-                                    return err("Device expected ACKNO in SendingResponse stage");
+                                    return err(PkError::UnexpectedCommand("ACKNO"));
                                 }
                                 self.status.set(Status::Other);
 
@@ -1216,7 +2926,22 @@ impl<
                                 } // 不可变借用在此结束
 
                                 match last_sent_op {
-                                    Operation::Return => {
+                                    Operation::Return | Operation::Notify => {
+                                        // NOTIF 已经带上了整个值，通知到此结束。快速链的 RTURN 也是一样
+                                        let progress = self.sending_data_progress.get();
+                                        let inline = match last_sent_op {
+                                            Operation::Notify => progress > 0,
+                                            _ => {
+                                                self.chain_express.get()
+                                                    && progress
+                                                        == self.data_return.borrow().len() as u64
+                                            }
+                                        };
+                                        if inline {
+                                            self.role.set(Role::Idle);
+                                            self.stage.set(Stage::Idle);
+                                            return None;
+                                        }
                                         // 收到对 RETURN 的 ACKNO
                                         let return_data_len =
                                             self.data_return.borrow().len() as u64;
@@ -1224,12 +2949,10 @@ impl<
                                             // 没有返回值，直接发送 ENDTR
                                             // self.stage.set(Stage::Idle); // Transaction ends
                                             // REMOVE: Do not set to Idle yet, wait for ENDTR's ACKNO
-                                            return send(Command {
-                                                msg_id: next_msg_id_for_send(),
-                                                operation: Operation::EndTransaction,
-                                                object: None,
-                                                data: None,
-                                            });
+                                            return send(self.end_transaction(
+                                                next_msg_id_for_send(),
+                                                &self.data_return.borrow(),
+                                            ));
                                         } else {
                                             // 有返回值
                                             let (data_chunk, _) =
@@ -1241,14 +2964,17 @@ impl<
                                                     }
                                                 };
 
-                                            return send(Command {
+                                            let command = send(Command {
                                                 msg_id: next_msg_id_for_send(),
                                                 operation: Operation::Data,
                                                 object: Some(
                                                     self.root_operation.get().to_name().to_string(),
                                                 ),
                                                 data: Some(data_chunk),
-                                            });
+                                            })?;
+                                            // 窗口模式下之后的分段不等这一段的 ACK
+                                            self.track_chunk(&command);
+                                            return Some(command);
                                         }
                                     }
                                     Operation::Data => {
@@ -1272,12 +2998,10 @@ impl<
                                                 data: Some(data_chunk),
                                             });
                                         } else {
-                                            return send(Command {
-                                                msg_id: next_msg_id_for_send(),
-                                                operation: Operation::EndTransaction,
-                                                object: None,
-                                                data: None,
-                                            });
+                                            return send(self.end_transaction(
+                                                next_msg_id_for_send(),
+                                                &self.data_return.borrow(),
+                                            ));
                                         }
                                     }
                                     Operation::EndTransaction => {
@@ -1293,9 +3017,9 @@ impl<
                                         return None;
                                     }
                                     _ => {
-                                        return err(
-                                            "Device received ACKNO for unexpected command in SendingResponse stage",
-                                        );
+                                        return err(PkError::UnexpectedCommand(
+                                            "ACKNO SDATA or ACKNO ENDTR",
+                                        ));
                                     }
                                 }
                             }
@@ -1316,38 +3040,356 @@ impl<
     /// The actual protocol exchange (beginning with a `START` packet) is driven by subsequent [`poll()`](crate::PkCommand::poll) calls.
     ///
     /// # Arguments
-    /// * `operation`: The root operation to perform (`SENDV`, `REQUV`, `INVOK`, or `PKVER`).
+    /// * `operation`: The root operation to perform (`SENDV`, `REQUV`, `INVOK`, `PKVER`, `LISTO`, `SUBSC` or `UNSUB`).
     /// * `object`: The target name (e.g., variable name for `REQUV`, method name for `INVOK`).
     /// * `data`: Optional parameter data (e.g., the value to set for `SENDV`).
     ///
     /// # Returns
     /// - `Ok(())`: The transaction was successfully queued.
-    /// - `Err(&'static str)`: The request was invalid (e.g., already in a transaction, not a root op).
+    /// - `Err(PkError::NotRootOperation(_))`: The operation cannot start a transaction.
+    /// - `Err(PkError::Busy)`: Another transaction is in progress, or waits to start again after a collision,
+    ///   or the outcome of the previous one was not [taken](crate::PkCommand::take_outcome) yet.
     pub fn perform(
         &self,
         operation: Operation,
         object: Option<String>,
         data: Option<Vec<u8>>,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), PkError> {
+        let deadline = self
+            .config
+            .deadline
+            .map(|deadline| Instant::now() + deadline);
+        self.start_chain(operation, object, data, deadline)
+    }
+
+    /// Like [`perform()`](crate::PkCommand::perform), but the chain must be over within `deadline`,
+    /// instead of the one set with [`PkCommandConfig::with_deadline()`].
+    ///
+    /// Once it is over, the chain is aborted with `ERROR`, and
+    /// [`take_outcome()`](crate::PkCommand::take_outcome) yields [`PkError::Timeout`].
+    ///
+    /// # Errors
+    /// Same as [`perform()`](crate::PkCommand::perform).
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::types::Operation;
+    /// use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
+    /// use std::time::Duration;
+    ///
+    /// let host = PkCommand::<_, _, std::time::Instant>::new(
+    ///     PkCommandConfig::default(64),
+    ///     PkHashmapVariable::new(vec![]),
+    ///     PkHashmapMethod::new(vec![]),
+    /// );
+    /// host.perform_within(
+    ///     Operation::Invoke,
+    ///     Some(String::from("LONGT")),
+    ///     None,
+    ///     Duration::from_secs(2),
+    /// )
+    /// .unwrap();
+    /// ```
+    pub fn perform_within(
+        &self,
+        operation: Operation,
+        object: Option<String>,
+        data: Option<Vec<u8>>,
+        deadline: Duration,
+    ) -> Result<(), PkError> {
+        self.start_chain(operation, object, data, Some(Instant::now() + deadline))
+    }
+
+    fn start_chain(
+        &self,
+        operation: Operation,
+        object: Option<String>,
+        data: Option<Vec<u8>>,
+        deadline: Option<Instant>,
+    ) -> Result<(), PkError> {
         if operation.is_root()
             && self.stage.get() == Stage::Idle
             && self.status.get() == Status::Other
             && self.role.get() == Role::Idle
+            && self.deferred.borrow().is_none()
+            && self.held_outcome.borrow().is_none()
         {
+            self.own_chain.set(true);
             self.root_operation.set(operation);
             self.root_object.replace(object);
             self.data_param.replace(data.unwrap_or(vec![]));
+            // 上一条链可能是作为 Device 服务的，清掉它的返回值和进度
+            self.data_return.replace(vec![]);
+            self.sending_data_progress.set(0);
+            self.reset_extensions(); // 在 ACKNO START 中协商
+            self.last_error.replace(None);
+            // Device 收到 START 后会放弃正在推送的通知
+            self.incoming_notification.replace(None);
             self.role.set(Role::Host);
             self.stage.set(Stage::Started);
             self.status.set(Status::Other);
+            self.chain_deadline.set(deadline);
+            self.awaits.set(0);
             Ok(())
         } else if !operation.is_root() {
-            Err("Cannot initiate a non-root operation")
+            Err(PkError::NotRootOperation(operation))
+        } else {
+            Err(PkError::Busy)
+        }
+    }
+
+    /// Cancels the transaction chain performed by this Host.
+    ///
+    /// The next [`poll()`](crate::PkCommand::poll) aborts the chain with an `ERROR` carrying
+    /// [`PkError::Cancelled`], so that the Device stops working on it: a method still running there is
+    /// [cancelled](crate::Pollable::cancel) too. If the `START` of the chain was not sent yet, or the
+    /// chain was put aside after a collision (see [`TieBreak`]), it is simply dropped.
+    ///
+    /// Either way, [`take_outcome()`](crate::PkCommand::take_outcome) then yields
+    /// [`PkError::Cancelled`] with [`FailureOrigin::Local`].
+    ///
+    /// # Returns
+    /// `false` if this side has no chain of its own to cancel.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::transport::{MemoryTransport, PkTransport};
+    /// use pk_command::types::{FailureOrigin, Operation, PkError, TransactionOutcome};
+    /// use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable, PkPromise};
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let (mut host_transport, mut device_transport) = MemoryTransport::pair();
+    /// std::thread::spawn(move || {
+    ///     let device = PkCommand::<_, _, Instant>::new(
+    ///         PkCommandConfig::default(64),
+    ///         PkHashmapVariable::new(vec![]),
+    ///         PkHashmapMethod::new(vec![(
+    ///             String::from("LONGT"),
+    ///             Box::new(|_| {
+    ///                 // Works until the Host gives up.
+    ///                 PkPromise::execute_cancellable(|_resolve, is_cancelled| {
+    ///                     while !is_cancelled() {
+    ///                         std::thread::sleep(Duration::from_millis(1));
+    ///                     }
+    ///                 })
+    ///             }),
+    ///         )]),
+    ///     );
+    ///     while device.run_with(&mut device_transport).is_ok() {}
+    /// });
+    ///
+    /// let host = PkCommand::<_, _, Instant>::new(
+    ///     PkCommandConfig::default(64),
+    ///     PkHashmapVariable::new(vec![]),
+    ///     PkHashmapMethod::new(vec![]),
+    /// );
+    /// host.perform(Operation::Invoke, Some(String::from("LONGT")), None)
+    ///     .unwrap();
+    /// let start = Instant::now();
+    /// while start.elapsed() < Duration::from_millis(200) {
+    ///     if let Some(bytes) = host_transport.recv().unwrap() {
+    ///         let _ = host.incoming_command(bytes);
+    ///     }
+    ///     if let Some(cmd) = host.poll() {
+    ///         host_transport.send(cmd.to_bytes()).unwrap();
+    ///     }
+    /// }
+    /// // The Device is still working. Give up.
+    /// assert!(host.cancel());
+    /// host.run_with(&mut host_transport).unwrap();
+    /// assert_eq!(
+    ///     host.take_outcome(),
+    ///     Some(TransactionOutcome::Failed {
+    ///         reason: PkError::Cancelled,
+    ///         origin: FailureOrigin::Local,
+    ///     })
+    /// );
+    /// ```
+    pub fn cancel(&self) -> bool {
+        let cancelled = if self.deferred.take().is_some() {
+            true
+        } else if self.role.get() != Role::Host
+            || self.stage.get() == Stage::Idle
+            || self.status.get() == Status::AwaitingErrAck
+        {
+            false
+        } else if self.stage.get() == Stage::Started && self.status.get() != Status::AwaitingAck {
+            // START 还没有发出，Device 什么都不知道
+            self.reset_transaction_state();
+            true
         } else {
-            Err("Cannot initiate an operation when the transaction is in progress")
+            self.cancelling.set(true);
+            return true;
+        };
+        if cancelled {
+            self.last_error
+                .replace(Some((PkError::Cancelled, FailureOrigin::Local)));
+        }
+        cancelled
+    }
+
+    /// Starts a session with a handshake, from the Host side.
+    ///
+    /// This performs a `PKVER` carrying the [`Capabilities`] of this side. A Device that supports
+    /// handshakes replies with its own, and both sides then adopt the smallest packet limit and
+    /// timeouts, and the extensions supported by both. (See [`Capabilities::negotiate()`].)
+    /// The negotiated values stay in effect until the next handshake.
+    ///
+    /// An older Device only replies with its version. The handshake then only checks the major
+    /// version, and the configuration of this side is kept.
+    ///
+    /// Drive the chain like any other. Once it is over, [`take_outcome()`](crate::PkCommand::take_outcome)
+    /// yields the version string of the Device, or [`PkError::Incompatible`] if the peers cannot
+    /// talk to each other.
+    ///
+    /// # Errors
+    /// Same as [`perform()`](crate::PkCommand::perform).
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::transport::MemoryTransport;
+    /// use pk_command::types::TransactionOutcome;
+    /// use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
+    ///
+    /// let (mut host_transport, mut device_transport) = MemoryTransport::pair();
+    /// std::thread::spawn(move || {
+    ///     let device = PkCommand::<_, _, std::time::Instant>::new(
+    ///         PkCommandConfig::default(32),
+    ///         PkHashmapVariable::new(vec![]),
+    ///         PkHashmapMethod::new(vec![]),
+    ///     );
+    ///     while device.run_with(&mut device_transport).is_ok() {}
+    /// });
+    ///
+    /// let host = PkCommand::<_, _, std::time::Instant>::new(
+    ///     PkCommandConfig::default(64),
+    ///     PkHashmapVariable::new(vec![]),
+    ///     PkHashmapMethod::new(vec![]),
+    /// );
+    /// host.handshake().unwrap();
+    /// host.run_with(&mut host_transport).unwrap();
+    /// assert!(matches!(
+    ///     host.take_outcome(),
+    ///     Some(TransactionOutcome::Completed(Some(_)))
+    /// ));
+    /// assert_eq!(host.capabilities().packet_limit, 32);
+    /// ```
+    pub fn handshake(&self) -> Result<(), PkError> {
+        self.perform(
+            Operation::GetVersion,
+            None,
+            Some(self.config.capabilities().to_bytes()),
+        )
+    }
+
+    /// Returns the session parameters currently in effect.
+    ///
+    /// These come from the configuration, until a [handshake](crate::PkCommand::handshake) negotiates them with the peer.
+    pub fn capabilities(&self) -> Capabilities {
+        self.session.get()
+    }
+
+    /// Subscribes to the changes of a variable on the Device, from the Host side.
+    ///
+    /// This performs a `SUBSC` root operation. Once the chain is over,
+    /// [`take_outcome()`](crate::PkCommand::take_outcome) yields the current value of the variable,
+    /// and from then on the Device pushes its new values, to be collected with
+    /// [`take_notification()`](crate::PkCommand::take_notification). The Device checks the variable
+    /// at most once per `min_interval`, or per the interval in its own configuration if that is
+    /// longer. (See [`PkCommandConfig::with_subscriptions()`].)
+    ///
+    /// Subscribing again to the same variable updates the interval. To cancel the subscription,
+    /// perform [`Operation::Unsubscribe`] on the variable. A [handshake](crate::PkCommand::handshake)
+    /// cancels all of them.
+    ///
+    /// # Errors
+    /// Same as [`perform()`](crate::PkCommand::perform).
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use pk_command::transport::MemoryTransport;
+    /// use pk_command::types::TransactionOutcome;
+    /// use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
+    ///
+    /// let (mut host_transport, mut device_transport) = MemoryTransport::pair();
+    /// std::thread::spawn(move || {
+    ///     let device = PkCommand::<_, _, std::time::Instant>::new(
+    ///         PkCommandConfig::default(64),
+    ///         PkHashmapVariable::new(vec![(
+    ///             String::from("TEMPR"),
+    ///             Some(b"21.5".to_vec()),
+    ///             Box::new(|_| {}),
+    ///         )]),
+    ///         PkHashmapMethod::new(vec![]),
+    ///     );
+    ///     while device.run_with(&mut device_transport).is_ok() {}
+    /// });
+    ///
+    /// let host = PkCommand::<_, _, std::time::Instant>::new(
+    ///     PkCommandConfig::default(64),
+    ///     PkHashmapVariable::new(vec![]),
+    ///     PkHashmapMethod::new(vec![]),
+    /// );
+    /// host.subscribe("TEMPR", Duration::from_millis(500)).unwrap();
+    /// host.run_with(&mut host_transport).unwrap();
+    /// assert_eq!(
+    ///     host.take_outcome(),
+    ///     Some(TransactionOutcome::Completed(Some(b"21.5".to_vec())))
+    /// );
+    /// ```
+    pub fn subscribe(&self, name: &str, min_interval: Duration) -> Result<(), PkError> {
+        self.perform(
+            Operation::Subscribe,
+            Some(name.to_string()),
+            Some(min_interval.as_millis().to_string().into_bytes()),
+        )
+    }
+
+    /// Takes the oldest notification pushed by the Device, from the Host side. (See [`Notification`].)
+    ///
+    /// Notifications are received between transaction chains, while [`poll()`](crate::PkCommand::poll)
+    /// is called. Only the latest value of each variable is kept until it is taken.
+    pub fn take_notification(&self) -> Option<Notification> {
+        self.notifications.borrow_mut().pop_front()
+    }
+
+    /// Returns the variable accessor.
+    ///
+    /// On the Device, this is how the application changes its variables while the state machine
+    /// owns them. Subscribed variables are checked for changes in [`poll()`](crate::PkCommand::poll).
+    pub fn variables(&self) -> &VA {
+        &self.variable_accessor
+    }
+
+    /// Returns how long this side waits for an `ACKNO` before retransmitting a command for the first time.
+    ///
+    /// This is the ACK timeout of the session, unless it adapts to the measured round-trip times.
+    /// (See [`PkCommandConfig::with_adaptive_timeout()`].) Each retransmission may then wait longer,
+    /// depending on the [`Backoff`].
+    pub fn retransmission_timeout(&self) -> Duration {
+        match self.rtt.get() {
+            Some(rtt) => rtt.rto(),
+            None => self.session.get().ack_timeout,
+        }
+    }
+
+    /// Returns a pseudo-random number for the [`Backoff`] jitter, mixing `noise` in.
+    fn next_random(&self, noise: u32) -> u32 {
+        // xorshift32
+        let mut x = self.jitter_state.get() ^ noise;
+        if x == 0 {
+            x = JITTER_SEED;
         }
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.jitter_state.set(x);
+        x
     }
 
+    /// Ends the current transaction chain and forgets its state, except for its outcome.
     fn reset_transaction_state(&self) {
         self.stage.set(Stage::Idle);
         self.status.set(Status::Other);
@@ -1357,13 +3399,171 @@ impl<
         self.data_return.borrow_mut().clear();
         self.sending_data_progress.set(0);
         self.device_op_pending.set(false);
+        self.device_should_return.set(false);
         self.device_await_deadline.set(None);
-        self.pending_pollable.borrow_mut().take(); // Clear the pollable
+        self.execution_deadline.set(None);
+        self.chain_deadline.set(None);
+        // 还没完成的方法不会再被轮询，通知它停下
+        if let Some(pollable) = self.pending_pollable.borrow_mut().take() {
+            pollable.cancel();
+        }
+        self.reset_extensions();
     }
 
-    /// Returns `true` if the state machine is currently [`Idle`](crate::types::Stage::Idle) (no active transaction).
+    /// Returns `true` if the state machine is currently [`Idle`](crate::types::Stage::Idle) (no active transaction),
+    /// and no transaction is waiting to start again after a collision. (See [`TieBreak`].)
     pub fn is_complete(&self) -> bool {
-        self.stage.get() == Stage::Idle
+        self.stage.get() == Stage::Idle && self.deferred.borrow().is_none()
+    }
+
+    /// Returns `true` if the state machine is idle and no `ERROR` is waiting for its acknowledgement,
+    /// i.e. a new transaction can be performed right away.
+    pub(crate) fn is_settled(&self) -> bool {
+        self.is_complete() && self.status.get() == Status::Other
+    }
+
+    /// Takes the outcome of the last transaction chain and resets the transaction state.
+    ///
+    /// Unlike [`get_return_data()`](crate::PkCommand::get_return_data), this tells a failed chain apart
+    /// from one that succeeded without returning data. Call it on the Host once
+    /// [`is_complete()`](crate::PkCommand::is_complete) returns `true`. If the peer starts a chain of
+    /// its own before that, the outcome is kept until it is taken.
+    ///
+    /// # Returns
+    /// - `Some(TransactionOutcome::Completed(data))`: The chain performed by this Host finished normally.
+    /// - `Some(TransactionOutcome::Failed { reason, origin })`: The chain was aborted with `ERROR`, by
+    ///   this side ([`FailureOrigin::Local`]) or by the peer ([`FailureOrigin::Remote`], the reason is
+    ///   then usually a [`PkError::Remote`] carrying the peer's description).
+    ///
+    /// For `REQUV`, [`TransactionOutcome::into_variable()`] tells a missing variable, an empty one and a value apart.
+    /// - `None`: A chain is still in progress, or there is no outcome to report (anymore).
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::transport::MemoryTransport;
+    /// use pk_command::types::{FailureOrigin, Operation, PkError, TransactionOutcome};
+    /// use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
+    ///
+    /// let (mut host_transport, mut device_transport) = MemoryTransport::pair();
+    /// std::thread::spawn(move || {
+    ///     let device = PkCommand::<_, _, std::time::Instant>::new(
+    ///         PkCommandConfig::default(64),
+    ///         PkHashmapVariable::new(vec![]),
+    ///         PkHashmapMethod::new(vec![]),
+    ///     );
+    ///     while device.run_with(&mut device_transport).is_ok() {}
+    /// });
+    ///
+    /// let host = PkCommand::<_, _, std::time::Instant>::new(
+    ///     PkCommandConfig::default(64),
+    ///     PkHashmapVariable::new(vec![]),
+    ///     PkHashmapMethod::new(vec![]),
+    /// );
+    /// host.perform(Operation::Invoke, Some(String::from("NOPE!")), None)
+    ///     .unwrap();
+    /// host.run_with(&mut host_transport).unwrap();
+    /// assert_eq!(
+    ///     host.take_outcome(),
+    ///     Some(TransactionOutcome::Failed {
+    ///         reason: PkError::Remote(String::from("METHOD method not found")),
+    ///         origin: FailureOrigin::Remote,
+    ///     })
+    /// );
+    /// ```
+    pub fn take_outcome(&self) -> Option<TransactionOutcome> {
+        if let Some(outcome) = self.held_outcome.take() {
+            return Some(outcome);
+        }
+        if !self.is_settled() {
+            return None;
+        }
+        self.own_chain.set(false);
+        if let Some((reason, origin)) = self.last_error.take() {
+            return Some(TransactionOutcome::Failed { reason, origin });
+        }
+        if self.role.get() == Role::Host {
+            return Some(TransactionOutcome::Completed(self.get_return_data()));
+        }
+        None
+    }
+
+    /// Drives the state machine over a blocking transport until one transaction chain is finished.
+    ///
+    /// This is the loop every application would otherwise write by hand: it receives packets from
+    /// `transport`, feeds them to [`incoming_command()`](crate::PkCommand::incoming_command), calls
+    /// [`poll()`](crate::PkCommand::poll) and sends whatever comes out. Malformed packets are dropped.
+    /// When there is nothing to do, it sleeps for the [idle wait](PkCommandConfig::with_idle_wait).
+    ///
+    /// - On the Host, call [`perform()`](crate::PkCommand::perform) first. This returns once the chain
+    ///   is over, and the result can be collected with [`get_return_data()`](crate::PkCommand::get_return_data).
+    /// - On the Device, this waits for the Host to start a chain, serves it, and returns once it is over.
+    ///   Call it in a loop to keep serving.
+    ///
+    /// A chain aborted by an `ERROR` also counts as finished.
+    ///
+    /// # Errors
+    /// Returns the error of the transport if sending or receiving fails, and the error of
+    /// [`incoming_command()`](crate::PkCommand::incoming_command) if a well-formed packet cannot be
+    /// queued (e.g. [`PkError::QueueFull`]).
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::transport::MemoryTransport;
+    /// use pk_command::types::Operation;
+    /// use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
+    ///
+    /// let (mut host_transport, mut device_transport) = MemoryTransport::pair();
+    ///
+    /// std::thread::spawn(move || {
+    ///     let device = PkCommand::<_, _, std::time::Instant>::new(
+    ///         PkCommandConfig::default(64),
+    ///         PkHashmapVariable::new(vec![(
+    ///             String::from("VARIA"),
+    ///             Some(b"value".to_vec()),
+    ///             Box::new(|_| {}),
+    ///         )]),
+    ///         PkHashmapMethod::new(vec![]),
+    ///     );
+    ///     while device.run_with(&mut device_transport).is_ok() {}
+    /// });
+    ///
+    /// let host = PkCommand::<_, _, std::time::Instant>::new(
+    ///     PkCommandConfig::default(64),
+    ///     PkHashmapVariable::new(vec![]),
+    ///     PkHashmapMethod::new(vec![]),
+    /// );
+    /// host.perform(Operation::RequireVariable, Some(String::from("VARIA")), None)
+    ///     .unwrap();
+    /// host.run_with(&mut host_transport).unwrap();
+    /// assert_eq!(host.get_return_data(), Some(b"value".to_vec()));
+    /// ```
+    pub fn run_with<T: PkTransport>(&self, transport: &mut T) -> Result<(), PkError> {
+        let mut started = !self.is_settled();
+        loop {
+            let received = transport.recv()?;
+            let idle = received.is_none();
+            if let Some(bytes) = received {
+                match self.incoming_command(bytes) {
+                    // 格式错误的包直接丢弃，对方会在 ACK 超时后重发
+                    Err(e) if e.is_malformed() => {}
+                    Err(e) => return Err(e),
+                    Ok(_) => {}
+                }
+            }
+            let sent = self.poll();
+            if let Some(cmd) = &sent {
+                transport.send(cmd.to_bytes())?;
+            }
+            if !self.is_settled() {
+                started = true;
+            } else if started {
+                return Ok(());
+            }
+            if idle && sent.is_none() {
+                #[cfg(feature = "std")]
+                std::thread::sleep(self.config.idle_wait);
+            }
+        }
     }
 
     /// Retrieves the return data from a finished transaction and resets the transaction state.
@@ -1371,11 +3571,20 @@ impl<
     /// This should be called by the Host after [`is_complete()`](crate::PkCommand::is_complete) returns `true` for a root
     /// operation that expects return data (e.g., `REQUV` or `INVOK`).
     ///
+    /// A failed chain also yields `None`. Use [`take_outcome()`](crate::PkCommand::take_outcome) to tell these apart.
+    ///
     /// # Returns
     /// - `Some(Vec<u8>)`: The returned payload.
     /// - `None`: If there was no data or the state machine is not in a completed host state.
     pub fn get_return_data(&self) -> Option<Vec<u8>> {
+        match self.held_outcome.take() {
+            Some(TransactionOutcome::Completed(data)) => return data,
+            held => {
+                self.held_outcome.replace(held);
+            }
+        }
         if self.stage.get() == Stage::Idle && self.role.get() == Role::Host {
+            self.own_chain.set(false);
             let data = self.data_return.borrow().clone();
             self.reset_transaction_state();
             if data.is_empty() {
@@ -1534,21 +3743,49 @@ impl<
             sending_data_progress: Cell::new(0),
             root_operation: Cell::new(Operation::Empty),
             root_object: RefCell::new(None),
-            command_buffer: RefCell::new(Command {
-                msg_id: 0,
-                operation: Operation::Empty,
-                object: None,
-                data: None,
-            }),
-            command_processed: Cell::new(true),
+            inbound_queue: RefCell::new(VecDeque::new()),
+            dropped_commands: Cell::new(0),
             last_command_time: Cell::new(Instant::now()),
             device_op_pending: Cell::new(false),
             device_await_deadline: Cell::new(None),
-            config,
             variable_accessor,
             method_accessor,
             pending_pollable: RefCell::new(None),
             device_should_return: Cell::new(false),
+            chain_checksum: Cell::new(Checksum::None),
+            last_ack: RefCell::new(None),
+            last_error: RefCell::new(None),
+            own_chain: Cell::new(false),
+            held_outcome: RefCell::new(None),
+            session: Cell::new(config.capabilities()),
+            subscriptions: RefCell::new(Vec::new()),
+            notifications: RefCell::new(VecDeque::new()),
+            incoming_notification: RefCell::new(None),
+            deferred: RefCell::new(None),
+            cancelling: Cell::new(false),
+            chain_deadline: Cell::new(None),
+            awaits: Cell::new(0),
+            execution_deadline: Cell::new(None),
+            retransmissions: Cell::new(0),
+            ack_wait: Cell::new(config.ack_timeout),
+            jitter_state: Cell::new(JITTER_SEED),
+            rtt: Cell::new(
+                config
+                    .rto_bounds
+                    .map(|(min, max)| RttEstimator::new(config.ack_timeout, min, max)),
+            ),
+            sent_at: Cell::new(Instant::now()),
+            chain_window: Cell::new(1),
+            in_flight: RefCell::new(VecDeque::new()),
+            held_chunks: RefCell::new(Vec::new()),
+            chain_express: Cell::new(false),
+            chain_piggyback: Cell::new(false),
+            implied_ack: Cell::new(false),
+            quiet_ack: Cell::new(false),
+            unacked: RefCell::new(VecDeque::new()),
+            resend: RefCell::new(VecDeque::new()),
+            rewinds: Cell::new(0),
+            config,
         }
     }
 }
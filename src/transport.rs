@@ -0,0 +1,355 @@
+//! Transport abstractions for moving PK Command packets between two parties.
+//!
+//! PK Command itself only turns packets into commands and back, and leaves the actual
+//! I/O to the application. The traits in this module describe that I/O so that
+//! higher-level helpers can drive the state machine on their own:
+//!
+//! - [`PkTransport`] is the blocking variant, driven by [`PkCommand::run_with()`](crate::PkCommand::run_with).
+//! - [`PkAsyncTransport`] is the async variant, driven by the async clients. (See [`client`](crate::client).)
+//!
+//! With the `std` feature, ready-made blocking transports are provided:
+//! - [`MemoryTransport`]: an in-memory loopback pair, handy for tests and simulations.
+//! - [`UdpTransport`]: a connected UDP socket.
+//! - [`UnixDatagramTransport`]: a connected Unix domain datagram socket (Unix only).
+//! - [`StreamTransport`]: any [`Read`](std::io::Read) + [`Write`](std::io::Write) byte stream, like a TCP
+//!   connection or a serial port, framed by one of the [`framing`](crate::types::framing) schemes.
+//!
+//! Their async counterparts, including a `StreamTransport` over `AsyncRead` + `AsyncWrite` byte
+//! streams, live in [`tokio_adapter`](crate::tokio_adapter) and [`smol_adapter`](crate::smol_adapter).
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use core::future::Future;
+
+use crate::types::PkError;
+#[cfg(feature = "std")]
+use crate::types::framing::{Framer, LengthPrefixFramer};
+
+/// A blocking, packet-oriented transport.
+///
+/// Each call to [`send()`](PkTransport::send) transmits exactly one PK Command packet,
+/// and each call to [`recv()`](PkTransport::recv) yields at most one received packet.
+///
+/// # Example
+/// ```
+/// use pk_command::transport::{MemoryTransport, PkTransport};
+///
+/// let (mut host, mut device) = MemoryTransport::pair();
+/// host.send(b"!!START".to_vec()).unwrap();
+/// assert_eq!(device.recv().unwrap(), Some(b"!!START".to_vec()));
+/// assert_eq!(device.recv().unwrap(), None);
+/// ```
+pub trait PkTransport {
+    /// Sends one packet to the peer.
+    fn send(&mut self, packet: Vec<u8>) -> Result<(), PkError>;
+
+    /// Receives the next packet from the peer, if there is one.
+    ///
+    /// Implementations should wait for a short while (e.g., a read timeout of a millisecond or so)
+    /// when nothing is available, so that drivers calling this in a loop do not spin. But they must
+    /// not block indefinitely, otherwise the timers of the state machine stop running.
+    ///
+    /// # Returns
+    /// - `Ok(Some(packet))`: A packet was received.
+    /// - `Ok(None)`: No packet is available at the moment.
+    /// - `Err(PkError::Transport(_))`: The transport is closed or broken.
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, PkError>;
+}
+
+/// An asynchronous, packet-oriented transport.
+///
+/// Each call to [`send()`](PkAsyncTransport::send) transmits exactly one PK Command packet,
+/// and each call to [`recv()`](PkAsyncTransport::recv) yields exactly one received packet.
+///
+/// # Cancellation
+///
+/// Drivers usually wait for [`recv()`](PkAsyncTransport::recv) with a timeout, so that they can
+/// keep the state machine's timers running. The future returned by `recv()` **must be
+/// cancel-safe**: dropping it before completion must not lose a packet. Channel receivers and
+/// datagram sockets of Tokio and smol satisfy this.
+///
+/// # Example
+/// ```
+/// use pk_command::transport::PkAsyncTransport;
+/// use pk_command::types::PkError;
+///
+/// struct Loopback(Vec<Vec<u8>>);
+///
+/// impl PkAsyncTransport for Loopback {
+///     async fn send(&mut self, packet: Vec<u8>) -> Result<(), PkError> {
+///         self.0.push(packet);
+///         Ok(())
+///     }
+///
+///     async fn recv(&mut self) -> Result<Vec<u8>, PkError> {
+///         self.0
+///             .pop()
+///             .ok_or(PkError::Transport("nothing to receive".to_string()))
+///     }
+/// }
+/// ```
+pub trait PkAsyncTransport {
+    /// Sends one packet to the peer.
+    fn send(&mut self, packet: Vec<u8>) -> impl Future<Output = Result<(), PkError>>;
+
+    /// Waits for the next packet from the peer.
+    ///
+    /// Returns [`PkError::Transport`] if the transport is closed or broken.
+    fn recv(&mut self) -> impl Future<Output = Result<Vec<u8>, PkError>>;
+}
+
+/// How long the ready-made transports wait in [`recv()`](PkTransport::recv) before reporting "no packet".
+#[cfg(feature = "std")]
+const RECV_WAIT: std::time::Duration = std::time::Duration::from_millis(1);
+
+#[cfg(feature = "std")]
+pub(crate) fn io_error(e: std::io::Error) -> PkError {
+    PkError::Transport(e.to_string())
+}
+
+/// Returns `true` for I/O errors that only mean "nothing to read right now".
+#[cfg(feature = "std")]
+fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+    )
+}
+
+/// An in-memory [`PkTransport`], created in connected pairs.
+///
+/// Each half can be moved to its own thread. When one half is dropped, the other one
+/// reports [`PkError::Transport`].
+///
+/// **Note**: This is only available when the `std` feature is enabled.
+#[cfg(feature = "std")]
+pub struct MemoryTransport {
+    tx: std::sync::mpsc::Sender<Vec<u8>>,
+    rx: std::sync::mpsc::Receiver<Vec<u8>>,
+}
+
+#[cfg(feature = "std")]
+impl MemoryTransport {
+    /// Creates two transports connected to each other.
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = std::sync::mpsc::channel();
+        let (b_tx, a_rx) = std::sync::mpsc::channel();
+        (
+            MemoryTransport { tx: a_tx, rx: a_rx },
+            MemoryTransport { tx: b_tx, rx: b_rx },
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl PkTransport for MemoryTransport {
+    fn send(&mut self, packet: Vec<u8>) -> Result<(), PkError> {
+        self.tx
+            .send(packet)
+            .map_err(|_| PkError::Transport(String::from("peer disconnected")))
+    }
+
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, PkError> {
+        match self.rx.recv_timeout(RECV_WAIT) {
+            Ok(packet) => Ok(Some(packet)),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                Err(PkError::Transport(String::from("peer disconnected")))
+            }
+        }
+    }
+}
+
+/// Size of the receive buffer of datagram transports. Large enough for any UDP datagram.
+#[cfg(feature = "std")]
+pub(crate) const DATAGRAM_BUFFER_SIZE: usize = 65536;
+
+/// A [`PkTransport`] over a connected UDP socket. Each datagram carries one packet.
+///
+/// **Note**: This is only available when the `std` feature is enabled.
+///
+/// # Example
+/// ```no_run
+/// use pk_command::transport::UdpTransport;
+/// use std::net::UdpSocket;
+///
+/// let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+/// socket.connect("192.168.1.10:5000").unwrap();
+/// let transport = UdpTransport::new(socket).unwrap();
+/// ```
+#[cfg(feature = "std")]
+pub struct UdpTransport {
+    socket: std::net::UdpSocket,
+    buffer: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl UdpTransport {
+    /// Wraps a UDP socket that is already [connected](std::net::UdpSocket::connect) to the peer.
+    ///
+    /// The read timeout of the socket is set to a millisecond.
+    pub fn new(socket: std::net::UdpSocket) -> std::io::Result<Self> {
+        socket.set_read_timeout(Some(RECV_WAIT))?;
+        Ok(UdpTransport {
+            socket,
+            buffer: vec![0; DATAGRAM_BUFFER_SIZE],
+        })
+    }
+
+    /// Returns the wrapped socket.
+    pub fn into_inner(self) -> std::net::UdpSocket {
+        self.socket
+    }
+}
+
+#[cfg(feature = "std")]
+impl PkTransport for UdpTransport {
+    fn send(&mut self, packet: Vec<u8>) -> Result<(), PkError> {
+        self.socket.send(&packet).map(|_| ()).map_err(io_error)
+    }
+
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, PkError> {
+        match self.socket.recv(&mut self.buffer) {
+            Ok(len) => Ok(Some(self.buffer[..len].to_vec())),
+            // A datagram sent before the peer was listening may come back as "connection refused".
+            Err(e) if is_transient(&e) || e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                Ok(None)
+            }
+            Err(e) => Err(io_error(e)),
+        }
+    }
+}
+
+/// A [`PkTransport`] over a connected Unix domain datagram socket. Each datagram carries one packet.
+///
+/// **Note**: This is only available on Unix platforms when the `std` feature is enabled.
+///
+/// # Example
+/// ```
+/// use pk_command::transport::UnixDatagramTransport;
+/// use std::os::unix::net::UnixDatagram;
+///
+/// let (a, b) = UnixDatagram::pair().unwrap();
+/// let host = UnixDatagramTransport::new(a).unwrap();
+/// let device = UnixDatagramTransport::new(b).unwrap();
+/// ```
+#[cfg(all(feature = "std", unix))]
+pub struct UnixDatagramTransport {
+    socket: std::os::unix::net::UnixDatagram,
+    buffer: Vec<u8>,
+}
+
+#[cfg(all(feature = "std", unix))]
+impl UnixDatagramTransport {
+    /// Wraps a Unix datagram socket that is already [connected](std::os::unix::net::UnixDatagram::connect)
+    /// to the peer (or created by [`UnixDatagram::pair()`](std::os::unix::net::UnixDatagram::pair)).
+    ///
+    /// The read timeout of the socket is set to a millisecond.
+    pub fn new(socket: std::os::unix::net::UnixDatagram) -> std::io::Result<Self> {
+        socket.set_read_timeout(Some(RECV_WAIT))?;
+        Ok(UnixDatagramTransport {
+            socket,
+            buffer: vec![0; DATAGRAM_BUFFER_SIZE],
+        })
+    }
+
+    /// Returns the wrapped socket.
+    pub fn into_inner(self) -> std::os::unix::net::UnixDatagram {
+        self.socket
+    }
+}
+
+#[cfg(all(feature = "std", unix))]
+impl PkTransport for UnixDatagramTransport {
+    fn send(&mut self, packet: Vec<u8>) -> Result<(), PkError> {
+        self.socket.send(&packet).map(|_| ()).map_err(io_error)
+    }
+
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, PkError> {
+        match self.socket.recv(&mut self.buffer) {
+            Ok(len) => Ok(Some(self.buffer[..len].to_vec())),
+            Err(e) if is_transient(&e) => Ok(None),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+}
+
+/// A [`PkTransport`] over a [`Read`](std::io::Read) + [`Write`](std::io::Write) byte stream,
+/// such as a TCP connection or a serial port.
+///
+/// Byte streams do not preserve packet boundaries, so packets are framed with a
+/// [`Framer`]. By default, the [`LengthPrefixFramer`] is used; on noisy links like UART,
+/// prefer the [`CobsFramer`](crate::types::framing::CobsFramer) with [`with_framer()`](StreamTransport::with_framer).
+///
+/// Reads should not block indefinitely: set a short read timeout on the stream (or make it
+/// non-blocking). `WouldBlock` and `TimedOut` errors are reported as "no packet".
+///
+/// **Note**: This is only available when the `std` feature is enabled.
+///
+/// # Example
+/// ```no_run
+/// use pk_command::transport::StreamTransport;
+/// use pk_command::types::framing::CobsFramer;
+/// use std::net::TcpStream;
+/// use std::time::Duration;
+///
+/// let stream = TcpStream::connect("192.168.1.10:5000").unwrap();
+/// stream
+///     .set_read_timeout(Some(Duration::from_millis(1)))
+///     .unwrap();
+/// let transport = StreamTransport::with_framer(stream, CobsFramer::new(64));
+/// ```
+#[cfg(feature = "std")]
+pub struct StreamTransport<S, F = LengthPrefixFramer> {
+    stream: S,
+    framer: F,
+}
+
+#[cfg(feature = "std")]
+impl<S: std::io::Read + std::io::Write> StreamTransport<S> {
+    /// Wraps a byte stream, framing packets with a [`LengthPrefixFramer`].
+    pub fn new(stream: S) -> Self {
+        Self::with_framer(stream, LengthPrefixFramer::default())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S: std::io::Read + std::io::Write, F: Framer> StreamTransport<S, F> {
+    /// Wraps a byte stream, framing packets with the given framer.
+    pub fn with_framer(stream: S, framer: F) -> Self {
+        StreamTransport { stream, framer }
+    }
+
+    /// Returns the wrapped stream. Bytes that were received but not yet returned as a packet are lost.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S: std::io::Read + std::io::Write, F: Framer> PkTransport for StreamTransport<S, F> {
+    fn send(&mut self, packet: Vec<u8>) -> Result<(), PkError> {
+        self.stream
+            .write_all(&self.framer.encode(&packet))
+            .map_err(io_error)?;
+        self.stream.flush().map_err(io_error)
+    }
+
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, PkError> {
+        if let Some(packet) = self.framer.next_frame() {
+            return Ok(Some(packet));
+        }
+        let mut chunk = [0u8; 256];
+        match self.stream.read(&mut chunk) {
+            Ok(0) => Err(PkError::Transport(String::from("stream closed"))),
+            Ok(len) => {
+                self.framer.push(&chunk[..len]);
+                Ok(self.framer.next_frame())
+            }
+            Err(e) if is_transient(&e) => Ok(None),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+}
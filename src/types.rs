@@ -14,6 +14,10 @@ use alloc::{
 
 use crate::util::msg_id;
 
+use std::time::Duration;
+
+pub mod framing;
+
 /// Defines the set of operations supported by the PK Command protocol.
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum Operation {
@@ -37,6 +41,32 @@ pub enum Operation {
     /// 5-character name: `PKVER`
     GetVersion,
 
+    /// To get the catalogue of the variables and methods exposed by the device. (See [`Catalogue`].)
+    ///
+    /// 5-character name: `LISTO`
+    ListObjects,
+
+    /// To subscribe to the changes of a variable on the device. (See [`Notification`].)
+    ///
+    /// The optional parameter is the minimum interval between two notifications, in milliseconds
+    /// as ASCII digits. The current value of the variable is returned.
+    ///
+    /// 5-character name: `SUBSC`
+    Subscribe,
+
+    /// To cancel a subscription made with [`Subscribe`](Operation::Subscribe).
+    ///
+    /// 5-character name: `UNSUB`
+    Unsubscribe,
+
+    /// To push the new value of a subscribed variable from the device to the host, outside any
+    /// transaction chain.
+    ///
+    /// This is used internally by the [`poll`](crate::PkCommand::poll) method and usually should not be used directly.
+    ///
+    /// 5-character name: `NOTIF`
+    Notify,
+
     /// To indicate the start of a transaction chain.
     ///
     /// This is used internally by the [`poll`](crate::PkCommand::poll) method to manage transaction stages
@@ -112,6 +142,10 @@ impl Operation {
             RequireVariable => "REQUV",
             Invoke => "INVOK",
             GetVersion => "PKVER",
+            ListObjects => "LISTO",
+            Subscribe => "SUBSC",
+            Unsubscribe => "UNSUB",
+            Notify => "NOTIF",
             Start => "START",
             EndTransaction => "ENDTR",
             Acknowledge => "ACKNO",
@@ -132,6 +166,10 @@ impl Operation {
             "REQUV" => Some(RequireVariable),
             "INVOK" => Some(Invoke),
             "PKVER" => Some(GetVersion),
+            "LISTO" => Some(ListObjects),
+            "SUBSC" => Some(Subscribe),
+            "UNSUB" => Some(Unsubscribe),
+            "NOTIF" => Some(Notify),
             "START" => Some(Start),
             "ENDTR" => Some(EndTransaction),
             "ACKNO" => Some(Acknowledge),
@@ -153,6 +191,9 @@ impl Operation {
                 | Operation::RequireVariable
                 | Operation::Invoke
                 | Operation::GetVersion
+                | Operation::ListObjects
+                | Operation::Subscribe
+                | Operation::Unsubscribe
         )
     }
 }
@@ -195,19 +236,19 @@ impl Command {
     /// * `msg_bytes`: The raw bytes received from the transport layer.
     ///
     /// # Returns
-    /// A [`Result`] containing the parsed [`Command`] or an error message.
+    /// A [`Result`] containing the parsed [`Command`] or the reason it is invalid.
     ///
     /// # Errors
-    /// Returns an error if the byte slice is not a valid PK Command. (For example, the length is too short, the MSG ID is invalid,
-    /// or the operation name is unrecognized.)
-    pub fn parse(msg_bytes: &[u8]) -> Result<Command, &'static str> {
+    /// Returns an error if the byte slice is not a valid PK Command: [`PkError::TooShort`], [`PkError::InvalidLength`],
+    /// [`PkError::InvalidMsgId`], [`PkError::UnknownOperation`], [`PkError::MissingSeparator`] or [`PkError::InvalidObject`].
+    pub fn parse(msg_bytes: &[u8]) -> Result<Command, PkError> {
         // 1. 检查最小长度
         if msg_bytes.len() < 7 {
-            return Err("Invalid length: message is too short.");
+            return Err(PkError::TooShort);
         }
 
         // 2. 解析 MSG ID
-        let msg_id_slice = msg_bytes.get(0..2).ok_or("Failed to slice MSG ID")?;
+        let msg_id_slice = &msg_bytes[0..2];
 
         // 3. 特殊处理 ERROR 指令
         if msg_id_slice == b"  " {
@@ -216,21 +257,24 @@ impl Command {
             let space1_slice = msg_bytes.get(7..8);
             let object_slice = msg_bytes.get(8..13);
 
-            let is_ackno_error = op_name_slice == Some(b"ACKNO")
-                && space1_slice == Some(b" ")
-                && object_slice == Some(b"ERROR");
-            let is_error_error = op_name_slice == Some(b"ERROR")
-                && space1_slice == Some(b" ")
-                && object_slice == Some(b"ERROR");
-
-            if !(is_ackno_error || is_error_error) {
-                return Err("Invalid ERROR command format.");
+            // 空格 MSG ID 只用于 ERROR 和 ACKNO ERROR
+            if op_name_slice != Some(b"ACKNO") && op_name_slice != Some(b"ERROR") {
+                return Err(PkError::InvalidMsgId);
+            }
+            if msg_bytes.len() < 13 {
+                return Err(PkError::InvalidLength);
+            }
+            if space1_slice != Some(b" ") {
+                return Err(PkError::MissingSeparator);
+            }
+            if object_slice != Some(b"ERROR") {
+                return Err(PkError::InvalidObject);
             }
 
             let data = if msg_bytes.len() > 14 {
                 // 检查数据前的空格
                 if msg_bytes.get(13..14) != Some(b" ") {
-                    return Err("Missing space before data in ERROR command.");
+                    return Err(PkError::MissingSeparator);
                 }
                 // unwrap is safe due to length check msg_bytes.len() > 14
                 Some(msg_bytes.get(14..).unwrap().to_vec())
@@ -238,7 +282,7 @@ impl Command {
                 // Exactly "  OP_NAME OBJECT"
                 None
             } else {
-                return Err("Invalid length for ERROR command.");
+                return Err(PkError::InvalidLength);
             };
 
             return Ok(Command {
@@ -254,16 +298,12 @@ impl Command {
         }
 
         // 4. 处理常规指令
-        let msg_id_str =
-            std::str::from_utf8(msg_id_slice).map_err(|_| "MSG ID is not valid UTF-8")?;
-        let msg_id = msg_id::to_u16(msg_id_str).map_err(|_| "Invalid MSG ID format.")?;
+        let msg_id_str = std::str::from_utf8(msg_id_slice).map_err(|_| PkError::InvalidMsgId)?;
+        let msg_id = msg_id::to_u16(msg_id_str)?;
 
-        let op_name_slice = msg_bytes
-            .get(2..7)
-            .ok_or("Failed to slice operation name.")?;
         let op_name_str =
-            std::str::from_utf8(op_name_slice).map_err(|_| "Operation name is not valid UTF-8")?;
-        let operation = Operation::from_name(op_name_str).ok_or("Unrecognized operation name.")?;
+            std::str::from_utf8(&msg_bytes[2..7]).map_err(|_| PkError::UnknownOperation)?;
+        let operation = Operation::from_name(op_name_str).ok_or(PkError::UnknownOperation)?;
 
         // 5. 根据长度和分隔符判断 object 和 data
         let (object, data) = match msg_bytes.len() {
@@ -273,29 +313,27 @@ impl Command {
             // 包含 OBJECT
             13 => {
                 if msg_bytes.get(7..8) != Some(b" ") {
-                    return Err("Missing space after operation name.");
+                    return Err(PkError::MissingSeparator);
                 }
-                let obj_slice = msg_bytes.get(8..13).ok_or("Failed to slice object.")?;
                 let obj_str =
-                    std::str::from_utf8(obj_slice).map_err(|_| "Object is not valid UTF-8")?;
+                    std::str::from_utf8(&msg_bytes[8..13]).map_err(|_| PkError::InvalidObject)?;
                 (Some(obj_str.to_string()), None)
             }
 
             // 包含 OBJECT 和 DATA
             len if len > 14 => {
                 if msg_bytes.get(7..8) != Some(b" ") || msg_bytes.get(13..14) != Some(b" ") {
-                    return Err("Missing space separator for object or data.");
+                    return Err(PkError::MissingSeparator);
                 }
-                let obj_slice = msg_bytes.get(8..13).ok_or("Failed to slice object.")?;
                 let obj_str =
-                    std::str::from_utf8(obj_slice).map_err(|_| "Object is not valid UTF-8")?;
+                    std::str::from_utf8(&msg_bytes[8..13]).map_err(|_| PkError::InvalidObject)?;
 
                 // unwrap is safe due to length check (len > 14)
                 let data_slice = msg_bytes.get(14..).unwrap();
                 (Some(obj_str.to_string()), Some(data_slice.to_vec()))
             }
             // 其他所有长度都是无效的
-            _ => return Err("Invalid message length."),
+            _ => return Err(PkError::InvalidLength),
         };
 
         Ok(Command {
@@ -461,6 +499,841 @@ impl std::fmt::Display for Command {
     }
 }
 
+/// Errors reported by PK Command.
+///
+/// When the state machine aborts a transaction chain because of an error, it sends the error to the
+/// peer in the `ERROR` command as a stable [code](PkError::code), followed by its details if any.
+/// (See [`to_wire()`](PkError::to_wire).) [`VariableNotFound`](PkError::VariableNotFound),
+/// [`AccessDenied`](PkError::AccessDenied), [`Incompatible`](PkError::Incompatible) and
+/// [`Cancelled`](PkError::Cancelled) are turned back into themselves on the other side, and every
+/// other error into a [`PkError::Remote`]. (See [`from_remote()`](PkError::from_remote).)
+///
+/// The [`Display`](std::fmt::Display) text is meant for humans only, and is not sent to the peer.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum PkError {
+    /// The packet is shorter than the shortest command (7 bytes).
+    TooShort,
+    /// The length of the packet does not match any command layout.
+    InvalidLength,
+    /// The MSG ID is not made of two characters from `!` to `~`, or is out of range.
+    InvalidMsgId,
+    /// The operation name is not a known one.
+    UnknownOperation,
+    /// A space separator between the operation, the object and the data is missing.
+    MissingSeparator,
+    /// The object is not valid UTF-8, or is not allowed for the operation.
+    InvalidObject,
+
+    /// A transaction is already in progress.
+    Busy,
+    /// The operation cannot start a transaction. (See [`Operation::is_root()`].)
+    NotRootOperation(Operation),
+    /// The inbound queue is full. (See [`QueueOverflow::Reject`].)
+    QueueFull,
+
+    /// The peer did not respond in time.
+    Timeout,
+    /// The peer sent a command that is not allowed at this point of the transaction. Carries what was expected.
+    UnexpectedCommand(&'static str),
+    /// The checksum of the transferred payload does not match. (See [`Checksum`].)
+    ChecksumMismatch,
+    /// The peer aborted the transaction with an `ERROR` command. Carries its description, which is
+    /// usually an error code followed by details, like `METHOD out of paper`.
+    Remote(String),
+    /// The Host cancelled the transaction. (See [`PkCommand::cancel()`](crate::PkCommand::cancel).)
+    Cancelled,
+
+    /// The variable does not exist. Carries its name.
+    VariableNotFound(String),
+    /// A variable accessor failed to read or write a variable. Carries a description.
+    Variable(String),
+    /// A method failed to start or to complete. Carries a description.
+    Method(String),
+    /// The Host may not run the operation on the object, like `SENDV` on a read-only variable.
+    /// Carries the name of the object. (See [`AccessMode`].)
+    AccessDenied(String),
+    /// Received data could not be decoded. Carries what was expected.
+    InvalidData(&'static str),
+    /// The peer cannot talk to this side, as found by the [handshake](crate::PkCommand::handshake).
+    /// Carries the reason.
+    Incompatible(String),
+
+    /// The underlying transport failed to send or receive a packet.
+    Transport(String),
+    /// The state machine reached an inconsistent state. This is a bug.
+    Internal(&'static str),
+}
+
+impl std::fmt::Display for PkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PkError::TooShort => write!(f, "command is too short"),
+            PkError::InvalidLength => write!(f, "invalid command length"),
+            PkError::InvalidMsgId => write!(f, "invalid MSG ID"),
+            PkError::UnknownOperation => write!(f, "unknown operation"),
+            PkError::MissingSeparator => write!(f, "missing space separator"),
+            PkError::InvalidObject => write!(f, "invalid object"),
+            PkError::Busy => write!(f, "a transaction is in progress"),
+            PkError::NotRootOperation(op) => write!(f, "{} is not a root operation", op.to_name()),
+            PkError::QueueFull => write!(f, "inbound queue is full"),
+            PkError::Timeout => write!(f, "operation timed out"),
+            PkError::UnexpectedCommand(expected) => {
+                write!(f, "unexpected command, should be {}", expected)
+            }
+            PkError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            PkError::Remote(msg) => write!(f, "transaction aborted by peer: {}", msg),
+            PkError::Cancelled => write!(f, "transaction cancelled"),
+            PkError::VariableNotFound(name) => write!(f, "variable not found: {}", name),
+            PkError::Variable(msg) => write!(f, "variable access failed: {}", msg),
+            PkError::Method(msg) => write!(f, "method failed: {}", msg),
+            PkError::AccessDenied(name) => write!(f, "access denied: {}", name),
+            PkError::InvalidData(expected) => write!(f, "invalid data, expected {}", expected),
+            PkError::Incompatible(reason) => write!(f, "incompatible peer: {}", reason),
+            PkError::Transport(msg) => write!(f, "transport error: {}", msg),
+            PkError::Internal(msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PkError {}
+
+impl PkError {
+    /// Returns the stable code which identifies this error in an `ERROR` command.
+    ///
+    /// The codes are listed in section 5.1 of the specification.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PkError::TooShort => "TOO_SHORT",
+            PkError::InvalidLength => "INVALID_LENGTH",
+            PkError::InvalidMsgId => "INVALID_MSG_ID",
+            PkError::UnknownOperation => "UNKNOWN_OPERATION",
+            PkError::MissingSeparator => "MISSING_SEPARATOR",
+            PkError::InvalidObject => "INVALID_OBJECT",
+            PkError::Busy => "BUSY",
+            PkError::NotRootOperation(_) => "NOT_ROOT",
+            PkError::QueueFull => "QUEUE_FULL",
+            PkError::Timeout => "TIMEOUT",
+            PkError::UnexpectedCommand(_) => "UNEXPECTED",
+            PkError::ChecksumMismatch => "CHECKSUM",
+            PkError::Remote(_) => "REMOTE",
+            PkError::Cancelled => "CANCELLED",
+            PkError::VariableNotFound(_) => "NOT_FOUND",
+            PkError::Variable(_) => "VARIABLE",
+            PkError::Method(_) => "METHOD",
+            PkError::AccessDenied(_) => "DENIED",
+            PkError::InvalidData(_) => "INVALID_DATA",
+            PkError::Incompatible(_) => "INCOMPATIBLE",
+            PkError::Transport(_) => "TRANSPORT",
+            PkError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    /// Returns `true` if this error means a received packet is not a valid command, as found by
+    /// [`Command::parse()`].
+    ///
+    /// Such packets are best dropped: the peer retransmits the command after its ACK timeout.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::types::{Command, PkError};
+    ///
+    /// assert!(Command::parse(b"!!").unwrap_err().is_malformed());
+    /// assert!(!PkError::QueueFull.is_malformed());
+    /// ```
+    pub fn is_malformed(&self) -> bool {
+        matches!(
+            self,
+            PkError::TooShort
+                | PkError::InvalidLength
+                | PkError::InvalidMsgId
+                | PkError::UnknownOperation
+                | PkError::MissingSeparator
+                | PkError::InvalidObject
+        )
+    }
+
+    /// Encodes this error as the description of an `ERROR` command: its [code](PkError::code),
+    /// followed by a space and its details if it carries any.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::types::PkError;
+    ///
+    /// assert_eq!(PkError::Timeout.to_wire(), "TIMEOUT");
+    /// assert_eq!(
+    ///     PkError::VariableNotFound(String::from("VARIA")).to_wire(),
+    ///     "NOT_FOUND VARIA"
+    /// );
+    /// ```
+    pub fn to_wire(&self) -> String {
+        let detail = match self {
+            PkError::NotRootOperation(op) => Some(op.to_name().to_string()),
+            PkError::UnexpectedCommand(expected) => Some(expected.to_string()),
+            PkError::InvalidData(expected) => Some(expected.to_string()),
+            PkError::Internal(msg) => Some(msg.to_string()),
+            PkError::Remote(detail)
+            | PkError::VariableNotFound(detail)
+            | PkError::Variable(detail)
+            | PkError::Method(detail)
+            | PkError::AccessDenied(detail)
+            | PkError::Incompatible(detail)
+            | PkError::Transport(detail) => Some(detail.clone()),
+            _ => None,
+        };
+        match detail {
+            Some(detail) if !detail.is_empty() => format!("{} {}", self.code(), detail),
+            _ => self.code().to_string(),
+        }
+    }
+
+    /// Turns the description of an `ERROR` received from the peer into an error.
+    ///
+    /// The codes of [`PkError::VariableNotFound`], [`PkError::AccessDenied`], [`PkError::Incompatible`]
+    /// and [`PkError::Cancelled`] are turned back into their own variants. Everything else, including
+    /// descriptions without a known code, becomes a [`PkError::Remote`] carrying the description as is.
+    pub fn from_remote(description: &str) -> PkError {
+        let (code, detail) = description.split_once(' ').unwrap_or((description, ""));
+        match code {
+            "NOT_FOUND" => PkError::VariableNotFound(detail.to_string()),
+            "DENIED" => PkError::AccessDenied(detail.to_string()),
+            "INCOMPATIBLE" => PkError::Incompatible(detail.to_string()),
+            "CANCELLED" => PkError::Cancelled,
+            _ => PkError::Remote(description.to_string()),
+        }
+    }
+}
+
+/// The value of a variable as requested by a Host with `REQUV`.
+/// (See [`TransactionOutcome::into_variable()`].)
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum VariableValue {
+    /// The Device has no variable with that name.
+    NotFound,
+    /// The variable exists, but its value is empty.
+    Empty,
+    /// The value of the variable.
+    Value(Vec<u8>),
+}
+
+/// How a Host may access a variable.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum AccessMode {
+    /// The variable can be read with `REQUV`, but not written with `SENDV`.
+    ReadOnly,
+    /// The variable can be written with `SENDV`, but not read with `REQUV`.
+    WriteOnly,
+    /// The variable can be read and written. **Default.**
+    #[default]
+    ReadWrite,
+}
+
+impl AccessMode {
+    /// Returns `true` if the variable can be read.
+    pub fn is_readable(&self) -> bool {
+        *self != AccessMode::WriteOnly
+    }
+
+    /// Returns `true` if the variable can be written.
+    pub fn is_writable(&self) -> bool {
+        *self != AccessMode::ReadOnly
+    }
+
+    fn to_name(self) -> &'static str {
+        match self {
+            AccessMode::ReadOnly => "R",
+            AccessMode::WriteOnly => "W",
+            AccessMode::ReadWrite => "RW",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<AccessMode> {
+        match name {
+            "R" => Some(AccessMode::ReadOnly),
+            "W" => Some(AccessMode::WriteOnly),
+            "RW" => Some(AccessMode::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of the [`Catalogue`] describing a variable.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct VariableInfo {
+    /// The 5-character name of the variable.
+    pub name: String,
+    /// Whether the variable can be read and/or written.
+    pub access: AccessMode,
+    /// A short, human-readable description. May be empty.
+    pub description: String,
+}
+
+/// An entry of the [`Catalogue`] describing a method.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct MethodInfo {
+    /// The 5-character name of the method.
+    pub name: String,
+    /// A short, human-readable description. May be empty.
+    pub description: String,
+}
+
+/// The variables and methods exposed by a device, as returned by the `LISTO` root operation.
+///
+/// On the wire, the catalogue is UTF-8 text with one entry per line:
+///
+/// ```text
+/// V RW VARIA A variable that can be read and written
+/// V R VERSN
+/// M ECHOO Returns its parameter
+/// ```
+///
+/// Variables start with `V` and their access mode (`R`, `W` or `RW`), methods start with `M`.
+/// The name follows, then the optional description. Line breaks in descriptions are replaced
+/// with spaces.
+///
+/// # Example
+/// ```
+/// use pk_command::types::{AccessMode, Catalogue, MethodInfo, VariableInfo};
+///
+/// let catalogue = Catalogue {
+///     variables: vec![VariableInfo {
+///         name: String::from("VARIA"),
+///         access: AccessMode::ReadOnly,
+///         description: String::from("Some value"),
+///     }],
+///     methods: vec![MethodInfo {
+///         name: String::from("ECHOO"),
+///         description: String::new(),
+///     }],
+/// };
+/// let bytes = catalogue.to_bytes();
+/// assert_eq!(bytes, b"V R VARIA Some value\nM ECHOO\n".to_vec());
+/// assert_eq!(Catalogue::parse(&bytes), Ok(catalogue));
+/// ```
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Catalogue {
+    /// The variables, as listed by [`PkVariableAccessor::list()`](crate::PkVariableAccessor::list).
+    pub variables: Vec<VariableInfo>,
+    /// The methods, as listed by [`PkMethodAccessor::list()`](crate::PkMethodAccessor::list).
+    pub methods: Vec<MethodInfo>,
+}
+
+impl Catalogue {
+    /// Serializes the catalogue for the wire.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn line(out: &mut String, head: &str, name: &str, description: &str) {
+            out.push_str(head);
+            out.push(' ');
+            out.push_str(name);
+            if !description.is_empty() {
+                out.push(' ');
+                out.extend(description.chars().map(|c| if c == '\n' { ' ' } else { c }));
+            }
+            out.push('\n');
+        }
+
+        let mut out = String::new();
+        for variable in &self.variables {
+            let head = format!("V {}", variable.access.to_name());
+            line(&mut out, &head, &variable.name, &variable.description);
+        }
+        for method in &self.methods {
+            line(&mut out, "M", &method.name, &method.description);
+        }
+        out.into_bytes()
+    }
+
+    /// Parses a catalogue received from a device.
+    ///
+    /// # Errors
+    /// Returns [`PkError::InvalidData`] if the bytes are not a valid catalogue.
+    pub fn parse(bytes: &[u8]) -> Result<Catalogue, PkError> {
+        const EXPECTED: &str = "a catalogue";
+        let text = std::str::from_utf8(bytes).map_err(|_| PkError::InvalidData(EXPECTED))?;
+        let mut catalogue = Catalogue::default();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let mut fields = line.splitn(2, ' ');
+            let kind = fields.next().unwrap_or_default();
+            let rest = fields.next().ok_or(PkError::InvalidData(EXPECTED))?;
+            match kind {
+                "V" => {
+                    let (access, rest) =
+                        rest.split_once(' ').ok_or(PkError::InvalidData(EXPECTED))?;
+                    let (name, description) = rest.split_once(' ').unwrap_or((rest, ""));
+                    catalogue.variables.push(VariableInfo {
+                        name: name.to_string(),
+                        access: AccessMode::from_name(access)
+                            .ok_or(PkError::InvalidData(EXPECTED))?,
+                        description: description.to_string(),
+                    });
+                }
+                "M" => {
+                    let (name, description) = rest.split_once(' ').unwrap_or((rest, ""));
+                    catalogue.methods.push(MethodInfo {
+                        name: name.to_string(),
+                        description: description.to_string(),
+                    });
+                }
+                _ => return Err(PkError::InvalidData(EXPECTED)),
+            }
+        }
+        Ok(catalogue)
+    }
+}
+
+/// The smallest packet limit a session can run with: the longest header (`SDATA` with a
+/// 5-character object, 14 bytes), a CRC-32 trailer, and at least one byte of payload.
+const MIN_PACKET_LIMIT: u64 = 14 + 4 + 1;
+
+/// The parameters exchanged by the [handshake](crate::PkCommand::handshake) at the start of a session.
+///
+/// Each side advertises its own configuration. Both then adopt the result of
+/// [`negotiate()`](Capabilities::negotiate): the smallest packet limit and timeouts, and the
+/// extensions supported by both.
+///
+/// On the wire, the capabilities are space-separated `KEY=VALUE` tokens, with times in milliseconds:
+///
+/// ```text
+/// MAJOR=1 PACKET=64 ACK=100 INTER=500 AWAIT=300 EXT=1
+/// ```
+///
+/// Unknown keys are ignored, so that later versions can add more.
+///
+/// # Example
+/// ```
+/// use pk_command::types::Capabilities;
+/// use std::time::Duration;
+///
+/// let host = Capabilities {
+///     major: 1,
+///     packet_limit: 64,
+///     ack_timeout: Duration::from_millis(100),
+///     inter_command_timeout: Duration::from_millis(500),
+///     await_interval: Duration::from_millis(300),
+///     extensions: Capabilities::CHECKSUM,
+/// };
+/// let device = Capabilities {
+///     packet_limit: 32,
+///     extensions: 0,
+///     ..host
+/// };
+///
+/// let session = host.negotiate(&device).unwrap();
+/// assert_eq!(session.packet_limit, 32);
+/// assert!(!session.supports(Capabilities::CHECKSUM));
+/// assert_eq!(Capabilities::parse(&host.to_bytes()), Ok(host));
+/// ```
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Capabilities {
+    /// The major version of the protocol. Peers with different major versions are incompatible.
+    pub major: u32,
+    /// The maximum length of a single packet, in bytes.
+    pub packet_limit: u64,
+    /// Timeout for waiting for an `ACKNO`.
+    pub ack_timeout: Duration,
+    /// Timeout for waiting for the next command in a sequence.
+    pub inter_command_timeout: Duration,
+    /// Interval at which a Device sends `AWAIT`.
+    pub await_interval: Duration,
+    /// Bitmap of the supported protocol extensions, made of the `Capabilities::*` flags.
+    pub extensions: u32,
+}
+
+impl Capabilities {
+    /// Extension flag: integrity checks. (See [`Checksum`].)
+    pub const CHECKSUM: u32 = 1 << 0;
+    /// Extension flag: windowed `SDATA` transfers. (See [`PkCommandConfig::with_window()`](crate::PkCommandConfig::with_window).)
+    pub const WINDOW: u32 = 1 << 1;
+    /// Extension flag: express chains. (See [`PkCommandConfig::with_express()`](crate::PkCommandConfig::with_express).)
+    pub const EXPRESS: u32 = 1 << 2;
+    /// Extension flag: piggybacked acknowledgements. (See [`PkCommandConfig::with_piggyback()`](crate::PkCommandConfig::with_piggyback).)
+    pub const PIGGYBACK: u32 = 1 << 3;
+
+    /// Returns `true` if all extensions in `flags` are supported.
+    pub fn supports(&self, flags: u32) -> bool {
+        self.extensions & flags == flags
+    }
+
+    /// Computes the parameters of a session between this side and `peer`.
+    ///
+    /// # Errors
+    /// Returns [`PkError::Incompatible`] if the major versions differ, or if the common packet limit
+    /// is too small to carry any data.
+    pub fn negotiate(&self, peer: &Capabilities) -> Result<Capabilities, PkError> {
+        if self.major != peer.major {
+            return Err(PkError::Incompatible(format!(
+                "protocol version {} is not {}",
+                peer.major, self.major
+            )));
+        }
+        let packet_limit = self.packet_limit.min(peer.packet_limit);
+        if packet_limit < MIN_PACKET_LIMIT {
+            return Err(PkError::Incompatible(format!(
+                "packet limit {} is too small",
+                packet_limit
+            )));
+        }
+        Ok(Capabilities {
+            major: self.major,
+            packet_limit,
+            ack_timeout: self.ack_timeout.min(peer.ack_timeout),
+            inter_command_timeout: self.inter_command_timeout.min(peer.inter_command_timeout),
+            await_interval: self.await_interval.min(peer.await_interval),
+            extensions: self.extensions & peer.extensions,
+        })
+    }
+
+    /// Serializes the capabilities for the wire.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!(
+            "MAJOR={} PACKET={} ACK={} INTER={} AWAIT={} EXT={}",
+            self.major,
+            self.packet_limit,
+            self.ack_timeout.as_millis(),
+            self.inter_command_timeout.as_millis(),
+            self.await_interval.as_millis(),
+            self.extensions
+        )
+        .into_bytes()
+    }
+
+    /// Parses the capabilities received from a peer.
+    ///
+    /// # Errors
+    /// Returns [`PkError::InvalidData`] if a value is malformed or a key is missing.
+    pub fn parse(bytes: &[u8]) -> Result<Capabilities, PkError> {
+        const EXPECTED: &str = "capabilities";
+        let text = std::str::from_utf8(bytes).map_err(|_| PkError::InvalidData(EXPECTED))?;
+        let mut values: [Option<u64>; 6] = [None; 6];
+        for token in text.split(' ').filter(|token| !token.is_empty()) {
+            let (key, value) = token
+                .split_once('=')
+                .ok_or(PkError::InvalidData(EXPECTED))?;
+            let index = match key {
+                "MAJOR" => 0,
+                "PACKET" => 1,
+                "ACK" => 2,
+                "INTER" => 3,
+                "AWAIT" => 4,
+                "EXT" => 5,
+                _ => continue,
+            };
+            values[index] = Some(value.parse().map_err(|_| PkError::InvalidData(EXPECTED))?);
+        }
+        let [
+            Some(major),
+            Some(packet_limit),
+            Some(ack),
+            Some(inter),
+            Some(await_interval),
+            Some(extensions),
+        ] = values
+        else {
+            return Err(PkError::InvalidData(EXPECTED));
+        };
+        Ok(Capabilities {
+            major: u32::try_from(major).map_err(|_| PkError::InvalidData(EXPECTED))?,
+            packet_limit,
+            ack_timeout: Duration::from_millis(ack),
+            inter_command_timeout: Duration::from_millis(inter),
+            await_interval: Duration::from_millis(await_interval),
+            extensions: u32::try_from(extensions).map_err(|_| PkError::InvalidData(EXPECTED))?,
+        })
+    }
+}
+
+/// Which side aborted a transaction chain. (See [`TransactionOutcome::Failed`].)
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum FailureOrigin {
+    /// This side sent the `ERROR`, e.g. because the peer timed out or an accessor failed.
+    Local,
+    /// The peer sent the `ERROR`.
+    Remote,
+}
+
+/// The outcome of a transaction chain, as reported by [`PkCommand::take_outcome()`](crate::PkCommand::take_outcome).
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum TransactionOutcome {
+    /// The chain finished normally. Carries the returned data, or `None` if nothing was returned.
+    Completed(Option<Vec<u8>>),
+    /// The chain was aborted with `ERROR`.
+    Failed {
+        /// Why the chain was aborted. When the peer aborted it, this is usually a [`PkError::Remote`]
+        /// carrying the description it sent. (See [`PkError::from_remote()`].)
+        reason: PkError,
+        /// Which side aborted the chain.
+        origin: FailureOrigin,
+    },
+}
+
+impl TransactionOutcome {
+    /// Interprets the outcome of a `REQUV` chain.
+    ///
+    /// # Errors
+    /// Returns the reason of the failure if the chain failed for any other reason than a missing variable.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::types::{FailureOrigin, PkError, TransactionOutcome, VariableValue};
+    ///
+    /// let outcome = TransactionOutcome::Failed {
+    ///     reason: PkError::VariableNotFound(String::from("VARIA")),
+    ///     origin: FailureOrigin::Remote,
+    /// };
+    /// assert_eq!(outcome.into_variable(), Ok(VariableValue::NotFound));
+    /// assert_eq!(
+    ///     TransactionOutcome::Completed(None).into_variable(),
+    ///     Ok(VariableValue::Empty)
+    /// );
+    /// ```
+    pub fn into_variable(self) -> Result<VariableValue, PkError> {
+        match self {
+            TransactionOutcome::Completed(None) => Ok(VariableValue::Empty),
+            TransactionOutcome::Completed(Some(value)) => Ok(VariableValue::Value(value)),
+            TransactionOutcome::Failed {
+                reason: PkError::VariableNotFound(_),
+                ..
+            } => Ok(VariableValue::NotFound),
+            TransactionOutcome::Failed { reason, .. } => Err(reason),
+        }
+    }
+}
+
+/// A new value of a subscribed variable, pushed by the Device.
+/// (See [`PkCommand::take_notification()`](crate::PkCommand::take_notification).)
+///
+/// The Host subscribes to a variable with the `SUBSC` root operation. From then on, the Device
+/// checks the variable at the negotiated interval and, when it has changed, pushes the new value
+/// outside of any transaction chain:
+///
+/// - `[MSG ID]NOTIF [NAME] [VALUE]` if the value fits in one packet and is not empty. This is the
+///   whole notification, acknowledged like any other command.
+/// - `[MSG ID]NOTIF [NAME]` otherwise, followed by the value in `SDATA NOTIF` packets and `ENDTR`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Notification {
+    /// The 5-character name of the variable.
+    pub name: String,
+    /// The new value of the variable.
+    pub value: Vec<u8>,
+}
+
+/// Integrity check carried by `SDATA` and `ENDTR` commands on noisy links.
+///
+/// This is an extension of the protocol, negotiated per transaction chain: the Host offers the
+/// checksum configured in its [`PkCommandConfig`](crate::PkCommandConfig) with `START`, and the Device
+/// accepts it if checksums are enabled in its own configuration. Otherwise (or when talking to a peer
+/// which does not know this extension) the chain runs without checksums.
+///
+/// When active,
+/// - every `SDATA` packet carries the checksum of its payload as a big-endian trailer. A packet
+///   with a wrong trailer is dropped without acknowledgement, so it is retransmitted on ACK timeout;
+/// - `ENDTR` carries the checksum of the whole transferred payload (`[MSG ID]ENDTR CRC32 [CHECKSUM]`).
+///   A mismatch aborts the chain with `ERROR`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Checksum {
+    /// No integrity check. **Default.**
+    #[default]
+    None,
+    /// CRC-16/CCITT-FALSE (polynomial `0x1021`, initial value `0xFFFF`), 2 bytes.
+    ///
+    /// 5-character name: `CRC16`
+    Crc16,
+    /// CRC-32 as used by Ethernet and zlib (reflected polynomial `0xEDB88320`), 4 bytes.
+    ///
+    /// 5-character name: `CRC32`
+    Crc32,
+}
+
+impl Checksum {
+    /// Returns the 5-character name of the checksum, used during negotiation and in `ENDTR`.
+    ///
+    /// Returns `None` for [`Checksum::None`].
+    pub fn to_name(&self) -> Option<&'static str> {
+        match self {
+            Checksum::None => None,
+            Checksum::Crc16 => Some("CRC16"),
+            Checksum::Crc32 => Some("CRC32"),
+        }
+    }
+
+    /// Creates a `Checksum` from its 5-character name.
+    pub fn from_name(name: &str) -> Option<Checksum> {
+        match name {
+            "CRC16" => Some(Checksum::Crc16),
+            "CRC32" => Some(Checksum::Crc32),
+            _ => None,
+        }
+    }
+
+    /// Returns the size of the checksum in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc16 => 2,
+            Checksum::Crc32 => 4,
+        }
+    }
+
+    /// Returns `true` for [`Checksum::None`].
+    pub fn is_none(&self) -> bool {
+        *self == Checksum::None
+    }
+
+    /// Computes the checksum of `data`, in big-endian byte order.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::types::Checksum;
+    /// assert_eq!(Checksum::Crc16.compute(b"123456789"), vec![0x29, 0xB1]);
+    /// assert_eq!(Checksum::None.compute(b"123456789"), vec![]);
+    /// ```
+    pub fn compute(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Checksum::None => Vec::new(),
+            Checksum::Crc16 => {
+                let mut crc: u16 = 0xFFFF;
+                for &byte in data {
+                    crc ^= (byte as u16) << 8;
+                    for _ in 0..8 {
+                        crc = if crc & 0x8000 != 0 {
+                            (crc << 1) ^ 0x1021
+                        } else {
+                            crc << 1
+                        };
+                    }
+                }
+                crc.to_be_bytes().to_vec()
+            }
+            Checksum::Crc32 => {
+                let mut crc: u32 = 0xFFFF_FFFF;
+                for &byte in data {
+                    crc ^= byte as u32;
+                    for _ in 0..8 {
+                        crc = if crc & 1 != 0 {
+                            (crc >> 1) ^ 0xEDB8_8320
+                        } else {
+                            crc >> 1
+                        };
+                    }
+                }
+                (!crc).to_be_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Splits the checksum trailer off `data` and verifies it.
+    ///
+    /// # Returns
+    /// The payload without the trailer, or `None` if the trailer is missing or wrong.
+    pub fn strip<'a>(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+        let payload_len = data.len().checked_sub(self.size())?;
+        let (payload, trailer) = data.split_at(payload_len);
+        if self.compute(payload) == trailer {
+            Some(payload)
+        } else {
+            None
+        }
+    }
+}
+
+/// What [`incoming_command()`](crate::PkCommand::incoming_command) does when the inbound queue is full.
+///
+/// The depth of the queue is set with [`PkCommandConfig::with_inbound_queue()`](crate::PkCommandConfig::with_inbound_queue).
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum QueueOverflow {
+    /// Drop the oldest queued command to make room for the new one. **Default.**
+    ///
+    /// Newer commands are usually retransmissions of (or replies to) older ones, so they are more useful.
+    #[default]
+    DropOldest,
+    /// Keep the queue as is and reject the new command with an error.
+    Reject,
+}
+
+/// How a side resolves a collision: both sides sending `START` at the same time.
+///
+/// Either side may [`perform()`](crate::PkCommand::perform) a transaction, so both may start one
+/// at the same time. The side that wins keeps waiting for the `ACKNO START` of its chain. The side
+/// that yields serves the chain of the peer first, and starts its own again afterwards. A side
+/// that receives the `START` of the peer before sending its own always yields.
+///
+/// The rule is set with [`PkCommandConfig::with_tie_break()`](crate::PkCommandConfig::with_tie_break).
+/// The two sides must be configured so that exactly one of them wins, e.g. [`Win`](TieBreak::Win)
+/// on one side and [`Yield`](TieBreak::Yield) on the other. A link on which only one side ever
+/// starts transactions does not need any of this.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum TieBreak {
+    /// This side always keeps its own chain.
+    Win,
+    /// This side always serves the chain of the peer first. **Default.**
+    #[default]
+    Yield,
+    /// The `START` with the larger MSG ID wins. As both sides usually count from the last chain,
+    /// ties are common: this side wins them if `wins_ties` is `true`.
+    MsgId {
+        /// Whether this side wins when both `START`s carry the same MSG ID.
+        wins_ties: bool,
+    },
+}
+
+impl TieBreak {
+    /// Returns `true` if this side keeps its own chain, given the MSG IDs of both `START`s.
+    pub fn wins(&self, own_msg_id: u16, peer_msg_id: u16) -> bool {
+        match self {
+            TieBreak::Win => true,
+            TieBreak::Yield => false,
+            TieBreak::MsgId { wins_ties } => match own_msg_id.cmp(&peer_msg_id) {
+                std::cmp::Ordering::Greater => true,
+                std::cmp::Ordering::Less => false,
+                std::cmp::Ordering::Equal => *wins_ties,
+            },
+        }
+    }
+}
+
+/// How the ACK timeout grows while a command is retransmitted.
+///
+/// Set with [`PkCommandConfig::with_retries()`](crate::PkCommandConfig::with_retries), along with the
+/// number of retransmissions after which a transaction fails.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Backoff {
+    /// Every retransmission waits for the same ACK timeout. **Default.**
+    #[default]
+    Fixed,
+    /// The timeout doubles with each retransmission of a command, up to `max_timeout`.
+    Exponential {
+        /// The longest to wait for an `ACKNO`.
+        max_timeout: Duration,
+        /// Whether to add up to a quarter of the timeout at random, so that both sides do not
+        /// retransmit in lockstep after losing packets at the same time. The timeout still never
+        /// exceeds `max_timeout`.
+        jitter: bool,
+    },
+}
+
+impl Backoff {
+    /// Returns how long to wait for an `ACKNO` after the next retransmission, given how long was
+    /// waited after the previous one. `random` is any random number, only used for the jitter.
+    pub fn next_timeout(&self, timeout: Duration, random: u32) -> Duration {
+        match self {
+            Backoff::Fixed => timeout,
+            Backoff::Exponential {
+                max_timeout,
+                jitter,
+            } => {
+                let timeout = timeout * 2;
+                let timeout = if *jitter {
+                    timeout + timeout / 1024 * (random % 257)
+                } else {
+                    timeout
+                };
+                // 抖动之后再限制，保证不会超过 max_timeout
+                timeout.min(*max_timeout)
+            }
+        }
+    }
+}
+
 /// Indicates the current acknowledgment status of the participant.
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub enum Status {
@@ -547,28 +1420,35 @@ mod tests {
     #[test]
     fn test_command_parse_invalid_error_msg_id() {
         // space msg_id's are only allowed in ERROR and ACKNO ERROR commands
-        let bytes = b"  START";
-        let result = Command::parse(bytes);
-        assert!(result.is_err());
+        assert_eq!(Command::parse(b"  START"), Err(PkError::InvalidMsgId));
     }
 
     #[test]
     fn test_command_parse_invalid_too_short() {
-        // assert_eq!(
-        //     Command::parse(b"!!STA"),
-        //     Err("Invalid length: message is too short.")
-        // );
-        let result = Command::parse(b"!!STA");
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Invalid length: message is too short.");
+        assert_eq!(Command::parse(b"!!STA"), Err(PkError::TooShort));
+    }
+
+    #[test]
+    fn test_command_parse_invalid_layout() {
+        assert_eq!(Command::parse(b"!!START_"), Err(PkError::InvalidLength));
+        assert_eq!(
+            Command::parse(b"!!SENDV VARIA_data"),
+            Err(PkError::MissingSeparator)
+        );
+        assert_eq!(
+            Command::parse(b"!!SENDV \xFF\xFEVAR"),
+            Err(PkError::InvalidObject)
+        );
+        assert_eq!(
+            Command::parse(b"  ERROR FAULT"),
+            Err(PkError::InvalidObject)
+        );
     }
 
     #[test]
     fn test_command_parse_invalid_msg_id() {
         // LF(0x0A) and CR(0x0D) is not in the charset
-        let result = Command::parse(b"\n\rSTART");
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Invalid MSG ID format.");
+        assert_eq!(Command::parse(b"\n\rSTART"), Err(PkError::InvalidMsgId));
     }
 
     #[test]
@@ -610,4 +1490,67 @@ mod tests {
         expected.extend_from_slice(b"Test error");
         assert_eq!(cmd.to_bytes(), expected);
     }
+
+    #[test]
+    fn test_checksum_check_values() {
+        // Check values of the catalogue of parametrised CRC algorithms
+        assert_eq!(Checksum::Crc16.compute(b"123456789"), vec![0x29, 0xB1]);
+        assert_eq!(
+            Checksum::Crc32.compute(b"123456789"),
+            vec![0xCB, 0xF4, 0x39, 0x26]
+        );
+        assert_eq!(Checksum::Crc32.compute(b""), vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_checksum_strip() {
+        for checksum in [Checksum::None, Checksum::Crc16, Checksum::Crc32] {
+            let mut data = b"payload".to_vec();
+            data.extend(checksum.compute(b"payload"));
+            assert_eq!(checksum.strip(&data), Some(&b"payload"[..]));
+            if !checksum.is_none() {
+                data[0] ^= 0x01;
+                assert_eq!(checksum.strip(&data), None);
+                assert_eq!(checksum.strip(b"x"), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tie_break() {
+        assert!(TieBreak::Win.wins(1, 2));
+        assert!(!TieBreak::Yield.wins(2, 1));
+        let high = TieBreak::MsgId { wins_ties: true };
+        let low = TieBreak::MsgId { wins_ties: false };
+        assert!(high.wins(3, 3) && !low.wins(3, 3));
+        // Both sides see the same pair of MSG IDs, swapped, so exactly one of them wins.
+        assert!(low.wins(4, 3) && !high.wins(3, 4));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let timeout = Duration::from_millis(100);
+        assert_eq!(Backoff::Fixed.next_timeout(timeout, 42), timeout);
+        let exponential = Backoff::Exponential {
+            max_timeout: Duration::from_millis(300),
+            jitter: false,
+        };
+        assert_eq!(
+            exponential.next_timeout(timeout, 42),
+            Duration::from_millis(200)
+        );
+        assert_eq!(
+            exponential.next_timeout(Duration::from_millis(200), 42),
+            Duration::from_millis(300)
+        );
+        // The jitter adds at most a quarter.
+        let jittered = Backoff::Exponential {
+            max_timeout: Duration::from_secs(1),
+            jitter: true,
+        };
+        for random in [0, 1, 256, 257, u32::MAX] {
+            let next = jittered.next_timeout(timeout, random);
+            assert!(next >= Duration::from_millis(200) && next <= Duration::from_millis(250));
+        }
+    }
 }
@@ -0,0 +1,586 @@
+//! Framing of PK Command packets on byte-stream transports.
+//!
+//! [`Command::parse()`](crate::types::Command::parse) expects exactly one packet per call. That holds on
+//! packet-oriented transports like HID, but not on byte streams like UART or TCP, where packets may be
+//! split into several reads or merged into one. A [`Framer`] restores the packet boundaries:
+//!
+//! - On the sending side, [`encode()`](Framer::encode) wraps the output of
+//!   [`Command::to_bytes()`](crate::types::Command::to_bytes) for the wire.
+//! - On the receiving side, arbitrary chunks of bytes are [`push()`](Framer::push)ed in, and whole
+//!   frames come out of [`next_frame()`](Framer::next_frame).
+//!
+//! Three framings are provided:
+//!
+//! | Framer                 | Wire format                                                        | Overhead          |
+//! |------------------------|--------------------------------------------------------------------|-------------------|
+//! | [`CobsFramer`]         | [COBS](https://en.wikipedia.org/wiki/Consistent_Overhead_Byte_Stuffing)-encoded packet, terminated by `0x00` | 2 bytes per 254 bytes |
+//! | [`SlipFramer`]         | [SLIP](https://datatracker.ietf.org/doc/html/rfc1055)-escaped packet, surrounded by `0xC0` | up to 2x          |
+//! | [`LengthPrefixFramer`] | 2-byte big-endian length, then the packet                          | 2 bytes           |
+//!
+//! All of them recover from garbage (line noise, a peer that starts talking mid-packet, ...):
+//! a corrupted or overlong frame is dropped and decoding resumes at the next frame boundary.
+//! The delimited framings (COBS, SLIP) resynchronize on the next delimiter; noise that happens
+//! to decode cleanly is handed out as a frame, and is then rejected by [`Command::parse()`](crate::types::Command::parse).
+//! The length-prefixed framing has no delimiter, so it skips bytes until it finds a length followed
+//! by a parsable command. (The check is pluggable, see [`LengthPrefixFramer::with_validator()`].)
+//!
+//! Frame length limits bound the memory spent on garbage. Set them to the `packet_limit` of your
+//! [`PkCommandConfig`](crate::PkCommandConfig) where possible.
+//!
+//! Only `alloc` is required, so the framers can be used in `no_std` environments as well.
+//!
+//! # Example
+//! ```
+//! use pk_command::types::Command;
+//! use pk_command::types::framing::{CobsFramer, Framer};
+//!
+//! let mut framer = CobsFramer::default();
+//! let wire = framer.encode(b"!!START");
+//!
+//! // The frame arrives in two reads, the second one together with the start of another frame.
+//! framer.push(&wire[..3]);
+//! assert_eq!(framer.next_frame(), None);
+//! framer.push(&wire[3..]);
+//! framer.push(&framer.encode(b"!\"ACKNO START")[..4]);
+//!
+//! let frame = framer.next_frame().unwrap();
+//! assert_eq!(frame, b"!!START".to_vec());
+//! assert!(Command::parse(&frame).is_ok());
+//! assert_eq!(framer.next_frame(), None);
+//! ```
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use crate::types::Command;
+
+/// Default limit for the length of a decoded frame.
+///
+/// This is also the largest packet a [`LengthPrefixFramer`] can carry.
+pub const DEFAULT_MAX_FRAME_LEN: usize = u16::MAX as usize;
+
+/// Turns packets into bytes for the wire, and a byte stream back into packets.
+pub trait Framer {
+    /// Encodes one packet (usually the output of [`Command::to_bytes()`](crate::types::Command::to_bytes))
+    /// for the wire.
+    fn encode(&self, packet: &[u8]) -> Vec<u8>;
+
+    /// Feeds a chunk of received bytes. The chunk may contain any part of any number of frames.
+    fn push(&mut self, bytes: &[u8]);
+
+    /// Takes the next complete frame out of the bytes pushed so far, if there is one.
+    ///
+    /// Corrupted frames are dropped silently. Call this repeatedly until it returns `None`,
+    /// as a single [`push()`](Framer::push) may complete several frames.
+    fn next_frame(&mut self) -> Option<Vec<u8>>;
+
+    /// Discards all buffered bytes.
+    fn reset(&mut self);
+}
+
+/// Takes the next delimited frame out of `buffer`, dropping overlong ones.
+///
+/// `discarding` tracks whether the bytes before the next delimiter belong to a frame that was
+/// already found to be overlong.
+fn take_delimited(
+    buffer: &mut Vec<u8>,
+    discarding: &mut bool,
+    delimiter: u8,
+    max_len: usize,
+) -> Option<Vec<u8>> {
+    loop {
+        let Some(pos) = buffer.iter().position(|&b| b == delimiter) else {
+            if buffer.len() > max_len {
+                // 帧太长了，丢掉已经收到的部分，直到下一个分隔符为止
+                buffer.clear();
+                *discarding = true;
+            }
+            return None;
+        };
+        let mut frame: Vec<u8> = buffer.drain(..=pos).collect();
+        frame.pop(); // the delimiter
+        if core::mem::take(discarding) || frame.is_empty() || frame.len() > max_len {
+            continue;
+        }
+        return Some(frame);
+    }
+}
+
+/// [COBS](https://en.wikipedia.org/wiki/Consistent_Overhead_Byte_Stuffing) framing:
+/// each packet is encoded without zero bytes and terminated by `0x00`.
+///
+/// This is the recommended framing for UART links: the overhead is small and constant,
+/// and a receiver resynchronizes at the very next `0x00`.
+pub struct CobsFramer {
+    max_frame_len: usize,
+    buffer: Vec<u8>,
+    discarding: bool,
+}
+
+impl CobsFramer {
+    /// Creates a COBS framer that drops frames whose decoded length exceeds `max_frame_len`.
+    pub fn new(max_frame_len: usize) -> Self {
+        CobsFramer {
+            max_frame_len,
+            buffer: Vec::new(),
+            discarding: false,
+        }
+    }
+
+    fn decode(encoded: &[u8]) -> Option<Vec<u8>> {
+        let mut decoded = Vec::with_capacity(encoded.len());
+        let mut i = 0;
+        while i < encoded.len() {
+            let code = encoded[i] as usize;
+            let end = i + code;
+            if code == 0 || end > encoded.len() {
+                return None;
+            }
+            decoded.extend_from_slice(&encoded[i + 1..end]);
+            i = end;
+            if code != 0xFF && i < encoded.len() {
+                decoded.push(0);
+            }
+        }
+        Some(decoded)
+    }
+}
+
+impl Default for CobsFramer {
+    /// Creates a COBS framer with a limit of [`DEFAULT_MAX_FRAME_LEN`].
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_LEN)
+    }
+}
+
+impl Framer for CobsFramer {
+    fn encode(&self, packet: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(packet.len() + packet.len() / 254 + 2);
+        let mut code_index = 0;
+        let mut code = 1u8;
+        encoded.push(0); // placeholder for the first code
+        for &byte in packet {
+            if byte != 0 {
+                encoded.push(byte);
+                code += 1;
+            }
+            if byte == 0 || code == 0xFF {
+                encoded[code_index] = code;
+                code_index = encoded.len();
+                encoded.push(0);
+                code = 1;
+            }
+        }
+        encoded[code_index] = code;
+        encoded.push(0x00);
+        encoded
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        // COBS 每 254 字节最多多出 1 字节
+        let max_encoded_len = self.max_frame_len + self.max_frame_len / 254 + 1;
+        loop {
+            let encoded = take_delimited(
+                &mut self.buffer,
+                &mut self.discarding,
+                0x00,
+                max_encoded_len,
+            )?;
+            match Self::decode(&encoded) {
+                Some(frame) if !frame.is_empty() && frame.len() <= self.max_frame_len => {
+                    return Some(frame);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.discarding = false;
+    }
+}
+
+/// [SLIP](https://datatracker.ietf.org/doc/html/rfc1055) framing: each packet is surrounded by
+/// `END` (`0xC0`) bytes, with `END` and `ESC` (`0xDB`) inside the packet escaped.
+///
+/// The leading `END` flushes any line noise the receiver has accumulated before the packet.
+pub struct SlipFramer {
+    max_frame_len: usize,
+    buffer: Vec<u8>,
+    discarding: bool,
+}
+
+impl SlipFramer {
+    const END: u8 = 0xC0;
+    const ESC: u8 = 0xDB;
+    const ESC_END: u8 = 0xDC;
+    const ESC_ESC: u8 = 0xDD;
+
+    /// Creates a SLIP framer that drops frames whose decoded length exceeds `max_frame_len`.
+    pub fn new(max_frame_len: usize) -> Self {
+        SlipFramer {
+            max_frame_len,
+            buffer: Vec::new(),
+            discarding: false,
+        }
+    }
+
+    fn decode(encoded: &[u8]) -> Option<Vec<u8>> {
+        let mut decoded = Vec::with_capacity(encoded.len());
+        let mut bytes = encoded.iter();
+        while let Some(&byte) = bytes.next() {
+            if byte == Self::ESC {
+                match bytes.next() {
+                    Some(&Self::ESC_END) => decoded.push(Self::END),
+                    Some(&Self::ESC_ESC) => decoded.push(Self::ESC),
+                    _ => return None,
+                }
+            } else {
+                decoded.push(byte);
+            }
+        }
+        Some(decoded)
+    }
+}
+
+impl Default for SlipFramer {
+    /// Creates a SLIP framer with a limit of [`DEFAULT_MAX_FRAME_LEN`].
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_LEN)
+    }
+}
+
+impl Framer for SlipFramer {
+    fn encode(&self, packet: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(packet.len() + 2);
+        encoded.push(Self::END);
+        for &byte in packet {
+            match byte {
+                Self::END => encoded.extend_from_slice(&[Self::ESC, Self::ESC_END]),
+                Self::ESC => encoded.extend_from_slice(&[Self::ESC, Self::ESC_ESC]),
+                _ => encoded.push(byte),
+            }
+        }
+        encoded.push(Self::END);
+        encoded
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        // 转义后最长是原来的两倍
+        let max_encoded_len = self.max_frame_len.saturating_mul(2);
+        loop {
+            let encoded = take_delimited(
+                &mut self.buffer,
+                &mut self.discarding,
+                Self::END,
+                max_encoded_len,
+            )?;
+            match Self::decode(&encoded) {
+                Some(frame) if frame.len() <= self.max_frame_len => return Some(frame),
+                _ => continue,
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.discarding = false;
+    }
+}
+
+/// Length-prefixed framing: each packet is preceded by its length as a 2-byte big-endian integer.
+///
+/// There is no delimiter to resynchronize on, so the receiver validates every candidate frame: the
+/// length must be within bounds, and the payload must pass a validator. If it does not, one byte is
+/// skipped and the search starts over.
+///
+/// By default the validator is [`is_command()`], so this framer only finds PK Command packets. Use
+/// [`with_validator()`](LengthPrefixFramer::with_validator) to carry other payloads.
+pub struct LengthPrefixFramer {
+    max_frame_len: usize,
+    buffer: Vec<u8>,
+    validator: fn(&[u8]) -> bool,
+}
+
+impl LengthPrefixFramer {
+    /// Creates a length-prefix framer that drops frames longer than `max_frame_len`.
+    ///
+    /// The limit is capped at [`DEFAULT_MAX_FRAME_LEN`], the largest length the prefix can express.
+    pub fn new(max_frame_len: usize) -> Self {
+        LengthPrefixFramer {
+            max_frame_len: max_frame_len.min(DEFAULT_MAX_FRAME_LEN),
+            buffer: Vec::new(),
+            validator: is_command,
+        }
+    }
+
+    /// Sets how candidate frames are told apart from garbage while resynchronizing.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::types::framing::{Framer, LengthPrefixFramer};
+    ///
+    /// let mut framer = LengthPrefixFramer::new(64).with_validator(|frame| frame.is_ascii());
+    /// let wire = framer.encode(b"hello");
+    /// framer.push(&wire);
+    /// assert_eq!(framer.next_frame(), Some(b"hello".to_vec()));
+    /// ```
+    pub fn with_validator(mut self, validator: fn(&[u8]) -> bool) -> Self {
+        self.validator = validator;
+        self
+    }
+
+    /// Looks for the next frame, and returns it along with how many bytes of the buffer to drop.
+    fn find_frame(&self) -> (usize, Option<Vec<u8>>) {
+        let mut start = 0;
+        loop {
+            let rest = &self.buffer[start..];
+            if rest.len() < 2 {
+                return (start, None);
+            }
+            let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+            if !(1..=self.max_frame_len).contains(&len) {
+                start += 1;
+                continue;
+            }
+            if rest.len() < 2 + len {
+                return (start, None);
+            }
+            let frame = &rest[2..2 + len];
+            if !(self.validator)(frame) {
+                // 不是一个合法的帧，说明长度前缀是垃圾数据，跳过一个字节重新找
+                start += 1;
+                continue;
+            }
+            return (start + 2 + len, Some(frame.to_vec()));
+        }
+    }
+}
+
+/// The default validator of [`LengthPrefixFramer`]: whether `frame` parses as a [`Command`].
+pub fn is_command(frame: &[u8]) -> bool {
+    Command::parse(frame).is_ok()
+}
+
+impl Default for LengthPrefixFramer {
+    /// Creates a length-prefix framer with a limit of [`DEFAULT_MAX_FRAME_LEN`].
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_LEN)
+    }
+}
+
+impl Framer for LengthPrefixFramer {
+    /// Encodes one packet for the wire.
+    ///
+    /// # Panics
+    /// If the packet is longer than [`DEFAULT_MAX_FRAME_LEN`].
+    fn encode(&self, packet: &[u8]) -> Vec<u8> {
+        let len =
+            u16::try_from(packet.len()).expect("packet is too long for a 2-byte length prefix");
+        let mut encoded = Vec::with_capacity(packet.len() + 2);
+        encoded.extend_from_slice(&len.to_be_bytes());
+        encoded.extend_from_slice(packet);
+        encoded
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let (consumed, frame) = self.find_frame();
+        // 跳过的垃圾和取出的帧一次性丢掉
+        self.buffer.drain(..consumed);
+        frame
+    }
+
+    fn reset(&mut self) {
+        self.buffer.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all<F: Framer>(framer: &mut F, wire: &[u8], chunk_size: usize) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        for chunk in wire.chunks(chunk_size) {
+            framer.push(chunk);
+            while let Some(frame) = framer.next_frame() {
+                frames.push(frame);
+            }
+        }
+        frames
+    }
+
+    fn roundtrip<F: Framer>(mut framer: F) {
+        let packets: [&[u8]; 4] = [
+            b"!!START",
+            b"!\"SDATA VARIA \x00\xC0\xDB\xDC\xDD\x00\x00",
+            b"!#ENDTR",
+            b"  ERROR ERROR something went wrong",
+        ];
+        let mut wire = Vec::new();
+        for packet in packets {
+            wire.extend(framer.encode(packet));
+        }
+        for chunk_size in [1, 2, 3, 7, 64, wire.len()] {
+            assert_eq!(
+                decode_all(&mut framer, &wire, chunk_size),
+                packets.map(|p| p.to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_cobs_roundtrip() {
+        roundtrip(CobsFramer::default());
+    }
+
+    #[test]
+    fn test_slip_roundtrip() {
+        roundtrip(SlipFramer::default());
+    }
+
+    #[test]
+    fn test_length_prefix_roundtrip() {
+        roundtrip(LengthPrefixFramer::default());
+    }
+
+    #[test]
+    fn test_cobs_encoding() {
+        let framer = CobsFramer::default();
+        assert_eq!(
+            framer.encode(&[0x11, 0x00, 0x22]),
+            vec![0x02, 0x11, 0x02, 0x22, 0x00]
+        );
+        assert_eq!(framer.encode(&[0x00]), vec![0x01, 0x01, 0x00]);
+
+        // A run of 254 non-zero bytes fills a whole block.
+        let long: Vec<u8> = (1..=254).collect();
+        let encoded = framer.encode(&long);
+        assert_eq!(encoded.len(), 254 + 3);
+        assert_eq!(encoded[0], 0xFF);
+        assert!(!encoded[..encoded.len() - 1].contains(&0));
+        assert_eq!(
+            CobsFramer::decode(&encoded[..encoded.len() - 1]),
+            Some(long)
+        );
+    }
+
+    #[test]
+    fn test_slip_encoding() {
+        let framer = SlipFramer::default();
+        assert_eq!(
+            framer.encode(&[0x01, 0xC0, 0xDB]),
+            vec![0xC0, 0x01, 0xDB, 0xDC, 0xDB, 0xDD, 0xC0]
+        );
+    }
+
+    #[test]
+    fn test_cobs_resync() {
+        let mut framer = CobsFramer::default();
+        let mut wire = b"\x13\x37garbage".to_vec(); // the tail of a frame we missed
+        wire.push(0x00);
+        wire.extend(framer.encode(b"!!START"));
+        wire.extend([0x05, 0x01, 0x00]); // a corrupted frame
+        wire.extend(framer.encode(b"!\"ENDTR"));
+        assert_eq!(
+            decode_all(&mut framer, &wire, 5),
+            vec![b"!!START".to_vec(), b"!\"ENDTR".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_slip_resync() {
+        let mut framer = SlipFramer::default();
+        let mut wire = b"noise".to_vec();
+        wire.extend(framer.encode(b"!!START"));
+        wire.extend([0xC0, b'x', 0xDB, b'y', 0xC0]); // an invalid escape
+        wire.extend(framer.encode(b"!\"ENDTR"));
+        assert_eq!(
+            decode_all(&mut framer, &wire, 4),
+            vec![b"noise".to_vec(), b"!!START".to_vec(), b"!\"ENDTR".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_length_prefix_resync() {
+        let mut framer = LengthPrefixFramer::new(64);
+        let mut wire = vec![0xFF, 0x00, 0x03, 0x00, 0x08];
+        wire.extend(b"garbage!");
+        wire.extend(framer.encode(b"!!START"));
+        wire.extend([0x00]);
+        wire.extend(framer.encode(b"!\"ENDTR"));
+        assert_eq!(
+            decode_all(&mut framer, &wire, 3),
+            vec![b"!!START".to_vec(), b"!\"ENDTR".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_length_prefix_skips_noise_in_bulk() {
+        let mut framer = LengthPrefixFramer::new(64);
+        // Noise whose length prefixes are always out of bounds.
+        let mut wire = vec![0xFF; 100_000];
+        wire.extend(framer.encode(b"!!START"));
+        assert_eq!(
+            decode_all(&mut framer, &wire, 4096),
+            vec![b"!!START".to_vec()]
+        );
+        assert!(framer.buffer.is_empty());
+    }
+
+    #[test]
+    fn test_length_prefix_validator() {
+        // Anything goes: the frame does not have to be a command.
+        let mut framer = LengthPrefixFramer::new(64).with_validator(|_| true);
+        let wire = framer.encode(b"hi");
+        assert_eq!(decode_all(&mut framer, &wire, 1), vec![b"hi".to_vec()]);
+
+        // The default one only accepts commands.
+        let mut framer = LengthPrefixFramer::new(64);
+        let mut wire = framer.encode(b"hi");
+        wire.extend(framer.encode(b"!!START"));
+        assert_eq!(decode_all(&mut framer, &wire, 1), vec![b"!!START".to_vec()]);
+    }
+
+    #[test]
+    fn test_overlong_frames_are_dropped() {
+        let mut framer = CobsFramer::new(16);
+        let mut wire = vec![0x42; 100]; // no delimiter for a long time
+        wire.push(0x00);
+        wire.extend(framer.encode(b"!!START"));
+        assert_eq!(
+            decode_all(&mut framer, &wire, 10),
+            vec![b"!!START".to_vec()]
+        );
+        assert!(framer.buffer.is_empty());
+
+        let mut framer = SlipFramer::new(16);
+        let mut wire = vec![0x42; 100];
+        wire.extend(framer.encode(b"!!START"));
+        assert_eq!(
+            decode_all(&mut framer, &wire, 10),
+            vec![b"!!START".to_vec()]
+        );
+
+        let mut framer = LengthPrefixFramer::new(16);
+        let mut wire = framer.encode(b"!!SDATA VARIA this is too long");
+        wire.extend(framer.encode(b"!!START"));
+        assert_eq!(
+            decode_all(&mut framer, &wire, 10),
+            vec![b"!!START".to_vec()]
+        );
+    }
+}
@@ -1,8 +1,13 @@
 #[cfg(feature = "std")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
 use std::sync::{Arc, RwLock};
 #[cfg(feature = "std")]
 use std::{cell::RefCell, pin::Pin};
 
+#[cfg(feature = "std")]
+use crate::__private::Resolved;
+
 pub mod msg_id {
     //! Module for handling PK Command Message IDs.
     //!
@@ -36,6 +41,8 @@ pub mod msg_id {
     #[cfg(not(feature = "std"))]
     use alloc::{format, string::String};
 
+    use crate::types::PkError;
+
     const BASE: u16 = 94;
     const OFFSET: u8 = b'!';
     const MAX_ID: u16 = BASE * BASE - 1;
@@ -56,14 +63,9 @@ pub mod msg_id {
     ///
     /// assert!(msg_id::to_u16("!").is_err()); // invalid length
     /// ```
-    pub fn to_u16(id_str: &str) -> Result<u16, &'static str> {
+    pub fn to_u16(id_str: &str) -> Result<u16, PkError> {
         if id_str.len() != 2 {
-            // This is an internal utility, so a simple error message is fine.
-            // For a public API, more descriptive errors might be preferred.
-            // However, given its use within the PK Command protocol, this is likely sufficient.
-            // The primary validation for msg_id format happens during command parsing.
-            // This function assumes the input string *should* be a valid 2-char ID.
-            return Err("Input string must be exactly 2 characters long.");
+            return Err(PkError::InvalidMsgId);
         }
 
         let bytes = id_str.as_bytes();
@@ -71,7 +73,7 @@ pub mod msg_id {
         let c2 = bytes[1];
 
         if !((b'!'..=b'~').contains(&c1) && (b'!'..=b'~').contains(&c2)) {
-            return Err("Input string contains invalid characters.");
+            return Err(PkError::InvalidMsgId);
         }
 
         let val1 = (c1 - OFFSET) as u16;
@@ -90,7 +92,7 @@ pub mod msg_id {
     /// * `id`: The u16 integer ID to convert (0-8835).
     ///
     /// # Returns
-    /// A `Result` containing the 2-character string ID, or an error message if the ID is out of range.
+    /// A `Result` containing the 2-character string ID, or [`PkError::InvalidMsgId`] if the ID is out of range.
     ///
     /// # Examples
     /// ```
@@ -102,9 +104,9 @@ pub mod msg_id {
     ///
     /// assert!(msg_id::from_u16(8836).is_err()); // out of range
     /// ```
-    pub fn from_u16(id: u16) -> Result<String, &'static str> {
+    pub fn from_u16(id: u16) -> Result<String, PkError> {
         if id > MAX_ID {
-            return Err("Input number is out of the valid range (0-8835).");
+            return Err(PkError::InvalidMsgId);
         }
 
         let val1 = id / BASE;
@@ -140,6 +142,19 @@ pub mod msg_id {
         (id + 1) % (MAX_ID + 1)
     }
 
+    /// Counts how many increments lead from `from` to `to`, handling rollover.
+    ///
+    /// # Examples
+    /// ```
+    /// use pk_command::msg_id;
+    /// assert_eq!(msg_id::distance(100, 103), 3);
+    /// assert_eq!(msg_id::distance(8835, 1), 2); // rollover
+    /// assert_eq!(msg_id::distance(1, 0), 8835);
+    /// ```
+    pub fn distance(from: u16, to: u16) -> u16 {
+        (to + (MAX_ID + 1) - from) % (MAX_ID + 1)
+    }
+
     #[cfg(test)]
     mod tests {
         use super::*;
@@ -184,6 +199,131 @@ pub mod msg_id {
             assert_eq!(increment(MAX_ID), 0); // Rollover
             assert_eq!(increment(100), 101);
         }
+
+        #[test]
+        fn test_msg_id_distance() {
+            assert_eq!(distance(5, 5), 0);
+            assert_eq!(distance(5, 6), 1);
+            assert_eq!(distance(MAX_ID, 0), 1); // Rollover
+            assert_eq!(distance(6, 5), MAX_ID);
+        }
+    }
+}
+
+pub(crate) mod rtt {
+    //! Estimation of the retransmission timeout from measured round-trip times, the way TCP does it
+    //! (RFC 6298).
+
+    use core::time::Duration;
+
+    /// Clock granularity. The variance term of the timeout never goes below this.
+    const GRANULARITY: Duration = Duration::from_millis(1);
+
+    /// Smoothed round-trip time and its variance, and the retransmission timeout derived from them.
+    #[derive(Clone, Copy, Debug)]
+    pub(crate) struct RttEstimator {
+        srtt: Option<Duration>,
+        rttvar: Duration,
+        rto: Duration,
+        min: Duration,
+        max: Duration,
+    }
+
+    impl RttEstimator {
+        /// Starts with `initial` as the timeout, until the first sample. The timeout always stays
+        /// between `min` and `max`.
+        pub(crate) fn new(initial: Duration, min: Duration, max: Duration) -> Self {
+            RttEstimator {
+                srtt: None,
+                rttvar: Duration::ZERO,
+                rto: initial.clamp(min, max),
+                min,
+                max,
+            }
+        }
+
+        /// Takes a measured round-trip time into account.
+        ///
+        /// Only commands that were not retransmitted may be measured: the `ACKNO` of a retransmitted
+        /// one may be for any of its copies (Karn's algorithm).
+        pub(crate) fn sample(&mut self, rtt: Duration) {
+            match self.srtt {
+                None => {
+                    self.srtt = Some(rtt);
+                    self.rttvar = rtt / 2;
+                }
+                Some(srtt) => {
+                    let delta = srtt.abs_diff(rtt);
+                    self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                    self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+                }
+            }
+            let srtt = self.srtt.unwrap_or(rtt);
+            self.rto = (srtt + (self.rttvar * 4).max(GRANULARITY)).clamp(self.min, self.max);
+        }
+
+        /// Doubles the timeout, after it ran out.
+        pub(crate) fn back_off(&mut self) {
+            self.rto = (self.rto * 2).min(self.max);
+        }
+
+        /// Returns the current retransmission timeout.
+        pub(crate) fn rto(&self) -> Duration {
+            self.rto
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn ms(millis: u64) -> Duration {
+            Duration::from_millis(millis)
+        }
+
+        #[test]
+        fn test_rtt_first_sample() {
+            let mut rtt = RttEstimator::new(ms(100), ms(10), ms(1000));
+            assert_eq!(rtt.rto(), ms(100));
+            rtt.sample(ms(20));
+            // SRTT = 20ms, RTTVAR = 10ms.
+            assert_eq!(rtt.rto(), ms(60));
+        }
+
+        #[test]
+        fn test_rtt_converges() {
+            let mut rtt = RttEstimator::new(ms(100), ms(1), ms(1000));
+            for _ in 0..100 {
+                rtt.sample(ms(8));
+            }
+            // The variance vanishes, down to the clock granularity.
+            assert!(rtt.rto() >= ms(9) && rtt.rto() <= ms(10), "{:?}", rtt.rto());
+            // A slower link raises it again.
+            rtt.sample(ms(80));
+            assert!(rtt.rto() > ms(80), "{:?}", rtt.rto());
+        }
+
+        #[test]
+        fn test_rtt_bounds() {
+            let mut rtt = RttEstimator::new(ms(100), ms(20), ms(50));
+            assert_eq!(rtt.rto(), ms(50));
+            rtt.sample(ms(1));
+            assert_eq!(rtt.rto(), ms(20));
+            rtt.sample(ms(500));
+            assert_eq!(rtt.rto(), ms(50));
+        }
+
+        #[test]
+        fn test_rtt_back_off() {
+            let mut rtt = RttEstimator::new(ms(15), ms(10), ms(50));
+            rtt.back_off();
+            assert_eq!(rtt.rto(), ms(30));
+            rtt.back_off();
+            assert_eq!(rtt.rto(), ms(50));
+            // The next measurement starts over from the estimate.
+            rtt.sample(ms(5));
+            assert_eq!(rtt.rto(), ms(15));
+        }
     }
 }
 
@@ -231,7 +371,7 @@ pub mod async_adapters {
         /// A `Pollable` adapter that spawns a `Future` onto the Tokio runtime and
         /// exposes its completion through the `Pollable` interface.
         ///
-        ///
+        /// If the transaction is [cancelled](crate::Pollable::cancel), the task is aborted.
         ///
         /// # Example with [`PkHashmapMethod`](crate::PkHashmapMethod)
         /// ```
@@ -246,36 +386,255 @@ pub mod async_adapters {
         /// ```
         #[allow(clippy::type_complexity)]
         pub struct TokioFuturePollable {
-            state: Arc<RwLock<Option<Result<Option<Vec<u8>>, String>>>>,
+            state: Arc<RwLock<Option<Result<Option<Vec<u8>>, crate::types::PkError>>>>,
+            task: tokio::task::JoinHandle<()>,
         }
 
         impl TokioFuturePollable {
             /// Spawn a future onto the Tokio runtime and return a `Pollable` that
             /// becomes ready when the future completes.
             ///
-            /// The provided `Future` must output `Result<Option<Vec<u8>>, String>`.
+            /// The provided `Future` must output `Result<Option<Vec<u8>>, PkError>`.
             pub fn from_future<F>(fut: F) -> Pin<Box<dyn crate::Pollable>>
             where
-                F: Future<Output = Result<Option<Vec<u8>>, String>> + Send + 'static,
+                F: Future<Output = Result<Option<Vec<u8>>, crate::types::PkError>> + Send + 'static,
             {
                 let state = Arc::new(RwLock::new(None));
                 let state_cloned = state.clone();
-                tokio::spawn(async move {
+                let task = tokio::spawn(async move {
                     let res = fut.await;
                     *state_cloned.write().unwrap() = Some(res);
                 });
-                Box::pin(TokioFuturePollable { state })
+                Box::pin(TokioFuturePollable { state, task })
             }
         }
 
         #[cfg(all(feature = "std", feature = "tokio-runtime"))] // for documentation
         impl crate::Pollable for TokioFuturePollable {
-            fn poll(&self) -> std::task::Poll<Result<Option<Vec<u8>>, String>> {
+            fn poll(&self) -> std::task::Poll<Result<Option<Vec<u8>>, crate::types::PkError>> {
                 match self.state.read().unwrap().as_ref() {
                     Some(r) => std::task::Poll::Ready(r.clone()),
                     None => std::task::Poll::Pending,
                 }
             }
+
+            fn cancel(&self) {
+                self.task.abort();
+            }
+        }
+
+        /// [`PkRuntime`](crate::client::PkRuntime) backed by Tokio's timers.
+        pub struct TokioRuntime;
+
+        impl crate::client::PkRuntime for TokioRuntime {
+            async fn timeout<F: Future>(
+                duration: std::time::Duration,
+                future: F,
+            ) -> Option<F::Output> {
+                tokio::time::timeout(duration, future).await.ok()
+            }
+        }
+
+        /// An async host client running on Tokio.
+        ///
+        /// See [`client::PkClient`](crate::client::PkClient) for details.
+        pub type PkClient<T, VA = crate::PkHashmapVariable, MA = crate::PkHashmapMethod> =
+            crate::client::PkClient<T, VA, MA, TokioRuntime>;
+
+        /// An in-memory [`PkAsyncTransport`](crate::transport::PkAsyncTransport) for Tokio, created in connected pairs.
+        ///
+        /// When one half is dropped, the other one reports [`PkError::Transport`](crate::types::PkError::Transport).
+        pub struct MemoryTransport {
+            tx: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+            rx: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+        }
+
+        impl MemoryTransport {
+            /// Creates two transports connected to each other.
+            pub fn pair() -> (Self, Self) {
+                let (a_tx, b_rx) = tokio::sync::mpsc::unbounded_channel();
+                let (b_tx, a_rx) = tokio::sync::mpsc::unbounded_channel();
+                (
+                    MemoryTransport { tx: a_tx, rx: a_rx },
+                    MemoryTransport { tx: b_tx, rx: b_rx },
+                )
+            }
+        }
+
+        impl crate::transport::PkAsyncTransport for MemoryTransport {
+            async fn send(&mut self, packet: Vec<u8>) -> Result<(), crate::types::PkError> {
+                self.tx.send(packet).map_err(|_| {
+                    crate::types::PkError::Transport(String::from("peer disconnected"))
+                })
+            }
+
+            async fn recv(&mut self) -> Result<Vec<u8>, crate::types::PkError> {
+                self.rx.recv().await.ok_or_else(|| {
+                    crate::types::PkError::Transport(String::from("peer disconnected"))
+                })
+            }
+        }
+
+        /// A [`PkAsyncTransport`](crate::transport::PkAsyncTransport) over a connected Tokio UDP socket.
+        /// Each datagram carries one packet.
+        pub struct UdpTransport {
+            socket: tokio::net::UdpSocket,
+            buffer: Vec<u8>,
+        }
+
+        impl UdpTransport {
+            /// Wraps a UDP socket that is already [connected](tokio::net::UdpSocket::connect) to the peer.
+            pub fn new(socket: tokio::net::UdpSocket) -> Self {
+                UdpTransport {
+                    socket,
+                    buffer: vec![0; crate::transport::DATAGRAM_BUFFER_SIZE],
+                }
+            }
+
+            /// Returns the wrapped socket.
+            pub fn into_inner(self) -> tokio::net::UdpSocket {
+                self.socket
+            }
+        }
+
+        impl crate::transport::PkAsyncTransport for UdpTransport {
+            async fn send(&mut self, packet: Vec<u8>) -> Result<(), crate::types::PkError> {
+                self.socket
+                    .send(&packet)
+                    .await
+                    .map(|_| ())
+                    .map_err(crate::transport::io_error)
+            }
+
+            async fn recv(&mut self) -> Result<Vec<u8>, crate::types::PkError> {
+                let len = self
+                    .socket
+                    .recv(&mut self.buffer)
+                    .await
+                    .map_err(crate::transport::io_error)?;
+                Ok(self.buffer[..len].to_vec())
+            }
+        }
+
+        /// A [`PkAsyncTransport`](crate::transport::PkAsyncTransport) over a connected Tokio Unix datagram socket.
+        /// Each datagram carries one packet.
+        ///
+        /// **Note**: This is only available on Unix platforms.
+        #[cfg(unix)]
+        pub struct UnixDatagramTransport {
+            socket: tokio::net::UnixDatagram,
+            buffer: Vec<u8>,
+        }
+
+        #[cfg(unix)]
+        impl UnixDatagramTransport {
+            /// Wraps a Unix datagram socket that is already [connected](tokio::net::UnixDatagram::connect)
+            /// to the peer (or created by [`UnixDatagram::pair()`](tokio::net::UnixDatagram::pair)).
+            pub fn new(socket: tokio::net::UnixDatagram) -> Self {
+                UnixDatagramTransport {
+                    socket,
+                    buffer: vec![0; crate::transport::DATAGRAM_BUFFER_SIZE],
+                }
+            }
+
+            /// Returns the wrapped socket.
+            pub fn into_inner(self) -> tokio::net::UnixDatagram {
+                self.socket
+            }
+        }
+
+        #[cfg(unix)]
+        impl crate::transport::PkAsyncTransport for UnixDatagramTransport {
+            async fn send(&mut self, packet: Vec<u8>) -> Result<(), crate::types::PkError> {
+                self.socket
+                    .send(&packet)
+                    .await
+                    .map(|_| ())
+                    .map_err(crate::transport::io_error)
+            }
+
+            async fn recv(&mut self) -> Result<Vec<u8>, crate::types::PkError> {
+                let len = self
+                    .socket
+                    .recv(&mut self.buffer)
+                    .await
+                    .map_err(crate::transport::io_error)?;
+                Ok(self.buffer[..len].to_vec())
+            }
+        }
+
+        /// A [`PkAsyncTransport`](crate::transport::PkAsyncTransport) over an async byte stream,
+        /// such as a Tokio TCP connection or a serial port, framed by a [`Framer`](crate::types::framing::Framer).
+        ///
+        /// This is the async counterpart of [`transport::StreamTransport`](crate::transport::StreamTransport):
+        /// by default, packets are framed with a [`LengthPrefixFramer`](crate::types::framing::LengthPrefixFramer).
+        /// [`recv()`](crate::transport::PkAsyncTransport::recv) is cancel-safe, as bytes are only
+        /// taken out of the stream once they are pushed into the framer.
+        pub struct StreamTransport<S, F = crate::types::framing::LengthPrefixFramer> {
+            stream: S,
+            framer: F,
+        }
+
+        impl<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin> StreamTransport<S> {
+            /// Wraps a byte stream, framing packets with a [`LengthPrefixFramer`](crate::types::framing::LengthPrefixFramer).
+            pub fn new(stream: S) -> Self {
+                Self::with_framer(stream, crate::types::framing::LengthPrefixFramer::default())
+            }
+        }
+
+        impl<S, F> StreamTransport<S, F>
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+            F: crate::types::framing::Framer,
+        {
+            /// Wraps a byte stream, framing packets with the given framer.
+            pub fn with_framer(stream: S, framer: F) -> Self {
+                StreamTransport { stream, framer }
+            }
+
+            /// Returns the wrapped stream. Bytes that were received but not yet returned as a packet are lost.
+            pub fn into_inner(self) -> S {
+                self.stream
+            }
+        }
+
+        impl<S, F> crate::transport::PkAsyncTransport for StreamTransport<S, F>
+        where
+            S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+            F: crate::types::framing::Framer,
+        {
+            async fn send(&mut self, packet: Vec<u8>) -> Result<(), crate::types::PkError> {
+                use tokio::io::AsyncWriteExt;
+                self.stream
+                    .write_all(&self.framer.encode(&packet))
+                    .await
+                    .map_err(crate::transport::io_error)?;
+                self.stream
+                    .flush()
+                    .await
+                    .map_err(crate::transport::io_error)
+            }
+
+            async fn recv(&mut self) -> Result<Vec<u8>, crate::types::PkError> {
+                use tokio::io::AsyncReadExt;
+                loop {
+                    if let Some(packet) = self.framer.next_frame() {
+                        return Ok(packet);
+                    }
+                    let mut chunk = [0u8; 256];
+                    let len = self
+                        .stream
+                        .read(&mut chunk)
+                        .await
+                        .map_err(crate::transport::io_error)?;
+                    if len == 0 {
+                        return Err(crate::types::PkError::Transport(String::from(
+                            "stream closed",
+                        )));
+                    }
+                    self.framer.push(&chunk[..len]);
+                }
+            }
         }
     }
 
@@ -307,15 +666,17 @@ pub mod async_adapters {
         //! ```
         use std::future::Future;
         use std::pin::Pin;
-        use std::sync::{Arc, RwLock};
+        use std::sync::{Arc, Mutex, RwLock};
 
         /// A `Pollable` adapter that spawns a `Future` onto the smol executor and
         /// exposes its completion through the `Pollable` interface.
         ///
-        ///
+        /// If the transaction is [cancelled](crate::Pollable::cancel), the task is dropped, which
+        /// cancels it.
         #[allow(clippy::type_complexity)]
         pub struct SmolFuturePollable {
-            state: Arc<RwLock<Option<Result<Option<Vec<u8>>, String>>>>,
+            state: Arc<RwLock<Option<Result<Option<Vec<u8>>, crate::types::PkError>>>>,
+            task: Mutex<Option<smol::Task<()>>>,
         }
 
         impl SmolFuturePollable {
@@ -323,28 +684,252 @@ pub mod async_adapters {
             /// becomes ready when the future completes.
             pub fn from_future<F>(fut: F) -> Pin<Box<dyn crate::Pollable>>
             where
-                F: Future<Output = Result<Option<Vec<u8>>, String>> + Send + 'static,
+                F: Future<Output = Result<Option<Vec<u8>>, crate::types::PkError>> + Send + 'static,
             {
                 let state = Arc::new(RwLock::new(None));
                 let state_cloned = state.clone();
-                // smol::spawn returns a Task which can be detached; detach so it runs in background
-                smol::spawn(async move {
+                // 不 detach：持有 Task 才能在取消时把它丢掉
+                let task = smol::spawn(async move {
                     let res = fut.await;
                     *state_cloned.write().unwrap() = Some(res);
+                });
+                Box::pin(SmolFuturePollable {
+                    state,
+                    task: Mutex::new(Some(task)),
                 })
-                .detach();
-                Box::pin(SmolFuturePollable { state })
             }
         }
 
         #[cfg(all(feature = "std", feature = "smol-runtime"))]
         impl crate::Pollable for SmolFuturePollable {
-            fn poll(&self) -> std::task::Poll<Result<Option<Vec<u8>>, String>> {
+            fn poll(&self) -> std::task::Poll<Result<Option<Vec<u8>>, crate::types::PkError>> {
                 match self.state.read().unwrap().as_ref() {
                     Some(r) => std::task::Poll::Ready(r.clone()),
                     None => std::task::Poll::Pending,
                 }
             }
+
+            fn cancel(&self) {
+                self.task.lock().unwrap().take();
+            }
+        }
+
+        /// [`PkRuntime`](crate::client::PkRuntime) backed by smol's timers.
+        pub struct SmolRuntime;
+
+        impl crate::client::PkRuntime for SmolRuntime {
+            async fn timeout<F: Future>(
+                duration: std::time::Duration,
+                future: F,
+            ) -> Option<F::Output> {
+                smol::future::or(async { Some(future.await) }, async {
+                    smol::Timer::after(duration).await;
+                    None
+                })
+                .await
+            }
+        }
+
+        /// An async host client running on smol.
+        ///
+        /// See [`client::PkClient`](crate::client::PkClient) for details.
+        pub type PkClient<T, VA = crate::PkHashmapVariable, MA = crate::PkHashmapMethod> =
+            crate::client::PkClient<T, VA, MA, SmolRuntime>;
+
+        /// An in-memory [`PkAsyncTransport`](crate::transport::PkAsyncTransport) for smol, created in connected pairs.
+        ///
+        /// When one half is dropped, the other one reports [`PkError::Transport`](crate::types::PkError::Transport).
+        pub struct MemoryTransport {
+            tx: async_channel::Sender<Vec<u8>>,
+            rx: async_channel::Receiver<Vec<u8>>,
+        }
+
+        impl MemoryTransport {
+            /// Creates two transports connected to each other.
+            pub fn pair() -> (Self, Self) {
+                let (a_tx, b_rx) = async_channel::unbounded();
+                let (b_tx, a_rx) = async_channel::unbounded();
+                (
+                    MemoryTransport { tx: a_tx, rx: a_rx },
+                    MemoryTransport { tx: b_tx, rx: b_rx },
+                )
+            }
+        }
+
+        impl crate::transport::PkAsyncTransport for MemoryTransport {
+            async fn send(&mut self, packet: Vec<u8>) -> Result<(), crate::types::PkError> {
+                self.tx.send(packet).await.map_err(|_| {
+                    crate::types::PkError::Transport(String::from("peer disconnected"))
+                })
+            }
+
+            async fn recv(&mut self) -> Result<Vec<u8>, crate::types::PkError> {
+                self.rx.recv().await.map_err(|_| {
+                    crate::types::PkError::Transport(String::from("peer disconnected"))
+                })
+            }
+        }
+
+        /// A [`PkAsyncTransport`](crate::transport::PkAsyncTransport) over a connected smol UDP socket.
+        /// Each datagram carries one packet.
+        pub struct UdpTransport {
+            socket: smol::net::UdpSocket,
+            buffer: Vec<u8>,
+        }
+
+        impl UdpTransport {
+            /// Wraps a UDP socket that is already [connected](smol::net::UdpSocket::connect) to the peer.
+            pub fn new(socket: smol::net::UdpSocket) -> Self {
+                UdpTransport {
+                    socket,
+                    buffer: vec![0; crate::transport::DATAGRAM_BUFFER_SIZE],
+                }
+            }
+
+            /// Returns the wrapped socket.
+            pub fn into_inner(self) -> smol::net::UdpSocket {
+                self.socket
+            }
+        }
+
+        impl crate::transport::PkAsyncTransport for UdpTransport {
+            async fn send(&mut self, packet: Vec<u8>) -> Result<(), crate::types::PkError> {
+                self.socket
+                    .send(&packet)
+                    .await
+                    .map(|_| ())
+                    .map_err(crate::transport::io_error)
+            }
+
+            async fn recv(&mut self) -> Result<Vec<u8>, crate::types::PkError> {
+                let len = self
+                    .socket
+                    .recv(&mut self.buffer)
+                    .await
+                    .map_err(crate::transport::io_error)?;
+                Ok(self.buffer[..len].to_vec())
+            }
+        }
+
+        /// A [`PkAsyncTransport`](crate::transport::PkAsyncTransport) over a connected smol Unix datagram socket.
+        /// Each datagram carries one packet.
+        ///
+        /// **Note**: This is only available on Unix platforms.
+        #[cfg(unix)]
+        pub struct UnixDatagramTransport {
+            socket: smol::net::unix::UnixDatagram,
+            buffer: Vec<u8>,
+        }
+
+        #[cfg(unix)]
+        impl UnixDatagramTransport {
+            /// Wraps a Unix datagram socket that is already [connected](smol::net::unix::UnixDatagram::connect)
+            /// to the peer (or created by [`UnixDatagram::pair()`](smol::net::unix::UnixDatagram::pair)).
+            pub fn new(socket: smol::net::unix::UnixDatagram) -> Self {
+                UnixDatagramTransport {
+                    socket,
+                    buffer: vec![0; crate::transport::DATAGRAM_BUFFER_SIZE],
+                }
+            }
+
+            /// Returns the wrapped socket.
+            pub fn into_inner(self) -> smol::net::unix::UnixDatagram {
+                self.socket
+            }
+        }
+
+        #[cfg(unix)]
+        impl crate::transport::PkAsyncTransport for UnixDatagramTransport {
+            async fn send(&mut self, packet: Vec<u8>) -> Result<(), crate::types::PkError> {
+                self.socket
+                    .send(&packet)
+                    .await
+                    .map(|_| ())
+                    .map_err(crate::transport::io_error)
+            }
+
+            async fn recv(&mut self) -> Result<Vec<u8>, crate::types::PkError> {
+                let len = self
+                    .socket
+                    .recv(&mut self.buffer)
+                    .await
+                    .map_err(crate::transport::io_error)?;
+                Ok(self.buffer[..len].to_vec())
+            }
+        }
+
+        /// A [`PkAsyncTransport`](crate::transport::PkAsyncTransport) over an async byte stream,
+        /// such as a smol TCP connection or a serial port, framed by a [`Framer`](crate::types::framing::Framer).
+        ///
+        /// This is the async counterpart of [`transport::StreamTransport`](crate::transport::StreamTransport):
+        /// by default, packets are framed with a [`LengthPrefixFramer`](crate::types::framing::LengthPrefixFramer).
+        /// [`recv()`](crate::transport::PkAsyncTransport::recv) is cancel-safe, as bytes are only
+        /// taken out of the stream once they are pushed into the framer.
+        pub struct StreamTransport<S, F = crate::types::framing::LengthPrefixFramer> {
+            stream: S,
+            framer: F,
+        }
+
+        impl<S: smol::io::AsyncRead + smol::io::AsyncWrite + Unpin> StreamTransport<S> {
+            /// Wraps a byte stream, framing packets with a [`LengthPrefixFramer`](crate::types::framing::LengthPrefixFramer).
+            pub fn new(stream: S) -> Self {
+                Self::with_framer(stream, crate::types::framing::LengthPrefixFramer::default())
+            }
+        }
+
+        impl<S, F> StreamTransport<S, F>
+        where
+            S: smol::io::AsyncRead + smol::io::AsyncWrite + Unpin,
+            F: crate::types::framing::Framer,
+        {
+            /// Wraps a byte stream, framing packets with the given framer.
+            pub fn with_framer(stream: S, framer: F) -> Self {
+                StreamTransport { stream, framer }
+            }
+
+            /// Returns the wrapped stream. Bytes that were received but not yet returned as a packet are lost.
+            pub fn into_inner(self) -> S {
+                self.stream
+            }
+        }
+
+        impl<S, F> crate::transport::PkAsyncTransport for StreamTransport<S, F>
+        where
+            S: smol::io::AsyncRead + smol::io::AsyncWrite + Unpin,
+            F: crate::types::framing::Framer,
+        {
+            async fn send(&mut self, packet: Vec<u8>) -> Result<(), crate::types::PkError> {
+                use smol::io::AsyncWriteExt;
+                self.stream
+                    .write_all(&self.framer.encode(&packet))
+                    .await
+                    .map_err(crate::transport::io_error)?;
+                self.stream
+                    .flush()
+                    .await
+                    .map_err(crate::transport::io_error)
+            }
+
+            async fn recv(&mut self) -> Result<Vec<u8>, crate::types::PkError> {
+                use smol::io::AsyncReadExt;
+                loop {
+                    if let Some(packet) = self.framer.next_frame() {
+                        return Ok(packet);
+                    }
+                    let mut chunk = [0u8; 256];
+                    let len = self
+                        .stream
+                        .read(&mut chunk)
+                        .await
+                        .map_err(crate::transport::io_error)?;
+                    if len == 0 {
+                        return Err(crate::types::PkError::Transport(String::from(
+                            "stream closed",
+                        )));
+                    }
+                    self.framer.push(&chunk[..len]);
+                }
+            }
         }
     }
     #[cfg(feature = "embassy-runtime")]
@@ -359,7 +944,6 @@ pub mod async_adapters {
         // Typically `std` is not available here
         extern crate alloc;
         use alloc::boxed::Box;
-        use alloc::string::String;
         use alloc::sync::Arc;
         use alloc::vec::Vec;
         use embassy_sync::once_lock::OnceLock;
@@ -385,7 +969,7 @@ pub mod async_adapters {
         /// ```
         pub struct EmbassyPollable(pub Arc<OnceLock<Vec<u8>>>);
         impl crate::Pollable for EmbassyPollable {
-            fn poll(&self) -> core::task::Poll<Result<Option<Vec<u8>>, String>> {
+            fn poll(&self) -> core::task::Poll<Result<Option<Vec<u8>>, crate::types::PkError>> {
                 match self.0.try_get() {
                     Some(data) => core::task::Poll::Ready(Ok(Some(data.clone()))),
                     None => core::task::Poll::Pending,
@@ -575,7 +1159,7 @@ pub mod async_adapters {
                         &self,
                         key: ::alloc::string::String,
                         param: ::alloc::vec::Vec<u8>
-                    ) -> ::core::result::Result<::core::pin::Pin<::alloc::boxed::Box<dyn ::pk_command::Pollable>>, ::alloc::string::String>
+                    ) -> ::core::result::Result<::core::pin::Pin<::alloc::boxed::Box<dyn ::pk_command::Pollable>>, ::pk_command::types::PkError>
                     {
                         let lock=::alloc::sync::Arc::new(::embassy_sync::once_lock::OnceLock::new());
                         let lock_clone=lock.clone();
@@ -589,7 +1173,7 @@ pub mod async_adapters {
                                 $method_name => {
                                     let token=$function(param, callback);
                                     self.spawner.spawn(token)
-                                        .map_err(|x| x.to_string())?;
+                                        .map_err(|x| ::pk_command::types::PkError::Method(x.to_string()))?;
                                     Ok(pollable)
                                 },
                             )*
@@ -597,7 +1181,7 @@ pub mod async_adapters {
                                 let mut err_msg = ::alloc::string::String::from("No method named ");
                                 err_msg.push_str(&key);
                                 err_msg.push_str(" found");
-                                Err(err_msg)
+                                Err(::pk_command::types::PkError::Method(err_msg))
                             }
                         }
                     }
@@ -622,6 +1206,10 @@ pub mod async_adapters {
 #[cfg(feature = "std")]
 pub type VariableChangeListener = Box<dyn Fn(Vec<u8>)>;
 
+/// Checks a new value of a variable of `PkHashmapVariable` before it is stored.
+#[cfg(feature = "std")]
+type VariableValidator = Box<dyn Fn(&[u8]) -> Result<(), crate::types::PkError>>;
+
 /// A wrapper for `std::collections::HashMap` that implements the `PkVariableAccessor` trait.
 ///
 ///
@@ -632,6 +1220,9 @@ pub type VariableChangeListener = Box<dyn Fn(Vec<u8>)>;
 #[cfg(feature = "std")]
 pub struct PkHashmapVariable {
     hashmap: std::collections::HashMap<String, (RefCell<Vec<u8>>, VariableChangeListener)>,
+    descriptions: std::collections::HashMap<String, String>,
+    validators: std::collections::HashMap<String, VariableValidator>,
+    access: std::collections::HashMap<String, crate::types::AccessMode>,
 }
 
 #[cfg(feature = "std")]
@@ -639,16 +1230,35 @@ impl crate::PkVariableAccessor for PkHashmapVariable {
     fn get(&self, key: String) -> Option<Vec<u8>> {
         self.hashmap.get(&key).map(|v| v.0.borrow().clone())
     }
-    fn set(&self, key: String, value: Vec<u8>) -> Result<(), String> {
+    fn set(&self, key: String, value: Vec<u8>) -> Result<(), crate::types::PkError> {
         if self.hashmap.contains_key(&key) {
+            if let Some(validate) = self.validators.get(&key) {
+                validate(&value)?;
+            }
             let v = self.hashmap.get(&key).unwrap();
             v.0.replace(value.clone());
             v.1(value);
             Ok(())
         } else {
-            Err(String::from("Key not found"))
+            Err(crate::types::PkError::VariableNotFound(key))
         }
     }
+    fn list(&self) -> Vec<crate::types::VariableInfo> {
+        let mut list: Vec<_> = self
+            .hashmap
+            .keys()
+            .map(|key| crate::types::VariableInfo {
+                name: key.clone(),
+                access: self.access(key),
+                description: self.descriptions.get(key).cloned().unwrap_or_default(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+    fn access(&self, key: &str) -> crate::types::AccessMode {
+        self.access.get(key).copied().unwrap_or_default()
+    }
 }
 #[cfg(feature = "std")]
 impl PkHashmapVariable {
@@ -668,7 +1278,87 @@ impl PkHashmapVariable {
             let (key, value, listener) = i;
             hashmap.insert(key, (RefCell::new(value.unwrap_or_default()), listener));
         }
-        PkHashmapVariable { hashmap }
+        PkHashmapVariable {
+            hashmap,
+            descriptions: std::collections::HashMap::new(),
+            validators: std::collections::HashMap::new(),
+            access: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Adds a variable holding a typed value. (See [`TypedVariable`](crate::codec::TypedVariable).)
+    ///
+    /// Values set by the Host are only stored if they decode as `T` and pass the validator.
+    pub fn with_typed<T: crate::codec::PkCodec + 'static>(
+        mut self,
+        variable: crate::codec::TypedVariable<T>,
+    ) -> Self {
+        let crate::codec::TypedVariable {
+            name,
+            initial,
+            validator,
+            listener,
+        } = variable;
+        self.validators.insert(
+            name.clone(),
+            Box::new(move |bytes| {
+                let value = T::decode(bytes)?;
+                match &validator {
+                    Some(validate) => validate(&value).map_err(crate::types::PkError::Variable),
+                    None => Ok(()),
+                }
+            }),
+        );
+        let listener: VariableChangeListener = Box::new(move |bytes| {
+            // 值在存入之前已经校验过，这里一定能解码
+            if let Some(listener) = &listener
+                && let Ok(value) = T::decode(&bytes)
+            {
+                listener(value);
+            }
+        });
+        self.hashmap
+            .insert(name, (RefCell::new(initial.encode()), listener));
+        self
+    }
+
+    /// Sets the description of a variable, as listed by the `LISTO` root operation.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkHashmapVariable;
+    ///
+    /// let vars = PkHashmapVariable::new(vec![(String::from("SPEED"), None, Box::new(|_| {}))])
+    ///     .describe("SPEED", "Fan speed in RPM");
+    /// ```
+    pub fn describe(mut self, key: &str, description: &str) -> Self {
+        self.descriptions
+            .insert(key.to_string(), description.to_string());
+        self
+    }
+
+    /// Sets how the Host may access a variable. Variables are [`ReadWrite`](crate::types::AccessMode::ReadWrite)
+    /// by default.
+    ///
+    /// A `REQUV` on a write-only variable or a `SENDV` on a read-only one is refused with
+    /// [`PkError::AccessDenied`](crate::types::PkError::AccessDenied). The code on the Device can
+    /// still read and write it through [`PkVariableAccessor`](crate::PkVariableAccessor).
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkHashmapVariable;
+    /// use pk_command::types::AccessMode;
+    ///
+    /// let vars = PkHashmapVariable::new(vec![(
+    ///     String::from("CALIB"),
+    ///     Some(vec![0x10, 0x27]),
+    ///     Box::new(|_| {}),
+    /// )])
+    /// .restrict("CALIB", AccessMode::ReadOnly);
+    /// ```
+    pub fn restrict(mut self, key: &str, access: crate::types::AccessMode) -> Self {
+        self.access.insert(key.to_string(), access);
+        self
     }
 }
 
@@ -715,18 +1405,41 @@ pub type MethodImplementation = Box<dyn Fn(Option<Vec<u8>>) -> Pin<Box<dyn crate
 #[cfg(feature = "std")]
 pub struct PkHashmapMethod {
     hashmap: std::collections::HashMap<String, MethodImplementation>,
+    descriptions: std::collections::HashMap<String, String>,
+    guards: std::collections::HashMap<String, Box<dyn Fn() -> bool>>,
 }
 
 #[cfg(feature = "std")]
 impl crate::PkMethodAccessor for PkHashmapMethod {
-    fn call(&self, key: String, param: Vec<u8>) -> Result<Pin<Box<dyn crate::Pollable>>, String> {
+    fn call(
+        &self,
+        key: String,
+        param: Vec<u8>,
+    ) -> Result<Pin<Box<dyn crate::Pollable>>, crate::types::PkError> {
         if self.hashmap.contains_key(&key) {
             let f = self.hashmap.get(&key).unwrap();
             Ok(f(Some(param)))
         } else {
-            Err(String::from("Method not found"))
+            Err(crate::types::PkError::Method(String::from(
+                "method not found",
+            )))
         }
     }
+    fn list(&self) -> Vec<crate::types::MethodInfo> {
+        let mut list: Vec<_> = self
+            .hashmap
+            .keys()
+            .map(|key| crate::types::MethodInfo {
+                name: key.clone(),
+                description: self.descriptions.get(key).cloned().unwrap_or_default(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+    fn is_permitted(&self, key: &str) -> bool {
+        self.guards.get(key).is_none_or(|guard| guard())
+    }
 }
 
 #[cfg(feature = "std")]
@@ -741,7 +1454,198 @@ impl PkHashmapMethod {
             let (key, method) = i;
             hashmap.insert(key, method);
         }
-        PkHashmapMethod { hashmap }
+        PkHashmapMethod {
+            hashmap,
+            descriptions: std::collections::HashMap::new(),
+            guards: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Sets the description of a method, as listed by the `LISTO` root operation.
+    pub fn describe(mut self, key: &str, description: &str) -> Self {
+        self.descriptions
+            .insert(key.to_string(), description.to_string());
+        self
+    }
+
+    /// Sets a function that decides whether the Host may call a method right now.
+    ///
+    /// While it returns `false`, an `INVOK` of the method is refused with
+    /// [`PkError::AccessDenied`](crate::types::PkError::AccessDenied). Like the listeners of
+    /// [`PkHashmapVariable`], it runs synchronously within [`poll()`](crate::PkCommand::poll).
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::{PkHashmapMethod, PkPromise};
+    /// use std::cell::Cell;
+    /// use std::rc::Rc;
+    ///
+    /// let unlocked = Rc::new(Cell::new(false));
+    /// let methods = PkHashmapMethod::new(vec![(
+    ///     String::from("RESET"),
+    ///     Box::new(|_| PkPromise::execute(|resolve| resolve(vec![]))),
+    /// )])
+    /// .guard("RESET", move || unlocked.get());
+    /// ```
+    pub fn guard(mut self, key: &str, guard: impl Fn() -> bool + 'static) -> Self {
+        self.guards.insert(key.to_string(), Box::new(guard));
+        self
+    }
+
+    /// Creates a builder that registers methods as typed closures. (See [`PkHashmapMethodBuilder`].)
+    pub fn builder() -> PkHashmapMethodBuilder {
+        PkHashmapMethodBuilder {
+            methods: PkHashmapMethod::new(vec![]),
+        }
+    }
+}
+
+/// Builds a [`PkHashmapMethod`] out of typed closures.
+///
+/// The parameter of each method is decoded into the argument of its closure, and the value the
+/// closure returns is encoded as the result, both with [`PkCodec`](crate::codec::PkCodec). Use `()`
+/// for methods without parameter or result, and tuples for several arguments. A parameter that
+/// does not decode is reported to the Host with `ERROR`, and the closure is not called.
+///
+/// **Note**: This is only available when the `std` feature is enabled.
+///
+/// # Example
+/// ```
+/// use pk_command::PkHashmapMethod;
+///
+/// let methods = PkHashmapMethod::builder()
+///     .method("ADDXY", |(a, b): (i32, i32)| a + b)
+///     .method("HELLO", |name: String| format!("Hello, {name}!"))
+///     .threaded_method("SLEEP", |millis: u64| {
+///         std::thread::sleep(std::time::Duration::from_millis(millis));
+///     })
+///     .describe("ADDXY", "Adds two numbers")
+///     .build();
+/// ```
+#[cfg(feature = "std")]
+pub struct PkHashmapMethodBuilder {
+    methods: PkHashmapMethod,
+}
+
+#[cfg(feature = "std")]
+impl PkHashmapMethodBuilder {
+    /// Adds a method that runs synchronously within [`poll()`](crate::PkCommand::poll).
+    ///
+    /// Keep it short: the state machine does not progress while it runs.
+    pub fn method<A, R, F>(self, name: &str, function: F) -> Self
+    where
+        A: crate::codec::PkCodec,
+        R: crate::codec::PkCodec,
+        F: Fn(A) -> R + 'static,
+    {
+        self.raw(
+            name,
+            Box::new(move |param| {
+                let result =
+                    A::decode(&param.unwrap_or_default()).map(|args| Some(function(args).encode()));
+                Box::pin(Resolved(result))
+            }),
+        )
+    }
+
+    /// Adds a method that runs in a background thread, like with [`PkPromise`].
+    pub fn threaded_method<A, R, F>(self, name: &str, function: F) -> Self
+    where
+        A: crate::codec::PkCodec + Send + 'static,
+        R: crate::codec::PkCodec,
+        F: Fn(A) -> R + Send + Sync + 'static,
+    {
+        let function = Arc::new(function);
+        self.raw(
+            name,
+            Box::new(move |param| match A::decode(&param.unwrap_or_default()) {
+                Ok(args) => {
+                    let function = function.clone();
+                    PkPromise::execute(move |resolve| resolve(function(args).encode()))
+                }
+                Err(e) => Box::pin(Resolved(Err(e))),
+            }),
+        )
+    }
+
+    /// Adds a method that runs as a future on the Tokio runtime, like with
+    /// [`TokioFuturePollable`](crate::tokio_adapter::TokioFuturePollable).
+    ///
+    /// **Note**: This is only available when the `tokio-runtime` feature is enabled.
+    #[cfg_attr(docsrs, doc(cfg(feature = "tokio-runtime")))]
+    #[cfg(feature = "tokio-runtime")]
+    pub fn tokio_method<A, R, F, Fut>(self, name: &str, function: F) -> Self
+    where
+        A: crate::codec::PkCodec,
+        R: crate::codec::PkCodec,
+        F: Fn(A) -> Fut + 'static,
+        Fut: std::future::Future<Output = R> + Send + 'static,
+    {
+        self.raw(
+            name,
+            Box::new(move |param| match A::decode(&param.unwrap_or_default()) {
+                Ok(args) => {
+                    let future = function(args);
+                    async_adapters::tokio::TokioFuturePollable::from_future(async move {
+                        Ok(Some(future.await.encode()))
+                    })
+                }
+                Err(e) => Box::pin(Resolved(Err(e))),
+            }),
+        )
+    }
+
+    /// Adds a method that runs as a future on the smol executor, like with
+    /// [`SmolFuturePollable`](crate::smol_adapter::SmolFuturePollable).
+    ///
+    /// **Note**: This is only available when the `smol-runtime` feature is enabled.
+    #[cfg_attr(docsrs, doc(cfg(feature = "smol-runtime")))]
+    #[cfg(feature = "smol-runtime")]
+    pub fn smol_method<A, R, F, Fut>(self, name: &str, function: F) -> Self
+    where
+        A: crate::codec::PkCodec,
+        R: crate::codec::PkCodec,
+        F: Fn(A) -> Fut + 'static,
+        Fut: std::future::Future<Output = R> + Send + 'static,
+    {
+        self.raw(
+            name,
+            Box::new(move |param| match A::decode(&param.unwrap_or_default()) {
+                Ok(args) => {
+                    let future = function(args);
+                    async_adapters::smol::SmolFuturePollable::from_future(async move {
+                        Ok(Some(future.await.encode()))
+                    })
+                }
+                Err(e) => Box::pin(Resolved(Err(e))),
+            }),
+        )
+    }
+
+    /// Adds a method working on raw bytes, as accepted by [`PkHashmapMethod::new()`].
+    pub fn raw(mut self, name: &str, implementation: MethodImplementation) -> Self {
+        self.methods
+            .hashmap
+            .insert(name.to_string(), implementation);
+        self
+    }
+
+    /// Sets the description of a method. (See [`PkHashmapMethod::describe()`].)
+    pub fn describe(mut self, name: &str, description: &str) -> Self {
+        self.methods = self.methods.describe(name, description);
+        self
+    }
+
+    /// Sets a function that decides whether the Host may call a method right now. (See
+    /// [`PkHashmapMethod::guard()`].)
+    pub fn guard(mut self, name: &str, guard: impl Fn() -> bool + 'static) -> Self {
+        self.methods = self.methods.guard(name, guard);
+        self
+    }
+
+    /// Finishes the [`PkHashmapMethod`].
+    pub fn build(self) -> PkHashmapMethod {
+        self.methods
     }
 }
 
@@ -757,6 +1661,7 @@ impl PkHashmapMethod {
 #[cfg(feature = "std")]
 pub struct PkPromise {
     return_value: Arc<RwLock<Option<Vec<u8>>>>,
+    cancelled: Arc<AtomicBool>,
 }
 #[cfg(feature = "std")]
 impl PkPromise {
@@ -798,29 +1703,68 @@ impl PkPromise {
     pub fn execute<T>(function: T) -> Pin<Box<Self>>
     where
         T: FnOnce(Box<dyn FnOnce(Vec<u8>) + Send + 'static>) + Send + 'static,
+    {
+        Self::execute_cancellable(move |resolve, _| function(resolve))
+    }
+
+    /// Like [`execute()`](PkPromise::execute), but the closure also receives a function telling
+    /// whether the promise was [cancelled](crate::Pollable::cancel).
+    ///
+    /// A thread cannot be stopped from the outside, so long tasks should check it from time to time
+    /// and give up once it returns `true`. The result of a cancelled promise is discarded anyway.
+    ///
+    /// # Example
+    /// ```
+    /// use pk_command::PkPromise;
+    /// let promise = PkPromise::execute_cancellable(|resolve, is_cancelled| {
+    ///     for _ in 0..100 {
+    ///         if is_cancelled() {
+    ///             return;
+    ///         }
+    ///         std::thread::sleep(std::time::Duration::from_millis(10));
+    ///     }
+    ///     resolve(b"done".to_vec());
+    /// });
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn execute_cancellable<T>(function: T) -> Pin<Box<Self>>
+    where
+        T: FnOnce(
+                Box<dyn FnOnce(Vec<u8>) + Send + 'static>,
+                Box<dyn Fn() -> bool + Send + 'static>,
+            ) + Send
+            + 'static,
     {
         let return_value_arc = Arc::new(RwLock::new(None));
         let return_value_clone = return_value_arc.clone();
+        let cancelled_arc = Arc::new(AtomicBool::new(false));
+        let cancelled_clone = cancelled_arc.clone();
         std::thread::spawn(move || {
             let resolve: Box<dyn FnOnce(Vec<u8>) + Send + 'static> =
                 Box::new(move |ret: Vec<u8>| {
                     // This resolve function is called by the user's function
                     *return_value_clone.write().unwrap() = Some(ret);
                 });
-            function(Box::new(resolve));
+            let is_cancelled = Box::new(move || cancelled_clone.load(Ordering::Relaxed));
+            function(resolve, is_cancelled);
         });
         Box::pin(PkPromise {
             return_value: return_value_arc,
+            cancelled: cancelled_arc,
         })
     }
 }
 #[cfg(feature = "std")]
 impl crate::Pollable for PkPromise {
-    fn poll(&self) -> std::task::Poll<Result<Option<Vec<u8>>, String>> {
+    fn poll(&self) -> std::task::Poll<Result<Option<Vec<u8>>, crate::types::PkError>> {
         let read_guard = self.return_value.read().unwrap();
         match read_guard.as_ref() {
             Some(data) => std::task::Poll::Ready(Ok(Some(data.clone()))),
             None => std::task::Poll::Pending,
         }
     }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
 }
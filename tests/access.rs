@@ -0,0 +1,169 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use common::{drive, host, is, perform};
+use pk_command::types::{AccessMode, FailureOrigin, Operation, PkError, TransactionOutcome};
+use pk_command::{
+    PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable, PkVariableAccessor,
+};
+
+fn variables(sets: Rc<Cell<u32>>) -> PkHashmapVariable {
+    PkHashmapVariable::new(vec![
+        (
+            String::from("CALIB"),
+            Some(b"1234".to_vec()),
+            Box::new(move |_| sets.set(sets.get() + 1)),
+        ),
+        (String::from("PASSW"), None, Box::new(|_| {})),
+        (String::from("VARIA"), None, Box::new(|_| {})),
+    ])
+    .restrict("CALIB", AccessMode::ReadOnly)
+    .restrict("PASSW", AccessMode::WriteOnly)
+}
+
+fn denied(name: &str) -> TransactionOutcome {
+    TransactionOutcome::Failed {
+        reason: PkError::AccessDenied(String::from(name)),
+        origin: FailureOrigin::Remote,
+    }
+}
+
+#[test]
+fn test_variable_access_modes() {
+    let sets = Rc::new(Cell::new(0));
+    let device = PkCommand::new(
+        PkCommandConfig::default(64),
+        variables(sets.clone()),
+        PkHashmapMethod::new(vec![]),
+    );
+    let host = host();
+
+    assert_eq!(
+        perform(&host, &device, Operation::RequireVariable, "CALIB", None)
+            .0
+            .unwrap(),
+        TransactionOutcome::Completed(Some(b"1234".to_vec()))
+    );
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::SendVariable,
+            "CALIB",
+            Some(b"0000".to_vec())
+        )
+        .0
+        .unwrap(),
+        denied("CALIB")
+    );
+    // The setter is not called at all.
+    assert_eq!(sets.get(), 0);
+
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::SendVariable,
+            "PASSW",
+            Some(b"hunter2".to_vec())
+        )
+        .0
+        .unwrap(),
+        TransactionOutcome::Completed(None)
+    );
+    assert_eq!(
+        perform(&host, &device, Operation::RequireVariable, "PASSW", None)
+            .0
+            .unwrap(),
+        denied("PASSW")
+    );
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::SendVariable,
+            "VARIA",
+            Some(b"value".to_vec())
+        )
+        .0
+        .unwrap(),
+        TransactionOutcome::Completed(None)
+    );
+}
+
+#[test]
+fn test_denied_before_parameter() {
+    let device = PkCommand::new(
+        PkCommandConfig::default(64),
+        variables(Rc::new(Cell::new(0))),
+        PkHashmapMethod::new(vec![]),
+    );
+    let host = host();
+
+    host.perform(
+        Operation::SendVariable,
+        Some(String::from("CALIB")),
+        Some(vec![0; 500]),
+    )
+    .unwrap();
+    let mut data_sent = false;
+    drive(&host, &device, |bytes, to_device| {
+        data_sent |= to_device && is(bytes, Operation::Data);
+        1
+    });
+    assert_eq!(host.take_outcome(), Some(denied("CALIB")));
+    assert!(!data_sent);
+}
+
+#[test]
+fn test_access_restricts_host_only() {
+    let vars = variables(Rc::new(Cell::new(0)));
+    assert_eq!(vars.set(String::from("CALIB"), b"4321".to_vec()), Ok(()));
+    assert_eq!(vars.get(String::from("PASSW")), Some(vec![]));
+    assert_eq!(vars.access("CALIB"), AccessMode::ReadOnly);
+    assert_eq!(vars.access("VARIA"), AccessMode::ReadWrite);
+    assert_eq!(vars.access("NOVAR"), AccessMode::ReadWrite);
+    assert_eq!(vars.list()[0].access, AccessMode::ReadOnly);
+}
+
+#[test]
+fn test_method_guard() {
+    let unlocked = Rc::new(Cell::new(false));
+    let guard = unlocked.clone();
+    let device = PkCommand::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![]),
+        PkHashmapMethod::builder()
+            .method("RESET", |()| 1u8)
+            .guard("RESET", move || guard.get())
+            .build(),
+    );
+    let host = host();
+
+    assert_eq!(
+        perform(&host, &device, Operation::Invoke, "RESET", None)
+            .0
+            .unwrap(),
+        denied("RESET")
+    );
+    unlocked.set(true);
+    assert_eq!(
+        perform(&host, &device, Operation::Invoke, "RESET", None)
+            .0
+            .unwrap(),
+        TransactionOutcome::Completed(Some(vec![1]))
+    );
+}
+
+#[test]
+fn test_access_denied_on_the_wire() {
+    assert_eq!(
+        PkError::from_remote(&PkError::AccessDenied(String::from("CALIB")).to_wire()),
+        PkError::AccessDenied(String::from("CALIB"))
+    );
+}
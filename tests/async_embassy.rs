@@ -100,10 +100,10 @@ fn test_embassy_invok_echo() {
 
             let mut data: Vec<u8> = Vec::new();
             for _ in 0..10000 {
-                if let Some(cmd_to_send) = host_pkc.poll() {
-                    if host_tx.send(cmd_to_send.to_bytes()).is_err() {
-                        break;
-                    }
+                if let Some(cmd_to_send) = host_pkc.poll()
+                    && host_tx.send(cmd_to_send.to_bytes()).is_err()
+                {
+                    break;
                 }
 
                 match host_rx.try_recv() {
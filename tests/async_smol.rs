@@ -2,9 +2,18 @@
 #![cfg(test)]
 
 use async_channel::{Receiver, Sender, unbounded};
-use pk_command::smol_adapter::SmolFuturePollable;
+use pk_command::codec::PkCodec;
+use pk_command::smol_adapter::{
+    MemoryTransport, PkClient, SmolFuturePollable, StreamTransport, UnixDatagramTransport,
+};
+use pk_command::transport::PkAsyncTransport;
+use pk_command::types::PkError;
+use pk_command::types::framing::CobsFramer;
 use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
-use std::time::Instant;
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 #[test]
 fn test_smol_integration_simple_invoke() {
@@ -55,3 +64,149 @@ fn test_smol_integration_simple_invoke() {
         }
     })
 }
+
+#[test]
+fn test_smol_client() {
+    smol::block_on(async {
+        let (host_transport, mut device_transport) = MemoryTransport::pair();
+
+        let host_pk = PkCommand::<_, _, Instant>::new(
+            PkCommandConfig::default(64),
+            PkHashmapVariable::new(vec![]),
+            PkHashmapMethod::new(vec![]),
+        );
+        let mut client = PkClient::new(host_transport, host_pk);
+
+        let method_impl = Box::new(move |param: Option<Vec<u8>>| {
+            SmolFuturePollable::from_future(async move {
+                smol::Timer::after(std::time::Duration::from_millis(400)).await;
+                Ok(param)
+            })
+        });
+        let device_pk = PkCommand::<_, _, Instant>::new(
+            PkCommandConfig::default(64),
+            PkHashmapVariable::new(vec![(
+                String::from("VARIA"),
+                Some(b"initial".to_vec()),
+                Box::new(|_| {}),
+            )]),
+            PkHashmapMethod::builder()
+                .raw("ECHOO", method_impl)
+                .smol_method("DOUBL", |x: u32| async move {
+                    smol::Timer::after(std::time::Duration::from_millis(50)).await;
+                    x * 2
+                })
+                .build(),
+        );
+
+        let done = Cell::new(false);
+        let device = async {
+            while !done.get() {
+                // One packet per poll: the state machine buffers a single incoming command.
+                let received =
+                    smol::future::or(async { Some(device_transport.recv().await) }, async {
+                        smol::Timer::after(std::time::Duration::from_millis(1)).await;
+                        None
+                    })
+                    .await;
+                if let Some(Ok(bytes)) = received {
+                    let _ = device_pk.incoming_command(bytes);
+                }
+                if let Some(cmd) = device_pk.poll() {
+                    let _ = device_transport.send(cmd.to_bytes()).await;
+                }
+            }
+        };
+        let host = async {
+            assert_eq!(
+                client.request_variable::<Vec<u8>>("VARIA").await.unwrap(),
+                b"initial".to_vec()
+            );
+            client
+                .send_variable("VARIA", b"updated".to_vec())
+                .await
+                .unwrap();
+            assert_eq!(
+                client.request_variable::<Vec<u8>>("VARIA").await.unwrap(),
+                b"updated".to_vec()
+            );
+            assert_eq!(
+                client
+                    .invoke("ECHOO", b"smol client".to_vec())
+                    .await
+                    .unwrap(),
+                b"smol client".to_vec()
+            );
+            assert_eq!(
+                client.invoke("DOUBL", 21u32.encode()).await,
+                Ok(42u32.encode())
+            );
+            assert_eq!(
+                client.version().await.unwrap(),
+                env!("CARGO_PKG_VERSION").to_string()
+            );
+            assert!(matches!(
+                client.invoke("NOSUCH", vec![]).await,
+                Err(PkError::Remote(_))
+            ));
+            done.set(true);
+        };
+        smol::future::zip(device, host).await;
+    })
+}
+
+#[test]
+fn test_smol_unix_datagram_transport() {
+    smol::block_on(async {
+        let (a, b) = smol::net::unix::UnixDatagram::pair().unwrap();
+        let mut host = UnixDatagramTransport::new(a);
+        let mut device = UnixDatagramTransport::new(b);
+
+        host.send(b"!!START".to_vec()).await.unwrap();
+        device.send(b"!!ACKNO START".to_vec()).await.unwrap();
+        assert_eq!(device.recv().await.unwrap(), b"!!START".to_vec());
+        assert_eq!(host.recv().await.unwrap(), b"!!ACKNO START".to_vec());
+    })
+}
+
+#[test]
+fn test_smol_stream_transport() {
+    smol::block_on(async {
+        let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let a = smol::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (b, _) = listener.accept().await.unwrap();
+        let mut host = StreamTransport::with_framer(a, CobsFramer::new(64));
+        let mut device = StreamTransport::with_framer(b, CobsFramer::new(64));
+
+        // Packets written back to back come out one by one.
+        host.send(b"!!START".to_vec()).await.unwrap();
+        host.send(b"!\"ENDTR".to_vec()).await.unwrap();
+        assert_eq!(device.recv().await.unwrap(), b"!!START".to_vec());
+        assert_eq!(device.recv().await.unwrap(), b"!\"ENDTR".to_vec());
+        device.send(b"!!ACKNO START".to_vec()).await.unwrap();
+        assert_eq!(host.recv().await.unwrap(), b"!!ACKNO START".to_vec());
+
+        drop(host);
+        assert!(matches!(device.recv().await, Err(PkError::Transport(_))));
+    })
+}
+
+#[test]
+fn test_smol_pollable_cancel() {
+    smol::block_on(async {
+        let finished = Arc::new(AtomicBool::new(false));
+        let finished_cloned = finished.clone();
+        let pollable = SmolFuturePollable::from_future(async move {
+            smol::Timer::after(Duration::from_millis(20)).await;
+            finished_cloned.store(true, Ordering::Relaxed);
+            Ok(None)
+        });
+        // The task is dropped, so it never finishes.
+        pollable.cancel();
+        smol::Timer::after(Duration::from_millis(50)).await;
+        assert!(!finished.load(Ordering::Relaxed));
+        assert!(pollable.poll().is_pending());
+    })
+}
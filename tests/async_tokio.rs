@@ -1,9 +1,20 @@
 #![cfg(feature = "tokio-runtime-test")]
 #![cfg(test)]
 
-use pk_command::tokio_adapter::TokioFuturePollable;
-use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
-use std::time::Instant;
+use pk_command::codec::{PkCodec, TypedVariable};
+use pk_command::tokio_adapter::{
+    MemoryTransport, PkClient, StreamTransport, TokioFuturePollable, UnixDatagramTransport,
+};
+use pk_command::transport::PkAsyncTransport;
+use pk_command::types::framing::CobsFramer;
+use pk_command::types::{Notification, PkError};
+use pk_command::{
+    PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable, PkVariableAccessor,
+};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
 
 #[tokio::test(flavor = "current_thread")]
@@ -60,3 +71,233 @@ async fn test_tokio_integration_simple_invoke() {
         tokio::time::sleep(std::time::Duration::from_millis(1)).await;
     }
 }
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_tokio_client() {
+    let (host_transport, mut device_transport) = MemoryTransport::pair();
+
+    let host_pk = PkCommand::<_, _, Instant>::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![]),
+        PkHashmapMethod::new(vec![]),
+    );
+    let mut client = PkClient::new(host_transport, host_pk);
+
+    let method_impl = Box::new(move |param: Option<Vec<u8>>| {
+        TokioFuturePollable::from_future(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+            Ok(param)
+        })
+    });
+    let device_pk = PkCommand::<_, _, Instant>::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![(
+            String::from("VARIA"),
+            Some(b"initial".to_vec()),
+            Box::new(|_| {}),
+        )])
+        .with_typed(TypedVariable::new("SPEED", 1200u16).validate(|rpm| {
+            if *rpm <= 3000 {
+                Ok(())
+            } else {
+                Err(String::from("too fast"))
+            }
+        })),
+        PkHashmapMethod::builder()
+            .raw("ECHOO", method_impl)
+            .tokio_method("DOUBL", |x: u32| async move {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                x * 2
+            })
+            .build(),
+    );
+
+    let done = Cell::new(false);
+    let device = async {
+        while !done.get() {
+            // One packet per poll: the state machine buffers a single incoming command.
+            let received =
+                tokio::time::timeout(std::time::Duration::from_millis(1), device_transport.recv())
+                    .await;
+            if let Ok(Ok(bytes)) = received {
+                let _ = device_pk.incoming_command(bytes);
+            }
+            if let Some(cmd) = device_pk.poll() {
+                let _ = device_transport.send(cmd.to_bytes()).await;
+            }
+        }
+    };
+    let host = async {
+        assert_eq!(
+            client.request_variable::<Vec<u8>>("VARIA").await.unwrap(),
+            b"initial".to_vec()
+        );
+        client
+            .send_variable("VARIA", b"updated".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.request_variable::<Vec<u8>>("VARIA").await.unwrap(),
+            b"updated".to_vec()
+        );
+        assert_eq!(
+            client
+                .invoke("ECHOO", b"tokio client".to_vec())
+                .await
+                .unwrap(),
+            b"tokio client".to_vec()
+        );
+        assert_eq!(
+            client.invoke("DOUBL", 21u32.encode()).await,
+            Ok(42u32.encode())
+        );
+        assert_eq!(
+            client.invoke("DOUBL", vec![]).await,
+            Err(PkError::Remote(String::from("INVALID_DATA u32")))
+        );
+        assert_eq!(
+            client.version().await.unwrap(),
+            env!("CARGO_PKG_VERSION").to_string()
+        );
+        assert!(matches!(
+            client.invoke("NOSUCH", vec![]).await,
+            Err(PkError::Remote(_))
+        ));
+        assert_eq!(
+            client.send_variable("NOVAR", b"lost".to_vec()).await,
+            Err(PkError::VariableNotFound(String::from("NOVAR")))
+        );
+
+        assert_eq!(client.request_variable::<u16>("SPEED").await, Ok(1200));
+        assert_eq!(
+            client.request_variable::<u32>("SPEED").await,
+            Err(PkError::InvalidData("u32"))
+        );
+        client.send_variable("SPEED", 2400u16).await.unwrap();
+        assert_eq!(
+            client.send_variable("SPEED", 3600u16).await,
+            Err(PkError::Remote(String::from("VARIABLE too fast")))
+        );
+        assert_eq!(
+            client.send_variable("SPEED", true).await,
+            Err(PkError::Remote(String::from("INVALID_DATA u16")))
+        );
+        assert_eq!(client.request_variable::<u16>("SPEED").await, Ok(2400));
+
+        assert_eq!(
+            client
+                .subscribe::<u16>("SPEED", std::time::Duration::ZERO)
+                .await,
+            Ok(2400)
+        );
+        device_pk
+            .variables()
+            .set(String::from("SPEED"), 2500u16.encode())
+            .unwrap();
+        assert_eq!(
+            client.next_notification().await,
+            Ok(Notification {
+                name: String::from("SPEED"),
+                value: 2500u16.encode(),
+            })
+        );
+        client.unsubscribe("SPEED").await.unwrap();
+        done.set(true);
+    };
+    tokio::join!(device, host);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_tokio_unix_datagram_transport() {
+    let (a, b) = tokio::net::UnixDatagram::pair().unwrap();
+    let mut host = UnixDatagramTransport::new(a);
+    let mut device = UnixDatagramTransport::new(b);
+
+    host.send(b"!!START".to_vec()).await.unwrap();
+    device.send(b"!!ACKNO START".to_vec()).await.unwrap();
+    assert_eq!(device.recv().await.unwrap(), b"!!START".to_vec());
+    assert_eq!(host.recv().await.unwrap(), b"!!ACKNO START".to_vec());
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_tokio_stream_transport() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let a = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (b, _) = listener.accept().await.unwrap();
+    let mut host = StreamTransport::new(a);
+    let mut device = StreamTransport::new(b);
+
+    // Packets written back to back come out one by one.
+    host.send(b"!!START".to_vec()).await.unwrap();
+    host.send(b"!\"ENDTR".to_vec()).await.unwrap();
+    assert_eq!(device.recv().await.unwrap(), b"!!START".to_vec());
+    assert_eq!(device.recv().await.unwrap(), b"!\"ENDTR".to_vec());
+
+    drop(host);
+    assert!(matches!(device.recv().await, Err(PkError::Transport(_))));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_tokio_client_over_stream() {
+    let (a, b) = tokio::net::UnixStream::pair().unwrap();
+    let host_pk = PkCommand::<_, _, Instant>::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![]),
+        PkHashmapMethod::new(vec![]),
+    );
+    let mut client = PkClient::new(
+        StreamTransport::with_framer(a, CobsFramer::new(64)),
+        host_pk,
+    );
+    let mut device_transport = StreamTransport::with_framer(b, CobsFramer::new(64));
+    let device_pk = PkCommand::<_, _, Instant>::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![(
+            String::from("VARIA"),
+            Some(vec![7; 200]),
+            Box::new(|_| {}),
+        )]),
+        PkHashmapMethod::new(vec![]),
+    );
+
+    let done = Cell::new(false);
+    let device = async {
+        while !done.get() {
+            let received =
+                tokio::time::timeout(std::time::Duration::from_millis(1), device_transport.recv())
+                    .await;
+            if let Ok(Ok(bytes)) = received {
+                let _ = device_pk.incoming_command(bytes);
+            }
+            if let Some(cmd) = device_pk.poll() {
+                let _ = device_transport.send(cmd.to_bytes()).await;
+            }
+        }
+    };
+    let host = async {
+        assert_eq!(
+            client.request_variable::<Vec<u8>>("VARIA").await,
+            Ok(vec![7; 200])
+        );
+        done.set(true);
+    };
+    tokio::join!(device, host);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_tokio_pollable_cancel() {
+    let finished = Arc::new(AtomicBool::new(false));
+    let finished_cloned = finished.clone();
+    let pollable = TokioFuturePollable::from_future(async move {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        finished_cloned.store(true, Ordering::Relaxed);
+        Ok(None)
+    });
+    // The task is aborted, so it never finishes.
+    pollable.cancel();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!finished.load(Ordering::Relaxed));
+    assert!(pollable.poll().is_pending());
+}
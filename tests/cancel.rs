@@ -0,0 +1,126 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use common::{Pk, cancellable_device, drive, host, host_with, is, wait_for};
+use pk_command::PkCommandConfig;
+use pk_command::types::{FailureOrigin, Operation, PkError, TieBreak, TransactionOutcome};
+
+/// Exchanges packets until the Device sends `operation`.
+fn until(host: &Pk, device: &Pk, operation: Operation) {
+    for _ in 0..10000 {
+        if let Some(cmd) = host.poll() {
+            device.incoming_command(cmd.to_bytes()).unwrap();
+        }
+        if let Some(cmd) = device.poll() {
+            let found = cmd.operation == operation;
+            host.incoming_command(cmd.to_bytes()).unwrap();
+            if found {
+                return;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    panic!("{} was never sent", operation.to_name());
+}
+
+fn cancelled(origin: FailureOrigin) -> Option<TransactionOutcome> {
+    Some(TransactionOutcome::Failed {
+        reason: PkError::Cancelled,
+        origin,
+    })
+}
+
+#[test]
+fn test_cancel_long_invoke() {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let host = host();
+    let device = cancellable_device(PkCommandConfig::default(64), stopped.clone());
+
+    host.perform(Operation::Invoke, Some(String::from("LONGT")), None)
+        .unwrap();
+    until(&host, &device, Operation::Await);
+    assert!(host.cancel());
+    // The first ERROR is lost, and sent again.
+    let mut errors = 0;
+    drive(&host, &device, |bytes, to_device| {
+        if to_device && is(bytes, Operation::Error) {
+            errors += 1;
+            return usize::from(errors > 1);
+        }
+        1
+    });
+    assert_eq!(errors, 2);
+    assert_eq!(host.take_outcome(), cancelled(FailureOrigin::Local));
+    assert_eq!(device.take_outcome(), cancelled(FailureOrigin::Remote));
+
+    assert!(wait_for(&stopped));
+}
+
+#[test]
+fn test_cancel_before_start() {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let host = host();
+    let device = cancellable_device(PkCommandConfig::default(64), stopped);
+
+    host.perform(Operation::Invoke, Some(String::from("LONGT")), None)
+        .unwrap();
+    assert!(host.cancel());
+    // Nothing was sent, so there is nothing to tell the Device.
+    let mut packets = 0;
+    drive(&host, &device, |_, _| {
+        packets += 1;
+        1
+    });
+    assert_eq!(packets, 0);
+    assert_eq!(host.take_outcome(), cancelled(FailureOrigin::Local));
+    assert_eq!(host.perform(Operation::GetVersion, None, None), Ok(()));
+}
+
+#[test]
+fn test_nothing_to_cancel() {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let host = host();
+    let device = cancellable_device(PkCommandConfig::default(64), stopped);
+    assert!(!host.cancel());
+
+    // The Device has no chain of its own.
+    host.perform(Operation::Invoke, Some(String::from("LONGT")), None)
+        .unwrap();
+    until(&host, &device, Operation::Await);
+    assert!(!device.cancel());
+    assert!(host.cancel());
+    drive(&host, &device, |_, _| 1);
+    assert!(!host.cancel());
+}
+
+#[test]
+fn test_cancel_deferred_chain() {
+    let pc = host_with(PkCommandConfig::default(64).with_tie_break(TieBreak::Win));
+    let mcu = host_with(PkCommandConfig::default(64).with_tie_break(TieBreak::Yield));
+    pc.perform(Operation::GetVersion, None, None).unwrap();
+    mcu.perform(Operation::GetVersion, None, None).unwrap();
+    let (start_pc, start_mcu) = (pc.poll().unwrap(), mcu.poll().unwrap());
+    pc.incoming_command(start_mcu.to_bytes()).unwrap();
+    mcu.incoming_command(start_pc.to_bytes()).unwrap();
+    // The MCU yields, and puts its chain aside.
+    pc.incoming_command(mcu.poll().unwrap().to_bytes()).unwrap();
+    assert!(mcu.cancel());
+
+    let mut starts = 0;
+    drive(&pc, &mcu, |bytes, _| {
+        starts += is(bytes, Operation::Start) as usize;
+        1
+    });
+    assert_eq!(starts, 0);
+    assert!(matches!(
+        pc.take_outcome(),
+        Some(TransactionOutcome::Completed(Some(_)))
+    ));
+    assert_eq!(mcu.take_outcome(), cancelled(FailureOrigin::Local));
+}
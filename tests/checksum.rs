@@ -0,0 +1,165 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use common::{Pk, device, host_with, is, perform, perform_over};
+use pk_command::PkCommandConfig;
+use pk_command::types::{Checksum, Command, FailureOrigin, Operation, PkError, TransactionOutcome};
+
+const PAYLOAD: &[u8] = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua.";
+
+fn config(checksum: Checksum) -> PkCommandConfig {
+    PkCommandConfig::default(64).with_checksum(checksum)
+}
+
+/// Runs an `INVOK ECHOO` chain over `link`, and returns its result. (See [`perform_over`].)
+fn echo(host: &Pk, device: &Pk, mut link: impl FnMut(&mut Vec<u8>, bool)) -> Option<Vec<u8>> {
+    let outcome = perform_over(
+        host,
+        device,
+        Operation::Invoke,
+        Some("ECHOO"),
+        Some(PAYLOAD.to_vec()),
+        |bytes, host_to_device| {
+            link(bytes, host_to_device);
+            1
+        },
+    );
+    match outcome {
+        Some(TransactionOutcome::Completed(data)) => data,
+        _ => None,
+    }
+}
+
+#[test]
+fn test_checksum_negotiated() {
+    for checksum in [Checksum::Crc16, Checksum::Crc32] {
+        let mut sent = Vec::new();
+        let result = echo(
+            &host_with(config(checksum)),
+            &device(config(checksum)),
+            |bytes, _| sent.push(Command::parse(bytes).unwrap()),
+        );
+        assert_eq!(result, Some(PAYLOAD.to_vec()));
+
+        let name = checksum.to_name().map(String::from);
+        assert_eq!(sent[0].object.as_deref(), Some("PKEXT"));
+        assert_eq!(sent[1].data, name.clone().map(String::into_bytes));
+        for cmd in &sent {
+            match cmd.operation {
+                Operation::Data => {
+                    let data = cmd.data.as_deref().unwrap();
+                    assert!(checksum.strip(data).is_some());
+                    assert!(data.len() <= 64 - 14);
+                }
+                Operation::EndTransaction => {
+                    assert_eq!(cmd.object, name);
+                    assert_eq!(cmd.data, Some(checksum.compute(PAYLOAD)));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn test_checksum_fallback() {
+    // The Device has checksums disabled: the chain runs without them.
+    let mut sent = Vec::new();
+    let result = echo(
+        &host_with(config(Checksum::Crc32)),
+        &device(config(Checksum::None)),
+        |bytes, _| sent.push(Command::parse(bytes).unwrap()),
+    );
+    assert_eq!(result, Some(PAYLOAD.to_vec()));
+    assert_eq!(sent[1].data, None);
+    let data_len: usize = sent
+        .iter()
+        .filter(|cmd| cmd.operation == Operation::Data)
+        .map(|cmd| cmd.data.as_ref().unwrap().len())
+        .sum();
+    assert_eq!(data_len, PAYLOAD.len() * 2);
+    assert!(
+        sent.iter()
+            .filter(|cmd| cmd.operation == Operation::EndTransaction)
+            .all(|cmd| cmd.object.is_none())
+    );
+
+    // The Host does not offer checksums: START stays plain.
+    let mut sent = Vec::new();
+    let result = echo(
+        &host_with(config(Checksum::None)),
+        &device(config(Checksum::Crc32)),
+        |bytes, _| sent.push(Command::parse(bytes).unwrap()),
+    );
+    assert_eq!(result, Some(PAYLOAD.to_vec()));
+    assert_eq!(sent[0].object, None);
+    assert_eq!(sent[1].data, None);
+}
+
+#[test]
+fn test_corrupted_packet_is_retransmitted() {
+    for to_device in [true, false] {
+        let mut data_packets = 0;
+        let mut corrupted = false;
+        let result = echo(
+            &host_with(config(Checksum::Crc16)),
+            &device(config(Checksum::Crc16)),
+            |bytes, host_to_device| {
+                if is(bytes, Operation::Data) && host_to_device == to_device {
+                    data_packets += 1;
+                    if !corrupted {
+                        corrupted = true;
+                        bytes[20] ^= 0x04; // a bit flip inside the payload
+                    }
+                }
+            },
+        );
+        assert_eq!(result, Some(PAYLOAD.to_vec()));
+        // 123 bytes in chunks of 48 bytes, plus one retransmission
+        assert_eq!(data_packets, 4);
+    }
+}
+
+#[test]
+fn test_corrupted_payload_aborts_chain() {
+    let mut errors = 0;
+    let result = echo(
+        &host_with(config(Checksum::Crc32)),
+        &device(config(Checksum::Crc32)),
+        |bytes, host_to_device| {
+            if host_to_device && is(bytes, Operation::EndTransaction) {
+                let last = bytes.len() - 1;
+                bytes[last] ^= 0x01;
+            }
+            if is(bytes, Operation::Error) {
+                errors += 1;
+            }
+        },
+    );
+    assert_eq!(result, None);
+    assert_eq!(errors, 1);
+}
+
+#[test]
+fn test_packet_limit_too_small() {
+    // 14 bytes of header and a CRC-32 trailer leave no room for data in 16 bytes.
+    let config = PkCommandConfig::default(16).with_checksum(Checksum::Crc32);
+    let host = host_with(config.clone());
+    let device = device(config);
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::SendVariable,
+            "SPEED",
+            Some(PAYLOAD.to_vec())
+        )
+        .0,
+        Some(TransactionOutcome::Failed {
+            reason: PkError::Incompatible(String::from("packet limit 16 is too small")),
+            origin: FailureOrigin::Local,
+        })
+    );
+}
@@ -0,0 +1,159 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use common::{host, perform};
+use pk_command::codec::{PkCodec, TypedVariable};
+use pk_command::types::{FailureOrigin, Operation, PkError, TransactionOutcome};
+use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
+
+fn roundtrip<T: PkCodec + PartialEq + std::fmt::Debug>(value: T) {
+    assert_eq!(T::decode(&value.encode()), Ok(value));
+}
+
+#[test]
+fn test_builtin_codecs() {
+    roundtrip(0x1234_5678u32);
+    roundtrip(-2i8);
+    roundtrip(i128::MIN);
+    roundtrip(1.5f32);
+    roundtrip(-0.25f64);
+    roundtrip(true);
+    roundtrip(String::from("héllo"));
+    roundtrip([1u16, 2, 3]);
+    roundtrip([[1u8, 2], [3, 4]]);
+    roundtrip::<[u32; 0]>([]);
+    roundtrip([(); 3]);
+    roundtrip([(1u8, -2i16), (3, -4)]);
+    roundtrip(());
+    roundtrip((7u8,));
+    roundtrip((1i32, -2i32));
+    roundtrip((true, [3u16, 4], String::from("tail")));
+    assert_eq!((1u8, 2u16).encode(), vec![1, 2, 0]);
+    assert_eq!([(1u8, 2u16), (3, 4)].encode(), vec![1, 2, 0, 3, 4, 0]);
+    assert_eq!(
+        <[(); 2]>::decode(&[0]),
+        Err(PkError::InvalidData("fixed-size array"))
+    );
+    assert_eq!(<()>::decode(&[0]), Err(PkError::InvalidData("no data")));
+    assert_eq!(
+        <(i32, i32)>::decode(&[1, 0, 0]),
+        Err(PkError::InvalidData("fixed-width tuple element"))
+    );
+    // Only the last element may have a variable width.
+    assert_eq!(
+        <(String, u8)>::decode(b"ab\x01"),
+        Err(PkError::InvalidData("fixed-width tuple element"))
+    );
+    assert_eq!(0x0102u16.encode(), vec![0x02, 0x01]);
+    assert_eq!(true.encode(), vec![1]);
+
+    assert_eq!(u16::decode(&[1, 2, 3]), Err(PkError::InvalidData("u16")));
+    assert_eq!(bool::decode(&[2]), Err(PkError::InvalidData("bool")));
+    assert_eq!(
+        String::decode(&[0xFF]),
+        Err(PkError::InvalidData("UTF-8 string"))
+    );
+    assert_eq!(
+        <[u16; 2]>::decode(&[1, 2, 3]),
+        Err(PkError::InvalidData("fixed-size array"))
+    );
+    assert_eq!(
+        <[u16; 2]>::decode(&[1, 2, 3, 4, 5, 6]),
+        Err(PkError::InvalidData("fixed-size array"))
+    );
+}
+
+#[cfg(all(feature = "cbor", feature = "postcard"))]
+#[test]
+fn test_serde_codecs() {
+    use pk_command::codec::{Cbor, Postcard};
+
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+    struct Settings {
+        name: String,
+        limits: (i16, i16),
+        enabled: bool,
+    }
+
+    let settings = || Settings {
+        name: String::from("fan"),
+        limits: (-40, 85),
+        enabled: true,
+    };
+    roundtrip(Cbor(settings()));
+    roundtrip(Postcard(settings()));
+    assert_eq!(
+        Cbor::<Settings>::decode(b"nope"),
+        Err(PkError::InvalidData("CBOR"))
+    );
+    assert_eq!(
+        Postcard::<Settings>::decode(&[]),
+        Err(PkError::InvalidData("postcard"))
+    );
+}
+
+#[test]
+fn test_typed_variable_validation() {
+    let changes = Rc::new(Cell::new(0));
+    let seen = changes.clone();
+    let device = PkCommand::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![]).with_typed(
+            TypedVariable::new("LIMIT", 10i32)
+                .validate(|limit| {
+                    if *limit >= 0 {
+                        Ok(())
+                    } else {
+                        Err(String::from("negative limit"))
+                    }
+                })
+                .on_change(move |limit| seen.set(limit)),
+        ),
+        PkHashmapMethod::new(vec![]),
+    );
+    let host = host();
+
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::SendVariable,
+            "LIMIT",
+            Some(42i32.encode())
+        )
+        .0,
+        Some(TransactionOutcome::Completed(None))
+    );
+    assert_eq!(changes.get(), 42);
+
+    for (value, reason) in [
+        ((-1i32).encode(), "VARIABLE negative limit"),
+        (vec![1, 2], "INVALID_DATA i32"),
+    ] {
+        assert_eq!(
+            perform(
+                &host,
+                &device,
+                Operation::SendVariable,
+                "LIMIT",
+                Some(value)
+            )
+            .0,
+            Some(TransactionOutcome::Failed {
+                reason: PkError::Remote(String::from(reason)),
+                origin: FailureOrigin::Remote,
+            })
+        );
+    }
+    // Rejected values are neither stored nor reported.
+    assert_eq!(changes.get(), 42);
+    assert_eq!(
+        perform(&host, &device, Operation::RequireVariable, "LIMIT", None).0,
+        Some(TransactionOutcome::Completed(Some(42i32.encode())))
+    );
+}
@@ -0,0 +1,204 @@
+//! A simulated link between a Host and a Device, shared by the integration tests.
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use pk_command::types::{Command, Operation, PkError, TransactionOutcome};
+use pk_command::{
+    PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapMethodBuilder, PkHashmapVariable,
+    PkMethodAccessor, PkPromise, PkVariableAccessor, Pollable,
+};
+
+pub type Pk = PkCommand<PkHashmapVariable, PkHashmapMethod, Instant>;
+
+/// A Host with 64-byte packets and no variables or methods of its own.
+pub fn host() -> Pk {
+    host_with(PkCommandConfig::default(64))
+}
+
+/// A Host with the given configuration and no variables or methods of its own.
+pub fn host_with(config: PkCommandConfig) -> Pk {
+    PkCommand::new(
+        config,
+        PkHashmapVariable::new(vec![]),
+        PkHashmapMethod::new(vec![]),
+    )
+}
+
+/// A method which always fails.
+struct Failing;
+
+impl Pollable for Failing {
+    fn poll(&self) -> Poll<Result<Option<Vec<u8>>, PkError>> {
+        Poll::Ready(Err(PkError::Method(String::from("out of paper"))))
+    }
+}
+
+/// A Device with a 4-byte `SPEED` variable, a 200-byte `TABLE` variable, an `ECHOO` method,
+/// a `SLEEP` method which returns its parameter after a while, and a `PRINT` method which fails.
+pub fn device(config: PkCommandConfig) -> Pk {
+    device_with(config, |methods| methods)
+}
+
+/// A Device like [`device`], with the methods added by `methods`.
+pub fn device_with(
+    config: PkCommandConfig,
+    methods: impl FnOnce(PkHashmapMethodBuilder) -> PkHashmapMethodBuilder,
+) -> Pk {
+    PkCommand::new(
+        config,
+        PkHashmapVariable::new(vec![
+            (
+                String::from("SPEED"),
+                Some(vec![1, 2, 3, 4]),
+                Box::new(|_| {}),
+            ),
+            (String::from("TABLE"), Some(vec![7; 200]), Box::new(|_| {})),
+        ]),
+        methods(
+            PkHashmapMethod::builder()
+                .method("ECHOO", |param: Vec<u8>| param)
+                .raw(
+                    "SLEEP",
+                    Box::new(|param| {
+                        PkPromise::execute(move |resolve| {
+                            std::thread::sleep(Duration::from_millis(50));
+                            resolve(param.unwrap_or_default())
+                        })
+                    }),
+                )
+                .raw(
+                    "PRINT",
+                    Box::new(|_| Box::pin(Failing) as Pin<Box<dyn Pollable>>),
+                ),
+        )
+        .build(),
+    )
+}
+
+/// A Device like [`device`], with a `LONGT` method that runs until it is cancelled, and then sets
+/// `stopped`.
+pub fn cancellable_device(config: PkCommandConfig, stopped: Arc<AtomicBool>) -> Pk {
+    device_with(config, |methods| {
+        methods.raw(
+            "LONGT",
+            Box::new(move |_| {
+                let stopped = stopped.clone();
+                PkPromise::execute_cancellable(move |_resolve, is_cancelled| {
+                    while !is_cancelled() {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                    stopped.store(true, Ordering::Relaxed);
+                })
+            }),
+        )
+    })
+}
+
+/// Waits up to 100ms for `flag` to be set. Returns whether it was.
+pub fn wait_for(flag: &AtomicBool) -> bool {
+    for _ in 0..100 {
+        if flag.load(Ordering::Relaxed) {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    false
+}
+
+/// Performs a chain over a lossless link and returns its outcome, along with every packet that
+/// went over the link.
+pub fn perform(
+    host: &Pk,
+    device: &Pk,
+    operation: Operation,
+    object: &str,
+    param: Option<Vec<u8>>,
+) -> (Option<TransactionOutcome>, Vec<Command>) {
+    let mut packets = Vec::new();
+    let outcome = perform_over(host, device, operation, Some(object), param, |bytes, _| {
+        packets.push(Command::parse(bytes).unwrap());
+        1
+    });
+    (outcome, packets)
+}
+
+/// Performs a chain over `link` and returns its outcome. (See [`drive`].)
+pub fn perform_over(
+    host: &Pk,
+    device: &Pk,
+    operation: Operation,
+    object: Option<&str>,
+    param: Option<Vec<u8>>,
+    link: impl FnMut(&mut Vec<u8>, bool) -> usize,
+) -> Option<TransactionOutcome> {
+    host.perform(operation, object.map(String::from), param)
+        .unwrap();
+    drive(host, device, link);
+    host.take_outcome()
+}
+
+/// Drives `host` and `device` until both are idle and nothing is in flight, and returns the result
+/// of the Host's transaction.
+pub fn run_chain(
+    host: &Pk,
+    device: &Pk,
+    link: impl FnMut(&mut Vec<u8>, bool) -> usize,
+) -> Option<Vec<u8>> {
+    drive(host, device, link);
+    host.get_return_data()
+}
+
+/// Drives `host` and `device` until both are idle and nothing is in flight. The result is left
+/// on the Host.
+///
+/// Every packet goes through `link`, along with whether it goes from the Host to the Device.
+/// `link` may alter the packet, and returns how many copies of it to deliver: `0` drops it,
+/// `2` duplicates it. Each side receives at most one packet per poll.
+pub fn drive<HV, HM, DV, DM>(
+    host: &PkCommand<HV, HM, Instant>,
+    device: &PkCommand<DV, DM, Instant>,
+    mut link: impl FnMut(&mut Vec<u8>, bool) -> usize,
+) where
+    HV: PkVariableAccessor,
+    HM: PkMethodAccessor,
+    DV: PkVariableAccessor,
+    DM: PkMethodAccessor,
+{
+    let mut to_device = VecDeque::new();
+    let mut to_host = VecDeque::new();
+    for _ in 0..10000 {
+        if let Some(bytes) = to_host.pop_front() {
+            let _ = host.incoming_command(bytes);
+        }
+        if let Some(cmd) = host.poll() {
+            let mut bytes = cmd.to_bytes();
+            let copies = link(&mut bytes, true);
+            to_device.extend(std::iter::repeat_n(bytes, copies));
+        }
+        if let Some(bytes) = to_device.pop_front() {
+            let _ = device.incoming_command(bytes);
+        }
+        if let Some(cmd) = device.poll() {
+            let mut bytes = cmd.to_bytes();
+            let copies = link(&mut bytes, false);
+            to_host.extend(std::iter::repeat_n(bytes, copies));
+        }
+        if host.is_complete() && device.is_complete() && to_host.is_empty() && to_device.is_empty()
+        {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    panic!("the chain did not finish");
+}
+
+/// Returns `true` if `bytes` is a command with the given operation.
+pub fn is(bytes: &[u8], operation: Operation) -> bool {
+    Command::parse(bytes).is_ok_and(|cmd| cmd.operation == operation)
+}
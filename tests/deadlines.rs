@@ -0,0 +1,103 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+
+use common::{cancellable_device, drive, host_with, is, perform, wait_for};
+use pk_command::PkCommandConfig;
+use pk_command::types::{FailureOrigin, Operation, PkError, TransactionOutcome};
+
+fn timed_out(origin: FailureOrigin) -> Option<TransactionOutcome> {
+    Some(TransactionOutcome::Failed {
+        reason: match origin {
+            FailureOrigin::Local => PkError::Timeout,
+            FailureOrigin::Remote => PkError::Remote(String::from("TIMEOUT")),
+        },
+        origin,
+    })
+}
+
+#[test]
+fn test_deadline() {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let host = host_with(PkCommandConfig::default(64).with_deadline(400));
+    let device = cancellable_device(PkCommandConfig::default(64), stopped.clone());
+
+    let start = Instant::now();
+    assert_eq!(
+        perform(&host, &device, Operation::Invoke, "LONGT", None).0,
+        timed_out(FailureOrigin::Local)
+    );
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    assert!(start.elapsed() < Duration::from_millis(1000));
+    assert_eq!(device.take_outcome(), timed_out(FailureOrigin::Remote));
+    assert!(wait_for(&stopped));
+
+    // A chain that is over in time is not affected.
+    assert_eq!(
+        perform(&host, &device, Operation::Invoke, "ECHOO", None).0,
+        Some(TransactionOutcome::Completed(None))
+    );
+}
+
+#[test]
+fn test_perform_within() {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let host = host_with(PkCommandConfig::default(64).with_deadline(60_000));
+    let device = cancellable_device(PkCommandConfig::default(64), stopped);
+
+    let start = Instant::now();
+    host.perform_within(
+        Operation::Invoke,
+        Some(String::from("LONGT")),
+        None,
+        Duration::from_millis(100),
+    )
+    .unwrap();
+    drive(&host, &device, |_, _| 1);
+    assert_eq!(host.take_outcome(), timed_out(FailureOrigin::Local));
+    assert!(start.elapsed() < Duration::from_millis(1000));
+}
+
+#[test]
+fn test_max_awaits() {
+    let stopped = Arc::new(AtomicBool::new(false));
+    // An `AWAIT` every 20ms.
+    let host = host_with(PkCommandConfig::new(100, 500, 20, 64).with_max_awaits(3));
+    let device = cancellable_device(PkCommandConfig::new(100, 500, 20, 64), stopped.clone());
+
+    host.perform(Operation::Invoke, Some(String::from("LONGT")), None)
+        .unwrap();
+    let mut awaits = 0;
+    drive(&host, &device, |bytes, to_device| {
+        awaits += (!to_device && is(bytes, Operation::Await)) as usize;
+        1
+    });
+    // The fourth one is refused.
+    assert_eq!(awaits, 4);
+    assert_eq!(host.take_outcome(), timed_out(FailureOrigin::Local));
+    assert!(wait_for(&stopped));
+}
+
+#[test]
+fn test_max_execution() {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let host = host_with(PkCommandConfig::default(64));
+    let device = cancellable_device(
+        PkCommandConfig::default(64).with_max_execution(200),
+        stopped.clone(),
+    );
+
+    let start = Instant::now();
+    assert_eq!(
+        perform(&host, &device, Operation::Invoke, "LONGT", None).0,
+        timed_out(FailureOrigin::Remote)
+    );
+    assert!(start.elapsed() < Duration::from_millis(1000));
+    assert_eq!(device.take_outcome(), timed_out(FailureOrigin::Local));
+    assert!(wait_for(&stopped));
+}
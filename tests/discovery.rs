@@ -0,0 +1,71 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use common::{Pk, host, perform_over};
+use pk_command::types::{
+    AccessMode, Catalogue, MethodInfo, Operation, PkError, TransactionOutcome, VariableInfo,
+};
+use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable, PkPromise};
+
+/// Lists the objects of `device`. (See [`perform_over`].)
+fn list(host: &Pk, device: &Pk) -> Result<Catalogue, PkError> {
+    match perform_over(host, device, Operation::ListObjects, None, None, |_, _| 1) {
+        Some(TransactionOutcome::Completed(data)) => Catalogue::parse(&data.unwrap_or_default()),
+        outcome => panic!("LISTO did not complete: {outcome:?}"),
+    }
+}
+
+#[test]
+fn test_list_objects() {
+    let device = PkCommand::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![
+            (String::from("VARIB"), None, Box::new(|_| {})),
+            (String::from("VARIA"), None, Box::new(|_| {})),
+        ])
+        .describe("VARIA", "The first\nvariable"),
+        PkHashmapMethod::new(vec![(
+            String::from("ECHOO"),
+            Box::new(|param| PkPromise::execute(|resolve| resolve(param.unwrap_or_default()))),
+        )])
+        .describe("ECHOO", "Returns its parameter"),
+    );
+    assert_eq!(
+        list(&host(), &device),
+        Ok(Catalogue {
+            variables: vec![
+                VariableInfo {
+                    name: String::from("VARIA"),
+                    access: AccessMode::ReadWrite,
+                    description: String::from("The first variable"),
+                },
+                VariableInfo {
+                    name: String::from("VARIB"),
+                    access: AccessMode::ReadWrite,
+                    description: String::new(),
+                },
+            ],
+            methods: vec![MethodInfo {
+                name: String::from("ECHOO"),
+                description: String::from("Returns its parameter"),
+            }],
+        })
+    );
+}
+
+#[test]
+fn test_list_nothing() {
+    assert_eq!(list(&host(), &host()), Ok(Catalogue::default()));
+}
+
+#[test]
+fn test_invalid_catalogue() {
+    for bytes in [&b"X VARIA\n"[..], b"V VARIA\n", b"V RWX VARIA\n", b"M\n"] {
+        assert_eq!(
+            Catalogue::parse(bytes),
+            Err(PkError::InvalidData("a catalogue"))
+        );
+    }
+}
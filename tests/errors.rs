@@ -0,0 +1,211 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use common::{device, host, host_with, is, perform, perform_over, run_chain};
+use pk_command::PkCommandConfig;
+use pk_command::types::{Checksum, Command, FailureOrigin, Operation, PkError, TransactionOutcome};
+
+#[test]
+fn test_perform_errors() {
+    let host = host();
+    assert_eq!(
+        host.perform(Operation::Data, None, None),
+        Err(PkError::NotRootOperation(Operation::Data))
+    );
+    assert_eq!(host.perform(Operation::GetVersion, None, None), Ok(()));
+    assert_eq!(
+        host.perform(Operation::GetVersion, None, None),
+        Err(PkError::Busy)
+    );
+}
+
+#[test]
+fn test_incoming_command_errors() {
+    let device = device(PkCommandConfig::default(64));
+    assert_eq!(
+        device.incoming_command(b"!!STA".to_vec()),
+        Err(PkError::TooShort)
+    );
+    assert_eq!(
+        device.incoming_command(b"!!HELLO".to_vec()),
+        Err(PkError::UnknownOperation)
+    );
+    assert_eq!(
+        device.incoming_command(b"!!SENDV_VARIA".to_vec()),
+        Err(PkError::MissingSeparator)
+    );
+    assert_eq!(
+        device.incoming_command(b"!!SENDV VAR".to_vec()),
+        Err(PkError::InvalidLength)
+    );
+    assert_eq!(device.poll(), None);
+}
+
+#[test]
+fn test_error_description_on_the_wire() {
+    let (host, device) = (host(), device(PkCommandConfig::default(64)));
+    let mut descriptions = Vec::new();
+
+    host.perform(Operation::Invoke, Some(String::from("NOPE!")), None)
+        .unwrap();
+    run_chain(&host, &device, |bytes, _| {
+        if is(bytes, Operation::Error) {
+            descriptions.push(Command::parse(bytes).unwrap().data.unwrap());
+        }
+        1
+    });
+
+    // A stable code followed by the details, whatever the Display text says.
+    let expected = PkError::Method(String::from("method not found"));
+    assert_eq!(descriptions, vec![expected.to_wire().into_bytes()]);
+    assert_eq!(expected.to_wire(), "METHOD method not found");
+}
+
+#[test]
+fn test_error_trait() {
+    let e: Box<dyn std::error::Error> = Box::new(PkError::NotRootOperation(Operation::Data));
+    assert_eq!(e.to_string(), "SDATA is not a root operation");
+}
+
+#[test]
+fn test_outcome_completed() {
+    let (host, device) = (host(), device(PkCommandConfig::default(64)));
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::Invoke,
+            "ECHOO",
+            Some(b"hello".to_vec())
+        )
+        .0,
+        Some(TransactionOutcome::Completed(Some(b"hello".to_vec())))
+    );
+    // The outcome is taken only once.
+    assert_eq!(host.take_outcome(), None);
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::Invoke,
+            "ECHOO",
+            Some(b"".to_vec())
+        )
+        .0,
+        Some(TransactionOutcome::Completed(None))
+    );
+}
+
+#[test]
+fn test_outcome_remote_failure() {
+    let (host, device) = (host(), device(PkCommandConfig::default(64)));
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::Invoke,
+            "PRINT",
+            Some(b"hello".to_vec())
+        )
+        .0,
+        Some(TransactionOutcome::Failed {
+            reason: PkError::Remote(String::from("METHOD out of paper")),
+            origin: FailureOrigin::Remote,
+        })
+    );
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::Invoke,
+            "NOPE!",
+            Some(b"hello".to_vec())
+        )
+        .0,
+        Some(TransactionOutcome::Failed {
+            reason: PkError::Remote(String::from("METHOD method not found")),
+            origin: FailureOrigin::Remote,
+        })
+    );
+}
+
+#[test]
+fn test_device_forgets_failed_chain() {
+    let (host, device) = (host(), device(PkCommandConfig::default(64)));
+    perform(&host, &device, Operation::Invoke, "PRINT", None);
+
+    // The failure of the previous chain is not reported again once the Device serves another one.
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::Invoke,
+            "ECHOO",
+            Some(b"hello".to_vec())
+        )
+        .0,
+        Some(TransactionOutcome::Completed(Some(b"hello".to_vec())))
+    );
+    assert_eq!(device.take_outcome(), None);
+}
+
+#[test]
+fn test_outcome_local_failure() {
+    let host = host_with(PkCommandConfig::default(64).with_checksum(Checksum::Crc16));
+    let device = device(PkCommandConfig::default(64).with_checksum(Checksum::Crc16));
+    let outcome = perform_over(
+        &host,
+        &device,
+        Operation::Invoke,
+        Some("ECHOO"),
+        Some(b"hello".to_vec()),
+        |bytes, host_to_device| {
+            if !host_to_device && is(bytes, Operation::EndTransaction) {
+                let last = bytes.len() - 1;
+                bytes[last] ^= 0x01;
+            }
+            1
+        },
+    );
+    assert_eq!(
+        outcome,
+        Some(TransactionOutcome::Failed {
+            reason: PkError::ChecksumMismatch,
+            origin: FailureOrigin::Local,
+        })
+    );
+}
+
+#[test]
+fn test_send_variable_failure() {
+    let host = host();
+    let device = device(PkCommandConfig::default(64));
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::SendVariable,
+            "NOPE!",
+            Some(b"value".to_vec())
+        )
+        .0,
+        Some(TransactionOutcome::Failed {
+            reason: PkError::VariableNotFound(String::from("NOPE!")),
+            origin: FailureOrigin::Remote,
+        })
+    );
+
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::SendVariable,
+            "SPEED",
+            Some(vec![5, 6, 7, 8])
+        )
+        .0,
+        Some(TransactionOutcome::Completed(None))
+    );
+}
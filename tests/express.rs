@@ -0,0 +1,207 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use common::{device, drive, host_with, perform};
+use pk_command::types::{Checksum, Command, Operation, TransactionOutcome};
+use pk_command::{PkCommandConfig, PkVariableAccessor};
+
+fn config(express: bool) -> PkCommandConfig {
+    PkCommandConfig::default(64).with_express(express)
+}
+
+fn operations(packets: &[Command]) -> Vec<Operation> {
+    packets.iter().map(|cmd| cmd.operation).collect()
+}
+
+#[test]
+fn test_small_read_takes_four_packets() {
+    let read = |express| {
+        let (host, device) = (host_with(config(express)), device(config(express)));
+        let (outcome, packets) = perform(&host, &device, Operation::RequireVariable, "SPEED", None);
+        assert_eq!(
+            outcome,
+            Some(TransactionOutcome::Completed(Some(vec![1, 2, 3, 4])))
+        );
+        packets
+    };
+    assert_eq!(read(false).len(), 16);
+    let packets = read(true);
+    assert_eq!(
+        operations(&packets),
+        [
+            Operation::Start,
+            Operation::Acknowledge,
+            Operation::RequireVariable,
+            Operation::Acknowledge
+        ]
+    );
+    assert_eq!(packets[1].data.as_deref(), Some(&b"EXPRESS"[..]));
+    assert_eq!(packets[3].data.as_deref(), Some(&[1, 2, 3, 4][..]));
+}
+
+#[test]
+fn test_small_write_and_call() {
+    let (host, device) = (host_with(config(true)), device(config(true)));
+    let (outcome, packets) = perform(
+        &host,
+        &device,
+        Operation::SendVariable,
+        "SPEED",
+        Some(vec![9; 4]),
+    );
+    assert_eq!(outcome, Some(TransactionOutcome::Completed(None)));
+    assert_eq!(packets.len(), 4);
+    assert_eq!(packets[2].data.as_deref(), Some(&[9; 4][..]));
+    assert_eq!(
+        device.variables().get(String::from("SPEED")),
+        Some(vec![9; 4])
+    );
+
+    // A method which returns right away answers in the ACKNO too.
+    let (outcome, packets) = perform(
+        &host,
+        &device,
+        Operation::Invoke,
+        "ECHOO",
+        Some(b"hi".to_vec()),
+    );
+    assert_eq!(
+        outcome,
+        Some(TransactionOutcome::Completed(Some(b"hi".to_vec())))
+    );
+    assert_eq!(packets.len(), 4);
+}
+
+#[test]
+fn test_running_method_answers_with_rturn() {
+    let (host, device) = (host_with(config(true)), device(config(true)));
+    let (outcome, packets) = perform(
+        &host,
+        &device,
+        Operation::Invoke,
+        "SLEEP",
+        Some(b"zz".to_vec()),
+    );
+    assert_eq!(
+        outcome,
+        Some(TransactionOutcome::Completed(Some(b"zz".to_vec())))
+    );
+    // AWAIT stands for the ACKNO of the root operation, and RTURN carries the result.
+    assert_eq!(
+        operations(&packets),
+        [
+            Operation::Start,
+            Operation::Acknowledge,
+            Operation::Invoke,
+            Operation::Await,
+            Operation::Acknowledge,
+            Operation::Return,
+            Operation::Acknowledge
+        ]
+    );
+    assert_eq!(packets[5].data.as_deref(), Some(&b"zz"[..]));
+}
+
+#[test]
+fn test_large_payloads_fall_back() {
+    let (host, device) = (host_with(config(true)), device(config(true)));
+
+    // The parameter does not fit in the root operation: the Host does not offer an express chain.
+    let (outcome, packets) = perform(
+        &host,
+        &device,
+        Operation::SendVariable,
+        "TABLE",
+        Some(vec![8; 100]),
+    );
+    assert_eq!(outcome, Some(TransactionOutcome::Completed(None)));
+    assert_eq!(packets[0].data, None);
+    assert!(packets.iter().any(|cmd| cmd.operation == Operation::Query));
+
+    // The result does not fit in the ACKNO: RTURN stands for it, followed by SDATA and ENDTR.
+    let (outcome, packets) = perform(&host, &device, Operation::RequireVariable, "TABLE", None);
+    assert_eq!(
+        outcome,
+        Some(TransactionOutcome::Completed(Some(vec![8; 100])))
+    );
+    assert_eq!(packets[3].operation, Operation::Return);
+    assert!(!packets.iter().any(|cmd| cmd.operation == Operation::Query));
+    assert!(packets.iter().any(|cmd| cmd.operation == Operation::Data));
+}
+
+#[test]
+fn test_express_with_plain_peer() {
+    for (host_express, device_express) in [(true, false), (false, true)] {
+        let (host, device) = (
+            host_with(config(host_express)),
+            device(config(device_express)),
+        );
+        let (outcome, packets) = perform(&host, &device, Operation::RequireVariable, "SPEED", None);
+        assert_eq!(
+            outcome,
+            Some(TransactionOutcome::Completed(Some(vec![1, 2, 3, 4])))
+        );
+        assert_eq!(packets.len(), 16);
+    }
+}
+
+#[test]
+fn test_express_on_lossy_link() {
+    let config = PkCommandConfig::new(20, 500, 30, 64)
+        .with_express(true)
+        .with_checksum(Checksum::Crc16);
+    let (host, device) = (host_with(config.clone()), device(config));
+    let chains = [
+        (Operation::SendVariable, "SPEED", Some(vec![5; 4]), None),
+        (Operation::RequireVariable, "SPEED", None, Some(vec![5; 4])),
+        (
+            Operation::Invoke,
+            "ECHOO",
+            Some(b"ok".to_vec()),
+            Some(b"ok".to_vec()),
+        ),
+        (
+            Operation::Invoke,
+            "SLEEP",
+            Some(b"zz".to_vec()),
+            Some(b"zz".to_vec()),
+        ),
+        (
+            Operation::RequireVariable,
+            "TABLE",
+            None,
+            Some(vec![7; 200]),
+        ),
+    ];
+    // Lose every third packet, and corrupt every other one carrying a result inline.
+    let (mut count, mut inline) = (0, 0);
+    for (operation, object, param, result) in chains {
+        host.perform(operation, Some(String::from(object)), param)
+            .unwrap();
+        drive(&host, &device, |bytes, to_device| {
+            count += 1;
+            let cmd = Command::parse(bytes).unwrap();
+            let carries_result = (cmd.operation == Operation::Return
+                || (cmd.operation == Operation::Acknowledge
+                    && cmd.object.as_deref() != Some("START")))
+                && cmd.data.is_some();
+            if !to_device && carries_result {
+                inline += 1;
+                if inline % 2 == 1 {
+                    let last = bytes.len() - 1;
+                    bytes[last] ^= 0xFF;
+                }
+            }
+            usize::from(count % 3 != 0)
+        });
+        assert_eq!(
+            host.take_outcome(),
+            Some(TransactionOutcome::Completed(result)),
+            "{}",
+            operation.to_name()
+        );
+    }
+    assert!(inline > 2);
+}
@@ -0,0 +1,139 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Pk, device, drive, host_with, is, run_chain};
+use pk_command::PkCommandConfig;
+use pk_command::types::{
+    Capabilities, Checksum, Command, FailureOrigin, Operation, PkError, TransactionOutcome,
+};
+
+fn handshake(host: &Pk, device: &Pk) -> TransactionOutcome {
+    host.handshake().unwrap();
+    drive(host, device, |_, _| 1);
+    host.take_outcome().unwrap()
+}
+
+#[test]
+fn test_handshake_adopts_minimum_values() {
+    let host = host_with(PkCommandConfig::new(100, 400, 300, 64));
+    let device = device(PkCommandConfig::new(50, 500, 300, 32));
+    let outcome = handshake(&host, &device);
+    assert_eq!(
+        outcome,
+        TransactionOutcome::Completed(Some(env!("CARGO_PKG_VERSION").as_bytes().to_vec()))
+    );
+
+    let session = host.capabilities();
+    assert_eq!(session, device.capabilities());
+    assert_eq!(session.packet_limit, 32);
+    assert_eq!(session.ack_timeout, Duration::from_millis(50));
+    assert_eq!(session.inter_command_timeout, Duration::from_millis(400));
+
+    // Both sides now slice their data for the smaller packet limit.
+    host.perform(
+        Operation::SendVariable,
+        Some(String::from("SPEED")),
+        Some(vec![b'y'; 100]),
+    )
+    .unwrap();
+    run_chain(&host, &device, |bytes, _| {
+        assert!(bytes.len() <= 32);
+        1
+    });
+    host.perform(
+        Operation::RequireVariable,
+        Some(String::from("SPEED")),
+        None,
+    )
+    .unwrap();
+    let value = run_chain(&host, &device, |bytes, _| {
+        assert!(bytes.len() <= 32);
+        1
+    });
+    assert_eq!(value, Some(vec![b'y'; 100]));
+}
+
+#[test]
+fn test_handshake_drops_unsupported_extensions() {
+    let host = host_with(PkCommandConfig::default(64).with_checksum(Checksum::Crc16));
+    let device = device(PkCommandConfig::default(64));
+    handshake(&host, &device);
+    assert!(!host.capabilities().supports(Capabilities::CHECKSUM));
+
+    // The checksum is not offered anymore.
+    host.perform(
+        Operation::RequireVariable,
+        Some(String::from("SPEED")),
+        None,
+    )
+    .unwrap();
+    let mut start = None;
+    drive(&host, &device, |bytes, _| {
+        if is(bytes, Operation::Start) {
+            start = Some(Command::parse(bytes).unwrap());
+        }
+        1
+    });
+    assert_eq!(start.unwrap().object, None);
+}
+
+#[test]
+fn test_incompatible_peer_is_refused() {
+    let host = host_with(PkCommandConfig::default(64));
+    let device = device(PkCommandConfig::default(16));
+    assert_eq!(
+        handshake(&host, &device),
+        TransactionOutcome::Failed {
+            reason: PkError::Incompatible(String::from("packet limit 16 is too small")),
+            origin: FailureOrigin::Remote,
+        }
+    );
+    // Neither side changed its parameters.
+    assert_eq!(host.capabilities().packet_limit, 64);
+    assert_eq!(device.capabilities().packet_limit, 16);
+}
+
+#[test]
+fn test_plain_version_request_is_unchanged() {
+    let host = host_with(PkCommandConfig::default(64));
+    let device = device(PkCommandConfig::default(32));
+    host.perform(Operation::GetVersion, None, None).unwrap();
+    drive(&host, &device, |_, _| 1);
+    assert_eq!(
+        host.get_return_data(),
+        Some(env!("CARGO_PKG_VERSION").as_bytes().to_vec())
+    );
+    assert_eq!(host.capabilities().packet_limit, 64);
+}
+
+#[test]
+fn test_capabilities_negotiation() {
+    let own = PkCommandConfig::default(64).capabilities();
+    assert_eq!(
+        own.negotiate(&Capabilities {
+            major: own.major + 1,
+            ..own
+        }),
+        Err(PkError::Incompatible(format!(
+            "protocol version {} is not {}",
+            own.major + 1,
+            own.major
+        )))
+    );
+    // Unknown keys are ignored, missing ones are not.
+    let mut bytes = own.to_bytes();
+    bytes.extend(b" FUTURE=42");
+    assert_eq!(Capabilities::parse(&bytes), Ok(own));
+    assert_eq!(
+        Capabilities::parse(b"MAJOR=1 PACKET=64"),
+        Err(PkError::InvalidData("capabilities"))
+    );
+    assert_eq!(
+        PkError::from_remote(&PkError::Incompatible(String::from("reason")).to_wire()),
+        PkError::Incompatible(String::from("reason"))
+    );
+}
@@ -0,0 +1,100 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use std::time::Duration;
+
+use common::{device, host};
+use pk_command::PkCommandConfig;
+use pk_command::types::{Command, Operation, PkError, QueueOverflow};
+
+const PAYLOAD: &[u8] = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua.";
+
+fn acked(cmd: Option<Command>) -> (u16, Option<String>) {
+    let cmd = cmd.expect("an ACKNO");
+    assert_eq!(cmd.operation, Operation::Acknowledge);
+    (cmd.msg_id, cmd.object)
+}
+
+#[test]
+fn test_burst_is_processed_in_order() {
+    let device = device(PkCommandConfig::default(64));
+    assert_eq!(device.incoming_command(b"!!START".to_vec()), Ok(3));
+    assert_eq!(device.incoming_command(b"!\"SENDV SPEED".to_vec()), Ok(2));
+    assert_eq!(device.incoming_command(b"!#SDATA VARIA hi".to_vec()), Ok(1));
+
+    assert_eq!(acked(device.poll()), (0, Some(String::from("START"))));
+    assert_eq!(acked(device.poll()), (1, Some(String::from("SENDV"))));
+    assert_eq!(acked(device.poll()), (2, Some(String::from("SDATA"))));
+}
+
+#[test]
+fn test_overflow_drops_oldest() {
+    let device =
+        device(PkCommandConfig::default(64).with_inbound_queue(2, QueueOverflow::DropOldest));
+    assert_eq!(device.incoming_command(b"!!INVOK ECHOO".to_vec()), Ok(1));
+    assert_eq!(device.incoming_command(b"!!START".to_vec()), Ok(0));
+    // The stray INVOK goes, and the chain starts normally.
+    assert_eq!(device.dropped_commands(), 0);
+    assert_eq!(device.incoming_command(b"!\"INVOK ECHOO".to_vec()), Ok(0));
+    assert_eq!(device.dropped_commands(), 1);
+
+    assert_eq!(acked(device.poll()), (0, Some(String::from("START"))));
+    assert_eq!(acked(device.poll()), (1, Some(String::from("INVOK"))));
+}
+
+#[test]
+fn test_overflow_rejects() {
+    let device = device(PkCommandConfig::default(64).with_inbound_queue(2, QueueOverflow::Reject));
+    assert_eq!(device.incoming_command(b"!!START".to_vec()), Ok(1));
+    assert_eq!(device.incoming_command(b"!\"INVOK ECHOO".to_vec()), Ok(0));
+    assert_eq!(
+        device.incoming_command(b"!#EMPTY".to_vec()),
+        Err(PkError::QueueFull)
+    );
+    assert_eq!(device.dropped_commands(), 0);
+
+    // Polling makes room again.
+    assert_eq!(acked(device.poll()), (0, Some(String::from("START"))));
+    assert_eq!(device.incoming_command(b"!#EMPTY".to_vec()), Ok(0));
+    assert_eq!(acked(device.poll()), (1, Some(String::from("INVOK"))));
+    assert_eq!(acked(device.poll()), (2, Some(String::from("EMPTY"))));
+}
+
+#[test]
+fn test_chain_with_bursty_link() {
+    // Packets are delivered in bursts, several of them between two polls of the receiver.
+    let host = host();
+    let device = device(PkCommandConfig::default(64));
+    let (mut to_device, mut to_host) = (Vec::new(), Vec::new());
+
+    host.perform(
+        Operation::Invoke,
+        Some(String::from("ECHOO")),
+        Some(PAYLOAD.to_vec()),
+    )
+    .unwrap();
+    for i in 0..10000 {
+        if i % 4 == 0 {
+            for bytes in to_host.drain(..) {
+                host.incoming_command(bytes).unwrap();
+            }
+            for bytes in to_device.drain(..) {
+                device.incoming_command(bytes).unwrap();
+            }
+        }
+        if let Some(cmd) = host.poll() {
+            to_device.push(cmd.to_bytes());
+        }
+        if let Some(cmd) = device.poll() {
+            to_host.push(cmd.to_bytes());
+        }
+        if host.is_complete() && device.is_complete() {
+            assert_eq!(host.get_return_data(), Some(PAYLOAD.to_vec()));
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    panic!("the chain did not finish");
+}
@@ -0,0 +1,316 @@
+#![cfg(feature = "macros")]
+#![cfg(test)]
+
+mod common;
+
+use std::cell::{Cell, RefCell};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Instant;
+
+use common::drive;
+use pk_command::codec::PkCodec;
+use pk_command::types::{
+    AccessMode, FailureOrigin, MethodInfo, Operation, PkError, TransactionOutcome, VariableInfo,
+};
+use pk_command::{
+    PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable, PkPromise, PkVariableAccessor,
+    PkVariables, Pollable, pk_methods,
+};
+
+/// A codec wrapper that encodes a `u16` as big-endian.
+struct BigEndian<T>(T);
+
+impl PkCodec for BigEndian<u16> {
+    fn encode(&self) -> Vec<u8> {
+        self.0.to_be_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, PkError> {
+        Ok(BigEndian(u16::from_be_bytes(
+            bytes.try_into().map_err(|_| PkError::InvalidData("u16"))?,
+        )))
+    }
+}
+
+#[derive(PkVariables)]
+struct Fan {
+    #[pk(description = "Fan speed in RPM", on_change = Self::count)]
+    speed: Cell<u16>,
+    #[pk(name = "VERSN", read_only)]
+    version: RefCell<String>,
+    #[pk(name = "SECRT", write_only)]
+    secret: RefCell<Vec<u8>>,
+    #[pk(name = "RAWBE", codec = BigEndian)]
+    raw: Cell<u16>,
+    #[pk(skip)]
+    changes: Rc<Cell<u32>>,
+}
+
+impl Fan {
+    fn count(&self) {
+        self.changes.set(self.changes.get() + 1);
+    }
+}
+
+struct Calculator;
+
+#[pk_methods]
+impl Calculator {
+    #[pk(name = "ADDXY", description = "Adds two numbers")]
+    fn add(&self, a: i32, b: i32) -> i32 {
+        a + b
+    }
+
+    fn greet(&self, name: String) -> String {
+        format!("Hello, {name}!")
+    }
+
+    #[pk(name = "NOTHN")]
+    fn nothing(&self) {}
+
+    #[pk(name = "SQUAR", pollable)]
+    fn square(&self, x: u64) -> Pin<Box<dyn Pollable>> {
+        PkPromise::execute(move |resolve| resolve((x * x).encode()))
+    }
+
+    #[pk(name = "RESET", guard = Self::locked)]
+    fn reset(&self) {}
+
+    #[pk(skip)]
+    fn locked(&self) -> bool {
+        false
+    }
+}
+
+type Device = PkCommand<Fan, Calculator, Instant>;
+type Host = PkCommand<PkHashmapVariable, PkHashmapMethod, Instant>;
+
+fn host() -> Host {
+    PkCommand::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![]),
+        PkHashmapMethod::new(vec![]),
+    )
+}
+
+fn fan(changes: Rc<Cell<u32>>) -> Fan {
+    Fan {
+        speed: Cell::new(1200),
+        version: RefCell::new(String::from("1.0.0")),
+        secret: RefCell::new(vec![]),
+        raw: Cell::new(0x0102),
+        changes,
+    }
+}
+
+fn device(changes: Rc<Cell<u32>>) -> Device {
+    PkCommand::new(PkCommandConfig::default(64), fan(changes), Calculator)
+}
+
+fn perform(
+    host: &Host,
+    device: &Device,
+    operation: Operation,
+    name: &str,
+    data: Option<Vec<u8>>,
+) -> TransactionOutcome {
+    host.perform(operation, Some(String::from(name)), data)
+        .unwrap();
+    drive(host, device, |_, _| 1);
+    host.take_outcome().unwrap()
+}
+
+#[test]
+fn test_derived_variables() {
+    let changes = Rc::new(Cell::new(0));
+    let (host, device) = (host(), device(changes.clone()));
+
+    assert_eq!(
+        perform(&host, &device, Operation::RequireVariable, "SPEED", None),
+        TransactionOutcome::Completed(Some(1200u16.encode()))
+    );
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::SendVariable,
+            "SPEED",
+            Some(1500u16.encode())
+        ),
+        TransactionOutcome::Completed(None)
+    );
+    assert_eq!(
+        perform(&host, &device, Operation::RequireVariable, "SPEED", None),
+        TransactionOutcome::Completed(Some(1500u16.encode()))
+    );
+    assert_eq!(changes.get(), 1);
+
+    assert_eq!(
+        perform(&host, &device, Operation::RequireVariable, "RAWBE", None),
+        TransactionOutcome::Completed(Some(vec![0x01, 0x02]))
+    );
+    assert_eq!(
+        perform(&host, &device, Operation::RequireVariable, "VERSN", None),
+        TransactionOutcome::Completed(Some(b"1.0.0".to_vec()))
+    );
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::SendVariable,
+            "VERSN",
+            Some(b"2.0.0".to_vec())
+        ),
+        TransactionOutcome::Failed {
+            reason: PkError::AccessDenied(String::from("VERSN")),
+            origin: FailureOrigin::Remote,
+        }
+    );
+    assert_eq!(
+        perform(&host, &device, Operation::RequireVariable, "SECRT", None),
+        TransactionOutcome::Failed {
+            reason: PkError::AccessDenied(String::from("SECRT")),
+            origin: FailureOrigin::Remote,
+        }
+    );
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::SendVariable,
+            "SPEED",
+            Some(vec![1])
+        ),
+        TransactionOutcome::Failed {
+            reason: PkError::Remote(String::from("INVALID_DATA u16")),
+            origin: FailureOrigin::Remote,
+        }
+    );
+    assert_eq!(changes.get(), 1);
+}
+
+#[test]
+fn test_derived_accessor() {
+    let fan = fan(Rc::new(Cell::new(0)));
+
+    assert_eq!(fan.set(String::from("SECRT"), b"hunter2".to_vec()), Ok(()));
+    assert_eq!(fan.secret.borrow().as_slice(), b"hunter2");
+    // Access modes only restrict the Host.
+    assert_eq!(fan.get(String::from("SECRT")), Some(b"hunter2".to_vec()));
+    assert_eq!(fan.set(String::from("VERSN"), b"2.0.0".to_vec()), Ok(()));
+    assert_eq!(fan.access("VERSN"), AccessMode::ReadOnly);
+    assert_eq!(fan.access("SECRT"), AccessMode::WriteOnly);
+    assert_eq!(fan.access("SPEED"), AccessMode::ReadWrite);
+    assert_eq!(fan.set(String::from("RAWBE"), vec![0x12, 0x34]), Ok(()));
+    assert_eq!(fan.raw.get(), 0x1234);
+    assert_eq!(fan.get(String::from("CHANG")), None);
+    assert_eq!(
+        fan.set(String::from("NOVAR"), vec![]),
+        Err(PkError::VariableNotFound(String::from("NOVAR")))
+    );
+    assert_eq!(
+        fan.list(),
+        vec![
+            VariableInfo {
+                name: String::from("SPEED"),
+                access: AccessMode::ReadWrite,
+                description: String::from("Fan speed in RPM"),
+            },
+            VariableInfo {
+                name: String::from("VERSN"),
+                access: AccessMode::ReadOnly,
+                description: String::new(),
+            },
+            VariableInfo {
+                name: String::from("SECRT"),
+                access: AccessMode::WriteOnly,
+                description: String::new(),
+            },
+            VariableInfo {
+                name: String::from("RAWBE"),
+                access: AccessMode::ReadWrite,
+                description: String::new(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_derived_methods() {
+    let (host, device) = (host(), device(Rc::new(Cell::new(0))));
+
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::Invoke,
+            "ADDXY",
+            Some((40i32, 2i32).encode())
+        ),
+        TransactionOutcome::Completed(Some(42i32.encode()))
+    );
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::Invoke,
+            "GREET",
+            Some(b"PK".to_vec())
+        ),
+        TransactionOutcome::Completed(Some(b"Hello, PK!".to_vec()))
+    );
+    assert_eq!(
+        perform(&host, &device, Operation::Invoke, "NOTHN", Some(vec![])),
+        TransactionOutcome::Completed(None)
+    );
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::Invoke,
+            "SQUAR",
+            Some(12u64.encode())
+        ),
+        TransactionOutcome::Completed(Some(144u64.encode()))
+    );
+    assert_eq!(
+        perform(&host, &device, Operation::Invoke, "RESET", Some(vec![])),
+        TransactionOutcome::Failed {
+            reason: PkError::AccessDenied(String::from("RESET")),
+            origin: FailureOrigin::Remote,
+        }
+    );
+    assert_eq!(
+        perform(&host, &device, Operation::Invoke, "ADDXY", Some(vec![1])),
+        TransactionOutcome::Failed {
+            reason: PkError::Remote(String::from("INVALID_DATA fixed-width tuple element")),
+            origin: FailureOrigin::Remote,
+        }
+    );
+    assert_eq!(
+        pk_command::PkMethodAccessor::list(&Calculator),
+        vec![
+            MethodInfo {
+                name: String::from("ADDXY"),
+                description: String::from("Adds two numbers"),
+            },
+            MethodInfo {
+                name: String::from("GREET"),
+                description: String::new(),
+            },
+            MethodInfo {
+                name: String::from("NOTHN"),
+                description: String::new(),
+            },
+            MethodInfo {
+                name: String::from("SQUAR"),
+                description: String::new(),
+            },
+            MethodInfo {
+                name: String::from("RESET"),
+                description: String::new(),
+            },
+        ]
+    );
+}
@@ -0,0 +1,99 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use common::{Pk, drive, host};
+use pk_command::codec::PkCodec;
+use pk_command::types::{FailureOrigin, Operation, PkError, TransactionOutcome};
+use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable, PkPromise};
+
+fn device() -> Pk {
+    PkCommand::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![]),
+        PkHashmapMethod::builder()
+            .method("ADDXY", |(a, b): (i32, i32)| a + b)
+            .method("GREET", |name: String| format!("Hello, {name}!"))
+            .method("NOTHN", |()| {})
+            .threaded_method("SQUAR", |x: u64| {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                x * x
+            })
+            .raw(
+                "ECHOO",
+                Box::new(|param| PkPromise::execute(|resolve| resolve(param.unwrap_or_default()))),
+            )
+            .describe("ADDXY", "Adds two numbers")
+            .build(),
+    )
+}
+
+fn invoke(host: &Pk, device: &Pk, name: &str, param: Vec<u8>) -> TransactionOutcome {
+    host.perform(Operation::Invoke, Some(String::from(name)), Some(param))
+        .unwrap();
+    drive(host, device, |_, _| 1);
+    host.take_outcome().unwrap()
+}
+
+#[test]
+fn test_typed_methods() {
+    let (host, device) = (host(), device());
+
+    assert_eq!(
+        invoke(&host, &device, "ADDXY", (40i32, 2i32).encode()),
+        TransactionOutcome::Completed(Some(42i32.encode()))
+    );
+    assert_eq!(
+        invoke(&host, &device, "GREET", String::from("PK").encode()),
+        TransactionOutcome::Completed(Some(b"Hello, PK!".to_vec()))
+    );
+    assert_eq!(
+        invoke(&host, &device, "SQUAR", 12u64.encode()),
+        TransactionOutcome::Completed(Some(144u64.encode()))
+    );
+    assert_eq!(
+        invoke(&host, &device, "ECHOO", b"raw".to_vec()),
+        TransactionOutcome::Completed(Some(b"raw".to_vec()))
+    );
+    // An empty result is reported as no data.
+    assert_eq!(
+        invoke(&host, &device, "NOTHN", vec![]),
+        TransactionOutcome::Completed(None)
+    );
+}
+
+#[test]
+fn test_typed_method_decode_failure() {
+    let (host, device) = (host(), device());
+
+    for (name, param, expected) in [
+        ("ADDXY", vec![1, 2, 3], "fixed-width tuple element"),
+        ("GREET", vec![0xFF], "UTF-8 string"),
+        ("SQUAR", vec![], "u64"),
+        ("NOTHN", vec![1], "no data"),
+    ] {
+        assert_eq!(
+            invoke(&host, &device, name, param),
+            TransactionOutcome::Failed {
+                reason: PkError::Remote(format!("INVALID_DATA {expected}")),
+                origin: FailureOrigin::Remote,
+            }
+        );
+    }
+}
+
+#[test]
+fn test_device_serves_consecutive_chains() {
+    let (host, device) = (host(), device());
+
+    // Nothing from a chain is left on the Device for the next one.
+    assert_eq!(
+        invoke(&host, &device, "ECHOO", vec![7; 200]),
+        TransactionOutcome::Completed(Some(vec![7; 200]))
+    );
+    assert_eq!(
+        invoke(&host, &device, "ECHOO", b"hi".to_vec()),
+        TransactionOutcome::Completed(Some(b"hi".to_vec()))
+    );
+}
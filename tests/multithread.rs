@@ -15,7 +15,7 @@ mod pk_command_integration_tests {
         operation: PkOperation,
         object: Option<String>,
         data: Option<Vec<u8>>,
-        then: Box<dyn Fn(Vec<u8>) -> ()>,
+        then: Box<dyn Fn(Vec<u8>)>,
     ) {
         let (host_tx, device_rx) = channel::<Vec<u8>>(); // Host -> Device
         let (device_tx, host_rx) = channel::<Vec<u8>>(); // Device -> Host
@@ -36,7 +36,7 @@ mod pk_command_integration_tests {
 
                 host_pkc
                     .perform(operation, object.clone(), data)
-                    .expect(&format!("Host failed to perform {:?}", operation));
+                    .unwrap_or_else(|_| panic!("Host failed to perform {:?}", operation));
                 println!("[Host] Performed {:?} for {:?}", operation, object);
 
                 let mut data: Vec<u8> = b"failed".into();
@@ -94,9 +94,9 @@ mod pk_command_integration_tests {
             .spawn(move || {
                 println!("[Device] Thread started");
                 let variable_listener = move |name: &'static str| {
-                    return move |_: Vec<u8>| {
+                    move |_: Vec<u8>| {
                         println!("[Variable Accessor] {} is changed", name);
-                    };
+                    }
                 };
                 let var_accessor = PkHashmapVariable::new(vec![
                     (
@@ -185,7 +185,7 @@ mod pk_command_integration_tests {
     }
 
     #[test]
-    fn test_requv_simulation() -> () {
+    fn test_requv_simulation() {
         threads_simulation(
             PkOperation::RequireVariable,
             Some("VARIA".to_string()),
@@ -195,7 +195,7 @@ mod pk_command_integration_tests {
     }
 
     #[test]
-    fn test_long_requv_simulation() -> () {
+    fn test_long_requv_simulation() {
         threads_simulation(
             PkOperation::RequireVariable,
             Some("LONGV".to_string()),
@@ -205,7 +205,7 @@ mod pk_command_integration_tests {
     }
 
     #[test]
-    fn test_sendv_simulation() -> () {
+    fn test_sendv_simulation() {
         threads_simulation(
             PkOperation::SendVariable,
             Some("VARIA".to_string()),
@@ -218,7 +218,7 @@ mod pk_command_integration_tests {
     }
 
     #[test]
-    fn test_long_sendv_simulation() -> () {
+    fn test_long_sendv_simulation() {
         threads_simulation(
             PkOperation::SendVariable,
             Some("LONGV".to_string()),
@@ -231,7 +231,7 @@ mod pk_command_integration_tests {
     }
 
     #[test]
-    fn test_invok_echo_simulation() -> () {
+    fn test_invok_echo_simulation() {
         threads_simulation(
             PkOperation::Invoke,
             Some("ECHOO".to_string()),
@@ -241,7 +241,7 @@ mod pk_command_integration_tests {
     }
 
     #[test]
-    fn test_invok_long_echo_simulation() -> () {
+    fn test_invok_long_echo_simulation() {
         threads_simulation(
             PkOperation::Invoke,
             Some("ECHOO".to_string()),
@@ -251,7 +251,7 @@ mod pk_command_integration_tests {
     }
 
     #[test]
-    fn test_invok_deviceid_simulation() -> () {
+    fn test_invok_deviceid_simulation() {
         threads_simulation(
             PkOperation::Invoke,
             Some("DEVID".to_string()),
@@ -261,7 +261,7 @@ mod pk_command_integration_tests {
     }
 
     #[test]
-    fn test_invok_longop_simulation() -> () {
+    fn test_invok_longop_simulation() {
         threads_simulation(
             PkOperation::Invoke,
             Some(String::from("LONGO")),
@@ -271,7 +271,7 @@ mod pk_command_integration_tests {
     }
 
     #[test]
-    fn test_get_version_simulation() -> () {
+    fn test_get_version_simulation() {
         threads_simulation(
             PkOperation::GetVersion,
             None,
@@ -0,0 +1,287 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::Duration;
+
+use common::{Pk, drive, is};
+use pk_command::transport::MemoryTransport;
+use pk_command::types::{Operation, TieBreak, TransactionOutcome};
+use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
+
+type Log = Rc<RefCell<Vec<String>>>;
+
+/// A peer with an `EVENT` variable, which logs who wrote it, and an `ECHOO` method.
+fn peer(name: &'static str, tie_break: TieBreak, log: Log) -> Pk {
+    PkCommand::new(
+        PkCommandConfig::default(64).with_tie_break(tie_break),
+        PkHashmapVariable::new(vec![(
+            String::from("EVENT"),
+            None,
+            Box::new(move |value| {
+                log.borrow_mut()
+                    .push(format!("{name} <- {}", String::from_utf8_lossy(&value)))
+            }),
+        )]),
+        PkHashmapMethod::builder()
+            .method("ECHOO", move |param: Vec<u8>| {
+                let mut reply = name.as_bytes().to_vec();
+                reply.extend(param);
+                reply
+            })
+            .build(),
+    )
+}
+
+/// Polls both peers once before delivering anything, so that both STARTs are on the wire.
+fn collide(a: &Pk, b: &Pk) {
+    let (start_a, start_b) = (a.poll().unwrap(), b.poll().unwrap());
+    assert_eq!(start_a.operation, Operation::Start);
+    assert_eq!(start_b.operation, Operation::Start);
+    a.incoming_command(start_b.to_bytes()).unwrap();
+    b.incoming_command(start_a.to_bytes()).unwrap();
+}
+
+/// Like [`drive`], but collects the outcome of each side as soon as its own chain is over,
+/// before it goes on to serve the other one.
+fn drive_both(
+    a: &Pk,
+    b: &Pk,
+    mut link: impl FnMut(&[u8]) -> usize,
+) -> (Option<TransactionOutcome>, Option<TransactionOutcome>) {
+    let mut outcomes = (None, None);
+    let mut queues = [VecDeque::new(), VecDeque::new()];
+    for _ in 0..10000 {
+        for (this, inbox, outbox) in [(a, 0, 1), (b, 1, 0)] {
+            if let Some(bytes) = queues[inbox].pop_front() {
+                let _ = this.incoming_command(bytes);
+            }
+            if let Some(cmd) = this.poll() {
+                let bytes = cmd.to_bytes();
+                queues[outbox].extend(std::iter::repeat_n(bytes.clone(), link(&bytes)));
+            }
+        }
+        outcomes.0 = outcomes.0.or_else(|| a.take_outcome());
+        outcomes.1 = outcomes.1.or_else(|| b.take_outcome());
+        if a.is_complete() && b.is_complete() && queues.iter().all(VecDeque::is_empty) {
+            return outcomes;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    panic!("the chains did not finish");
+}
+
+fn raise(peer: &Pk, event: &str) {
+    peer.perform(
+        Operation::SendVariable,
+        Some(String::from("EVENT")),
+        Some(event.as_bytes().to_vec()),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_both_sides_initiate_in_turn() {
+    let log = Log::default();
+    let pc = peer("pc", TieBreak::Win, log.clone());
+    let mcu = peer("mcu", TieBreak::Yield, log.clone());
+
+    for round in 0..3 {
+        raise(&mcu, &format!("button {round}"));
+        drive(&pc, &mcu, |_, _| 1);
+        assert_eq!(
+            mcu.take_outcome(),
+            Some(TransactionOutcome::Completed(None))
+        );
+        assert_eq!(pc.take_outcome(), None);
+
+        pc.perform(
+            Operation::Invoke,
+            Some(String::from("ECHOO")),
+            Some(vec![b'0' + round]),
+        )
+        .unwrap();
+        drive(&pc, &mcu, |_, _| 1);
+        assert_eq!(
+            pc.take_outcome(),
+            Some(TransactionOutcome::Completed(Some(vec![
+                b'm',
+                b'c',
+                b'u',
+                b'0' + round
+            ])))
+        );
+        assert_eq!(mcu.take_outcome(), None);
+    }
+    assert_eq!(
+        *log.borrow(),
+        vec!["pc <- button 0", "pc <- button 1", "pc <- button 2"]
+    );
+}
+
+#[test]
+fn test_outcome_survives_peer_chain() {
+    let log = Log::default();
+    let pc = peer("pc", TieBreak::Win, log.clone());
+    let mcu = peer("mcu", TieBreak::Yield, log.clone());
+
+    // The PC has not taken the outcome of its chain yet when the MCU starts one of its own.
+    pc.perform(
+        Operation::Invoke,
+        Some(String::from("ECHOO")),
+        Some(b"1".to_vec()),
+    )
+    .unwrap();
+    drive(&pc, &mcu, |_, _| 1);
+    raise(&mcu, "button");
+    drive(&pc, &mcu, |_, _| 1);
+
+    assert_eq!(
+        mcu.take_outcome(),
+        Some(TransactionOutcome::Completed(None))
+    );
+    assert_eq!(
+        pc.take_outcome(),
+        Some(TransactionOutcome::Completed(Some(b"mcu1".to_vec())))
+    );
+    assert_eq!(pc.take_outcome(), None);
+    assert_eq!(*log.borrow(), vec!["pc <- button"]);
+}
+
+#[test]
+fn test_simultaneous_starts() {
+    let log = Log::default();
+    let pc = peer("pc", TieBreak::Win, log.clone());
+    let mcu = peer("mcu", TieBreak::Yield, log.clone());
+
+    raise(&pc, "from pc");
+    raise(&mcu, "from mcu");
+    collide(&pc, &mcu);
+    let mut starts = 0;
+    let outcomes = drive_both(&pc, &mcu, |bytes| {
+        starts += is(bytes, Operation::Start) as usize;
+        1
+    });
+
+    // The winner goes first, then the side that yielded starts its chain again.
+    assert_eq!(*log.borrow(), vec!["mcu <- from pc", "pc <- from mcu"]);
+    assert_eq!(starts, 1);
+    assert_eq!(
+        outcomes,
+        (
+            Some(TransactionOutcome::Completed(None)),
+            Some(TransactionOutcome::Completed(None))
+        )
+    );
+}
+
+#[test]
+fn test_start_not_yet_sent_yields() {
+    let log = Log::default();
+    let pc = peer("pc", TieBreak::Yield, log.clone());
+    let mcu = peer("mcu", TieBreak::Win, log.clone());
+
+    // The MCU receives the START of the PC before it has sent its own: there is no collision,
+    // even though it would win one.
+    raise(&pc, "from pc");
+    raise(&mcu, "from mcu");
+    mcu.incoming_command(pc.poll().unwrap().to_bytes()).unwrap();
+    assert_eq!(mcu.poll().unwrap().operation, Operation::Acknowledge);
+    drive_both(&pc, &mcu, |_| 1);
+    assert_eq!(*log.borrow(), vec!["mcu <- from pc", "pc <- from mcu"]);
+}
+
+#[test]
+fn test_simultaneous_starts_by_msg_id() {
+    let log = Log::default();
+    let a = peer("a", TieBreak::MsgId { wins_ties: false }, log.clone());
+    let b = peer("b", TieBreak::MsgId { wins_ties: true }, log.clone());
+
+    // After a chain, both sides count from the same MSG ID: B wins the tie.
+    raise(&a, "first");
+    drive(&a, &b, |_, _| 1);
+    a.take_outcome();
+    raise(&a, "from a");
+    raise(&b, "from b");
+    collide(&a, &b);
+    drive_both(&a, &b, |_| 1);
+    assert_eq!(
+        *log.borrow(),
+        vec!["b <- first", "a <- from b", "b <- from a"]
+    );
+}
+
+#[test]
+fn test_simultaneous_starts_on_lossy_link() {
+    let log = Log::default();
+    let pc = peer("pc", TieBreak::Win, log.clone());
+    let mcu = peer("mcu", TieBreak::Yield, log.clone());
+
+    raise(&pc, "from pc");
+    raise(&mcu, "from mcu");
+    collide(&pc, &mcu);
+    // Lose every third packet after the collision.
+    let mut count = 0;
+    let outcomes = drive_both(&pc, &mcu, |_| {
+        count += 1;
+        usize::from(count % 3 != 0)
+    });
+    assert_eq!(*log.borrow(), vec!["mcu <- from pc", "pc <- from mcu"]);
+    assert_eq!(
+        outcomes,
+        (
+            Some(TransactionOutcome::Completed(None)),
+            Some(TransactionOutcome::Completed(None))
+        )
+    );
+}
+
+#[test]
+fn test_peers_over_transport() {
+    let (mut pc_transport, mut mcu_transport) = MemoryTransport::pair();
+    let mcu = std::thread::spawn(move || {
+        let mcu = peer("mcu", TieBreak::Yield, Log::default());
+        for round in 0..5u8 {
+            raise(&mcu, &format!("button {round}"));
+            mcu.run_with(&mut mcu_transport).unwrap();
+            assert_eq!(
+                mcu.take_outcome(),
+                Some(TransactionOutcome::Completed(None))
+            );
+            // Serve the PC in the meantime.
+            mcu.run_with(&mut mcu_transport).unwrap();
+        }
+    });
+
+    let log = Log::default();
+    let pc = peer("pc", TieBreak::Win, log.clone());
+    for round in 0..5u8 {
+        // Serve the event of the MCU, then ask something back.
+        pc.run_with(&mut pc_transport).unwrap();
+        pc.perform(
+            Operation::Invoke,
+            Some(String::from("ECHOO")),
+            Some(vec![round]),
+        )
+        .unwrap();
+        pc.run_with(&mut pc_transport).unwrap();
+        assert_eq!(
+            pc.take_outcome(),
+            Some(TransactionOutcome::Completed(Some(vec![
+                b'm', b'c', b'u', round
+            ])))
+        );
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    mcu.join().unwrap();
+    assert_eq!(
+        *log.borrow(),
+        (0..5)
+            .map(|round| format!("pc <- button {round}"))
+            .collect::<Vec<_>>()
+    );
+}
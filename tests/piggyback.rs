@@ -0,0 +1,169 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use common::{device, drive, host_with, perform};
+use pk_command::PkCommandConfig;
+use pk_command::types::{Command, Operation, QueueOverflow, TransactionOutcome};
+
+/// Half the ACK timeout is well below the time `SLEEP` takes, and the `AWAIT` interval well above.
+fn config(piggyback: bool) -> PkCommandConfig {
+    PkCommandConfig::new(40, 500, 300, 64).with_piggyback(piggyback)
+}
+
+/// A chain to perform, with its parameter and result.
+type Chain = (Operation, &'static str, Option<Vec<u8>>, Option<Vec<u8>>);
+
+fn chains() -> Vec<Chain> {
+    vec![
+        (
+            Operation::RequireVariable,
+            "SPEED",
+            None,
+            Some(vec![1, 2, 3, 4]),
+        ),
+        (
+            Operation::SendVariable,
+            "SPEED",
+            Some(vec![1, 2, 3, 4]),
+            None,
+        ),
+        (
+            Operation::Invoke,
+            "ECHOO",
+            Some(vec![5; 300]),
+            Some(vec![5; 300]),
+        ),
+        (
+            Operation::Invoke,
+            "SLEEP",
+            Some(b"zz".to_vec()),
+            Some(b"zz".to_vec()),
+        ),
+    ]
+}
+
+#[test]
+fn test_piggyback_saves_packets() {
+    let (plain_host, plain_device) = (host_with(config(false)), device(config(false)));
+    let (host, device) = (host_with(config(true)), device(config(true)));
+    // A method which runs longer than half the ACK timeout is acknowledged with AWAIT instead.
+    let totals = [(16, 9), (14, 8), (36, 19), (16, 11)];
+    assert_eq!(chains().len(), totals.len());
+    for ((operation, object, param, result), total) in chains().into_iter().zip(totals) {
+        let (outcome, plain) =
+            perform(&plain_host, &plain_device, operation, object, param.clone());
+        assert_eq!(outcome, Some(TransactionOutcome::Completed(result.clone())));
+        let (outcome, packets) = perform(&host, &device, operation, object, param);
+        assert_eq!(outcome, Some(TransactionOutcome::Completed(result)));
+
+        // Only START, AWAIT and the last ENDTR are acknowledged, and QUERY is left out.
+        assert_eq!(
+            (plain.len(), packets.len()),
+            total,
+            "{}",
+            operation.to_name()
+        );
+        let count = |operation| {
+            packets
+                .iter()
+                .filter(|cmd| cmd.operation == operation)
+                .count()
+        };
+        assert_eq!(count(Operation::Acknowledge), 2 + count(Operation::Await));
+        assert!(!packets.iter().any(|cmd| cmd.operation == Operation::Query));
+        assert_eq!(
+            packets
+                .iter()
+                .filter(|cmd| cmd.object.as_deref() == Some("ENDTR"))
+                .count(),
+            1
+        );
+    }
+}
+
+#[test]
+fn test_piggyback_is_negotiated() {
+    let (host, device) = (host_with(config(true)), device(config(true)));
+    let (_, packets) = perform(&host, &device, Operation::RequireVariable, "SPEED", None);
+    assert_eq!(packets[0].data.as_deref(), Some(&b"PIGGYBACK"[..]));
+    assert_eq!(packets[1].data.as_deref(), Some(&b"PIGGYBACK"[..]));
+}
+
+#[test]
+fn test_piggyback_with_plain_peer() {
+    for (host_piggyback, device_piggyback) in [(true, false), (false, true)] {
+        let (host, device) = (
+            host_with(config(host_piggyback)),
+            device(config(device_piggyback)),
+        );
+        let (outcome, packets) = perform(&host, &device, Operation::RequireVariable, "SPEED", None);
+        assert_eq!(
+            outcome,
+            Some(TransactionOutcome::Completed(Some(vec![1, 2, 3, 4])))
+        );
+        assert_eq!(packets.len(), 16);
+    }
+}
+
+#[test]
+fn test_piggyback_on_lossy_link() {
+    let config = PkCommandConfig::new(20, 500, 30, 64).with_piggyback(true);
+    let (host, device) = (host_with(config.clone()), device(config));
+    // Lose a fourth of the packets at random, and the first answer to each ENDTR.
+    let (mut seed, mut lost) = (7u32, 0);
+    for (operation, object, param, result) in chains() {
+        host.perform(operation, Some(String::from(object)), param)
+            .unwrap();
+        let (mut endtr_sent, mut answered) = (false, false);
+        drive(&host, &device, |bytes, to_device| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let cmd = Command::parse(bytes).unwrap();
+            if to_device && cmd.operation == Operation::EndTransaction {
+                endtr_sent = true;
+            } else if !to_device && endtr_sent && !answered {
+                answered = true;
+                lost += 1;
+                return 0;
+            }
+            usize::from((seed >> 16) % 4 != 0)
+        });
+        assert_eq!(
+            host.take_outcome(),
+            Some(TransactionOutcome::Completed(result)),
+            "{}",
+            operation.to_name()
+        );
+    }
+    assert!(lost >= 4);
+}
+
+#[test]
+fn test_piggyback_with_express() {
+    // Express chains leave fewer packets to save, but still work together.
+    let config = config(true).with_express(true);
+    let (host, device) = (host_with(config.clone()), device(config));
+    let totals = [4, 4, 19, 7];
+    assert_eq!(chains().len(), totals.len());
+    for ((operation, object, param, result), total) in chains().into_iter().zip(totals) {
+        let (outcome, packets) = perform(&host, &device, operation, object, param);
+        assert_eq!(outcome, Some(TransactionOutcome::Completed(result)));
+        assert_eq!(packets.len(), total, "{}", operation.to_name());
+    }
+}
+
+#[test]
+fn test_piggyback_with_window() {
+    // Windows already send SDATA without waiting, so they are not used together.
+    let config = PkCommandConfig::default(64)
+        .with_piggyback(true)
+        .with_window(4)
+        .with_inbound_queue(8, QueueOverflow::DropOldest);
+    let (host, device) = (host_with(config.clone()), device(config));
+    for (operation, object, param, result) in chains() {
+        let (outcome, packets) = perform(&host, &device, operation, object, param);
+        assert_eq!(outcome, Some(TransactionOutcome::Completed(result)));
+        assert_eq!(packets[1].data.as_deref(), Some(&b"WINDOW=4"[..]));
+    }
+}
@@ -0,0 +1,366 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use common::{Pk, host, host_with, is, run_chain};
+use pk_command::types::{Backoff, Command, FailureOrigin, Operation, PkError, TransactionOutcome};
+use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable, PkPromise};
+
+const PAYLOAD: &[u8] = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua.";
+
+/// A Device with a `VARIA` variable and an `ECHOO` method, counting changes and invocations.
+fn device(changes: Arc<Mutex<Vec<Vec<u8>>>>, invocations: Arc<AtomicUsize>) -> Pk {
+    PkCommand::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![(
+            String::from("VARIA"),
+            None,
+            Box::new(move |value| changes.lock().unwrap().push(value)),
+        )]),
+        PkHashmapMethod::new(vec![(
+            String::from("ECHOO"),
+            Box::new(move |param| {
+                invocations.fetch_add(1, Ordering::SeqCst);
+                PkPromise::execute(|resolve| resolve(param.unwrap_or_default()))
+            }),
+        )]),
+    )
+}
+
+/// Drops the first `ACKNO` of every acknowledged command, in both directions.
+fn lose_first_acks() -> impl FnMut(&mut Vec<u8>, bool) -> usize {
+    let mut lost = HashSet::new();
+    move |bytes, host_to_device| {
+        let cmd = Command::parse(bytes).unwrap();
+        if cmd.operation == Operation::Acknowledge && lost.insert((cmd.object, host_to_device)) {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+#[test]
+fn test_lost_acks_invoke() {
+    let invocations = Arc::new(AtomicUsize::new(0));
+    let (host, device) = (host(), device(Arc::default(), invocations.clone()));
+
+    host.perform(
+        Operation::Invoke,
+        Some(String::from("ECHOO")),
+        Some(PAYLOAD.to_vec()),
+    )
+    .unwrap();
+    assert_eq!(
+        run_chain(&host, &device, lose_first_acks()),
+        Some(PAYLOAD.to_vec())
+    );
+    assert_eq!(invocations.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_lost_acks_send_variable() {
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let (host, device) = (host(), device(changes.clone(), Arc::default()));
+
+    host.perform(
+        Operation::SendVariable,
+        Some(String::from("VARIA")),
+        Some(PAYLOAD.to_vec()),
+    )
+    .unwrap();
+    assert_eq!(run_chain(&host, &device, lose_first_acks()), None);
+    assert_eq!(*changes.lock().unwrap(), vec![PAYLOAD.to_vec()]);
+
+    // The next chain is not disturbed by the retransmissions of the previous one.
+    host.perform(
+        Operation::RequireVariable,
+        Some(String::from("VARIA")),
+        None,
+    )
+    .unwrap();
+    assert_eq!(
+        run_chain(&host, &device, lose_first_acks()),
+        Some(PAYLOAD.to_vec())
+    );
+}
+
+#[test]
+fn test_duplicated_packets() {
+    // Every packet arrives twice, so every command is also acknowledged twice.
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let invocations = Arc::new(AtomicUsize::new(0));
+    let (host, device) = (host(), device(changes.clone(), invocations.clone()));
+
+    host.perform(
+        Operation::SendVariable,
+        Some(String::from("VARIA")),
+        Some(PAYLOAD.to_vec()),
+    )
+    .unwrap();
+    assert_eq!(run_chain(&host, &device, |_, _| 2), None);
+    assert_eq!(*changes.lock().unwrap(), vec![PAYLOAD.to_vec()]);
+
+    host.perform(
+        Operation::Invoke,
+        Some(String::from("ECHOO")),
+        Some(PAYLOAD.to_vec()),
+    )
+    .unwrap();
+    assert_eq!(run_chain(&host, &device, |_, _| 2), Some(PAYLOAD.to_vec()));
+    assert_eq!(invocations.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_no_errors_on_lossy_link() {
+    let (host, device) = (host(), device(Arc::default(), Arc::default()));
+    let mut errors = 0;
+    let mut lose = lose_first_acks();
+
+    host.perform(
+        Operation::Invoke,
+        Some(String::from("ECHOO")),
+        Some(PAYLOAD.to_vec()),
+    )
+    .unwrap();
+    let result = run_chain(&host, &device, |bytes, host_to_device| {
+        if is(bytes, Operation::Error) {
+            errors += 1;
+        }
+        lose(bytes, host_to_device)
+    });
+    assert_eq!(result, Some(PAYLOAD.to_vec()));
+    assert_eq!(errors, 0);
+}
+
+/// Polls `pk` alone, as if the peer was unplugged, until it gives up. Returns when each command
+/// was sent, and the outcome.
+fn unplugged(pk: &Pk) -> (Vec<(Operation, Instant)>, TransactionOutcome) {
+    let mut sent = Vec::new();
+    let start = Instant::now();
+    loop {
+        if let Some(cmd) = pk.poll() {
+            sent.push((cmd.operation, Instant::now()));
+        }
+        if let Some(outcome) = pk.take_outcome() {
+            return (sent, outcome);
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "still retransmitting"
+        );
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_retries_run_out() {
+    let host = host_with(PkCommandConfig::new(10, 500, 300, 64).with_retries(3, Backoff::Fixed));
+    host.perform(Operation::GetVersion, None, None).unwrap();
+    let (sent, outcome) = unplugged(&host);
+    // `START` and 3 retransmissions, then the same for `ERROR`.
+    let operations: Vec<_> = sent.iter().map(|(operation, _)| *operation).collect();
+    assert_eq!(
+        operations,
+        [[Operation::Start; 4], [Operation::Error; 4]].concat()
+    );
+    assert_eq!(
+        outcome,
+        TransactionOutcome::Failed {
+            reason: PkError::Timeout,
+            origin: FailureOrigin::Local,
+        }
+    );
+    assert_eq!(host.perform(Operation::GetVersion, None, None), Ok(()));
+}
+
+#[test]
+fn test_exponential_backoff() {
+    let host = host_with(PkCommandConfig::new(10, 500, 300, 64).with_retries(
+        4,
+        Backoff::Exponential {
+            max_timeout: Duration::from_millis(40),
+            jitter: false,
+        },
+    ));
+    host.perform(Operation::GetVersion, None, None).unwrap();
+    let (sent, _) = unplugged(&host);
+    let starts: Vec<_> = sent
+        .iter()
+        .filter(|(operation, _)| *operation == Operation::Start)
+        .map(|(_, at)| *at)
+        .collect();
+    assert_eq!(starts.len(), 5);
+    // 10ms, 20ms, 40ms, 40ms.
+    let gaps: Vec<_> = starts.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    for (gap, expected) in gaps.iter().zip([10, 20, 40, 40]) {
+        assert!(*gap >= Duration::from_millis(expected), "{gaps:?}");
+    }
+    assert!(gaps[0] < Duration::from_millis(30), "{gaps:?}");
+}
+
+#[test]
+fn test_jitter_stays_below_max_timeout() {
+    let max_timeout = Duration::from_millis(40);
+    let backoff = Backoff::Exponential {
+        max_timeout,
+        jitter: true,
+    };
+    for random in [0, 1, 128, 256, 1000, u32::MAX] {
+        let mut timeout = Duration::from_millis(10);
+        for _ in 0..6 {
+            timeout = backoff.next_timeout(timeout, random);
+            assert!(timeout <= max_timeout, "{timeout:?} with {random}");
+        }
+        assert_eq!(timeout, max_timeout);
+    }
+    // Below the limit, the jitter adds up to a quarter.
+    assert_eq!(
+        backoff.next_timeout(Duration::from_millis(8), 256),
+        Duration::from_millis(16) + Duration::from_millis(16) / 1024 * 256
+    );
+}
+
+/// Drives `host` and `device` like [`common::drive`], over a link that delays every packet by
+/// `delay`. Returns how many packets the Host sent.
+fn drive_delayed(host: &Pk, device: &Pk, delay: Duration) -> usize {
+    let mut sent = 0;
+    let mut to_device = VecDeque::new();
+    let mut to_host: VecDeque<(Instant, Vec<u8>)> = VecDeque::new();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        if to_host
+            .front()
+            .is_some_and(|(due, _)| *due <= Instant::now())
+        {
+            let _ = host.incoming_command(to_host.pop_front().unwrap().1);
+        }
+        if let Some(cmd) = host.poll() {
+            sent += 1;
+            to_device.push_back((Instant::now() + delay, cmd.to_bytes()));
+        }
+        if to_device
+            .front()
+            .is_some_and(|(due, _)| *due <= Instant::now())
+        {
+            let _ = device.incoming_command(to_device.pop_front().unwrap().1);
+        }
+        if let Some(cmd) = device.poll() {
+            to_host.push_back((Instant::now() + delay, cmd.to_bytes()));
+        }
+        if host.is_complete() && device.is_complete() && to_host.is_empty() && to_device.is_empty()
+        {
+            return sent;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    panic!("the chain did not finish");
+}
+
+#[test]
+fn test_adaptive_timeout() {
+    let host = host_with(PkCommandConfig::new(200, 2000, 1000, 64).with_adaptive_timeout(5, 1000));
+    let device = device(Arc::default(), Arc::default());
+    assert_eq!(host.retransmission_timeout(), Duration::from_millis(200));
+
+    // A fast link brings the timeout down.
+    host.perform(
+        Operation::Invoke,
+        Some(String::from("ECHOO")),
+        Some(PAYLOAD.to_vec()),
+    )
+    .unwrap();
+    assert_eq!(run_chain(&host, &device, |_, _| 1), Some(PAYLOAD.to_vec()));
+    let fast = host.retransmission_timeout();
+    assert!(fast >= Duration::from_millis(5), "{fast:?}");
+    assert!(fast < Duration::from_millis(50), "{fast:?}");
+
+    // A round trip of 60ms is too slow for it: the first commands are retransmitted, until the
+    // timeout has grown past the round-trip time.
+    host.perform(
+        Operation::Invoke,
+        Some(String::from("ECHOO")),
+        Some(PAYLOAD.to_vec()),
+    )
+    .unwrap();
+    drive_delayed(&host, &device, Duration::from_millis(30));
+    assert_eq!(host.get_return_data(), Some(PAYLOAD.to_vec()));
+    let slow = host.retransmission_timeout();
+    assert!(slow > Duration::from_millis(60), "{slow:?}");
+}
+
+#[test]
+fn test_retransmitted_commands_are_not_measured() {
+    let host = host_with(PkCommandConfig::new(100, 2000, 1000, 64).with_adaptive_timeout(5, 1000));
+    let device = device(Arc::default(), Arc::default());
+    host.perform(Operation::GetVersion, None, None).unwrap();
+
+    // The first START is lost.
+    assert!(host.poll().is_some());
+    let start = loop {
+        if let Some(cmd) = host.poll() {
+            break cmd;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    };
+    device.incoming_command(start.to_bytes()).unwrap();
+    host.incoming_command(device.poll().unwrap().to_bytes())
+        .unwrap();
+    // The ACKNO comes right away, but it may be for the first START: it is not measured, and
+    // the timeout stays doubled.
+    let next = host.poll().unwrap();
+    assert_eq!(host.retransmission_timeout(), Duration::from_millis(200));
+
+    // The next command is measured.
+    device.incoming_command(next.to_bytes()).unwrap();
+    host.incoming_command(device.poll().unwrap().to_bytes())
+        .unwrap();
+    host.poll();
+    assert!(host.retransmission_timeout() < Duration::from_millis(50));
+}
+
+#[test]
+fn test_retransmission_restarts_timer() {
+    let host = host_with(PkCommandConfig::new(20, 500, 300, 64).with_retries(3, Backoff::Fixed));
+    host.perform(Operation::GetVersion, None, None).unwrap();
+    let (sent, _) = unplugged(&host);
+    let starts: Vec<_> = sent
+        .iter()
+        .filter(|(operation, _)| *operation == Operation::Start)
+        .map(|(_, at)| *at)
+        .collect();
+    assert_eq!(starts.len(), 4);
+    // Each retransmission waits a full timeout again, not only the first one.
+    for pair in starts.windows(2) {
+        assert!(pair[1] - pair[0] >= Duration::from_millis(20), "{starts:?}");
+    }
+}
+
+#[test]
+fn test_lost_result_chunk_is_resent_alone() {
+    let (host, device) = (host(), device(Arc::default(), Arc::default()));
+    host.perform(
+        Operation::Invoke,
+        Some(String::from("ECHOO")),
+        Some(PAYLOAD.to_vec()),
+    )
+    .unwrap();
+    // The first chunk of the result is lost. Only that chunk is sent again, not the whole result.
+    let mut returned = Vec::new();
+    let result = run_chain(&host, &device, |bytes, host_to_device| {
+        if host_to_device || !is(bytes, Operation::Data) {
+            return 1;
+        }
+        returned.push(bytes.clone());
+        usize::from(returned.len() > 1)
+    });
+    assert_eq!(result, Some(PAYLOAD.to_vec()));
+    let chunks = HashSet::<_>::from_iter(&returned).len();
+    assert_eq!(returned.len(), chunks + 1);
+}
@@ -0,0 +1,363 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{Pk, drive, host, host_with, is};
+use pk_command::types::{
+    AccessMode, Command, FailureOrigin, Notification, Operation, PkError, TransactionOutcome,
+};
+use pk_command::{
+    PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable, PkVariableAccessor,
+};
+
+fn device(config: PkCommandConfig) -> Pk {
+    PkCommand::new(
+        config,
+        PkHashmapVariable::new(vec![
+            (
+                String::from("SPEED"),
+                Some(b"1200".to_vec()),
+                Box::new(|_| {}),
+            ),
+            (
+                String::from("TEMPR"),
+                Some(b"21".to_vec()),
+                Box::new(|_| {}),
+            ),
+            (String::from("PASSW"), None, Box::new(|_| {})),
+        ])
+        .restrict("PASSW", AccessMode::WriteOnly),
+        PkHashmapMethod::new(vec![]),
+    )
+}
+
+fn perform(
+    host: &Pk,
+    device: &Pk,
+    operation: Operation,
+    name: &str,
+    data: Option<Vec<u8>>,
+) -> TransactionOutcome {
+    host.perform(operation, Some(String::from(name)), data)
+        .unwrap();
+    drive(host, device, |_, _| 1);
+    host.take_outcome().unwrap()
+}
+
+fn subscribe(host: &Pk, device: &Pk, name: &str) -> TransactionOutcome {
+    host.subscribe(name, Duration::ZERO).unwrap();
+    drive(host, device, |_, _| 1);
+    host.take_outcome().unwrap()
+}
+
+fn set(device: &Pk, name: &str, value: &[u8]) {
+    device
+        .variables()
+        .set(String::from(name), value.to_vec())
+        .unwrap();
+}
+
+/// Drives both sides until they are idle, and returns how many packets were exchanged.
+fn exchange(host: &Pk, device: &Pk) -> usize {
+    let mut packets = 0;
+    drive(host, device, |_, _| {
+        packets += 1;
+        1
+    });
+    packets
+}
+
+fn notification(name: &str, value: &[u8]) -> Option<Notification> {
+    Some(Notification {
+        name: String::from(name),
+        value: value.to_vec(),
+    })
+}
+
+#[test]
+fn test_subscription_pushes_changes() {
+    let (host, device) = (
+        host(),
+        device(PkCommandConfig::default(64).with_subscriptions(8, 0)),
+    );
+
+    assert_eq!(
+        subscribe(&host, &device, "SPEED"),
+        TransactionOutcome::Completed(Some(b"1200".to_vec()))
+    );
+    // Nothing is pushed while the value does not change.
+    assert_eq!(exchange(&host, &device), 0);
+    assert_eq!(host.take_notification(), None);
+
+    set(&device, "SPEED", b"1500");
+    // The value fits in NOTIF: one packet and its ACKNO.
+    assert_eq!(exchange(&host, &device), 2);
+    assert_eq!(host.take_notification(), notification("SPEED", b"1500"));
+    assert_eq!(host.take_notification(), None);
+
+    // Variables without a subscription are not watched.
+    set(&device, "TEMPR", b"22");
+    assert_eq!(exchange(&host, &device), 0);
+}
+
+#[test]
+fn test_large_and_empty_notifications() {
+    // A 20-byte packet carries 6 bytes of data.
+    let host = host_with(PkCommandConfig::default(20));
+    let device = device(PkCommandConfig::default(20).with_subscriptions(8, 0));
+    subscribe(&host, &device, "SPEED");
+
+    let value: Vec<u8> = (0..20).collect();
+    set(&device, "SPEED", &value);
+    let mut operations = Vec::new();
+    drive(&host, &device, |bytes, to_device| {
+        if !to_device {
+            operations.push(Command::parse(bytes).unwrap().operation);
+        }
+        1
+    });
+    assert_eq!(
+        operations,
+        vec![
+            Operation::Notify,
+            Operation::Data,
+            Operation::Data,
+            Operation::Data,
+            Operation::Data,
+            Operation::EndTransaction,
+        ]
+    );
+    assert_eq!(host.take_notification(), notification("SPEED", &value));
+
+    set(&device, "SPEED", b"");
+    assert_eq!(exchange(&host, &device), 4);
+    assert_eq!(host.take_notification(), notification("SPEED", b""));
+}
+
+#[test]
+fn test_notifications_are_rate_limited() {
+    let (host, device) = (
+        host(),
+        device(PkCommandConfig::default(64).with_subscriptions(8, 50)),
+    );
+    // The Host asks for no interval, so the one of the Device applies.
+    subscribe(&host, &device, "SPEED");
+
+    let start = Instant::now();
+    let mut received = Vec::new();
+    let mut counter = 0u32;
+    while start.elapsed() < Duration::from_millis(220) {
+        counter += 1;
+        set(&device, "SPEED", counter.to_string().as_bytes());
+        if let Some(cmd) = device.poll() {
+            host.incoming_command(cmd.to_bytes()).unwrap();
+        }
+        if let Some(cmd) = host.poll() {
+            device.incoming_command(cmd.to_bytes()).unwrap();
+        }
+        while let Some(notification) = host.take_notification() {
+            received.push(notification.value);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    // At most once per 50ms.
+    assert!(
+        (2..=5).contains(&received.len()),
+        "{} notifications",
+        received.len()
+    );
+    assert!(received.windows(2).all(|pair| pair[0] != pair[1]));
+}
+
+#[test]
+fn test_unsubscribe() {
+    let (host, device) = (
+        host(),
+        device(PkCommandConfig::default(64).with_subscriptions(8, 0)),
+    );
+    subscribe(&host, &device, "SPEED");
+    subscribe(&host, &device, "TEMPR");
+
+    assert_eq!(
+        perform(&host, &device, Operation::Unsubscribe, "SPEED", None),
+        TransactionOutcome::Completed(None)
+    );
+    set(&device, "SPEED", b"1500");
+    set(&device, "TEMPR", b"25");
+    exchange(&host, &device);
+    assert_eq!(host.take_notification(), notification("TEMPR", b"25"));
+    assert_eq!(host.take_notification(), None);
+
+    // Unsubscribing twice is fine.
+    assert_eq!(
+        perform(&host, &device, Operation::Unsubscribe, "SPEED", None),
+        TransactionOutcome::Completed(None)
+    );
+}
+
+#[test]
+fn test_subscription_errors() {
+    let (host, device) = (
+        host(),
+        device(PkCommandConfig::default(64).with_subscriptions(1, 0)),
+    );
+
+    assert_eq!(
+        subscribe(&host, &device, "NOVAR"),
+        TransactionOutcome::Failed {
+            reason: PkError::VariableNotFound(String::from("NOVAR")),
+            origin: FailureOrigin::Remote,
+        }
+    );
+    assert_eq!(
+        subscribe(&host, &device, "PASSW"),
+        TransactionOutcome::Failed {
+            reason: PkError::AccessDenied(String::from("PASSW")),
+            origin: FailureOrigin::Remote,
+        }
+    );
+    assert_eq!(
+        perform(
+            &host,
+            &device,
+            Operation::Subscribe,
+            "SPEED",
+            Some(b"soon".to_vec())
+        ),
+        TransactionOutcome::Failed {
+            reason: PkError::Remote(String::from("INVALID_DATA interval in milliseconds")),
+            origin: FailureOrigin::Remote,
+        }
+    );
+    subscribe(&host, &device, "SPEED");
+    // Subscribing again does not take another slot.
+    subscribe(&host, &device, "SPEED");
+    assert_eq!(
+        subscribe(&host, &device, "TEMPR"),
+        TransactionOutcome::Failed {
+            reason: PkError::Remote(String::from("VARIABLE too many subscriptions")),
+            origin: FailureOrigin::Remote,
+        }
+    );
+}
+
+#[test]
+fn test_host_start_preempts_notification() {
+    let (host, device) = (
+        host(),
+        device(PkCommandConfig::default(64).with_subscriptions(8, 0)),
+    );
+    subscribe(&host, &device, "SPEED");
+
+    set(&device, "SPEED", b"1500");
+    // The Device pushes a notification while the Host starts a chain.
+    host.perform(
+        Operation::RequireVariable,
+        Some(String::from("TEMPR")),
+        None,
+    )
+    .unwrap();
+    let notif = device.poll().unwrap();
+    assert_eq!(notif.operation, Operation::Notify);
+    host.incoming_command(notif.to_bytes()).unwrap();
+
+    drive(&host, &device, |_, _| 1);
+    assert_eq!(
+        host.take_outcome(),
+        Some(TransactionOutcome::Completed(Some(b"21".to_vec())))
+    );
+    // The preempted notification is sent again once the chain is over.
+    exchange(&host, &device);
+    assert_eq!(host.take_notification(), notification("SPEED", b"1500"));
+}
+
+#[test]
+fn test_lost_notification_ack_is_not_duplicated() {
+    let (host, device) = (
+        host(),
+        device(PkCommandConfig::default(64).with_subscriptions(8, 0)),
+    );
+    subscribe(&host, &device, "SPEED");
+
+    set(&device, "SPEED", b"1500");
+    let mut dropped = false;
+    drive(&host, &device, |bytes, to_device| {
+        if to_device && is(bytes, Operation::Acknowledge) && !dropped {
+            dropped = true;
+            return 0;
+        }
+        1
+    });
+    assert!(dropped);
+    assert_eq!(host.take_notification(), notification("SPEED", b"1500"));
+    assert_eq!(host.take_notification(), None);
+}
+
+#[test]
+fn test_notifications_keep_latest_value() {
+    let (host, device) = (
+        host(),
+        device(PkCommandConfig::default(64).with_subscriptions(8, 0)),
+    );
+    subscribe(&host, &device, "SPEED");
+
+    set(&device, "SPEED", b"1300");
+    exchange(&host, &device);
+    set(&device, "SPEED", b"1400");
+    exchange(&host, &device);
+    // Only the latest value is kept until it is taken.
+    assert_eq!(host.take_notification(), notification("SPEED", b"1400"));
+    assert_eq!(host.take_notification(), None);
+}
+
+#[test]
+fn test_handshake_cancels_subscriptions() {
+    let (host, device) = (
+        host(),
+        device(PkCommandConfig::default(64).with_subscriptions(8, 0)),
+    );
+    subscribe(&host, &device, "SPEED");
+
+    host.handshake().unwrap();
+    drive(&host, &device, |_, _| 1);
+    host.take_outcome();
+
+    set(&device, "SPEED", b"1500");
+    assert_eq!(exchange(&host, &device), 0);
+    assert_eq!(host.take_notification(), None);
+}
+
+#[test]
+fn test_notification_with_tiny_packet_limit() {
+    let host = host();
+    let device = device(PkCommandConfig::default(12).with_subscriptions(8, 0));
+    subscribe(&host, &device, "SPEED");
+
+    set(&device, "SPEED", b"1500");
+    let mut operations = Vec::new();
+    drive(&host, &device, |bytes, to_device| {
+        operations.push((to_device, Command::parse(bytes).unwrap().operation));
+        1
+    });
+    // Not even one byte fits, so the Device gives up on the notification instead of underflowing.
+    assert_eq!(
+        operations,
+        vec![
+            (false, Operation::Notify),
+            (true, Operation::Acknowledge),
+            (false, Operation::Error),
+            (true, Operation::Acknowledge),
+        ]
+    );
+    assert_eq!(host.take_notification(), None);
+    assert_eq!(
+        device.take_outcome(),
+        Some(TransactionOutcome::Failed {
+            reason: PkError::Incompatible(String::from("packet limit 12 is too small")),
+            origin: FailureOrigin::Local,
+        })
+    );
+}
@@ -0,0 +1,225 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+use std::thread;
+
+use pk_command::transport::{MemoryTransport, PkTransport, StreamTransport, UdpTransport};
+use pk_command::types::{Backoff, Operation, PkError, QueueOverflow};
+use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable, PkPromise};
+
+const LONGV: &[u8] = b"(this is a long value)Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua.";
+
+/// Serves `chains` transaction chains on the device side, then returns.
+fn spawn_device<T: PkTransport + Send + 'static>(
+    mut transport: T,
+    chains: usize,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let device = PkCommand::<_, _, std::time::Instant>::new(
+            PkCommandConfig::default(64),
+            PkHashmapVariable::new(vec![(
+                String::from("LONGV"),
+                Some(LONGV.to_vec()),
+                Box::new(|_| {}),
+            )]),
+            PkHashmapMethod::new(vec![(
+                String::from("ECHOO"),
+                Box::new(|param| PkPromise::execute(|resolve| resolve(param.unwrap_or_default()))),
+            )]),
+        );
+        for _ in 0..chains {
+            device.run_with(&mut transport).unwrap();
+        }
+    })
+}
+
+/// Runs a `REQUV` and an `INVOK` over the given pair of transports.
+fn exercise<T: PkTransport + Send + 'static>(mut host_transport: T, device_transport: T) {
+    let device = spawn_device(device_transport, 2);
+    let host = PkCommand::<_, _, std::time::Instant>::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![]),
+        PkHashmapMethod::new(vec![]),
+    );
+
+    host.perform(
+        Operation::RequireVariable,
+        Some(String::from("LONGV")),
+        None,
+    )
+    .unwrap();
+    host.run_with(&mut host_transport).unwrap();
+    assert_eq!(host.get_return_data(), Some(LONGV.to_vec()));
+
+    host.perform(
+        Operation::Invoke,
+        Some(String::from("ECHOO")),
+        Some(b"over the wire".to_vec()),
+    )
+    .unwrap();
+    host.run_with(&mut host_transport).unwrap();
+    assert_eq!(host.get_return_data(), Some(b"over the wire".to_vec()));
+
+    device.join().unwrap();
+}
+
+#[test]
+fn test_memory_transport() {
+    let (host, device) = MemoryTransport::pair();
+    exercise(host, device);
+}
+
+#[test]
+fn test_memory_transport_disconnect() {
+    let (mut host, device) = MemoryTransport::pair();
+    drop(device);
+    assert!(host.recv().is_err());
+    assert!(host.send(b"!!START".to_vec()).is_err());
+}
+
+#[test]
+fn test_udp_transport() {
+    let host = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let device = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    host.connect(device.local_addr().unwrap()).unwrap();
+    device.connect(host.local_addr().unwrap()).unwrap();
+    exercise(
+        UdpTransport::new(host).unwrap(),
+        UdpTransport::new(device).unwrap(),
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_datagram_transport() {
+    use pk_command::transport::UnixDatagramTransport;
+
+    let (host, device) = std::os::unix::net::UnixDatagram::pair().unwrap();
+    exercise(
+        UnixDatagramTransport::new(host).unwrap(),
+        UnixDatagramTransport::new(device).unwrap(),
+    );
+}
+
+#[test]
+fn test_stream_transport() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (device, _) = listener.accept().unwrap();
+    for stream in [&host, &device] {
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_millis(1)))
+            .unwrap();
+    }
+    exercise(StreamTransport::new(host), StreamTransport::new(device));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_stream_transport_with_framers() {
+    use pk_command::types::framing::{CobsFramer, SlipFramer};
+
+    let (host, device) = std::os::unix::net::UnixStream::pair().unwrap();
+    for stream in [&host, &device] {
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_millis(1)))
+            .unwrap();
+    }
+    exercise(
+        StreamTransport::with_framer(host.try_clone().unwrap(), CobsFramer::new(64)),
+        StreamTransport::with_framer(device.try_clone().unwrap(), CobsFramer::new(64)),
+    );
+    exercise(
+        StreamTransport::with_framer(host, SlipFramer::new(64)),
+        StreamTransport::with_framer(device, SlipFramer::new(64)),
+    );
+}
+
+#[test]
+fn test_stream_transport_reassembles_packets() {
+    // Two packets arriving in one read, followed by an incomplete one.
+    let mut bytes = vec![0, 7];
+    bytes.extend_from_slice(b"!!START");
+    bytes.extend_from_slice(&[0, 13]);
+    bytes.extend_from_slice(b"!!ACKNO START");
+    bytes.extend_from_slice(&[0, 5]);
+    bytes.extend_from_slice(b"\"!EN");
+
+    let mut transport = StreamTransport::new(std::io::Cursor::new(bytes));
+    assert_eq!(transport.recv().unwrap(), Some(b"!!START".to_vec()));
+    assert_eq!(transport.recv().unwrap(), Some(b"!!ACKNO START".to_vec()));
+    // The stream is exhausted before the last packet is complete.
+    assert!(transport.recv().is_err());
+}
+
+/// A transport which never waits: it receives `packet` (or nothing) right away, every time, and
+/// throws away whatever is sent.
+struct Restless {
+    packet: Option<Vec<u8>>,
+    recvs: usize,
+}
+
+impl PkTransport for Restless {
+    fn send(&mut self, _packet: Vec<u8>) -> Result<(), PkError> {
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, PkError> {
+        self.recvs += 1;
+        Ok(self.packet.clone())
+    }
+}
+
+/// A Host with no variables or methods, and no peer.
+fn lone_host(
+    config: PkCommandConfig,
+) -> PkCommand<PkHashmapVariable, PkHashmapMethod, std::time::Instant> {
+    PkCommand::new(
+        config,
+        PkHashmapVariable::new(vec![]),
+        PkHashmapMethod::new(vec![]),
+    )
+}
+
+#[test]
+fn test_run_with_sleeps_when_idle() {
+    let pk = lone_host(
+        PkCommandConfig::new(10, 500, 300, 64)
+            .with_retries(1, Backoff::Fixed)
+            .with_idle_wait(5),
+    );
+    let mut transport = Restless {
+        packet: None,
+        recvs: 0,
+    };
+    pk.perform(Operation::GetVersion, None, None).unwrap();
+    // Nobody answers: `START` and `ERROR` are sent twice each, about 40ms in all.
+    pk.run_with(&mut transport).unwrap();
+    assert!(transport.recvs < 50, "{} receptions", transport.recvs);
+}
+
+#[test]
+fn test_run_with_reports_full_queue() {
+    let pk = lone_host(PkCommandConfig::default(64).with_inbound_queue(1, QueueOverflow::Reject));
+    pk.perform(Operation::GetVersion, None, None).unwrap();
+    assert!(pk.poll().is_some());
+    // Polling after the cancellation sends `ERROR` without taking a command from the queue, so
+    // the second `ACKNO` does not fit in it.
+    assert!(pk.cancel());
+    let mut transport = Restless {
+        packet: Some(b"!!ACKNO START".to_vec()),
+        recvs: 0,
+    };
+    assert_eq!(pk.run_with(&mut transport), Err(PkError::QueueFull));
+}
+
+#[test]
+fn test_run_with_drops_malformed_packets() {
+    let pk = lone_host(PkCommandConfig::new(10, 500, 300, 64).with_retries(1, Backoff::Fixed));
+    let mut transport = Restless {
+        packet: Some(b"garbage".to_vec()),
+        recvs: 0,
+    };
+    pk.perform(Operation::GetVersion, None, None).unwrap();
+    assert_eq!(pk.run_with(&mut transport), Ok(()));
+}
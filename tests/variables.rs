@@ -0,0 +1,94 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use common::{Pk, drive, host};
+use pk_command::types::{FailureOrigin, Operation, PkError, TransactionOutcome, VariableValue};
+use pk_command::{PkCommand, PkCommandConfig, PkHashmapMethod, PkHashmapVariable};
+
+fn device() -> Pk {
+    PkCommand::new(
+        PkCommandConfig::default(64),
+        PkHashmapVariable::new(vec![
+            (
+                String::from("VARIA"),
+                Some(b"value".to_vec()),
+                Box::new(|_| {}),
+            ),
+            (String::from("EMPTY"), None, Box::new(|_| {})),
+        ]),
+        PkHashmapMethod::new(vec![]),
+    )
+}
+
+fn request(host: &Pk, device: &Pk, name: &str) -> TransactionOutcome {
+    host.perform(Operation::RequireVariable, Some(String::from(name)), None)
+        .unwrap();
+    drive(host, device, |_, _| 1);
+    host.take_outcome().unwrap()
+}
+
+#[test]
+fn test_request_value() {
+    let (host, device) = (host(), device());
+    assert_eq!(
+        request(&host, &device, "VARIA").into_variable(),
+        Ok(VariableValue::Value(b"value".to_vec()))
+    );
+}
+
+#[test]
+fn test_request_empty() {
+    let (host, device) = (host(), device());
+    assert_eq!(
+        request(&host, &device, "EMPTY").into_variable(),
+        Ok(VariableValue::Empty)
+    );
+}
+
+#[test]
+fn test_request_not_found() {
+    let (host, device) = (host(), device());
+    let outcome = request(&host, &device, "NOVAR");
+    assert_eq!(
+        outcome,
+        TransactionOutcome::Failed {
+            reason: PkError::VariableNotFound(String::from("NOVAR")),
+            origin: FailureOrigin::Remote,
+        }
+    );
+    assert_eq!(outcome.into_variable(), Ok(VariableValue::NotFound));
+
+    // The chain after the failed one is not affected.
+    assert_eq!(
+        request(&host, &device, "VARIA").into_variable(),
+        Ok(VariableValue::Value(b"value".to_vec()))
+    );
+}
+
+#[test]
+fn test_other_failures_are_errors() {
+    let outcome = TransactionOutcome::Failed {
+        reason: PkError::Timeout,
+        origin: FailureOrigin::Local,
+    };
+    assert_eq!(outcome.into_variable(), Err(PkError::Timeout));
+    assert_eq!(
+        PkError::from_remote(&PkError::VariableNotFound(String::from("VARIA")).to_wire()),
+        PkError::VariableNotFound(String::from("VARIA"))
+    );
+    assert_eq!(
+        PkError::from_remote("out of paper"),
+        PkError::Remote(String::from("out of paper"))
+    );
+    assert_eq!(
+        PkError::from_remote("NOT_FOUND"),
+        PkError::VariableNotFound(String::new())
+    );
+    // The Display text is not a wire contract.
+    assert_eq!(
+        PkError::from_remote(&PkError::VariableNotFound(String::from("VARIA")).to_string()),
+        PkError::Remote(String::from("variable not found: VARIA"))
+    );
+}
@@ -0,0 +1,180 @@
+#![cfg(feature = "std")]
+#![cfg(test)]
+
+mod common;
+
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use common::{Pk, device, drive, host_with, is};
+use pk_command::PkCommandConfig;
+use pk_command::types::{Checksum, Command, Operation, QueueOverflow, TransactionOutcome};
+
+/// 2 KB, about 40 `SDATA` packets of 64 bytes.
+fn payload() -> Vec<u8> {
+    (0..2048u32).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+fn config(window: u16) -> PkCommandConfig {
+    PkCommandConfig::default(64)
+        .with_window(window)
+        .with_inbound_queue(16, QueueOverflow::DropOldest)
+}
+
+fn echo(host: &Pk) {
+    host.perform(
+        Operation::Invoke,
+        Some(String::from("ECHOO")),
+        Some(payload()),
+    )
+    .unwrap();
+}
+
+/// Drives `host` and `device` like [`drive`], over a link that delays every packet by `delay`.
+fn drive_delayed(host: &Pk, device: &Pk, delay: Duration) {
+    let mut to_device: VecDeque<(Instant, Vec<u8>)> = VecDeque::new();
+    let mut to_host: VecDeque<(Instant, Vec<u8>)> = VecDeque::new();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        if to_host
+            .front()
+            .is_some_and(|(due, _)| *due <= Instant::now())
+        {
+            let _ = host.incoming_command(to_host.pop_front().unwrap().1);
+        }
+        if let Some(cmd) = host.poll() {
+            to_device.push_back((Instant::now() + delay, cmd.to_bytes()));
+        }
+        if to_device
+            .front()
+            .is_some_and(|(due, _)| *due <= Instant::now())
+        {
+            let _ = device.incoming_command(to_device.pop_front().unwrap().1);
+        }
+        if let Some(cmd) = device.poll() {
+            to_host.push_back((Instant::now() + delay, cmd.to_bytes()));
+        }
+        if host.is_complete() && device.is_complete() && to_host.is_empty() && to_device.is_empty()
+        {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    panic!("the chain did not finish");
+}
+
+/// Runs an `ECHOO` chain over a link with a round trip of 10ms, and returns how long it took.
+fn timed_echo(host: &Pk, device: &Pk) -> Duration {
+    echo(host);
+    let start = Instant::now();
+    drive_delayed(host, device, Duration::from_millis(5));
+    assert_eq!(host.get_return_data(), Some(payload()));
+    start.elapsed()
+}
+
+#[test]
+fn test_window_saves_round_trips() {
+    let plain = timed_echo(&host_with(config(1)), &device(config(1)));
+    let windowed = timed_echo(&host_with(config(8)), &device(config(8)));
+    assert!(windowed * 2 < plain, "{windowed:?} vs {plain:?}");
+}
+
+#[test]
+fn test_window_is_negotiated() {
+    let (host, device) = (host_with(config(16)), device(config(4)));
+    echo(&host);
+    let mut ack_start = None;
+    drive(&host, &device, |bytes, _| {
+        let cmd = Command::parse(bytes).unwrap();
+        if cmd.operation == Operation::Acknowledge
+            && cmd.object.as_deref() == Some("START")
+            && ack_start.is_none()
+        {
+            ack_start = cmd.data;
+        }
+        1
+    });
+    // The Device accepts the smaller window.
+    assert_eq!(ack_start.as_deref(), Some(&b"WINDOW=4"[..]));
+    assert_eq!(host.get_return_data(), Some(payload()));
+}
+
+#[test]
+fn test_only_lost_chunks_are_retransmitted() {
+    for (host_window, device_window) in [(8, 8), (1, 1)] {
+        let (host, device) = (
+            host_with(config(host_window)),
+            device(config(device_window)),
+        );
+        echo(&host);
+        // Lose the first copy of every fifth SDATA, in both directions.
+        let mut seen = HashSet::new();
+        let (mut sent, mut lost) = (0, 0);
+        drive(&host, &device, |bytes, to_device| {
+            let cmd = Command::parse(bytes).unwrap();
+            if cmd.operation != Operation::Data {
+                return 1;
+            }
+            sent += 1;
+            if seen.insert((cmd.msg_id, to_device)) && seen.len() % 5 == 0 {
+                lost += 1;
+                return 0;
+            }
+            1
+        });
+        assert_eq!(host.get_return_data(), Some(payload()));
+        assert_eq!(sent, seen.len() + lost, "window of {host_window}");
+    }
+}
+
+#[test]
+fn test_window_on_lossy_link() {
+    let config = PkCommandConfig::new(20, 500, 300, 64)
+        .with_window(8)
+        .with_inbound_queue(16, QueueOverflow::DropOldest)
+        .with_checksum(Checksum::Crc16);
+    let (host, device) = (host_with(config.clone()), device(config));
+    for _ in 0..3 {
+        echo(&host);
+        // Lose every third packet, and corrupt every seventh SDATA.
+        let (mut count, mut chunks) = (0, 0);
+        drive(&host, &device, |bytes, _| {
+            count += 1;
+            if is(bytes, Operation::Data) {
+                chunks += 1;
+                if chunks % 7 == 0 {
+                    let last = bytes.len() - 1;
+                    bytes[last] ^= 0xFF;
+                }
+            }
+            usize::from(count % 3 != 0)
+        });
+        assert_eq!(
+            host.take_outcome(),
+            Some(TransactionOutcome::Completed(Some(payload())))
+        );
+    }
+}
+
+#[test]
+fn test_window_with_plain_peer() {
+    // Either side may not enable windows: the transfers fall back to one packet at a time.
+    for (host_window, device_window) in [(8, 1), (1, 8)] {
+        let (host, device) = (
+            host_with(config(host_window)),
+            device(config(device_window)),
+        );
+        echo(&host);
+        let mut unacknowledged = 0;
+        drive(&host, &device, |bytes, _| {
+            if is(bytes, Operation::Acknowledge) {
+                unacknowledged = 0;
+            } else {
+                unacknowledged += 1;
+                assert_eq!(unacknowledged, 1);
+            }
+            1
+        });
+        assert_eq!(host.get_return_data(), Some(payload()));
+    }
+}